@@ -0,0 +1,24 @@
+#![no_main]
+// Types in `proto` use the names from the DNS RFCs, not Rust casing.
+#![allow(clippy::upper_case_acronyms)]
+// Only the decoder and encoder are used here.
+#![allow(dead_code)]
+
+// `rdns` is a binary crate, so the module is included directly.
+#[path = "../../src/proto.rs"]
+mod proto;
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+use proto::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(packet) = Packet::decode(Bytes::copy_from_slice(data)) else {
+        return;
+    };
+
+    let mut buf = Vec::new();
+    packet.encode(&mut buf);
+    assert_eq!(buf.len(), packet.encoded_len());
+});
@@ -0,0 +1,98 @@
+//! Reusable buffers for the UDP hot paths.
+//!
+//! Buffers are taken from a global pool and returned to it when dropped.
+//! Received datagrams are split off as [`Bytes`] sharing the allocation of the
+//! buffer, which is reused once all of them are dropped. In the steady state
+//! neither receiving nor sending a datagram allocates.
+
+use std::ops::{Deref, DerefMut};
+
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+
+/// Capacity reserved for receiving a single datagram.
+pub const RECV_SIZE: usize = 1500;
+
+/// Maximum number of idle buffers kept in the pool.
+const MAX_POOLED: usize = 1024;
+
+/// Buffers that grew beyond this capacity are freed instead of being pooled.
+const MAX_CAPACITY: usize = 64 * 1024;
+
+static POOL: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+
+/// Takes a buffer from the pool, allocating a new one if the pool is empty.
+///
+/// The buffer is always empty, but may not have any capacity left.
+pub fn get() -> PooledBuf {
+    let buf = POOL.lock().pop().unwrap_or_default();
+    PooledBuf { buf }
+}
+
+/// A buffer that is returned to the pool when dropped.
+#[derive(Debug)]
+pub struct PooledBuf {
+    buf: BytesMut,
+}
+
+impl PooledBuf {
+    /// Splits off the contents of the buffer.
+    ///
+    /// The allocation is shared with the returned [`Bytes`] and reused by the
+    /// pool once they are dropped.
+    pub fn take(&mut self) -> Bytes {
+        self.buf.split().freeze()
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if self.buf.capacity() > MAX_CAPACITY {
+            return;
+        }
+
+        self.buf.clear();
+
+        let mut pool = POOL.lock();
+        if pool.len() < MAX_POOLED {
+            pool.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get;
+
+    #[test]
+    fn take_reuses_allocation() {
+        let mut buf = get();
+        buf.reserve(1500);
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+
+        let bytes = buf.take();
+        assert_eq!(bytes, &b"hello"[..]);
+        assert_eq!(bytes.as_ptr(), ptr);
+        drop(bytes);
+
+        // The split off bytes were dropped, the buffer starts over at the
+        // beginning of its allocation.
+        buf.reserve(1500);
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+}
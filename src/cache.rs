@@ -8,36 +8,63 @@ use crate::proto::{Class, Fqdn, Question, RecordData, Type};
 
 #[derive(Debug, Default)]
 pub struct Cache {
-    entries: RwLock<HashMap<Question, Resource>>,
-    expiration: RwLock<BTreeMap<Instant, Question>>,
+    entries: RwLock<HashMap<Key, Resource>>,
+    expiration: RwLock<BTreeMap<Instant, Key>>,
     wakeup: Notify,
 }
 
+/// Answers to queries with the DO bit include DNSSEC records, so they are
+/// cached apart from the answers to queries without it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    question: Question,
+    dnssec_ok: bool,
+}
+
 impl Cache {
-    pub fn get(&self, question: &Question) -> Option<Resource> {
-        self.entries.read().get(question).cloned()
+    pub fn get(&self, question: &Question, dnssec_ok: bool) -> Option<Resource> {
+        let key = Key {
+            question: question.clone(),
+            dnssec_ok,
+        };
+        self.entries.read().get(&key).cloned()
     }
 
-    pub fn insert(&self, resource: Resource) {
-        let question = Question {
-            name: resource.name.clone(),
-            qtype: resource.r#type,
-            qclass: resource.class,
+    pub fn insert(&self, resource: Resource, dnssec_ok: bool) {
+        let key = Key {
+            question: Question {
+                name: resource.name.clone(),
+                qtype: resource.r#type,
+                qclass: resource.class,
+            },
+            dnssec_ok,
         };
 
         self.expiration
             .write()
-            .insert(resource.valid_until, question.clone());
-        self.entries.write().insert(question, resource);
+            .insert(resource.valid_until, key.clone());
+        self.entries.write().insert(key, resource);
         self.wakeup.notify_one();
     }
 
-    pub fn remove_first(&self) -> Option<Resource> {
-        if let Some((_, question)) = self.expiration.write().pop_first() {
-            self.entries.write().remove(&question)
-        } else {
-            None
+    /// Removes all entries that expired by `now`.
+    pub fn remove_expired(&self, now: Instant) -> Vec<Resource> {
+        let mut expiration = self.expiration.write();
+        let mut entries = self.entries.write();
+
+        let mut expired = Vec::new();
+        while let Some(entry) = expiration.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            expired.extend(entries.remove(&entry.remove()));
         }
+        expired
+    }
+
+    /// Returns the number of entries waiting for their expiration.
+    pub fn expiration_len(&self) -> usize {
+        self.expiration.read().len()
     }
 
     pub fn next_expiration(&self) -> Option<Instant> {
@@ -60,3 +87,89 @@ impl Resource {
         self.valid_until - Instant::now()
     }
 }
+
+/// Removes duplicate records from `resources`, keeping the first occurrence.
+///
+/// Records are duplicates if their name, type, class and data are equal. The
+/// TTL is not considered.
+pub fn dedup(resources: &mut Vec<Resource>) {
+    let mut index = 0;
+    while index < resources.len() {
+        let (head, tail) = resources.split_at(index);
+        let resource = &tail[0];
+        let duplicate = head.iter().any(|other| {
+            other
+                .name
+                .as_bytes()
+                .eq_ignore_ascii_case(resource.name.as_bytes())
+                && other.r#type == resource.r#type
+                && other.class == resource.class
+                && other.data == resource.data
+        });
+
+        if duplicate {
+            resources.remove(index);
+        } else {
+            index += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use crate::proto::{Class, Fqdn, RecordData, Type};
+
+    use super::{dedup, Cache, Resource};
+
+    #[test]
+    fn dedup_resources() {
+        let resource = |name: &str, addr: Ipv4Addr, ttl: u64| Resource {
+            name: Fqdn(name.as_bytes().to_vec()),
+            r#type: Type::A,
+            class: Class::In,
+            data: RecordData::A(addr),
+            valid_until: Instant::now() + Duration::from_secs(ttl),
+        };
+
+        let mut resources = vec![
+            resource("example.com.", Ipv4Addr::new(192, 0, 2, 1), 60),
+            resource("example.com.", Ipv4Addr::new(192, 0, 2, 2), 60),
+            resource("EXAMPLE.com.", Ipv4Addr::new(192, 0, 2, 1), 30),
+        ];
+        dedup(&mut resources);
+
+        assert_eq!(resources.len(), 2);
+        assert_eq!(
+            resources[0].data,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(
+            resources[1].data,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 2))
+        );
+    }
+
+    #[test]
+    fn removes_all_expired() {
+        let now = Instant::now();
+        let resource = |name: &str, valid_until| Resource {
+            name: Fqdn(name.as_bytes().to_vec()),
+            r#type: Type::A,
+            class: Class::In,
+            data: RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            valid_until,
+        };
+
+        let cache = Cache::default();
+        cache.insert(resource("a.example.", now - Duration::from_secs(2)), false);
+        cache.insert(resource("b.example.", now - Duration::from_secs(1)), false);
+        cache.insert(resource("c.example.", now + Duration::from_secs(60)), false);
+
+        assert_eq!(cache.remove_expired(now).len(), 2);
+        assert_eq!(cache.expiration_len(), 1);
+        assert!(cache.remove_expired(now).is_empty());
+    }
+}
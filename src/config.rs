@@ -1,46 +1,1834 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
 
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use crate::upstream::proxy::Proxy;
+
+/// The version of the config layout understood by this build.
+///
+/// Older layouts are migrated when the config is loaded.
+pub const CONFIG_VERSION: u64 = 2;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
-    pub bind: SocketAddr,
+    /// Version of the config layout. Configs without a version are version 0.
+    #[serde(default)]
+    pub version: u64,
+    /// Addresses of the plain UDP and TCP frontends, either a single address
+    /// or a list, e.g. to bind IPv4 and IPv6 separately.
+    #[serde(deserialize_with = "deserialize_binds")]
+    pub bind: Vec<Bind>,
+    /// Upstreams per zone. Zone names in all per-zone maps are lowercased.
+    #[serde(deserialize_with = "deserialize_zones")]
     pub zones: HashMap<String, Vec<ResolverConfig>>,
     pub http: Http,
+    #[serde(default)]
+    pub frontend: Frontend,
+    #[serde(default)]
+    pub chaos: Chaos,
+    /// Shadow upstreams per zone to compare the answers of the zone against.
+    #[serde(default, deserialize_with = "deserialize_zones")]
+    pub diff: HashMap<String, Diff>,
+    /// How the upstreams are queried and their answers cached per zone.
+    /// Zones without an entry use the defaults of [`Strategy`].
+    #[serde(default, deserialize_with = "deserialize_zones")]
+    pub strategy: HashMap<String, Strategy>,
+    /// Zones that are answered locally and never forwarded, e.g. `internal.`
+    /// or the zones of RFC 6303. Zones below them may still be forwarded.
+    #[serde(default, deserialize_with = "deserialize_zones")]
+    pub local_zones: HashMap<String, LocalZone>,
+    #[serde(default)]
+    pub local: Local,
+    #[serde(default)]
+    pub ddr: Ddr,
+    #[serde(default)]
+    pub allowlist: Allowlist,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Seconds that queries and connections in progress get to complete on shutdown.
+    #[serde(default = "Config::default_grace_period")]
+    pub grace_period: u64,
+    /// DSCP value that responses to clients and queries to UDP upstreams are marked with.
+    #[serde(default)]
+    pub dscp: Option<Dscp>,
+    /// Name of the user to switch to once all listeners are bound.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Name of the group to switch to once all listeners are bound. Defaults
+    /// to the primary group of `user`.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Whether to restrict the syscalls of the process once all listeners
+    /// are bound, and the files to the ones named by the config. Requires
+    /// the `sandbox` feature.
+    #[serde(default)]
+    pub sandbox: bool,
+    /// Faults injected into the exchanges with upstreams, keyed by the upstream address.
+    #[cfg(feature = "fault-injection")]
+    #[serde(default)]
+    pub faults: HashMap<String, Faults>,
 }
 
 impl Config {
-    pub fn from_file<P>(path: P) -> Self
+    fn default_grace_period() -> u64 {
+        5
+    }
+
+    /// Returns the certificates, keys and other files named by the config.
+    ///
+    /// Files that are replaced while running, like the `resolv.conf` of
+    /// system upstreams, are returned as their directory.
+    pub fn files(&self) -> Vec<&Path> {
+        let mut files = Vec::new();
+
+        #[cfg(feature = "dtls")]
+        let frontends = [&self.frontend.tls, &self.frontend.dtls];
+        #[cfg(not(feature = "dtls"))]
+        let frontends = [&self.frontend.tls];
+        for tls in frontends.into_iter().flatten() {
+            files.extend([tls.cert.as_path(), &tls.key]);
+            files.extend(tls.client_auth.as_ref().map(|auth| auth.ca.as_path()));
+        }
+        if let Some(quic) = &self.frontend.quic {
+            files.extend([quic.cert.as_path(), &quic.key]);
+            files.extend(quic.client_auth.as_ref().map(|auth| auth.ca.as_path()));
+        }
+
+        for resolver in self.resolvers() {
+            match resolver {
+                ResolverConfig::Udp(conf) => files.extend(
+                    conf.tls_upgrade
+                        .as_ref()
+                        .and_then(|upgrade| upgrade.ca_file.as_deref()),
+                ),
+                ResolverConfig::Https(conf) => files.extend(conf.ca_file.as_deref()),
+                ResolverConfig::Kubernetes(conf) => {
+                    files.extend(conf.token_file.as_deref());
+                    files.extend(conf.ca_file.as_deref());
+                }
+                ResolverConfig::System(conf) => files.extend(conf.path.parent()),
+                ResolverConfig::Tcp(_) | ResolverConfig::Consul(_) | ResolverConfig::Custom(_) => {}
+            }
+        }
+
+        files
+    }
+
+    pub fn from_file<P>(path: P) -> Result<Self, String>
     where
         P: AsRef<Path>,
     {
-        let buf = std::fs::read_to_string(path).unwrap();
-        serde_json::from_str(&buf).unwrap()
+        let path = path.as_ref();
+        let buf = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let mut value = serde_json::from_str(&buf).map_err(|err| err.to_string())?;
+        migrate(&mut value)?;
+
+        let config: Self = serde_json::from_value(value).map_err(|err| err.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns the upstreams of all zones, including the shadow upstreams.
+    fn resolvers(&self) -> impl Iterator<Item = &ResolverConfig> {
+        let diffs = self.diff.values().flat_map(|diff| &diff.resolvers);
+        self.zones.values().flatten().chain(diffs)
+    }
+
+    /// Checks the settings that can only be checked together.
+    fn validate(&self) -> Result<(), String> {
+        for resolver in self.resolvers() {
+            resolver.validate()?;
+        }
+
+        #[cfg(feature = "dtls")]
+        let frontends = [&self.frontend.tls, &self.frontend.dtls];
+        #[cfg(not(feature = "dtls"))]
+        let frontends = [&self.frontend.tls];
+        let client_auths = frontends
+            .into_iter()
+            .flatten()
+            .map(|tls| &tls.client_auth)
+            .chain(self.frontend.quic.as_ref().map(|quic| &quic.client_auth));
+        for client_auth in client_auths.flatten() {
+            client_auth.validate()?;
+        }
+
+        if self.ddr.enabled && self.ddr.name.is_empty() {
+            return Err("ddr.name must be set to the name in the certificates".to_owned());
+        }
+
+        for (zone, strategy) in &self.strategy {
+            if strategy
+                .min_ttl
+                .zip(strategy.max_ttl)
+                .is_some_and(|(min, max)| min > max)
+            {
+                return Err(format!("min_ttl of zone {} is greater than max_ttl", zone));
+            }
+        }
+
+        // The debug probe skips the allowlist and must not reach DoH clients.
+        if self.http.enabled && self.http.doh && self.http.admin.is_none() {
+            return Err(
+                "http.doh requires http.admin, so that the admin endpoints are not public"
+                    .to_owned(),
+            );
+        }
+
+        for zone in self.local_zones.keys() {
+            if self.zones.contains_key(zone) {
+                return Err(format!("zone {} is both local and forwarded", zone));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether a listener on the IPv6 address `addr` only accepts
+    /// IPv6 traffic.
+    ///
+    /// Unless configured, this is the case if the same port is also bound on
+    /// IPv4, so that both listeners can coexist.
+    pub fn v6only(&self, addr: SocketAddr) -> bool {
+        self.frontend.v6only.unwrap_or_else(|| {
+            self.bind
+                .iter()
+                .any(|bind| bind.addr.is_ipv4() && bind.addr.port() == addr.port())
+        })
+    }
+}
+
+/// Parses a socket address. IPv6 addresses may carry the scope as either an
+/// interface index or name, e.g. `[fe80::1%eth0]:53`.
+fn parse_socket_addr(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+
+    let invalid = || format!("invalid socket address {}", s);
+    let (ip, port) = s
+        .strip_prefix('[')
+        .and_then(|s| s.split_once("]:"))
+        .ok_or_else(invalid)?;
+    let (ip, interface) = ip.split_once('%').ok_or_else(invalid)?;
+    let ip: Ipv6Addr = ip.parse().map_err(|_| invalid())?;
+    let port = port.parse().map_err(|_| invalid())?;
+
+    let name = CString::new(interface).map_err(|_| invalid())?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(format!("unknown interface {} in {}", interface, s));
+    }
+
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, index)))
+}
+
+fn deserialize_socket_addr<'de, D>(deserializer: D) -> Result<SocketAddr, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_socket_addr(&s).map_err(D::Error::custom)
+}
+
+/// Deserializes a map keyed by zone names, which are lowercased as names
+/// are compared case-insensitively.
+fn deserialize_zones<'de, D, T>(deserializer: D) -> Result<HashMap<String, T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let zones = HashMap::<String, T>::deserialize(deserializer)?;
+    let mut lowercase = HashMap::with_capacity(zones.len());
+    for (zone, value) in zones {
+        let zone = zone.to_ascii_lowercase();
+        if lowercase.contains_key(&zone) {
+            return Err(D::Error::custom(format!("zone {} is set twice", zone)));
+        }
+        lowercase.insert(zone, value);
+    }
+
+    Ok(lowercase)
+}
+
+/// Deserializes a number of upstreams, which is at least 1.
+fn deserialize_race<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = usize::deserialize(deserializer)?;
+    if value == 0 {
+        return Err(D::Error::custom("invalid race 0, must be at least 1"));
+    }
+
+    Ok(value)
+}
+
+/// Deserializes the prefix length of an IPv4 subnet.
+fn deserialize_v4_prefix<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = u8::deserialize(deserializer)?;
+    if value > 32 {
+        return Err(D::Error::custom(format!(
+            "invalid IPv4 prefix length {}",
+            value
+        )));
+    }
+
+    Ok(value)
+}
+
+/// Deserializes the prefix length of an IPv6 subnet.
+fn deserialize_v6_prefix<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = u8::deserialize(deserializer)?;
+    if value > 128 {
+        return Err(D::Error::custom(format!(
+            "invalid IPv6 prefix length {}",
+            value
+        )));
+    }
+
+    Ok(value)
+}
+
+/// Deserializes an EDNS UDP payload size, which is at least 512.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc6891#section-6.2.5
+fn deserialize_payload_size<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = u16::deserialize(deserializer)?;
+    if value < 512 {
+        return Err(D::Error::custom(format!(
+            "invalid payload size {}, must be at least 512",
+            value
+        )));
+    }
+
+    Ok(value)
+}
+
+/// Deserializes a number that is at least 1.
+fn deserialize_nonzero<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::default() {
+        return Err(D::Error::custom("invalid value 0, must be at least 1"));
+    }
+
+    Ok(value)
+}
+
+/// Deserializes a number between 0.0 and 1.0.
+fn deserialize_fraction<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = f64::deserialize(deserializer)?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(D::Error::custom(format!(
+            "invalid fraction {}, must be between 0.0 and 1.0",
+            value
+        )));
+    }
+
+    Ok(value)
+}
+
+fn deserialize_binds<'de, D>(deserializer: D) -> Result<Vec<Bind>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Binds {
+        One(BindEntry),
+        Many(Vec<BindEntry>),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BindEntry {
+        Addr(String),
+        Bind(Bind),
+    }
+
+    let binds = match Binds::deserialize(deserializer)? {
+        Binds::One(bind) => vec![bind],
+        Binds::Many(binds) => binds,
+    };
+    binds
+        .into_iter()
+        .map(|bind| match bind {
+            BindEntry::Addr(addr) => Ok(Bind {
+                addr: parse_socket_addr(&addr).map_err(D::Error::custom)?,
+                name: None,
+            }),
+            BindEntry::Bind(bind) => Ok(bind),
+        })
+        .collect()
+}
+
+/// Returns the label of a listener in the metrics.
+fn label(name: &Option<String>, addr: SocketAddr) -> String {
+    name.clone().unwrap_or_else(|| addr.to_string())
+}
+
+/// An address of the plain UDP and TCP frontends.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bind {
+    #[serde(deserialize_with = "deserialize_socket_addr")]
+    pub addr: SocketAddr,
+    /// Name of the listener in the metrics, defaults to the address.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl Bind {
+    pub fn label(&self) -> String {
+        label(&self.name, self.addr)
     }
 }
 
+/// Migrates a config in an older layout to [`CONFIG_VERSION`].
+fn migrate(config: &mut Value) -> Result<(), String> {
+    let Some(config) = config.as_object_mut() else {
+        return Err("config is not an object".to_owned());
+    };
+
+    let version = match config.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| format!("invalid version {}", version))?,
+        None => 0,
+    };
+
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "config version {} is newer than the supported version {}",
+            version, CONFIG_VERSION
+        ));
+    }
+
+    if version < 1 {
+        // Version 1 renamed `metrics` to `http` as it also serves the debug endpoints.
+        if let Some(http) = config.remove("metrics") {
+            tracing::warn!("config: `metrics` is deprecated, use `http` instead");
+            config.entry("http").or_insert(http);
+        }
+    }
+
+    if version < 2 {
+        // Version 2 moved the per-zone `race`, `client_subnet` and `failover`
+        // into the strategy of the zone.
+        for key in ["race", "client_subnet", "failover"] {
+            let Some(settings) = config.remove(key) else {
+                continue;
+            };
+            let Value::Object(settings) = settings else {
+                return Err(format!("invalid {} {}", key, settings));
+            };
+            tracing::warn!("config: `{}` is deprecated, use `strategy` instead", key);
+
+            let strategies = config
+                .entry("strategy")
+                .or_insert_with(|| Value::Object(Default::default()));
+            let Some(strategies) = strategies.as_object_mut() else {
+                return Err(format!("invalid strategy {}", strategies));
+            };
+            for (zone, value) in settings {
+                let strategy = strategies
+                    .entry(zone.clone())
+                    .or_insert_with(|| Value::Object(Default::default()));
+                let Some(strategy) = strategy.as_object_mut() else {
+                    return Err(format!("invalid strategy of zone {}", zone));
+                };
+                if strategy.contains_key(key) {
+                    return Err(format!("{} of zone {} is set twice", key, zone));
+                }
+                strategy.insert(key.to_owned(), value);
+            }
+        }
+    }
+
+    if version < CONFIG_VERSION {
+        tracing::warn!(
+            "config: migrated from version {} to {}, set `\"version\": {}` after updating the config",
+            version,
+            CONFIG_VERSION,
+            CONFIG_VERSION
+        );
+        config.insert("version".to_owned(), Value::from(CONFIG_VERSION));
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ResolverConfig {
     Udp(UdpResolver),
+    Tcp(TcpResolver),
     Https(HttpResolver),
+    Consul(ConsulResolver),
+    Kubernetes(KubernetesResolver),
+    System(SystemResolver),
+    Custom(CustomResolver),
+}
+
+impl ResolverConfig {
+    /// Returns the transport of the upstream, or `None` if it answers
+    /// queries itself or has a custom transport.
+    pub fn transport(&self) -> Option<Transport> {
+        match self {
+            Self::Udp(_) | Self::System(_) => Some(Transport::Udp),
+            Self::Tcp(_) => Some(Transport::Tcp),
+            Self::Https(_) => Some(Transport::Https),
+            Self::Consul(_) | Self::Kubernetes(_) | Self::Custom(_) => None,
+        }
+    }
+
+    /// Returns the tier of the upstream.
+    pub fn tier(&self) -> u32 {
+        match self {
+            Self::Udp(conf) => conf.tier,
+            Self::Tcp(conf) => conf.tier,
+            Self::Https(conf) => conf.tier,
+            Self::System(conf) => conf.tier,
+            Self::Custom(conf) => conf.tier,
+            Self::Consul(_) | Self::Kubernetes(_) => 0,
+        }
+    }
+
+    /// Checks the settings of the upstream that can only be checked together.
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Udp(conf) => {
+                validate_source(conf.addr, conf.source, &conf.proxy)?;
+                match &conf.tls_upgrade {
+                    Some(upgrade) => upgrade.validate(conf.addr),
+                    None => Ok(()),
+                }
+            }
+            Self::Tcp(conf) => validate_source(conf.addr, conf.source, &conf.proxy),
+            // The CA certificate is loaded again when the zones are built.
+            Self::Kubernetes(conf) => {
+                conf.url()?;
+                conf.client().map(drop)
+            }
+            Self::Https(_) | Self::Consul(_) | Self::System(_) | Self::Custom(_) => Ok(()),
+        }
+    }
+}
+
+/// Checks that the `source` address of the upstream at `addr` has the
+/// family of the address connected to.
+fn validate_source(
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+    proxy: &Option<Proxy>,
+) -> Result<(), String> {
+    // Connections through a proxy are made to the proxy.
+    let peer = proxy.as_ref().map_or(addr, |proxy| proxy.addr);
+    if source.is_some_and(|source| source.is_ipv4() != peer.is_ipv4()) {
+        return Err(format!(
+            "source address of upstream {} has a different family",
+            addr
+        ));
+    }
+
+    Ok(())
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UdpResolver {
+    #[serde(deserialize_with = "deserialize_socket_addr")]
     pub addr: SocketAddr,
     pub timeout: u64,
+    #[serde(default)]
+    pub mode: ResolutionMode,
+    /// Name of the interface (e.g. a WireGuard tunnel) the upstream is only reachable through.
+    ///
+    /// Queries are sent through this interface only and fail immediately while it is down.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// The local address queries are sent from, e.g. on multi-homed hosts.
+    #[serde(default)]
+    pub source: Option<IpAddr>,
+    /// URL of a SOCKS5 (`socks5://`) or HTTP CONNECT (`http://`) proxy.
+    /// Queries are sent over TCP through the proxy.
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    /// EDNS UDP payload size advertised in queries. Larger responses are
+    /// truncated by the upstream and repeated over TCP.
+    #[serde(
+        default = "UdpResolver::default_payload_size",
+        deserialize_with = "deserialize_payload_size"
+    )]
+    pub payload_size: u16,
+    /// Keeps the TCP connection to the upstream open while it is idle.
+    #[serde(default)]
+    pub keepalive: Option<KeepAlive>,
+    /// Sends queries over DNS over TLS while the upstream supports it.
+    #[serde(default)]
+    pub tls_upgrade: Option<TlsUpgrade>,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub concurrency: Option<Concurrency>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Upstreams of higher tiers are only queried if all upstreams of
+    /// lower tiers are unhealthy or failed. Queries are spread over the
+    /// upstreams of the same tier.
+    #[serde(default)]
+    pub tier: u32,
+}
+
+impl UdpResolver {
+    fn default_payload_size() -> u16 {
+        1232
+    }
+}
+
+/// An upstream that is only queried over TCP, e.g. where UDP is filtered or
+/// responses are large.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TcpResolver {
+    #[serde(deserialize_with = "deserialize_socket_addr")]
+    pub addr: SocketAddr,
+    pub timeout: u64,
+    #[serde(default)]
+    pub mode: ResolutionMode,
+    /// Name of the interface the upstream is only reachable through.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// The local address connections are made from.
+    #[serde(default)]
+    pub source: Option<IpAddr>,
+    /// URL of a SOCKS5 (`socks5://`) or HTTP CONNECT (`http://`) proxy.
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    /// Keeps the connection to the upstream open while it is idle.
+    #[serde(default)]
+    pub keepalive: Option<KeepAlive>,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub concurrency: Option<Concurrency>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub tier: u32,
+}
+
+/// Probes sent on an idle TCP connection to an upstream, so that the next
+/// query doesn't have to open a new one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeepAlive {
+    /// Seconds between two probes on an idle connection. Shortened to half
+    /// the idle timeout the upstream announces with edns-tcp-keepalive.
+    #[serde(
+        default = "KeepAlive::default_interval",
+        deserialize_with = "deserialize_nonzero"
+    )]
+    pub interval: u64,
+    /// Seconds after the last query until probes are no longer sent.
+    #[serde(default = "KeepAlive::default_max_idle")]
+    pub max_idle: u64,
+    /// Name whose NS records are queried by the probes.
+    #[serde(default = "KeepAlive::default_name")]
+    pub name: String,
+}
+
+impl KeepAlive {
+    fn default_interval() -> u64 {
+        10
+    }
+
+    fn default_max_idle() -> u64 {
+        300
+    }
+
+    fn default_name() -> String {
+        String::from(".")
+    }
+}
+
+/// Opportunistic upgrade of a UDP upstream to DNS over TLS.
+///
+/// The TLS port of the upstream is probed in the background. Queries are
+/// sent over UDP until the probe succeeds, and again whenever TLS fails.
+/// Without a `server_name` any certificate is accepted, which only protects
+/// from passive eavesdroppers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsUpgrade {
+    #[serde(default = "TlsUpgrade::default_port")]
+    pub port: u16,
+    /// Seconds until the port is probed again after TLS failed.
+    #[serde(default = "TlsUpgrade::default_retry")]
+    pub retry: u64,
+    /// Name the certificate of the upstream is strictly verified for.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Path to the PEM encoded CA certificates the upstream is verified
+    /// with, instead of the public CAs. Requires `server_name`.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Base64 encoded SHA-256 hashes of public keys (SPKI), see
+    /// `HttpResolver::pins`. Requires `server_name`.
+    #[serde(default)]
+    pub pins: Vec<String>,
+}
+
+impl TlsUpgrade {
+    fn default_port() -> u16 {
+        853
+    }
+
+    /// Checks the verification settings of the upstream at `addr`.
+    fn validate(&self, addr: SocketAddr) -> Result<(), String> {
+        match &self.server_name {
+            Some(name) => {
+                if ServerName::try_from(name.as_str()).is_err() {
+                    return Err(format!("invalid server name {} of upstream {}", name, addr));
+                }
+            }
+            None => {
+                if self.ca_file.is_some() || !self.pins.is_empty() {
+                    return Err(format!(
+                        "ca_file and pins of upstream {} require a server_name",
+                        addr
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn default_retry() -> u64 {
+        3600
+    }
+}
+
+/// The role of an upstream resolver.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResolutionMode {
+    /// The upstream resolves queries recursively.
+    #[default]
+    Forward,
+    /// The upstream is an authoritative server for the zone.
+    Authoritative,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HttpResolver {
     pub url: String,
     pub timeout: u64,
+    /// Seconds after which idle pooled connections are closed.
+    #[serde(default = "HttpResolver::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds after which pooled connections are replaced, even if they are in use.
+    ///
+    /// Stateful firewalls may silently drop long-lived connections.
+    #[serde(default)]
+    pub max_lifetime: Option<u64>,
+    /// Send queries with GET instead of POST, so that HTTP caches in front
+    /// of the upstream can answer them.
+    #[serde(default)]
+    pub get: bool,
+    /// Headers added to every request, e.g. to authenticate with the upstream.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// The local address connections are made from.
+    #[serde(default)]
+    pub source: Option<IpAddr>,
+    /// Name of the interface connections are bound to, e.g. a VRF.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// URL of a SOCKS5 (`socks5://`) or HTTP CONNECT (`http://`) proxy
+    /// connections are made through.
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    /// Plain DNS server the hostname of the upstream is resolved with.
+    ///
+    /// The addresses are resolved again once their TTL expired. Without
+    /// one the system resolver is used.
+    #[serde(default)]
+    pub bootstrap: Option<SocketAddr>,
+    /// Path to the PEM encoded CA certificates the upstream is verified
+    /// with, instead of the public CAs.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Base64 encoded SHA-256 hashes of public keys (SPKI). The certificate
+    /// of the upstream or one of its intermediates must have one of them.
+    #[serde(default)]
+    pub pins: Vec<String>,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub concurrency: Option<Concurrency>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Upstreams of higher tiers are only queried if all upstreams of
+    /// lower tiers are unhealthy or failed. Queries are spread over the
+    /// upstreams of the same tier.
+    #[serde(default)]
+    pub tier: u32,
+}
+
+/// Limit of concurrent queries to an upstream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Concurrency {
+    /// Maximum number of queries in flight.
+    #[serde(deserialize_with = "deserialize_nonzero")]
+    pub max: usize,
+    /// Maximum number of queries waiting for others to complete. Further
+    /// queries fail over to the next upstream.
+    #[serde(default)]
+    pub queue: usize,
+}
+
+/// Limit of the rate of queries to an upstream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Maximum number of queries per second.
+    #[serde(deserialize_with = "deserialize_nonzero")]
+    pub qps: u32,
+    /// Number of queries that may be sent at once after a quiet period.
+    /// Defaults to `qps`.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Maximum milliseconds a query waits for its turn. Queries that would
+    /// wait longer fail over to the next upstream.
+    #[serde(default = "RateLimit::default_max_wait")]
+    pub max_wait: u64,
+}
+
+impl RateLimit {
+    fn default_max_wait() -> u64 {
+        100
+    }
+}
+
+/// Retries of queries to an upstream that failed with a timeout or a
+/// network error, before failing over to the next upstream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Retry {
+    /// Number of attempts, including the first one.
+    #[serde(default = "Retry::default_attempts")]
+    pub attempts: u32,
+    /// Milliseconds to wait before the first retry, doubled for every
+    /// further retry.
+    #[serde(default = "Retry::default_backoff")]
+    pub backoff: u64,
+    /// Maximum milliseconds to wait between retries.
+    #[serde(default = "Retry::default_max_backoff")]
+    pub max_backoff: u64,
+    /// Fraction of the backoff that is randomized, between 0.0 and 1.0, so
+    /// that queries failed by the same outage are not retried in lockstep.
+    #[serde(
+        default = "Retry::default_jitter",
+        deserialize_with = "deserialize_fraction"
+    )]
+    pub jitter: f64,
+}
+
+impl Retry {
+    fn default_attempts() -> u32 {
+        1
+    }
+
+    fn default_backoff() -> u64 {
+        50
+    }
+
+    fn default_max_backoff() -> u64 {
+        1000
+    }
+
+    fn default_jitter() -> f64 {
+        0.5
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: Self::default_attempts(),
+            backoff: Self::default_backoff(),
+            max_backoff: Self::default_max_backoff(),
+            jitter: Self::default_jitter(),
+        }
+    }
+}
+
+impl HttpResolver {
+    fn default_idle_timeout() -> u64 {
+        30
+    }
+}
+
+/// Answers service names from the Consul catalog.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsulResolver {
+    pub url: String,
+    pub timeout: u64,
+    /// TTL of the returned records. Records with a TTL of 0 are always
+    /// answered from the watched state of the service.
+    #[serde(default)]
+    pub ttl: u32,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Answers service names from the endpoints of Kubernetes services.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KubernetesResolver {
+    pub url: String,
+    pub timeout: u64,
+    #[serde(default)]
+    pub ttl: u32,
+    /// Path to the service account token.
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    /// Path to the PEM encoded CA certificate of the API server.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+}
+
+impl KubernetesResolver {
+    pub fn url(&self) -> Result<reqwest::Url, String> {
+        reqwest::Url::parse(&self.url).map_err(|err| format!("invalid url {}: {}", self.url, err))
+    }
+
+    /// Returns the client for the API server, which also trusts `ca_file`.
+    pub fn client(&self) -> Result<reqwest::Client, String> {
+        let mut client = reqwest::ClientBuilder::new().use_rustls_tls();
+        if let Some(path) = &self.ca_file {
+            let pem = std::fs::read(path)
+                .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|err| format!("invalid certificate {}: {}", path.display(), err))?;
+            if certs.is_empty() {
+                return Err(format!("no certificate in {}", path.display()));
+            }
+            for cert in certs {
+                client = client.add_root_certificate(cert);
+            }
+        }
+
+        client
+            .build()
+            .map_err(|err| format!("failed to build the client for {}: {}", self.url, err))
+    }
+}
+
+/// Forwards to the nameservers of the system, e.g. the ones configured by
+/// DHCP. The file is reloaded when it changes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SystemResolver {
+    #[serde(default = "SystemResolver::default_path")]
+    pub path: PathBuf,
+    /// Timeout of a query to one nameserver. The nameservers are queried
+    /// in order until one responds.
+    pub timeout: u64,
+    #[serde(default)]
+    pub tier: u32,
+}
+
+impl SystemResolver {
+    fn default_path() -> PathBuf {
+        PathBuf::from("/etc/resolv.conf")
+    }
+}
+
+/// An upstream with a transport that is not built in, see
+/// [`Registry`](crate::upstream::registry::Registry).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CustomResolver {
+    /// Name the transport is registered with.
+    pub transport: String,
+    /// Address of the upstream as the transport understands it, also shown
+    /// in logs and metrics.
+    pub addr: String,
+    pub timeout: u64,
+    /// Settings of the transport, passed on as they are.
+    #[serde(default)]
+    pub options: Value,
+    #[serde(default)]
+    pub tier: u32,
+}
+
+/// Additional frontends besides plain UDP and TCP on `bind`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Frontend {
+    #[serde(default)]
+    pub udp: UdpFrontend,
+    #[serde(default)]
+    pub tcp: TcpFrontend,
+    #[serde(default)]
+    pub tls: Option<TlsFrontend>,
+    #[serde(default)]
+    pub quic: Option<QuicFrontend>,
+    /// Whether listeners on IPv6 addresses only accept IPv6 traffic, instead
+    /// of also accepting IPv4 traffic on the same port. See [`Config::v6only`]
+    /// for the default.
+    #[serde(default)]
+    pub v6only: Option<bool>,
+    /// How queries that cannot be decoded are answered on UDP and TCP.
+    #[serde(default)]
+    pub bad_queries: BadQueries,
+    /// How queries with more than one question are answered.
+    #[serde(default)]
+    pub multiple_questions: MultipleQuestions,
+    /// Number of consecutive restarts of a failed frontend after which the
+    /// process exits instead. `None` restarts it indefinitely.
+    #[serde(default)]
+    pub max_restarts: Option<u32>,
+    /// DNS over DTLS, using the same certificate config as DNS over TLS.
+    ///
+    /// See https://datatracker.ietf.org/doc/html/rfc8094
+    #[cfg(feature = "dtls")]
+    #[serde(default)]
+    pub dtls: Option<TlsFrontend>,
+}
+
+/// How queries that cannot be decoded are answered.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BadQueries {
+    /// Unknown opcodes are answered with NOTIMP, all other malformed queries
+    /// with FORMERR.
+    #[default]
+    Respond,
+    /// Malformed queries are silently dropped.
+    Drop,
+}
+
+/// How queries with more than one question are answered.
+///
+/// The DNS standards don't define the semantics of such queries.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MultipleQuestions {
+    /// The queries are answered with FORMERR, like most other servers do.
+    #[default]
+    Reject,
+    /// All questions are resolved concurrently and the answers are merged.
+    /// If any question fails, the response only carries the error of the
+    /// first failed question.
+    Resolve,
+}
+
+/// The plain UDP frontend on `bind`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UdpFrontend {
+    /// Number of sockets bound to the same address with `SO_REUSEPORT`, each
+    /// served by its own task. The kernel balances the queries between them.
+    #[serde(default = "UdpFrontend::default_workers")]
+    pub workers: usize,
+    /// Use generic segmentation and receive offload if the kernel supports it.
+    #[serde(default = "UdpFrontend::default_offload")]
+    pub offload: bool,
+    /// Maximum size of a query, larger datagrams are dropped. Needs to be
+    /// raised to accept queries in jumbo frames.
+    #[serde(default = "UdpFrontend::default_max_query_size")]
+    pub max_query_size: usize,
+    /// Maximum size of a response, regardless of the payload size announced
+    /// by the client. Larger responses are truncated.
+    #[serde(default = "default_max_message_size")]
+    pub max_response_size: usize,
+}
+
+impl UdpFrontend {
+    fn default_workers() -> usize {
+        1
+    }
+
+    fn default_offload() -> bool {
+        true
+    }
+
+    fn default_max_query_size() -> usize {
+        1500
+    }
+}
+
+impl Default for UdpFrontend {
+    fn default() -> Self {
+        Self {
+            workers: Self::default_workers(),
+            offload: Self::default_offload(),
+            max_query_size: Self::default_max_query_size(),
+            max_response_size: default_max_message_size(),
+        }
+    }
+}
+
+/// The largest message that fits into the length prefix of stream transports.
+fn default_max_message_size() -> usize {
+    usize::from(u16::MAX)
+}
+
+/// The plain TCP frontend on `bind`. The limits also apply to DNS over TLS.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TcpFrontend {
+    /// Seconds after which idle connections are closed.
+    ///
+    /// See https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.3
+    #[serde(default = "TcpFrontend::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds after which a response that the client doesn't read closes
+    /// the connection.
+    #[serde(default = "TcpFrontend::default_write_timeout")]
+    pub write_timeout: u64,
+    /// Maximum number of queries of a single connection that are resolved
+    /// concurrently. Further queries are not read until one completes.
+    #[serde(default = "TcpFrontend::default_max_queued_queries")]
+    pub max_queued_queries: usize,
+    /// Maximum number of open connections per listener. Once reached, the
+    /// connection that has been idle for the longest time is closed for every
+    /// new one.
+    #[serde(default = "TcpFrontend::default_max_connections")]
+    pub max_connections: usize,
+    /// Maximum size of a query. Connections sending a larger one are closed.
+    #[serde(default = "default_max_message_size")]
+    pub max_query_size: usize,
+    /// Maximum size of a response. Larger responses are truncated.
+    #[serde(default = "default_max_message_size")]
+    pub max_response_size: usize,
+}
+
+impl TcpFrontend {
+    fn default_idle_timeout() -> u64 {
+        10
+    }
+
+    fn default_write_timeout() -> u64 {
+        10
+    }
+
+    fn default_max_queued_queries() -> usize {
+        32
+    }
+
+    fn default_max_connections() -> usize {
+        512
+    }
+}
+
+impl Default for TcpFrontend {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Self::default_idle_timeout(),
+            write_timeout: Self::default_write_timeout(),
+            max_queued_queries: Self::default_max_queued_queries(),
+            max_connections: Self::default_max_connections(),
+            max_query_size: default_max_message_size(),
+            max_response_size: default_max_message_size(),
+        }
+    }
+}
+
+/// DNS over TLS.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc7858
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TlsFrontend {
+    /// Usually port 853.
+    #[serde(deserialize_with = "deserialize_socket_addr")]
+    pub bind: SocketAddr,
+    /// Name of the listener in the metrics, defaults to the address.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Path to the PEM encoded certificate chain.
+    pub cert: PathBuf,
+    /// Path to the PEM encoded private key.
+    pub key: PathBuf,
+    /// Seconds after which idle connections, including unfinished handshakes, are closed.
+    #[serde(default = "TlsFrontend::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Only accept clients with a valid certificate.
+    #[serde(default)]
+    pub client_auth: Option<ClientAuth>,
+    /// Issue stateless session tickets, so that reconnecting clients can
+    /// resume their session without a full handshake. Otherwise only the
+    /// last 256 sessions are kept in memory for resumption.
+    #[serde(default = "TlsFrontend::default_session_tickets")]
+    pub session_tickets: bool,
+}
+
+impl TlsFrontend {
+    pub fn label(&self) -> String {
+        label(&self.name, self.bind)
+    }
+
+    fn default_idle_timeout() -> u64 {
+        10
+    }
+
+    fn default_session_tickets() -> bool {
+        true
+    }
+}
+
+/// Authentication of clients by their TLS certificate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientAuth {
+    /// Path to the PEM encoded CA certificates that client certificates must
+    /// be issued by.
+    pub ca: PathBuf,
+    /// DNS names and IP addresses of which the client certificate must carry
+    /// at least one as a subject alternative name. Empty allows every
+    /// certificate issued by the CAs.
+    #[serde(default)]
+    pub allowed_names: Vec<String>,
+}
+
+impl ClientAuth {
+    fn validate(&self) -> Result<(), String> {
+        for name in &self.allowed_names {
+            if ServerName::try_from(name.as_str()).is_err() {
+                return Err(format!("invalid allowed name {}", name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// DNS over QUIC.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc9250
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuicFrontend {
+    /// Usually port 853.
+    #[serde(deserialize_with = "deserialize_socket_addr")]
+    pub bind: SocketAddr,
+    /// Name of the listener in the metrics, defaults to the address.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Path to the PEM encoded certificate chain.
+    pub cert: PathBuf,
+    /// Path to the PEM encoded private key.
+    pub key: PathBuf,
+    /// Seconds after which idle connections are closed.
+    #[serde(default = "QuicFrontend::default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Also serve DNS over HTTP/3 on the same port.
+    #[serde(default)]
+    pub http3: bool,
+    /// Only accept clients with a valid certificate.
+    #[serde(default)]
+    pub client_auth: Option<ClientAuth>,
+    /// Issue stateless session tickets, so that reconnecting clients can
+    /// resume their session without a full handshake. Otherwise only the
+    /// last 256 sessions are kept in memory for resumption.
+    #[serde(default = "QuicFrontend::default_session_tickets")]
+    pub session_tickets: bool,
+}
+
+impl QuicFrontend {
+    pub fn label(&self) -> String {
+        label(&self.name, self.bind)
+    }
+
+    fn default_idle_timeout() -> u64 {
+        30
+    }
+
+    fn default_session_tickets() -> bool {
+        true
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Http {
     pub enabled: bool,
+    #[serde(deserialize_with = "deserialize_socket_addr")]
     pub bind: SocketAddr,
+    /// Name of the listener in the metrics, defaults to the address.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Serve DNS over HTTPS queries on `/dns-query` and JSON queries on `/resolve`.
+    ///
+    /// TLS is expected to be terminated by a reverse proxy. Requires `admin`.
+    #[serde(default)]
+    pub doh: bool,
+    /// Separate listener for `/metrics` and `/debug/probe`, which are then
+    /// no longer served on `bind`.
+    #[serde(default)]
+    pub admin: Option<Bind>,
+    /// Log every request with the `rdns::access` target.
+    #[serde(default)]
+    pub access_log: bool,
+}
+
+impl Http {
+    pub fn label(&self) -> String {
+        label(&self.name, self.bind)
+    }
+}
+
+/// Answers for the CHAOS class introspection names.
+///
+/// Queries for names without a configured answer are refused.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Chaos {
+    pub enabled: bool,
+    /// Answer for `version.bind` and `version.server`.
+    pub version: Option<String>,
+    /// Answer for `hostname.bind`.
+    pub hostname: Option<String>,
+    /// Answer for `id.server`.
+    pub id: Option<String>,
+}
+
+/// Answers for the names and addresses of the server itself.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Local {
+    pub enabled: bool,
+    /// Names of the server. Defaults to the hostname of the system.
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Addresses of the server. Defaults to the bind addresses that are not unspecified.
+    #[serde(default)]
+    pub addrs: Vec<IpAddr>,
+}
+
+/// Discovery of Designated Resolvers, advertising the encrypted frontends in
+/// SVCB records of `_dns.resolver.arpa`.
+///
+/// Clients only upgrade if the certificates are also valid for the address
+/// they sent the query to.
+/// See https://datatracker.ietf.org/doc/html/rfc9462
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Ddr {
+    pub enabled: bool,
+    /// Name of the server in the certificates of the encrypted frontends.
+    #[serde(default)]
+    pub name: String,
+    /// Port on which a reverse proxy serves DNS over HTTPS with HTTP/2 for
+    /// the HTTP frontend. Only advertised if `http.doh` is enabled.
+    #[serde(default)]
+    pub https_port: Option<u16>,
+}
+
+/// Only resolves names within the listed domains.
+///
+/// Queries for all other names are answered with NXDOMAIN.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Allowlist {
+    pub enabled: bool,
+    /// Domains that are resolved, including all their subdomains.
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+/// Limits on the work accepted from clients.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Limits {
+    /// Maximum number of queries resolved concurrently by every UDP worker,
+    /// `null` for no limit.
+    #[serde(default = "Limits::default_udp_inflight")]
+    pub udp_inflight: Option<usize>,
+    /// How queries rejected because a limit was hit are answered.
+    #[serde(default)]
+    pub rejection: Rejection,
+}
+
+impl Limits {
+    fn default_udp_inflight() -> Option<usize> {
+        Some(1024)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            udp_inflight: Self::default_udp_inflight(),
+            rejection: Rejection::default(),
+        }
+    }
+}
+
+/// How queries rejected by a limit are answered.
+///
+/// Responses carry an Extended DNS Error describing the reason if the client
+/// supports EDNS.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    #[default]
+    Refused,
+    ServFail,
+    /// Rejected queries are not answered at all.
+    Drop,
+}
+
+/// A Differentiated Services codepoint, between 0 and 63.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc2474#section-3
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// Returns the value of the IPv4 TOS and IPv6 Traffic Class fields.
+    ///
+    /// The lower two bits are used for ECN and left unset.
+    pub fn tos(self) -> u32 {
+        u32::from(self.0) << 2
+    }
+}
+
+impl TryFrom<u8> for Dscp {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value < 64 {
+            Ok(Self(value))
+        } else {
+            Err(format!(
+                "invalid DSCP value {}, must be less than 64",
+                value
+            ))
+        }
+    }
+}
+
+impl From<Dscp> for u8 {
+    fn from(dscp: Dscp) -> Self {
+        dscp.0
+    }
+}
+
+/// Faults injected into the exchanges with an upstream.
+#[cfg(feature = "fault-injection")]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Faults {
+    /// Fraction of queries that are never answered, so that they time out.
+    #[serde(default)]
+    pub drop_rate: f64,
+    /// Fraction of responses with a flipped byte, which are usually malformed.
+    #[serde(default)]
+    pub corrupt_rate: f64,
+    /// Fraction of responses that are truncated, as if the upstream did not
+    /// repeat them over TCP.
+    #[serde(default)]
+    pub truncate_rate: f64,
+    /// Milliseconds added to every exchange.
+    #[serde(default)]
+    pub latency: u64,
+    /// Fraction of exchanges that are delayed by `spike_latency` on top of
+    /// `latency`.
+    #[serde(default)]
+    pub spike_rate: f64,
+    /// Milliseconds added to an exchange hit by a spike.
+    #[serde(default)]
+    pub spike_latency: u64,
+    /// Seed for the random decisions, making them reproducible.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// How the upstreams of a zone are queried and their answers cached.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Strategy {
+    /// Upstreams with this transport are queried before the other upstreams
+    /// of their tier.
+    #[serde(default)]
+    pub prefer: Option<Transport>,
+    #[serde(default)]
+    pub balance: Balance,
+    /// Number of upstreams that queries are sent to concurrently. The first
+    /// answer is used and the other queries are cancelled.
+    #[serde(
+        default = "Strategy::default_race",
+        deserialize_with = "deserialize_race"
+    )]
+    pub race: usize,
+    /// Whether SERVFAIL, REFUSED and NOTIMP responses move on to the next
+    /// upstream.
+    #[serde(default = "Strategy::default_failover")]
+    pub failover: bool,
+    /// How the EDNS Client Subnet option is sent to the upstreams.
+    #[serde(default)]
+    pub client_subnet: ClientSubnetPolicy,
+    /// Minimum seconds answers are cached for.
+    #[serde(default)]
+    pub min_ttl: Option<u32>,
+    /// Maximum seconds answers are cached for.
+    #[serde(default)]
+    pub max_ttl: Option<u32>,
+}
+
+impl Strategy {
+    fn default_race() -> usize {
+        1
+    }
+
+    fn default_failover() -> bool {
+        true
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Self {
+            prefer: None,
+            balance: Balance::default(),
+            race: Self::default_race(),
+            failover: Self::default_failover(),
+            client_subnet: ClientSubnetPolicy::default(),
+            min_ttl: None,
+            max_ttl: None,
+        }
+    }
+}
+
+/// The protocol queries are sent to an upstream with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transport {
+    /// Plain DNS over UDP, falling back to TCP.
+    Udp,
+    /// Plain DNS over TCP only.
+    Tcp,
+    Https,
+}
+
+/// How queries are spread over the upstreams of the same tier.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Balance {
+    /// Every query starts at a random upstream.
+    Random,
+    /// Every query starts at the upstream after the one the last query
+    /// started at.
+    RoundRobin,
+    /// Every query starts at the first upstream, the others are only
+    /// queried if it fails. This keeps the order of the config, as before
+    /// the other strategies existed.
+    #[default]
+    Ordered,
+}
+
+/// How queries for names in a zone that is never forwarded are answered.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc6303
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LocalZone {
+    /// The name does not exist.
+    NxDomain,
+    /// The server refuses to answer the query.
+    Refused,
+}
+
+/// How the EDNS Client Subnet option is sent to the upstreams of a zone.
+///
+/// Answers to queries with a client subnet are never cached.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc7871
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientSubnetPolicy {
+    /// The option of the client is removed.
+    #[default]
+    Strip,
+    /// The option of the client is sent as is.
+    Forward,
+    /// The option of the client is shortened to the prefix lengths. Clients
+    /// without one get the subnet of their source address instead.
+    Synthesize {
+        #[serde(
+            default = "ClientSubnetPolicy::default_v4_prefix",
+            deserialize_with = "deserialize_v4_prefix"
+        )]
+        v4_prefix: u8,
+        #[serde(
+            default = "ClientSubnetPolicy::default_v6_prefix",
+            deserialize_with = "deserialize_v6_prefix"
+        )]
+        v6_prefix: u8,
+    },
+}
+
+impl ClientSubnetPolicy {
+    fn default_v4_prefix() -> u8 {
+        24
+    }
+
+    fn default_v6_prefix() -> u8 {
+        56
+    }
+}
+
+/// Repeats queries against a second set of upstreams and logs any
+/// difference in the answers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Diff {
+    pub resolvers: Vec<ResolverConfig>,
+    /// Fraction of queries that are compared, between 0.0 and 1.0.
+    #[serde(default = "Diff::default_sample")]
+    pub sample: f64,
+}
+
+impl Diff {
+    fn default_sample() -> f64 {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use crate::upstream::proxy::ProxyKind;
+
+    use super::{
+        migrate, parse_socket_addr, ClientSubnetPolicy, Concurrency, Config, Dscp, KeepAlive,
+        RateLimit, ResolverConfig, Retry, CONFIG_VERSION,
+    };
+
+    #[test]
+    fn migrate_metrics_to_http() {
+        let mut value = json!({
+            "bind": "127.0.0.1:53",
+            "zones": {},
+            "metrics": { "enabled": true, "bind": "127.0.0.1:8080" },
+        });
+        migrate(&mut value).unwrap();
+
+        let config: Config = serde_json::from_value(value).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert!(config.http.enabled);
+    }
+
+    #[test]
+    fn migrate_zone_settings_to_strategy() {
+        let mut value = json!({
+            "version": 1,
+            "bind": "127.0.0.1:53",
+            "zones": {},
+            "http": { "enabled": false, "bind": "127.0.0.1:8080" },
+            "race": { ".": 2 },
+            "failover": { "net.": false },
+            "client_subnet": { ".": "Forward" },
+            "strategy": { ".": { "max_ttl": 30 } },
+        });
+        migrate(&mut value).unwrap();
+
+        let config: Config = serde_json::from_value(value).unwrap();
+        let root = &config.strategy["."];
+        assert_eq!(root.race, 2);
+        assert_eq!(root.client_subnet, ClientSubnetPolicy::Forward);
+        assert_eq!(root.max_ttl, Some(30));
+        assert!(!config.strategy["net."].failover);
+        assert_eq!(config.strategy["net."].race, 1);
+
+        let mut value = json!({
+            "version": 1,
+            "client_subnet": { ".": "Forward" },
+            "strategy": { ".": { "client_subnet": "Strip" } },
+        });
+        assert!(migrate(&mut value).is_err());
+    }
+
+    #[test]
+    fn strategy_conflicts() {
+        let path = std::env::temp_dir().join(format!("rdns-config-{}.json", std::process::id()));
+        let load = |fields: serde_json::Value| {
+            let mut config = json!({
+                "bind": "127.0.0.1:53",
+                "zones": { ".": [] },
+                "http": { "enabled": false, "bind": "127.0.0.1:8080" },
+            });
+            for (key, value) in fields.as_object().unwrap() {
+                config[key] = value.clone();
+            }
+            std::fs::write(&path, config.to_string()).unwrap();
+            Config::from_file(&path)
+        };
+
+        assert!(load(json!({ "strategy": { ".": { "race": 2 } } })).is_ok());
+        assert!(load(json!({ "strategy": { ".": { "race": 0 } } })).is_err());
+        let ttl = json!({ "strategy": { ".": { "min_ttl": 60, "max_ttl": 30 } } });
+        assert!(load(ttl).is_err());
+        let prefix = json!({ "Synthesize": { "v4_prefix": 33 } });
+        assert!(load(json!({ "strategy": { ".": { "client_subnet": prefix } } })).is_err());
+        assert!(load(json!({ "local_zones": { ".": "Refused" } })).is_err());
+        assert!(
+            load(json!({ "zones": { "Corp.": [] }, "local_zones": { "corp.": "Refused" } }))
+                .is_err()
+        );
+        assert!(load(json!({ "zones": { "corp.": [], "Corp.": [] } })).is_err());
+        let race = json!({ "version": 1, "race": { ".": 2 }, "strategy": { ".": { "race": 3 } } });
+        assert!(load(race).is_err());
+
+        let udp = |source: &str| json!({ "Udp": { "addr": "192.0.2.1:53", "timeout": 1, "source": source } });
+        assert!(load(json!({ "zones": { ".": [udp("192.0.2.2")] } })).is_ok());
+        assert!(load(json!({ "zones": { ".": [udp("2001:db8::1")] } })).is_err());
+
+        let tls = |names| {
+            let client_auth = json!({ "ca": "ca.pem", "allowed_names": names });
+            json!({ "tls": { "bind": "127.0.0.1:853", "cert": "cert.pem", "key": "key.pem", "client_auth": client_auth } })
+        };
+        assert!(load(json!({ "frontend": tls(json!(["edge.example", "192.0.2.1"])) })).is_ok());
+        assert!(load(json!({ "frontend": tls(json!(["not a name"])) })).is_err());
+        assert!(load(json!({ "ddr": { "enabled": true } })).is_err());
+        assert!(load(json!({ "ddr": { "enabled": true, "name": "dns.example" } })).is_ok());
+
+        let upgrade = |upgrade| json!({ "Udp": { "addr": "192.0.2.1:53", "timeout": 1, "tls_upgrade": upgrade } });
+        let strict = json!({ "server_name": "dns.example", "pins": ["AAAA"] });
+        assert!(load(json!({ "zones": { ".": [upgrade(strict)] } })).is_ok());
+        let invalid = json!({ "server_name": "not a name" });
+        assert!(load(json!({ "zones": { ".": [upgrade(invalid)] } })).is_err());
+        let pins = json!({ "pins": ["AAAA"] });
+        assert!(load(json!({ "zones": { ".": [upgrade(pins)] } })).is_err());
+
+        let mut http = json!({ "enabled": true, "bind": "127.0.0.1:8080", "doh": true });
+        assert!(load(json!({ "http": http })).is_err());
+        http["admin"] = json!({ "addr": "127.0.0.1:8081" });
+        assert!(load(json!({ "http": http })).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrate_rejects_newer_version() {
+        let mut value = json!({ "version": CONFIG_VERSION + 1 });
+        assert!(migrate(&mut value).is_err());
+    }
+
+    #[test]
+    fn dscp_range() {
+        assert_eq!(
+            serde_json::from_value::<Dscp>(json!(46)).unwrap().tos(),
+            0xb8
+        );
+        assert!(serde_json::from_value::<Dscp>(json!(64)).is_err());
+    }
+
+    #[test]
+    fn retry_jitter_range() {
+        let retry = |jitter| serde_json::from_value::<Retry>(json!({ "jitter": jitter }));
+        assert_eq!(retry(json!(0.25)).unwrap().jitter, 0.25);
+        assert!(retry(json!(-0.5)).is_err());
+        assert!(retry(json!(1.5)).is_err());
+    }
+
+    #[test]
+    fn rate_limit_qps() {
+        let limit = |qps| serde_json::from_value::<RateLimit>(json!({ "qps": qps }));
+        assert_eq!(limit(10).unwrap().qps, 10);
+        assert!(limit(0).is_err());
+    }
+
+    #[test]
+    fn concurrency_max() {
+        let limit = |max| serde_json::from_value::<Concurrency>(json!({ "max": max }));
+        assert_eq!(limit(4).unwrap().max, 4);
+        assert!(limit(0).is_err());
+    }
+
+    #[test]
+    fn keepalive_interval() {
+        let keepalive =
+            |interval| serde_json::from_value::<KeepAlive>(json!({ "interval": interval }));
+        assert_eq!(keepalive(30).unwrap().interval, 30);
+        assert!(keepalive(0).is_err());
+    }
+
+    #[test]
+    fn kubernetes_ca_file() {
+        let path = std::env::temp_dir().join(format!("rdns-ca-{}.pem", std::process::id()));
+        let kubernetes = |ca_file: &Path| {
+            let conf = json!({
+                "Kubernetes": { "url": "https://192.0.2.1", "timeout": 1, "ca_file": ca_file },
+            });
+            serde_json::from_value::<ResolverConfig>(conf)
+                .unwrap()
+                .validate()
+        };
+
+        assert!(kubernetes(Path::new("testdata/certs/ca.pem")).is_ok());
+        assert!(kubernetes(&path).is_err());
+        std::fs::write(&path, "not a certificate").unwrap();
+        assert!(kubernetes(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn payload_size_range() {
+        let udp = |size| {
+            let conf =
+                json!({ "Udp": { "addr": "192.0.2.1:53", "timeout": 1, "payload_size": size } });
+            serde_json::from_value::<ResolverConfig>(conf)
+        };
+        assert!(udp(1232).is_ok());
+        assert!(udp(511).is_err());
+    }
+
+    #[test]
+    fn parse_scoped_addr() {
+        // The loopback interface doesn't have the same index everywhere.
+        let index = unsafe { libc::if_nametoindex(c"lo".as_ptr()) };
+        assert_ne!(index, 0);
+
+        let addr = parse_socket_addr("[fe80::1%lo]:53").unwrap();
+        let numeric = format!("[fe80::1%{}]:53", index);
+        assert_eq!(addr, parse_socket_addr(&numeric).unwrap());
+        assert!(parse_socket_addr("[fe80::1%nonexistent0]:53").is_err());
+        assert!(parse_socket_addr("127.0.0.1%lo:53").is_err());
+    }
+
+    #[test]
+    fn bind_one_or_many() {
+        let config = json!({ "zones": {}, "http": { "enabled": false, "bind": "127.0.0.1:8080" } });
+        let with_bind = |bind| {
+            let mut config = config.clone();
+            config["bind"] = bind;
+            serde_json::from_value::<Config>(config).unwrap()
+        };
+
+        let config = with_bind(json!("[::]:53"));
+        assert_eq!(config.bind[0].label(), "[::]:53");
+        assert!(!config.v6only(config.bind[0].addr));
+
+        let config = with_bind(json!(["0.0.0.0:53", { "addr": "[::]:53", "name": "v6" }]));
+        assert_eq!(config.bind.len(), 2);
+        assert_eq!(config.bind[1].label(), "v6");
+        assert!(config.v6only(config.bind[1].addr));
+    }
+
+    #[test]
+    fn https_proxy() {
+        let with_proxy = |proxy| {
+            serde_json::from_value::<ResolverConfig>(json!({
+                "Https": { "url": "https://dns.example/dns-query", "timeout": 5, "proxy": proxy },
+            }))
+        };
+
+        let ResolverConfig::Https(conf) = with_proxy("socks5://127.0.0.1:9050").unwrap() else {
+            unreachable!();
+        };
+        assert_eq!(conf.proxy.unwrap().kind, ProxyKind::Socks5);
+        assert!(with_proxy("socks5://proxy.example:9050").is_err());
+        assert!(with_proxy("ftp://127.0.0.1").is_err());
+    }
 }
@@ -0,0 +1,129 @@
+//! Discovery of Designated Resolvers.
+//!
+//! Clients that know the server only by its address query the SVCB records
+//! of `_dns.resolver.arpa` to learn about its encrypted frontends.
+//!
+//! See https://datatracker.ietf.org/doc/html/rfc9462
+
+use std::net::IpAddr;
+
+use bytes::Bytes;
+
+use crate::config::Config;
+use crate::http::doh;
+use crate::proto::{Fqdn, RecordData, SvcParam, SvcbData};
+
+/// The name queried by clients to discover the encrypted frontends.
+pub const NAME: &str = "_dns.resolver.arpa.";
+
+/// Builds the SVCB records of all enabled encrypted frontends.
+pub fn records(config: &Config) -> Vec<RecordData> {
+    let ddr = &config.ddr;
+    if !ddr.enabled {
+        return Vec::new();
+    }
+
+    // The name is checked to be set by Config::validate.
+    let mut target = ddr.name.clone();
+    if !target.ends_with('.') {
+        target.push('.');
+    }
+    let dohpath = format!("{}{{?dns}}", doh::PATH);
+
+    let mut endpoints = Vec::new();
+    if let Some(tls) = &config.frontend.tls {
+        endpoints.push((
+            tls.bind.port(),
+            hint(tls.bind.ip()),
+            vec![SvcParam::alpn(&["dot"])],
+        ));
+    }
+    if let Some(quic) = &config.frontend.quic {
+        let hint = hint(quic.bind.ip());
+        endpoints.push((
+            quic.bind.port(),
+            hint.clone(),
+            vec![SvcParam::alpn(&["doq"])],
+        ));
+        if quic.http3 {
+            endpoints.push((
+                quic.bind.port(),
+                hint,
+                vec![SvcParam::alpn(&["h3"]), SvcParam::dohpath(&dohpath)],
+            ));
+        }
+    }
+    let http = &config.http;
+    if let Some(port) = ddr.https_port.filter(|_| http.enabled && http.doh) {
+        // The reverse proxy terminating TLS is reached on the same address
+        // as the query, but not necessarily the one the HTTP frontend is
+        // bound to.
+        endpoints.push((
+            port,
+            None,
+            vec![SvcParam::alpn(&["h2"]), SvcParam::dohpath(&dohpath)],
+        ));
+    }
+
+    endpoints
+        .into_iter()
+        .enumerate()
+        .map(|(index, (port, hint, mut params))| {
+            params.push(SvcParam::port(port));
+            params.extend(hint);
+            params.sort_by_key(|param| param.key);
+
+            RecordData::SVCB(SvcbData {
+                priority: index as u16 + 1,
+                target: Fqdn::new_unchecked(target.clone()),
+                params,
+            })
+        })
+        .collect()
+}
+
+/// Returns the address hint of a frontend bound to `ip`.
+fn hint(ip: IpAddr) -> Option<SvcParam> {
+    let (key, value) = match ip {
+        _ if ip.is_unspecified() => return None,
+        IpAddr::V4(ip) => (SvcParam::IPV4HINT, Bytes::copy_from_slice(&ip.octets())),
+        IpAddr::V6(ip) => (SvcParam::IPV6HINT, Bytes::copy_from_slice(&ip.octets())),
+    };
+
+    Some(SvcParam { key, value })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config::Config;
+
+    use super::records;
+
+    #[test]
+    fn advertises_encrypted_frontends() {
+        let config = json!({
+            "bind": "0.0.0.0:53",
+            "zones": {},
+            "http": { "enabled": true, "bind": "127.0.0.1:8080", "doh": true },
+            "frontend": {
+                "tls": { "bind": "192.0.2.1:853", "cert": "cert.pem", "key": "key.pem" },
+                "quic": { "bind": "[::]:853", "cert": "cert.pem", "key": "key.pem", "http3": true },
+            },
+            "ddr": { "enabled": true, "name": "dns.example", "https_port": 443 },
+        });
+        let config: Config = serde_json::from_value(config).unwrap();
+
+        let records: Vec<_> = records(&config).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            records,
+            [
+                "1 dns.example. alpn=dot port=853 ipv4hint=192.0.2.1",
+                "2 dns.example. alpn=doq port=853",
+                "3 dns.example. alpn=h3 port=853 dohpath=\"/dns-query{?dns}\"",
+                "4 dns.example. alpn=h2 port=443 dohpath=\"/dns-query{?dns}\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Comparison of the answers of two upstream sets.
+//!
+//! Sampled queries that were answered by the primary upstreams of a zone are
+//! repeated against the shadow upstreams configured in [`Config::diff`] and
+//! any difference between both answers is logged.
+//!
+//! [`Config::diff`]: crate::config::Config::diff
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+
+use crate::proto::{Question, ResourceRecord, ResponseCode};
+use crate::upstream::{QueryFlags, ResolverError, Upstream};
+
+/// The outcome of resolving a question from an upstream set.
+///
+/// Errors that don't come from the upstream (e.g. timeouts) are not
+/// comparable and never make it into an `Outcome`.
+pub type Outcome = Result<Vec<ResourceRecord>, ResponseCode>;
+
+/// A single difference between the primary and the shadow answer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// The upstream sets responded with different response codes.
+    ResponseCode {
+        primary: ResponseCode,
+        shadow: ResponseCode,
+    },
+    /// A record only the primary upstreams returned.
+    Missing(String),
+    /// A record only the shadow upstreams returned.
+    Extra(String),
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ResponseCode { primary, shadow } => {
+                write!(f, "rcode mismatch: {:?} != {:?}", primary, shadow)
+            }
+            Self::Missing(record) => write!(f, "missing record: {}", record),
+            Self::Extra(record) => write!(f, "extra record: {}", record),
+        }
+    }
+}
+
+/// A question that was answered by the primary upstreams and should be
+/// compared against the shadow upstreams.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub question: Question,
+    pub primary: Outcome,
+}
+
+/// Resolves `question` using the first upstream in `resolvers` that answers.
+pub async fn resolve(upstreams: &[Upstream], question: &Question) -> Option<Outcome> {
+    for resolver in upstreams.iter().map(|upstream| &upstream.resolver) {
+        match resolver.resolve(question, &QueryFlags::default()).await {
+            Ok(answer) => return Some(Ok(answer.records)),
+            Err(ResolverError::ResponseCode(code, _)) => return Some(Err(code)),
+            Err(err) => {
+                tracing::debug!("shadow upstream {} failed: {:?}", resolver.addr(), err);
+            }
+        }
+    }
+
+    None
+}
+
+/// Returns all differences between the `primary` and `shadow` outcome.
+///
+/// TTLs, the order of records and the case of owner names are ignored.
+pub fn compare(primary: &Outcome, shadow: &Outcome) -> Vec<Difference> {
+    let code = |outcome: &Outcome| match outcome {
+        Ok(_) => ResponseCode::Ok,
+        Err(code) => *code,
+    };
+
+    if code(primary) != code(shadow) {
+        return vec![Difference::ResponseCode {
+            primary: code(primary),
+            shadow: code(shadow),
+        }];
+    }
+
+    let (Ok(primary), Ok(shadow)) = (primary, shadow) else {
+        return Vec::new();
+    };
+
+    let primary = records(primary);
+    let shadow = records(shadow);
+
+    let mut diffs: Vec<_> = primary
+        .difference(&shadow)
+        .cloned()
+        .map(Difference::Missing)
+        .collect();
+    diffs.extend(shadow.difference(&primary).cloned().map(Difference::Extra));
+    diffs
+}
+
+fn records(records: &[ResourceRecord]) -> BTreeSet<String> {
+    records
+        .iter()
+        .map(|record| {
+            format!(
+                "{} {} {} {}",
+                record.name.to_string().to_ascii_lowercase(),
+                record.class,
+                record.r#type,
+                record.rdata
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::proto::{Class, Fqdn, RecordData, ResourceRecord, ResponseCode, Type};
+
+    use super::{compare, Difference};
+
+    fn a(name: &str, addr: Ipv4Addr, ttl: u32) -> ResourceRecord {
+        ResourceRecord {
+            name: Fqdn(name.as_bytes().to_vec()),
+            r#type: Type::A,
+            class: Class::In,
+            ttl,
+            rdata: RecordData::A(addr),
+        }
+    }
+
+    #[test]
+    fn compare_ignores_ttl_order_and_case() {
+        let primary = Ok(vec![
+            a("example.com.", Ipv4Addr::new(192, 0, 2, 1), 300),
+            a("example.com.", Ipv4Addr::new(192, 0, 2, 2), 300),
+        ]);
+        let shadow = Ok(vec![
+            a("EXAMPLE.com.", Ipv4Addr::new(192, 0, 2, 2), 60),
+            a("example.com.", Ipv4Addr::new(192, 0, 2, 1), 60),
+        ]);
+
+        assert_eq!(compare(&primary, &shadow), vec![]);
+    }
+
+    #[test]
+    fn compare_records() {
+        let primary = Ok(vec![a("example.com.", Ipv4Addr::new(192, 0, 2, 1), 300)]);
+        let shadow = Ok(vec![a("example.com.", Ipv4Addr::new(192, 0, 2, 2), 300)]);
+
+        assert_eq!(
+            compare(&primary, &shadow),
+            vec![
+                Difference::Missing("example.com. IN A 192.0.2.1".to_owned()),
+                Difference::Extra("example.com. IN A 192.0.2.2".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_response_code() {
+        let primary = Ok(vec![a("example.com.", Ipv4Addr::new(192, 0, 2, 1), 300)]);
+        let shadow = Err(ResponseCode::NameError);
+
+        assert_eq!(
+            compare(&primary, &shadow),
+            vec![Difference::ResponseCode {
+                primary: ResponseCode::Ok,
+                shadow: ResponseCode::NameError,
+            }]
+        );
+    }
+}
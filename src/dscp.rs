@@ -0,0 +1,41 @@
+//! Marking of outgoing packets with a DSCP value.
+//!
+//! Network operators can use the marking to prioritize DNS traffic.
+
+use std::io;
+use std::os::fd::AsFd;
+
+use socket2::SockRef;
+
+use crate::config::Dscp;
+
+/// Marks all packets sent through `socket` with `dscp`.
+///
+/// For TCP listeners the marking is inherited by the accepted connections.
+pub fn set<S>(socket: &S, dscp: Dscp) -> io::Result<()>
+where
+    S: AsFd,
+{
+    let socket = SockRef::from(socket);
+    if socket.local_addr()?.is_ipv6() {
+        socket.set_tclass_v6(dscp.tos())?;
+        // Dual-stack sockets use the TOS option for IPv4 peers. It is not
+        // supported on IPv6-only sockets, which never need it.
+        let _ = socket.set_tos(dscp.tos());
+        Ok(())
+    } else {
+        socket.set_tos(dscp.tos())
+    }
+}
+
+/// Applies the configured DSCP value to a frontend socket.
+pub fn set_frontend<S>(socket: &S, dscp: Option<Dscp>)
+where
+    S: AsFd,
+{
+    if let Some(dscp) = dscp {
+        if let Err(err) = set(socket, dscp) {
+            tracing::warn!("failed to set DSCP on frontend socket: {}", err);
+        }
+    }
+}
@@ -0,0 +1,624 @@
+//! DNS over DTLS.
+//!
+//! Datagrams are demultiplexed by their source address into one DTLS session
+//! per client, every record of a session carries exactly one DNS message.
+//!
+//! Clients must prove that they own their address before any state is kept
+//! for them. ClientHellos without a valid cookie are answered with a
+//! stateless HelloVerifyRequest, sessions are only created for the
+//! ClientHello that repeats the cookie.
+//! See https://datatracker.ietf.org/doc/html/rfc6347#section-4.2.1
+//!
+//! See https://datatracker.ietf.org/doc/html/rfc8094
+
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use foreign_types::ForeignType;
+use futures::{select_biased, FutureExt};
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::ssl::{
+    Ssl, SslContext, SslFiletype, SslMethod, SslOptions, SslRef, SslVerifyMode, SslVersion,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_openssl::SslStream;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+use crate::config::TlsFrontend;
+use crate::dscp;
+use crate::metrics::Listener;
+use crate::state::State;
+
+use super::check_header;
+use super::socket;
+use super::tls::{allowed_names, has_allowed_name};
+use super::udp::answer_datagram;
+
+/// Maximum size of the datagrams we send.
+///
+/// See https://www.dnsflagday.net/2020/
+const MTU: u32 = 1232;
+
+/// Maximum size of a response, leaving room for the DTLS record header and
+/// the AEAD expansion within the [`MTU`].
+const MAX_RESPONSE_SIZE: usize = MTU as usize - 64;
+
+/// Maximum number of concurrent sessions. ClientHellos with a valid cookie
+/// are dropped while the limit is reached.
+const MAX_SESSIONS: usize = 4096;
+
+/// Maximum number of datagrams queued for a single session.
+const SESSION_QUEUE_SIZE: usize = 16;
+
+/// DTLS version 1.0, used in HelloVerifyRequests for every version.
+const DTLS1_VERSION: u16 = 0xfeff;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_HELLO_VERIFY_REQUEST: u8 = 3;
+
+const RECORD_HEADER_LEN: usize = 13;
+const HANDSHAKE_HEADER_LEN: usize = 12;
+
+// Not exposed by openssl-sys.
+extern "C" {
+    fn DTLSv1_listen(ssl: *mut openssl_sys::SSL, client: *mut c_void) -> c_int;
+    fn BIO_ADDR_new() -> *mut c_void;
+    fn BIO_ADDR_free(addr: *mut c_void);
+}
+
+pub struct DtlsServer {
+    socket: Arc<UdpSocket>,
+    context: SslContext,
+    /// Key of the cookies sent in HelloVerifyRequests.
+    secret: [u8; 32],
+    /// Index of the client address in the ex data of every [`Ssl`].
+    addr_index: Index<Ssl, SocketAddr>,
+    idle_timeout: Duration,
+    label: String,
+}
+
+impl DtlsServer {
+    pub async fn new(config: &TlsFrontend, v6only: bool) -> Self {
+        let mut builder = SslContext::builder(SslMethod::dtls()).unwrap();
+        builder
+            .set_min_proto_version(Some(SslVersion::DTLS1_2))
+            .unwrap();
+        builder.set_certificate_chain_file(&config.cert).unwrap();
+        builder
+            .set_private_key_file(&config.key, SslFiletype::PEM)
+            .unwrap();
+        builder.check_private_key().unwrap();
+
+        if let Some(client_auth) = &config.client_auth {
+            builder.set_ca_file(&client_auth.ca).unwrap();
+            let names = allowed_names(client_auth);
+            let mode = SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+            builder.set_verify_callback(mode, move |preverified, ctx| {
+                // The names are only checked on the client certificate
+                // itself, not on its issuers.
+                if !preverified || names.is_empty() || ctx.error_depth() != 0 {
+                    return preverified;
+                }
+
+                ctx.current_cert()
+                    .and_then(|cert| cert.to_der().ok())
+                    .is_some_and(|der| has_allowed_name(&CertificateDer::from(der), &names))
+            });
+        }
+
+        // The cookies are verified again by `DTLSv1_listen`, which sets up
+        // the session to continue after the HelloVerifyRequest.
+        let mut secret = [0; 32];
+        openssl::rand::rand_bytes(&mut secret).unwrap();
+        let addr_index = Ssl::new_ex_index::<SocketAddr>().unwrap();
+        let mut options = SslOptions::COOKIE_EXCHANGE | SslOptions::NO_QUERY_MTU;
+        if !config.session_tickets {
+            options |= SslOptions::NO_TICKET;
+        }
+        builder.set_options(options);
+        builder.set_cookie_generate_cb(move |ssl, buf| {
+            let cookie = ssl_cookie(&secret, ssl, addr_index)?;
+            buf[..cookie.len()].copy_from_slice(&cookie);
+            Ok(cookie.len())
+        });
+        builder.set_cookie_verify_cb(move |ssl, buf| {
+            ssl_cookie(&secret, ssl, addr_index).is_ok_and(|cookie| cookie_eq(&cookie, buf))
+        });
+
+        let socket = socket::bind_udp(config.bind, v6only, false).unwrap();
+        let socket = UdpSocket::from_std(socket).unwrap();
+        Self {
+            socket: Arc::new(socket),
+            context: builder.build(),
+            secret,
+            addr_index,
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            label: config.label(),
+        }
+    }
+
+    pub async fn poll(&self, state: &'static State) -> Result<(), io::Error> {
+        dscp::set_frontend(&*self.socket, state.config.dscp);
+
+        let listener = state.metrics.listeners.register("dtls", &self.label);
+        let mut sessions: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+
+        loop {
+            let mut buf = BytesMut::with_capacity(1500);
+            let (_, addr) = select_biased! {
+                () = state.shutdown.triggered().fuse() => return Ok(()),
+                res = self.socket.recv_buf_from(&mut buf).fuse() => res?,
+            };
+            let buf = buf.freeze();
+
+            if let Some(tx) = sessions.get(&addr) {
+                match tx.try_send(buf) {
+                    Ok(()) => continue,
+                    // The client is sending faster than the session can
+                    // handle, DTLS tolerates lost datagrams.
+                    Err(mpsc::error::TrySendError::Full(_)) => continue,
+                    Err(mpsc::error::TrySendError::Closed(buf)) => {
+                        sessions.remove(&addr);
+                        self.accept(&mut sessions, addr, buf, state, &listener)
+                            .await;
+                    }
+                }
+            } else {
+                self.accept(&mut sessions, addr, buf, state, &listener)
+                    .await;
+            }
+        }
+    }
+
+    /// Handles a datagram from a client without a session.
+    ///
+    /// Only ClientHellos are accepted. Without a valid cookie they are
+    /// answered with a HelloVerifyRequest and nothing is kept for the client.
+    async fn accept(
+        &self,
+        sessions: &mut HashMap<SocketAddr, mpsc::Sender<Bytes>>,
+        addr: SocketAddr,
+        buf: Bytes,
+        state: &'static State,
+        listener: &Arc<Listener>,
+    ) {
+        let Some(hello) = ClientHello::parse(&buf) else {
+            return;
+        };
+
+        let Ok(cookie) = cookie(&self.secret, addr) else {
+            return;
+        };
+        if cookie_eq(&cookie, hello.cookie) {
+            self.spawn_session(sessions, addr, buf, state, listener);
+            return;
+        }
+
+        let request = hello_verify_request(&hello, &cookie);
+        if let Err(err) = self.socket.send_to(&request, addr).await {
+            tracing::debug!("failed to send HelloVerifyRequest to {}: {}", addr, err);
+        }
+    }
+
+    fn spawn_session(
+        &self,
+        sessions: &mut HashMap<SocketAddr, mpsc::Sender<Bytes>>,
+        addr: SocketAddr,
+        buf: Bytes,
+        state: &'static State,
+        listener: &Arc<Listener>,
+    ) {
+        if sessions.len() >= MAX_SESSIONS {
+            sessions.retain(|_, tx| !tx.is_closed());
+            if sessions.len() >= MAX_SESSIONS {
+                return;
+            }
+        }
+
+        let ssl = match self.ssl(addr) {
+            Ok(ssl) => ssl,
+            Err(err) => {
+                tracing::error!("failed to create DTLS session: {}", err);
+                return;
+            }
+        };
+        if !listen(&ssl, &buf) {
+            return;
+        }
+
+        let (tx, rx) = mpsc::channel(SESSION_QUEUE_SIZE);
+        sessions.insert(addr, tx);
+
+        let datagrams = Datagrams {
+            socket: self.socket.clone(),
+            addr,
+            rx,
+        };
+        let idle_timeout = self.idle_timeout;
+        let guard = state.shutdown.guard();
+        let listener = listener.clone();
+        tokio::task::spawn(async move {
+            let _guard = guard;
+            if let Err(err) = handle_session(ssl, datagrams, state, &listener, idle_timeout).await {
+                tracing::debug!("DTLS session with {} failed: {}", addr, err);
+            }
+        });
+    }
+
+    fn ssl(&self, addr: SocketAddr) -> Result<Ssl, ErrorStack> {
+        let mut ssl = Ssl::new(&self.context)?;
+        ssl.set_ex_data(self.addr_index, addr);
+        ssl.set_mtu(MTU)?;
+        Ok(ssl)
+    }
+}
+
+async fn handle_session(
+    ssl: Ssl,
+    datagrams: Datagrams,
+    state: &State,
+    listener: &Listener,
+    idle_timeout: Duration,
+) -> Result<(), io::Error> {
+    let addr = datagrams.addr;
+    let mut stream = SslStream::new(ssl, datagrams).map_err(io::Error::other)?;
+
+    match tokio::time::timeout(idle_timeout, Pin::new(&mut stream).accept()).await {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => return Err(io::Error::other(err)),
+        Err(_) => return Ok(()),
+    }
+
+    let mut buf = vec![0; usize::from(u16::MAX)];
+    loop {
+        // Every read returns the contents of a single record.
+        let read = tokio::time::timeout(idle_timeout, stream.read(&mut buf));
+        let res = select_biased! {
+            () = state.shutdown.triggered().fuse() => return Ok(()),
+            res = read.fuse() => res,
+        };
+
+        let len = match res {
+            Ok(Ok(0)) | Err(_) => return Ok(()),
+            Ok(Ok(len)) => len,
+            Ok(Err(err)) => return Err(err),
+        };
+
+        if !check_header(&buf[..len], state) {
+            continue;
+        }
+
+        let query = Bytes::copy_from_slice(&buf[..len]);
+        if let Some(response) =
+            answer_datagram(query, addr, state, listener, MAX_RESPONSE_SIZE).await
+        {
+            stream.write_all(&response).await?;
+        }
+    }
+}
+
+/// Feeds the ClientHello `hello` with a verified cookie to `ssl`, so that the
+/// handshake continues after the HelloVerifyRequest.
+///
+/// Returns `false` if OpenSSL rejects the ClientHello.
+fn listen(ssl: &Ssl, hello: &[u8]) -> bool {
+    // SAFETY: The memory BIOs hold a copy of `hello`. They are owned by `ssl`
+    // once set and freed when the stream replaces them.
+    unsafe {
+        let rbio = openssl_sys::BIO_new(openssl_sys::BIO_s_mem());
+        let wbio = openssl_sys::BIO_new(openssl_sys::BIO_s_mem());
+        if rbio.is_null()
+            || wbio.is_null()
+            || openssl_sys::BIO_write(rbio, hello.as_ptr().cast(), hello.len() as c_int)
+                != hello.len() as c_int
+        {
+            openssl_sys::BIO_free_all(rbio);
+            openssl_sys::BIO_free_all(wbio);
+            return false;
+        }
+
+        openssl_sys::SSL_set_bio(ssl.as_ptr(), rbio, wbio);
+
+        // The address is required, even though it is unknown to the BIO.
+        let client = BIO_ADDR_new();
+        if client.is_null() {
+            return false;
+        }
+        let res = DTLSv1_listen(ssl.as_ptr(), client);
+        BIO_ADDR_free(client);
+        res > 0
+    }
+}
+
+/// Computes the cookie of the client `addr`.
+fn cookie(secret: &[u8], addr: SocketAddr) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(addr.to_string().as_bytes())?;
+    signer.sign_to_vec()
+}
+
+/// Computes the cookie of the client of `ssl`.
+fn ssl_cookie(
+    secret: &[u8],
+    ssl: &SslRef,
+    addr_index: Index<Ssl, SocketAddr>,
+) -> Result<Vec<u8>, ErrorStack> {
+    match ssl.ex_data(addr_index) {
+        Some(addr) => cookie(secret, *addr),
+        None => Err(ErrorStack::get()),
+    }
+}
+
+/// Compares two cookies in constant time.
+fn cookie_eq(a: &[u8], b: &[u8]) -> bool {
+    // `memcmp::eq` panics on different lengths.
+    a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
+/// The fields of an unfragmented ClientHello needed to answer it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct ClientHello<'a> {
+    /// Epoch and sequence number of the record.
+    record_seq: &'a [u8],
+    message_seq: u16,
+    cookie: &'a [u8],
+}
+
+impl<'a> ClientHello<'a> {
+    /// Parses the first record of `buf`, returning `None` if it is not a
+    /// ClientHello.
+    fn parse(buf: &'a [u8]) -> Option<Self> {
+        let record = buf.get(..RECORD_HEADER_LEN)?;
+        if record[0] != CONTENT_TYPE_HANDSHAKE || record[3..5] != [0, 0] {
+            return None;
+        }
+        let record_len = usize::from(u16::from_be_bytes([record[11], record[12]]));
+        let fragment = buf.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len)?;
+
+        let handshake = fragment.get(..HANDSHAKE_HEADER_LEN)?;
+        let len = &handshake[1..4];
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO
+            || handshake[6..9] != [0, 0, 0]
+            || handshake[9..12] != *len
+        {
+            return None;
+        }
+
+        // The version and random precede the session ID.
+        let body = &fragment[HANDSHAKE_HEADER_LEN..];
+        let session_id_len = usize::from(*body.get(34)?);
+        let cookie_len = usize::from(*body.get(35 + session_id_len)?);
+        let cookie = body.get(36 + session_id_len..36 + session_id_len + cookie_len)?;
+
+        Some(Self {
+            record_seq: &record[3..11],
+            message_seq: u16::from_be_bytes([handshake[4], handshake[5]]),
+            cookie,
+        })
+    }
+}
+
+/// Encodes a HelloVerifyRequest with `cookie` in response to `hello`.
+fn hello_verify_request(hello: &ClientHello<'_>, cookie: &[u8]) -> Vec<u8> {
+    let body_len = 3 + cookie.len();
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + HANDSHAKE_HEADER_LEN + body_len);
+
+    buf.put_u8(CONTENT_TYPE_HANDSHAKE);
+    buf.put_u16(DTLS1_VERSION);
+    // The record repeats the sequence number of the ClientHello.
+    buf.put_slice(hello.record_seq);
+    buf.put_u16((HANDSHAKE_HEADER_LEN + body_len) as u16);
+
+    buf.put_u8(HANDSHAKE_HELLO_VERIFY_REQUEST);
+    buf.put_uint(body_len as u64, 3);
+    buf.put_u16(hello.message_seq);
+    buf.put_uint(0, 3);
+    buf.put_uint(body_len as u64, 3);
+
+    buf.put_u16(DTLS1_VERSION);
+    buf.put_u8(cookie.len() as u8);
+    buf.put_slice(cookie);
+    buf
+}
+
+/// The datagrams of a single client on the shared socket.
+struct Datagrams {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl AsyncRead for Datagrams {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(datagram)) => {
+                // Datagrams that don't fit are truncated, just like on a socket.
+                let len = datagram.len().min(buf.remaining());
+                buf.put_slice(&datagram[..len]);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for Datagrams {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, self.addr)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use openssl::ssl::{Ssl, SslContext, SslMethod, SslVerifyMode};
+    use serde_json::json;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::UdpSocket;
+    use tokio_openssl::SslStream;
+
+    use crate::state::State;
+
+    use super::{hello_verify_request, ClientHello, DtlsServer};
+
+    /// A ClientHello with the record sequence number 1, message sequence
+    /// number 1, a session ID of 2 bytes and `cookie`.
+    fn client_hello(cookie: &[u8]) -> Vec<u8> {
+        let mut body = vec![0xfe, 0xfd];
+        body.extend([7; 32]);
+        body.extend([2, 0xaa, 0xbb]);
+        body.push(cookie.len() as u8);
+        body.extend(cookie);
+        // Cipher suites and compression methods.
+        body.extend([0, 2, 0xc0, 0x2b, 1, 0]);
+
+        let len = (body.len() as u32).to_be_bytes();
+        let mut handshake = vec![1, len[1], len[2], len[3], 0, 1, 0, 0, 0];
+        handshake.extend(&len[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![22, 0xfe, 0xfd, 0, 0, 0, 0, 0, 0, 0, 1];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn parse_client_hello() {
+        let buf = client_hello(&[1, 2, 3]);
+        let hello = ClientHello::parse(&buf).unwrap();
+        assert_eq!(hello.record_seq, [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(hello.message_seq, 1);
+        assert_eq!(hello.cookie, [1, 2, 3]);
+
+        assert!(ClientHello::parse(&client_hello(&[]))
+            .unwrap()
+            .cookie
+            .is_empty());
+
+        // Truncated, application data and fragmented handshakes.
+        assert_eq!(ClientHello::parse(&buf[..buf.len() - 1]), None);
+        let mut other = buf.clone();
+        other[0] = 23;
+        assert_eq!(ClientHello::parse(&other), None);
+        let mut fragment = buf.clone();
+        fragment[13 + 11] -= 1;
+        assert_eq!(ClientHello::parse(&fragment), None);
+    }
+
+    #[test]
+    fn encode_hello_verify_request() {
+        let buf = client_hello(&[]);
+        let hello = ClientHello::parse(&buf).unwrap();
+
+        let request = hello_verify_request(&hello, &[9; 4]);
+        assert_eq!(
+            request,
+            [
+                22, 0xfe, 0xff, 0, 0, 0, 0, 0, 0, 0, 1, 0, 19, // Record
+                3, 0, 0, 7, 0, 1, 0, 0, 0, 0, 0, 7, // Handshake
+                0xfe, 0xff, 4, 9, 9, 9, 9,
+            ]
+        );
+    }
+
+    /// A DTLS client socket.
+    struct Connected(UdpSocket);
+
+    impl AsyncRead for Connected {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.0.poll_recv(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Connected {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.poll_send(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_with_cookie_exchange() {
+        let config = json!({
+            "bind": "127.0.0.1:0",
+            "zones": {},
+            "http": { "enabled": false, "bind": "127.0.0.1:0" },
+        });
+        let state: &'static State = Box::leak(Box::new(
+            State::new(serde_json::from_value(config).unwrap()).unwrap(),
+        ));
+        let frontend = serde_json::from_value(json!({
+            "bind": "127.0.0.1:0",
+            "cert": "testdata/certs/dot.pem",
+            "key": "testdata/certs/dot-key.pem",
+        }))
+        .unwrap();
+        let server = DtlsServer::new(&frontend, false).await;
+        let addr = server.socket.local_addr().unwrap();
+        tokio::task::spawn(async move { server.poll(state).await });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+        context.set_verify(SslVerifyMode::NONE);
+        let ssl = Ssl::new(&context.build()).unwrap();
+        let mut stream = SslStream::new(ssl, Connected(socket)).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), Pin::new(&mut stream).connect())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1 +1,441 @@
+#[cfg(feature = "dtls")]
+pub mod dtls;
+mod offload;
+pub mod quic;
+pub mod socket;
+pub mod tcp;
+pub mod tls;
 pub mod udp;
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::future;
+
+use crate::config;
+use crate::metrics::Metrics;
+use crate::proto::{
+    DecodeError, Edns, ExtendedError, Header, OpCode, Packet, Qr, QueryHead, ResourceRecord,
+    ResponseCode,
+};
+use crate::state::{Resolution, State};
+use crate::upstream::{QueryFlags, ResolverError};
+
+/// The UDP payload size we announce in our own OPT records.
+///
+/// See https://www.dnsflagday.net/2020/
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// Answers a query from `client` and returns the response.
+///
+/// The address of the `client` is unknown for queries through a reverse proxy.
+pub async fn handle_query(packet: Packet, client: Option<IpAddr>, state: &State) -> Packet {
+    let mut answers = Vec::new();
+    let mut response_code = ResponseCode::Ok;
+
+    // We only implement EDNS version 0. Newer versions must be rejected with
+    // BADVERS and an OPT record carrying the highest version we support.
+    // See https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.3
+    if packet.edns.as_ref().is_some_and(|edns| edns.version > 0) {
+        state
+            .metrics
+            .badvers_responses
+            .fetch_add(1, Ordering::Relaxed);
+
+        return Packet {
+            edns: Some(Edns {
+                udp_payload_size: EDNS_PAYLOAD_SIZE,
+                extended_rcode: Edns::BADVERS,
+                version: 0,
+                dnssec_ok: false,
+                options: Default::default(),
+            }),
+            ..response(packet, ResponseCode::Ok, Vec::new())
+        };
+    }
+
+    match packet.opcode {
+        OpCode::Query => (),
+        // We don't have any zones that could be updated.
+        OpCode::Update => response_code = ResponseCode::Refused,
+        _ => response_code = ResponseCode::NotImplemented,
+    }
+
+    if packet.questions.len() > 1
+        && state.config.frontend.multiple_questions == config::MultipleQuestions::Reject
+    {
+        response_code = ResponseCode::FormatError;
+    }
+
+    let mut options = Vec::new();
+    if response_code == ResponseCode::Ok {
+        let flags = QueryFlags::from_query(&packet, client);
+        let results = future::join_all(
+            packet
+                .questions
+                .iter()
+                .map(|question| state.resolve(question, &flags)),
+        )
+        .await;
+        (response_code, answers, options) = merge_answers(results);
+    }
+
+    answer(packet, response_code, answers, options)
+}
+
+/// Answers a standard query from the cache, using only the header, question
+/// and OPT record `edns` of the query.
+///
+/// Returns the query again if it is not answered from the cache, it must then
+/// be fully decoded and answered with [`handle_query`].
+pub fn answer_cached(
+    head: QueryHead,
+    edns: Option<Edns>,
+    client: Option<IpAddr>,
+    state: &State,
+) -> Result<Packet, QueryHead> {
+    let [question] = head.questions.as_slice() else {
+        return Err(head);
+    };
+    if head.opcode != OpCode::Query || edns.as_ref().is_some_and(|edns| edns.version > 0) {
+        return Err(head);
+    }
+
+    let flags = QueryFlags::new(head.header.cd(), edns.as_ref(), client);
+    let Some(resolution) = state.resolve_cached(question, &flags) else {
+        return Err(head);
+    };
+
+    let (response_code, answers, options) = merge_answers(vec![Ok(resolution)]);
+    Ok(answer(
+        head.into_query(edns),
+        response_code,
+        answers,
+        options,
+    ))
+}
+
+/// Builds the response to `query` with the resolved `answers` and EDNS
+/// `options`.
+fn answer(
+    query: Packet,
+    response_code: ResponseCode,
+    answers: Vec<ResourceRecord>,
+    options: Vec<u8>,
+) -> Packet {
+    // An OPT record must only be sent to clients that sent one themselves.
+    // See https://datatracker.ietf.org/doc/html/rfc6891#section-7
+    let edns = query.edns.as_ref().map(|edns| Edns {
+        udp_payload_size: EDNS_PAYLOAD_SIZE,
+        extended_rcode: 0,
+        version: 0,
+        dnssec_ok: edns.dnssec_ok,
+        options: options.into(),
+    });
+
+    Packet {
+        edns,
+        ..response(query, response_code, answers)
+    }
+}
+
+/// Merges the answers and EDNS options to all questions of a query.
+///
+/// If any question failed, no answers are returned and the response code and
+/// options are those of the first failed question.
+fn merge_answers(
+    results: Vec<Result<Resolution, ResolverError>>,
+) -> (ResponseCode, Vec<ResourceRecord>, Vec<u8>) {
+    let mut answers = Vec::new();
+    let mut options = Vec::new();
+    for result in results {
+        let response_code = match result {
+            Ok(resp) => {
+                options.extend(resp.options);
+                answers.extend(resp.resources.into_iter().map(|answer| ResourceRecord {
+                    r#type: answer.r#type,
+                    class: answer.class,
+                    ttl: answer.ttl().as_secs() as u32,
+                    rdata: answer.data,
+                    name: answer.name,
+                }));
+                continue;
+            }
+            Err(ResolverError::Refused) => ResponseCode::Refused,
+            Err(ResolverError::ResponseCode(code, options)) => {
+                return (code, Vec::new(), options.into());
+            }
+            Err(err) => {
+                tracing::error!("failed to resolve query: {:?}", err);
+                ResponseCode::ServerFailure
+            }
+        };
+
+        return (response_code, Vec::new(), Vec::new());
+    }
+
+    (ResponseCode::Ok, answers, options)
+}
+
+/// Returns `true` if the header of `buf` looks like a query we may answer.
+///
+/// This is checked before any work is spent on the packet. Everything else,
+/// including responses reflected at us by clients spoofing our address, is
+/// counted as malformed and must be dropped without a response.
+pub fn check_header(buf: &[u8], state: &State) -> bool {
+    let valid = is_query_header(buf);
+    if !valid {
+        state.metrics.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+    valid
+}
+
+fn is_query_header(buf: &[u8]) -> bool {
+    let Some(header) = buf.get(..Header::SIZE) else {
+        return false;
+    };
+
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let qdcount = u16::from_be_bytes([header[4], header[5]]);
+    let ancount = u16::from_be_bytes([header[6], header[7]]);
+    let nscount = u16::from_be_bytes([header[8], header[9]]);
+
+    if flags & (1 << 15) != 0 {
+        return false;
+    }
+
+    match OpCode::from_u16((flags >> 11) & 0b1111) {
+        // Standard queries carry exactly the question. Answer and authority
+        // records are only found in responses.
+        Some(OpCode::Query) => qdcount != 0 && ancount == 0 && nscount == 0,
+        // DSO messages have no question.
+        // See https://datatracker.ietf.org/doc/html/rfc8490#section-5.4
+        Some(OpCode::Dso) => true,
+        _ => qdcount != 0,
+    }
+}
+
+/// Builds the response to the query `buf` that failed to decode with `err`.
+///
+/// Returns `None` if the query is dropped, either because of
+/// [`Frontend::bad_queries`] or because it has no complete header to respond to.
+///
+/// [`Frontend::bad_queries`]: config::Frontend::bad_queries
+pub fn bad_query(buf: &[u8], err: &DecodeError, state: &State) -> Option<[u8; Header::SIZE]> {
+    let metrics = &state.metrics;
+    let (response_code, counter) = match err {
+        DecodeError::InvalidOpCode => (ResponseCode::NotImplemented, &metrics.bad_queries_notimp),
+        _ => (ResponseCode::FormatError, &metrics.bad_queries_formerr),
+    };
+
+    let response = match state.config.frontend.bad_queries {
+        config::BadQueries::Respond => error_header(buf, response_code),
+        config::BadQueries::Drop => None,
+    };
+
+    match response {
+        Some(_) => counter.fetch_add(1, Ordering::Relaxed),
+        None => metrics.bad_queries_dropped.fetch_add(1, Ordering::Relaxed),
+    };
+    response
+}
+
+/// Builds a response without any sections to the query `buf`, echoing its
+/// transaction ID, opcode and RD flag.
+///
+/// Returns `None` if `buf` has no complete header or is a response itself.
+fn error_header(buf: &[u8], response_code: ResponseCode) -> Option<[u8; Header::SIZE]> {
+    let header = buf.get(..Header::SIZE)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+
+    // Never answer responses, they are either misdirected
+    // or spoofed to reflect traffic back at us.
+    if flags & (1 << 15) != 0 {
+        return None;
+    }
+
+    // QR, opcode and RD.
+    let flags = (1 << 15) | (flags & 0b0111_1001_0000_0000) | response_code.to_u16();
+
+    let mut response = [0; Header::SIZE];
+    response[..2].copy_from_slice(&header[..2]);
+    response[2..4].copy_from_slice(&flags.to_be_bytes());
+    Some(response)
+}
+
+/// The reason a query was rejected without being resolved.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Too many queries are already being resolved.
+    Inflight,
+}
+
+impl Rejection {
+    fn extended_error(self) -> ExtendedError {
+        match self {
+            Self::Inflight => ExtendedError {
+                info_code: ExtendedError::OTHER,
+                extra_text: "Overloaded".to_owned(),
+            },
+        }
+    }
+
+    fn counter(self, metrics: &Metrics) -> &AtomicU64 {
+        match self {
+            Self::Inflight => &metrics.rejected_inflight,
+        }
+    }
+}
+
+/// Builds the response to a `query` that was rejected for `reason`.
+///
+/// The response code is chosen by [`Limits::rejection`], the reason is
+/// attached as an Extended DNS Error if the client supports EDNS. Returns `None`
+/// if the query is dropped instead.
+///
+/// [`Limits::rejection`]: config::Limits::rejection
+pub fn reject(query: Packet, reason: Rejection, state: &State) -> Option<Packet> {
+    reason
+        .counter(&state.metrics)
+        .fetch_add(1, Ordering::Relaxed);
+
+    let response_code = match state.config.limits.rejection {
+        config::Rejection::Refused => ResponseCode::Refused,
+        config::Rejection::ServFail => ResponseCode::ServerFailure,
+        config::Rejection::Drop => return None,
+    };
+
+    // An OPT record must only be sent to clients that sent one themselves.
+    // See https://datatracker.ietf.org/doc/html/rfc6891#section-7
+    let edns = query.edns.as_ref().map(|_| {
+        let mut options = Vec::new();
+        reason.extended_error().encode(&mut options);
+
+        Edns {
+            udp_payload_size: EDNS_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: options.into(),
+        }
+    });
+
+    Some(Packet {
+        edns,
+        ..response(query, response_code, Vec::new())
+    })
+}
+
+/// Builds the response to `query`.
+fn response(query: Packet, response_code: ResponseCode, answers: Vec<ResourceRecord>) -> Packet {
+    Packet {
+        transaction_id: query.transaction_id,
+        qr: Qr::Response,
+        opcode: query.opcode,
+        authoritative_answer: false,
+        recursion_desired: query.recursion_desired,
+        recursion_available: true,
+        // We never validate answers ourselves.
+        authentic_data: false,
+        checking_disabled: query.checking_disabled,
+        truncated: false,
+        response_code,
+        questions: query.questions,
+        raw_questions: query.raw_questions,
+        answers,
+        additional: Vec::new(),
+        authority: Vec::new(),
+        edns: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use crate::cache::Resource;
+    use crate::proto::{Class, Fqdn, RecordData, ResponseCode, Type};
+    use crate::state::Resolution;
+    use crate::upstream::ResolverError;
+
+    use super::{error_header, is_query_header, merge_answers};
+
+    #[test]
+    fn error_header_echoes_query() {
+        // ID 0x1234, opcode 7 (unassigned), RD, one question.
+        let query = [0x12, 0x34, 0x39, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0xff];
+        let response = error_header(&query, ResponseCode::NotImplemented).unwrap();
+        assert_eq!(response, [0x12, 0x34, 0xb9, 0x04, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Incomplete headers and responses are never answered.
+        assert_eq!(error_header(&query[..11], ResponseCode::FormatError), None);
+        let mut response = query;
+        response[2] |= 0x80;
+        assert_eq!(error_header(&response, ResponseCode::FormatError), None);
+    }
+
+    #[test]
+    fn is_query_header_rejects_responses() {
+        // ID 0x1234, RD, one question.
+        let mut query = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        assert!(is_query_header(&query));
+        assert!(!is_query_header(&query[..11]));
+
+        // An answer record, as in a response with QR cleared.
+        query[7] = 1;
+        assert!(!is_query_header(&query));
+        query[7] = 0;
+
+        // No question.
+        query[5] = 0;
+        assert!(!is_query_header(&query));
+
+        // DSO messages never have a question.
+        query[2] = 6 << 3;
+        assert!(is_query_header(&query));
+
+        query[2] |= 0x80;
+        assert!(!is_query_header(&query));
+    }
+
+    #[test]
+    fn merge_answers_first_error_wins() {
+        let resource = |name: &str| Resource {
+            name: Fqdn(name.as_bytes().to_vec()),
+            r#type: Type::A,
+            class: Class::In,
+            data: RecordData::A(Ipv4Addr::LOCALHOST),
+            valid_until: Instant::now() + Duration::from_secs(60),
+        };
+
+        let (code, answers, options) = merge_answers(vec![
+            Ok(vec![resource("a.example.")].into()),
+            Ok(Resolution {
+                resources: vec![resource("b.example.")],
+                options: vec![0, 15, 0, 2, 0, 3],
+            }),
+        ]);
+        assert_eq!(code, ResponseCode::Ok);
+        assert_eq!(options, [0, 15, 0, 2, 0, 3]);
+        let names: Vec<_> = answers.iter().map(|answer| answer.name.clone()).collect();
+        assert_eq!(
+            names,
+            [Fqdn(b"a.example.".to_vec()), Fqdn(b"b.example.".to_vec())]
+        );
+
+        let (code, answers, options) = merge_answers(vec![
+            Ok(vec![resource("a.example.")].into()),
+            Err(ResolverError::ResponseCode(
+                ResponseCode::NameError,
+                Bytes::from_static(&[0, 15, 0, 2, 0, 23]),
+            )),
+            Err(ResolverError::Refused),
+        ]);
+        assert_eq!(code, ResponseCode::NameError);
+        assert!(answers.is_empty());
+        assert_eq!(options, [0, 15, 0, 2, 0, 23]);
+    }
+}
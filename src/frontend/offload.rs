@@ -0,0 +1,266 @@
+//! UDP generic segmentation and receive offload.
+//!
+//! With GSO a single `sendmsg` carries multiple datagrams to the same
+//! destination, which the kernel (or the NIC) splits into segments of equal
+//! size. GRO is the receiving counterpart, the kernel coalesces datagrams from
+//! the same source and reports the size of the segments.
+//!
+//! Both are only available on Linux, on other platforms every datagram is sent
+//! and received on its own.
+//!
+//! See https://lwn.net/Articles/752184/
+
+/// Maximum number of segments in a single send, `UDP_MAX_SEGMENTS` in the kernel.
+pub const MAX_SEGMENTS: usize = 64;
+
+/// Maximum size of a single segment.
+///
+/// Segments must fit into the MTU of the outgoing interface, which we don't
+/// know. Larger responses are sent on their own and may be fragmented.
+///
+/// See https://www.dnsflagday.net/2020/
+pub const MAX_SEGMENT_SIZE: usize = 1232;
+
+/// Maximum total size of a single send, the UDP payload of an IPv4 datagram.
+pub const MAX_SEND_SIZE: usize = u16::MAX as usize - 20 - 8;
+
+/// Size of the buffer required to receive coalesced datagrams.
+pub const MAX_RECV_SIZE: usize = u16::MAX as usize;
+
+/// Returns the number of leading datagrams with the lengths `lens` that can be
+/// sent in a single GSO send.
+///
+/// All segments must have the same size, except for the last one which may be
+/// shorter.
+pub fn batch_len<I>(lens: I) -> usize
+where
+    I: IntoIterator<Item = usize>,
+{
+    let mut lens = lens.into_iter();
+    let Some(segment_size) = lens.next() else {
+        return 0;
+    };
+    if segment_size > MAX_SEGMENT_SIZE {
+        return 1;
+    }
+
+    let mut count = 1;
+    let mut total = segment_size;
+    for len in lens {
+        if len > segment_size || count == MAX_SEGMENTS || total + len > MAX_SEND_SIZE {
+            break;
+        }
+
+        count += 1;
+        total += len;
+
+        if len < segment_size {
+            break;
+        }
+    }
+
+    count
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::{enable_gro, enable_gso, recv_from, send_to};
+
+#[cfg(not(target_os = "linux"))]
+pub use fallback::{enable_gro, enable_gso, recv_from, send_to};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::net::SocketAddr;
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    use socket2::{SockAddr, SockRef};
+    use tokio::net::UdpSocket;
+
+    /// Enables GRO on `socket`, returns `false` if the kernel does not support it.
+    pub fn enable_gro(socket: &UdpSocket) -> bool {
+        set_option(socket, libc::UDP_GRO, 1).is_ok()
+    }
+
+    /// Returns `true` if the kernel supports GSO on `socket`.
+    pub fn enable_gso(socket: &UdpSocket) -> bool {
+        // A segment size of 0 only disables segmenting sends without a
+        // control message, but fails if GSO is not supported at all.
+        set_option(socket, libc::UDP_SEGMENT, 0).is_ok()
+    }
+
+    fn set_option(socket: &UdpSocket, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                option,
+                ptr::addr_of!(value).cast(),
+                mem::size_of_val(&value) as libc::socklen_t,
+            )
+        };
+
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Receives datagrams into `buf` without blocking.
+    ///
+    /// Returns the received length, the source address and the size of the
+    /// coalesced segments. Only the last segment may be shorter.
+    pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        // u64s to align the control messages.
+        let mut control = [0u64; 8];
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = ptr::addr_of_mut!(storage).cast();
+        hdr.msg_namelen = mem::size_of_val(&storage) as libc::socklen_t;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr().cast();
+        hdr.msg_controllen = mem::size_of_val(&control) as _;
+
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut hdr, 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = len as usize;
+
+        let mut stride = len;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cmsg.is_null() {
+            let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if level == libc::SOL_UDP && ty == libc::UDP_GRO {
+                let size: libc::c_int =
+                    unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()) };
+                stride = size as usize;
+            }
+
+            cmsg = unsafe { libc::CMSG_NXTHDR(&hdr, cmsg) };
+        }
+
+        let addr = unsafe { SockAddr::new(storage, hdr.msg_namelen) };
+        let addr = addr
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid source address"))?;
+
+        Ok((len, addr, stride.max(1)))
+    }
+
+    /// Sends `buf` to `addr` in segments of `segment_size` without blocking.
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] if the segments cannot be
+    /// offloaded, in which case they must be sent on their own.
+    pub fn send_to(
+        socket: &UdpSocket,
+        buf: &[u8],
+        addr: SocketAddr,
+        segment_size: usize,
+    ) -> io::Result<usize> {
+        if segment_size >= buf.len() {
+            return SockRef::from(socket).send_to(buf, &addr.into());
+        }
+
+        let addr = SockAddr::from(addr);
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr().cast_mut().cast(),
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 4];
+
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = addr.as_ptr().cast_mut().cast();
+        hdr.msg_namelen = addr.len();
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr().cast();
+        hdr.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast(), segment_size as u16);
+        }
+
+        let len = unsafe { libc::sendmsg(socket.as_raw_fd(), &hdr, 0) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            // Not every device supports segmentation offload, which is only
+            // reported once we try to use it.
+            return match err.raw_os_error() {
+                Some(libc::EIO | libc::EINVAL) => {
+                    Err(io::Error::new(io::ErrorKind::Unsupported, err))
+                }
+                _ => Err(err),
+            };
+        }
+
+        Ok(len as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod fallback {
+    use std::io;
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    pub fn enable_gro(_: &UdpSocket) -> bool {
+        false
+    }
+
+    pub fn enable_gso(_: &UdpSocket) -> bool {
+        false
+    }
+
+    pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+        let (len, addr) = socket.try_recv_from(buf)?;
+        Ok((len, addr, len.max(1)))
+    }
+
+    pub fn send_to(
+        socket: &UdpSocket,
+        buf: &[u8],
+        addr: SocketAddr,
+        segment_size: usize,
+    ) -> io::Result<usize> {
+        if segment_size < buf.len() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        socket.try_send_to(buf, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_len, MAX_SEGMENTS, MAX_SEGMENT_SIZE};
+
+    #[test]
+    fn batch_len_equal_segments() {
+        assert_eq!(batch_len([]), 0);
+        assert_eq!(batch_len([100]), 1);
+        assert_eq!(batch_len([100, 100, 100]), 3);
+        assert_eq!(batch_len(vec![100; 100]), MAX_SEGMENTS);
+    }
+
+    #[test]
+    fn batch_len_shorter_last_segment() {
+        assert_eq!(batch_len([100, 100, 50, 50]), 3);
+        assert_eq!(batch_len([100, 200]), 1);
+        assert_eq!(batch_len([MAX_SEGMENT_SIZE + 1, 10]), 1);
+    }
+}
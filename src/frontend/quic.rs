@@ -0,0 +1,323 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{select_biased, FutureExt};
+use hyper::{Request, Response, StatusCode};
+use quinn::crypto::rustls::{HandshakeData, QuicServerConfig};
+use quinn::{
+    Connection, Endpoint, EndpointConfig, IdleTimeout, ReadToEndError, RecvStream, SendStream,
+    ServerConfig, TokioRuntime, TransportConfig, VarInt,
+};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::version::TLS13;
+
+use crate::config::QuicFrontend;
+use crate::http::doh;
+use crate::metrics::Listener;
+use crate::proto::{Packet, Qr};
+use crate::state::State;
+
+use super::handle_query;
+use super::socket;
+use super::tls::{client_verifier, load_cert, ticketer};
+
+/// ALPN protocol identifier of DNS over QUIC.
+const ALPN_DOQ: &[u8] = b"doq";
+/// ALPN protocol identifier of HTTP/3.
+const ALPN_H3: &[u8] = b"h3";
+
+/// The client violated the DoQ protocol.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc9250#section-8.4
+const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(0x2);
+
+pub struct QuicServer {
+    endpoint: Endpoint,
+    label: String,
+}
+
+impl QuicServer {
+    pub async fn new(config: &QuicFrontend, v6only: bool) -> Self {
+        let (certs, key) = load_cert(&config.cert, &config.key);
+
+        // QUIC requires TLS 1.3.
+        let mut tls = tokio_rustls::rustls::ServerConfig::builder_with_provider(Arc::new(
+            ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&TLS13])
+        .unwrap()
+        .with_client_cert_verifier(client_verifier(config.client_auth.as_ref()))
+        .with_single_cert(certs, key)
+        .unwrap();
+        tls.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+        if config.http3 {
+            tls.alpn_protocols.push(ALPN_H3.to_vec());
+        }
+        if config.session_tickets {
+            tls.ticketer = ticketer();
+        }
+
+        let mut transport = TransportConfig::default();
+        transport.max_idle_timeout(Some(
+            IdleTimeout::try_from(Duration::from_secs(config.idle_timeout)).unwrap(),
+        ));
+        // DoQ queries are only sent over bidirectional streams, HTTP/3 needs
+        // unidirectional streams for its control and QPACK streams.
+        if !config.http3 {
+            transport.max_concurrent_uni_streams(VarInt::from_u32(0));
+        }
+
+        let mut server =
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).unwrap()));
+        server.transport_config(Arc::new(transport));
+
+        let socket = socket::bind_udp(config.bind, v6only, false).unwrap();
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server),
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .unwrap();
+        Self {
+            endpoint,
+            label: config.label(),
+        }
+    }
+
+    pub async fn poll(&self, state: &'static State) -> Result<(), io::Error> {
+        let doq = state.metrics.listeners.register("quic", &self.label);
+        let h3 = state.metrics.listeners.register("h3", &self.label);
+
+        loop {
+            let incoming = select_biased! {
+                () = state.shutdown.triggered().fuse() => break,
+                incoming = self.endpoint.accept().fuse() => incoming,
+            };
+            let Some(incoming) = incoming else {
+                return Ok(());
+            };
+
+            let guard = state.shutdown.guard();
+            let (doq, h3) = (doq.clone(), h3.clone());
+            tokio::task::spawn(async move {
+                let _guard = guard;
+                let addr = incoming.remote_address();
+                let conn = match incoming.await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::debug!("QUIC handshake with {} failed: {}", addr, err);
+                        return;
+                    }
+                };
+
+                // Only the protocols we announced can be negotiated.
+                let protocol = conn
+                    .handshake_data()
+                    .and_then(|data| data.downcast::<HandshakeData>().ok())
+                    .and_then(|data| data.protocol);
+                if protocol.as_deref() == Some(ALPN_H3) {
+                    handle_h3_connection(conn, state, h3).await;
+                } else {
+                    handle_connection(conn, state, doq).await;
+                }
+            });
+        }
+
+        // Connections are closed once their last query is answered, give
+        // them the chance to deliver the responses.
+        self.endpoint.wait_idle().await;
+        Ok(())
+    }
+}
+
+async fn handle_connection(conn: Connection, state: &'static State, listener: Arc<Listener>) {
+    loop {
+        let stream = select_biased! {
+            () = state.shutdown.triggered().fuse() => return,
+            stream = conn.accept_bi().fuse() => stream,
+        };
+
+        let (send, recv) = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::debug!("connection to {} closed: {}", conn.remote_address(), err);
+                return;
+            }
+        };
+
+        // Every query has its own stream, they are answered independently.
+        let conn = conn.clone();
+        let guard = state.shutdown.guard();
+        let listener = listener.clone();
+        tokio::task::spawn(async move {
+            let _guard = guard;
+            let peer = conn.remote_address();
+            if let Err(code) = handle_stream(send, recv, peer, state, &listener).await {
+                conn.close(code, b"");
+            }
+        });
+    }
+}
+
+/// Serves DNS over HTTP/3.
+async fn handle_h3_connection(conn: Connection, state: &'static State, listener: Arc<Listener>) {
+    let addr = conn.remote_address();
+    let mut conn =
+        match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                tracing::debug!("HTTP/3 connection to {} failed: {}", addr, err);
+                return;
+            }
+        };
+
+    loop {
+        let resolver = select_biased! {
+            () = state.shutdown.triggered().fuse() => {
+                // Tell the client which requests are still answered.
+                let _ = conn.shutdown(0).await;
+                return;
+            },
+            resolver = conn.accept().fuse() => resolver,
+        };
+
+        let resolver = match resolver {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => return,
+            Err(err) => {
+                tracing::debug!("HTTP/3 connection to {} closed: {}", addr, err);
+                return;
+            }
+        };
+
+        let guard = state.shutdown.guard();
+        let listener = listener.clone();
+        tokio::task::spawn(async move {
+            let _guard = guard;
+            let (req, mut stream) = match resolver.resolve_request().await {
+                Ok(req) => req,
+                Err(err) => {
+                    tracing::debug!("invalid HTTP/3 request from {}: {}", addr, err);
+                    return;
+                }
+            };
+
+            let mut body = BytesMut::new();
+            let mut too_large = false;
+            loop {
+                match stream.recv_data().await {
+                    Ok(Some(mut chunk)) => {
+                        if body.len() + chunk.remaining() > doh::MAX_BODY_SIZE {
+                            too_large = true;
+                            break;
+                        }
+
+                        while chunk.has_remaining() {
+                            let len = chunk.chunk().len();
+                            body.extend_from_slice(chunk.chunk());
+                            chunk.advance(len);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(_) => return,
+                }
+            }
+
+            let resp = if too_large {
+                Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .body(Bytes::new())
+                    .unwrap()
+            } else {
+                let (parts, ()) = req.into_parts();
+                let req = Request::from_parts(parts, body.freeze());
+                doh::dns_query(req, Some(addr.ip()), state, &listener).await
+            };
+
+            let (parts, body) = resp.into_parts();
+            let res = async {
+                stream
+                    .send_response(Response::from_parts(parts, ()))
+                    .await?;
+                stream.send_data(body).await?;
+                stream.finish().await
+            };
+            if let Err(err) = res.await {
+                tracing::debug!("failed to respond to {}: {}", addr, err);
+            }
+        });
+    }
+}
+
+/// Answers the single query sent on a stream.
+///
+/// Returns the error code the connection must be closed with if the client
+/// violated the protocol.
+async fn handle_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    peer: SocketAddr,
+    state: &State,
+    listener: &Listener,
+) -> Result<(), VarInt> {
+    // The client sends exactly one length-prefixed message and then
+    // finishes the stream.
+    // See https://datatracker.ietf.org/doc/html/rfc9250#section-4.2
+    let buf = match recv.read_to_end(2 + usize::from(u16::MAX)).await {
+        Ok(buf) => buf,
+        Err(ReadToEndError::TooLong) => return Err(DOQ_PROTOCOL_ERROR),
+        // The stream was reset or the connection lost, there is no one to
+        // answer anymore.
+        Err(ReadToEndError::Read(_)) => return Ok(()),
+    };
+
+    if buf.len() < 2 || usize::from(u16::from_be_bytes([buf[0], buf[1]])) != buf.len() - 2 {
+        return Err(DOQ_PROTOCOL_ERROR);
+    }
+
+    let mut buf = Bytes::from(buf);
+    let head = match Packet::decode_query_head(buf.split_off(2)) {
+        Ok(head) => head,
+        Err(err) => {
+            tracing::trace!("failed to decode packet: {:?}", err);
+            return Err(DOQ_PROTOCOL_ERROR);
+        }
+    };
+
+    // The message ID must be 0, the stream already identifies the query.
+    // See https://datatracker.ietf.org/doc/html/rfc9250#section-4.2.1
+    if head.header.qr() != Qr::Request || head.header.transaction_id != 0 {
+        return Err(DOQ_PROTOCOL_ERROR);
+    }
+
+    listener.queries.fetch_add(1, Ordering::Relaxed);
+    let start = Instant::now();
+
+    let packet = match head.into_packet() {
+        Ok(packet) => packet,
+        Err(err) => {
+            tracing::trace!("failed to decode packet: {:?}", err);
+            return Err(DOQ_PROTOCOL_ERROR);
+        }
+    };
+
+    let response = handle_query(packet, Some(peer.ip()), state).await;
+    listener.response_times.observe(start.elapsed());
+
+    let len = response.encoded_len();
+    let mut buf = Vec::with_capacity(2 + len);
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    response.encode(&mut buf);
+
+    // Write errors mean the client cancelled the query.
+    if send.write_all(&buf).await.is_ok() {
+        let _ = send.finish();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Binding of the listening sockets.
+//!
+//! The sockets are created explicitly instead of relying on the defaults of
+//! the OS, which differ e.g. in whether IPv6 sockets accept IPv4 traffic.
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+
+use socket2::{Domain, Socket, Type};
+
+/// Backlog of pending connections, the same as used by tokio.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Binds a non-blocking UDP socket to `addr`.
+///
+/// With `reuse_port` multiple sockets can be bound to the same address.
+pub fn bind_udp(addr: SocketAddr, v6only: bool, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, v6only)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Binds a non-blocking TCP listener to `addr`.
+pub fn bind_tcp(addr: SocketAddr, v6only: bool) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, v6only)?;
+    // Restarts must not wait for the connections of the previous process to
+    // leave TIME_WAIT.
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+fn new_socket(addr: SocketAddr, ty: Type, v6only: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::bind_udp;
+
+    #[test]
+    fn v6only_allows_separate_ipv4_socket() {
+        let Ok(v6) = bind_udp("[::]:0".parse().unwrap(), true, false) else {
+            // The host has no IPv6 support.
+            return;
+        };
+        let port = v6.local_addr().unwrap().port();
+        bind_udp(SocketAddr::from(([0, 0, 0, 0], port)), false, false).unwrap();
+    }
+}
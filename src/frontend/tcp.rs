@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::{self, FutureExt};
+use futures::select_biased;
+use futures::stream::{FuturesUnordered, StreamExt};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+use crate::dscp;
+use crate::metrics::Listener;
+use crate::proto::{DsoTlv, OpCode, Packet, Qr, QueryHead, ResponseCode};
+use crate::state::State;
+
+use super::udp::truncate;
+use super::{bad_query, check_header, handle_query, socket};
+
+/// Minimum number of bytes read from a connection at once.
+const MIN_READ_SIZE: usize = 512;
+
+#[derive(Debug)]
+pub struct TcpServer {
+    listener: TcpListener,
+    label: String,
+}
+
+impl TcpServer {
+    /// Binds a new server to `addr`, which shows up as `label` in the metrics.
+    pub async fn new(addr: SocketAddr, label: String, v6only: bool) -> Result<Self, io::Error> {
+        Self::from_std(socket::bind_tcp(addr, v6only)?, label)
+    }
+
+    /// Serves on an already bound, non-blocking listener.
+    pub fn from_std(listener: std::net::TcpListener, label: String) -> Result<Self, io::Error> {
+        let listener = TcpListener::from_std(listener)?;
+        Ok(Self { listener, label })
+    }
+
+    /// Returns the address the server is bound to, e.g. to learn the port
+    /// picked by the OS when binding port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.listener.local_addr()
+    }
+
+    pub async fn poll(&self, state: &'static State) -> Result<(), io::Error> {
+        dscp::set_frontend(&self.listener, state.config.dscp);
+
+        let config = &state.config.frontend.tcp;
+        let connections = Arc::new(Connections::new(config.max_connections));
+        let idle_timeout = Duration::from_secs(config.idle_timeout);
+        let listener = state.metrics.listeners.register("tcp", &self.label);
+
+        loop {
+            let (stream, addr) = select_biased! {
+                () = state.shutdown.triggered().fuse() => return Ok(()),
+                res = self.listener.accept().fuse() => res?,
+            };
+            let conn = connections.insert();
+            let guard = state.shutdown.guard();
+
+            let listener = listener.clone();
+            tokio::task::spawn(async move {
+                let _guard = guard;
+                if let Err(err) =
+                    handle_connection(stream, addr, state, &conn, idle_timeout, &listener).await
+                {
+                    tracing::debug!("connection to {} failed: {}", addr, err);
+                }
+            });
+        }
+    }
+}
+
+/// The open connections of a listener.
+///
+/// Once the limit is reached, the connection that has been idle for the longest
+/// time is closed for every new one.
+#[derive(Debug)]
+pub struct Connections {
+    max: usize,
+    epoch: Instant,
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, Arc<Activity>>>,
+}
+
+impl Connections {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            epoch: Instant::now(),
+            next_id: AtomicU64::new(0),
+            open: Mutex::new(HashMap::default()),
+        }
+    }
+
+    /// Registers a new connection, evicting the most idle one if the limit is reached.
+    pub fn insert(self: &Arc<Self>) -> Connection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let activity = Arc::new(Activity {
+            last_active: AtomicU64::new(self.now()),
+            evicted: Notify::new(),
+        });
+
+        let mut open = self.open.lock();
+        if open.len() >= self.max {
+            let idle = open
+                .iter()
+                .min_by_key(|(_, activity)| activity.last_active.load(Ordering::Relaxed))
+                .map(|(id, _)| *id);
+
+            if let Some(activity) = idle.and_then(|id| open.remove(&id)) {
+                tracing::debug!("connection limit reached, closing the most idle connection");
+                activity.evicted.notify_one();
+            }
+        }
+        open.insert(id, activity.clone());
+        drop(open);
+
+        Connection {
+            id,
+            activity,
+            connections: self.clone(),
+        }
+    }
+
+    /// Milliseconds since the creation of the listener.
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+}
+
+#[derive(Debug)]
+struct Activity {
+    /// See [`Connections::now`].
+    last_active: AtomicU64,
+    evicted: Notify,
+}
+
+/// An open connection, removed from its [`Connections`] when dropped.
+#[derive(Debug)]
+pub struct Connection {
+    id: u64,
+    activity: Arc<Activity>,
+    connections: Arc<Connections>,
+}
+
+impl Connection {
+    /// Marks the connection as active, once a complete message was read.
+    fn touch(&self) {
+        self.activity
+            .last_active
+            .store(self.connections.now(), Ordering::Relaxed);
+    }
+
+    /// Completes once the connection was evicted to make room for a new one.
+    pub async fn evicted(&self) {
+        self.activity.evicted.notified().await;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.open.lock().remove(&self.id);
+    }
+}
+
+/// Serves the length-prefixed messages of a stream connection.
+///
+/// The connection is closed once the client has been idle for `idle_timeout` or
+/// `conn` was evicted. On shutdown no further queries are read and the
+/// connection is closed once the outstanding ones are answered. Every request is
+/// counted in the metrics of `listener`.
+pub async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    state: &State,
+    conn: &Connection,
+    idle_timeout: Duration,
+    listener: &Listener,
+) -> Result<(), io::Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = BytesMut::new();
+    let mut tasks = FuturesUnordered::new();
+    // No further queries are read, the connection is closed once the
+    // outstanding ones are answered.
+    let mut closing = false;
+    let config = &state.config.frontend.tcp;
+    let max_queued_queries = config.max_queued_queries.max(1);
+    // Larger responses don't fit into the length prefix.
+    let max_response_size = config.max_response_size.min(usize::from(u16::MAX));
+
+    loop {
+        // Pipelined queries are resolved concurrently and answered as soon as
+        // they complete, the client matches them by their message ID.
+        // See https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.1.1
+        while tasks.len() < max_queued_queries {
+            let message = match next_message(&mut buf, config.max_query_size) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(len) => {
+                    tracing::debug!("query of {} bytes exceeds the maximum size", len);
+                    state.metrics.rejected_size.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            };
+            // Only complete messages count as activity, so that clients
+            // trickling bytes are still evicted first.
+            conn.touch();
+
+            if !check_header(&message, state) {
+                return Ok(());
+            }
+
+            let head = match Packet::decode_query_head(message.clone()) {
+                Ok(head) => head,
+                Err(err) => {
+                    tracing::trace!("failed to decode packet: {:?}", err);
+                    match bad_query(&message, &err, state) {
+                        Some(response) => {
+                            write_message(&mut writer, &response, conn, state).await?
+                        }
+                        None => return Ok(()),
+                    }
+                    continue;
+                }
+            };
+
+            if head.header.qr() != Qr::Request {
+                return Ok(());
+            }
+
+            listener.queries.fetch_add(1, Ordering::Relaxed);
+
+            if head.opcode == OpCode::Dso {
+                if let Some(response) = handle_dso(head) {
+                    write_response(&mut writer, &response, conn, state).await?;
+                }
+                continue;
+            }
+
+            let packet = match head.into_packet() {
+                Ok(packet) => packet,
+                Err(err) => {
+                    tracing::trace!("failed to decode packet: {:?}", err);
+                    match bad_query(&message, &err, state) {
+                        Some(response) => {
+                            write_message(&mut writer, &response, conn, state).await?
+                        }
+                        None => return Ok(()),
+                    }
+                    continue;
+                }
+            };
+
+            let start = Instant::now();
+            tasks.push(async move {
+                let mut response = handle_query(packet, Some(peer.ip()), state).await;
+                truncate(&mut response, max_response_size);
+                listener.response_times.observe(start.elapsed());
+                response
+            });
+        }
+
+        // The client may close its side after sending its last query, but
+        // still expects the responses.
+        if closing && tasks.is_empty() {
+            return Ok(());
+        }
+
+        // The connection is only idle while there are no outstanding queries.
+        // See https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.3
+        let idle = tasks.is_empty();
+        let read = !closing && tasks.len() < max_queued_queries;
+
+        let event = {
+            let shutdown = async {
+                if closing {
+                    future::pending().await
+                } else {
+                    state.shutdown.triggered().await
+                }
+            };
+            let incoming = async {
+                if !read {
+                    return future::pending().await;
+                }
+
+                if idle {
+                    match tokio::time::timeout(idle_timeout, reader.read_buf(&mut buf)).await {
+                        Ok(res) => res.map(Some),
+                        Err(_) => Ok(None),
+                    }
+                } else {
+                    reader.read_buf(&mut buf).await.map(Some)
+                }
+            };
+
+            select_biased! {
+                () = conn.evicted().fuse() => Event::Evicted,
+                () = shutdown.fuse() => Event::Shutdown,
+                response = tasks.select_next_some() => Event::Response(response),
+                res = incoming.fuse() => Event::Read(res?),
+            }
+        };
+
+        match event {
+            Event::Response(response) => {
+                write_response(&mut writer, &response, conn, state).await?
+            }
+            Event::Evicted => return Ok(()),
+            Event::Shutdown => closing = true,
+            Event::Read(None) => return Ok(()),
+            Event::Read(Some(0)) => closing = true,
+            Event::Read(Some(_)) => (),
+        }
+    }
+}
+
+enum Event {
+    Response(Packet),
+    Evicted,
+    Shutdown,
+    /// The number of bytes read, `None` if the connection timed out.
+    Read(Option<usize>),
+}
+
+/// Splits the next complete message off `buf`.
+///
+/// Every message is prefixed with its length. Returns the length as an error
+/// if it exceeds `max_len`, without waiting for the message.
+/// See https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2
+fn next_message(buf: &mut BytesMut, max_len: usize) -> Result<Option<Bytes>, usize> {
+    let Some(prefix) = buf.get(..2) else {
+        buf.reserve(2 + MIN_READ_SIZE);
+        return Ok(None);
+    };
+
+    let len = usize::from(u16::from_be_bytes([prefix[0], prefix[1]]));
+    if len > max_len {
+        return Err(len);
+    }
+
+    if buf.len() < 2 + len {
+        buf.reserve(2 + len - buf.len());
+        return Ok(None);
+    }
+
+    buf.advance(2);
+    Ok(Some(buf.split_to(len).freeze()))
+}
+
+async fn write_response<W>(
+    writer: &mut W,
+    response: &Packet,
+    conn: &Connection,
+    state: &State,
+) -> Result<(), io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = response.encoded_len();
+    let mut buf = Vec::with_capacity(2 + len);
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    response.encode(&mut buf);
+
+    write_all(writer, &buf, conn, state).await
+}
+
+async fn write_message<W>(
+    writer: &mut W,
+    message: &[u8],
+    conn: &Connection,
+    state: &State,
+) -> Result<(), io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(2 + message.len());
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+
+    write_all(writer, &buf, conn, state).await
+}
+
+/// Writes `buf` unless the client stops reading.
+///
+/// Gives up after the write timeout, or once `conn` is evicted or the shutdown
+/// is triggered while the write is blocked.
+async fn write_all<W>(
+    writer: &mut W,
+    buf: &[u8],
+    conn: &Connection,
+    state: &State,
+) -> Result<(), io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let timeout = Duration::from_secs(state.config.frontend.tcp.write_timeout);
+    let write = tokio::time::timeout(timeout, writer.write_all(buf));
+
+    // Writes that complete right away still go through after the shutdown.
+    select_biased! {
+        res = write.fuse() => match res {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out")),
+        },
+        () = conn.evicted().fuse() => Err(io::ErrorKind::ConnectionAborted.into()),
+        () = state.shutdown.triggered().fuse() => Err(io::ErrorKind::ConnectionAborted.into()),
+    }
+}
+
+/// Handles a DSO message.
+///
+/// We don't implement any DSO types, so every DSO request is answered with DSOTYPENI.
+/// See https://datatracker.ietf.org/doc/html/rfc8490#section-5.1.1
+fn handle_dso(head: QueryHead) -> Option<Packet> {
+    let response_code = match head.decode_dso() {
+        Ok(tlvs) => {
+            // Unidirectional messages have no response.
+            if head.header.transaction_id == 0 {
+                return None;
+            }
+
+            // A request must contain a primary TLV. Padding is never valid as one.
+            match tlvs.first() {
+                Some(tlv) if tlv.r#type != DsoTlv::ENCRYPTION_PADDING => ResponseCode::DsoTypeNi,
+                _ => ResponseCode::FormatError,
+            }
+        }
+        Err(_) => ResponseCode::FormatError,
+    };
+
+    Some(Packet {
+        transaction_id: head.header.transaction_id,
+        qr: Qr::Response,
+        opcode: OpCode::Dso,
+        authoritative_answer: false,
+        truncated: false,
+        recursion_desired: false,
+        recursion_available: false,
+        authentic_data: false,
+        checking_disabled: false,
+        response_code,
+        questions: Vec::new(),
+        raw_questions: None,
+        answers: Vec::new(),
+        authority: Vec::new(),
+        additional: Vec::new(),
+        edns: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use futures::FutureExt;
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::state::State;
+
+    use super::{handle_connection, next_message, write_all, Connections};
+
+    fn state() -> State {
+        let config = json!({
+            "bind": "127.0.0.1:0",
+            "zones": {},
+            "http": { "enabled": false, "bind": "127.0.0.1:0" },
+            "frontend": { "tcp": { "write_timeout": 1 } },
+        });
+        State::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn connections_evict_most_idle() {
+        let connections = Arc::new(Connections::new(2));
+        let first = connections.insert();
+        let second = connections.insert();
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        first.touch();
+
+        let _third = connections.insert();
+        assert!(second.evicted().now_or_never().is_some());
+        assert!(first.evicted().now_or_never().is_none());
+        assert_eq!(connections.open.lock().len(), 2);
+
+        drop(second);
+        assert_eq!(connections.open.lock().len(), 2);
+        drop(first);
+        assert_eq!(connections.open.lock().len(), 1);
+    }
+
+    #[test]
+    fn next_message_waits_for_complete_messages() {
+        let mut buf = BytesMut::new();
+        assert_eq!(next_message(&mut buf, 512), Ok(None));
+
+        buf.extend_from_slice(&[0, 3, 1, 2]);
+        assert_eq!(next_message(&mut buf, 512), Ok(None));
+
+        buf.extend_from_slice(&[3, 0, 2, 4]);
+        assert_eq!(
+            next_message(&mut buf, 512).unwrap().as_deref(),
+            Some(&[1, 2, 3][..])
+        );
+        assert_eq!(next_message(&mut buf, 512), Ok(None));
+        assert_eq!(&buf[..], &[0, 2, 4]);
+    }
+
+    #[test]
+    fn next_message_rejects_large_messages() {
+        let mut buf = BytesMut::from(&[2, 1][..]);
+        assert_eq!(next_message(&mut buf, 512), Err(513));
+        assert_eq!(next_message(&mut buf, 513), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn write_times_out() {
+        let state = state();
+        let connections = Arc::new(Connections::new(1));
+        let conn = connections.insert();
+        // The client never reads.
+        let (mut writer, _client) = tokio::io::duplex(1);
+
+        let err = write_all(&mut writer, &[0; 16], &conn, &state)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn write_stops_on_eviction() {
+        let state = state();
+        let connections = Arc::new(Connections::new(1));
+        let conn = connections.insert();
+        let (mut writer, _client) = tokio::io::duplex(1);
+
+        let write = write_all(&mut writer, &[0; 16], &conn, &state);
+        let _other = connections.insert();
+        let err = write.await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn partial_messages_are_idle() {
+        let state = state();
+        let listener = state.metrics.listeners.register("tcp", "test");
+        let connections = Arc::new(Connections::new(2));
+        let first = connections.insert();
+        let (stream, mut client) = tokio::io::duplex(512);
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let second = connections.insert();
+
+        // Half of a length prefix.
+        client.write_all(&[0]).await.unwrap();
+        let peer = "127.0.0.1:53".parse().unwrap();
+        let conn = handle_connection(
+            stream,
+            peer,
+            &state,
+            &first,
+            Duration::from_secs(10),
+            &listener,
+        );
+        let evict = async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let _third = connections.insert();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        };
+        tokio::select! {
+            res = conn => res.unwrap(),
+            () = evict => panic!("connection was not evicted"),
+        }
+        assert!(second.evicted().now_or_never().is_none());
+    }
+}
@@ -0,0 +1,284 @@
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{select_biased, FutureExt};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+use tokio_rustls::rustls::client::verify_server_name;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::server::{ParsedCertificate, ProducesTickets, WebPkiClientVerifier};
+use tokio_rustls::rustls::{
+    CertificateError, DigitallySignedStruct, DistinguishedName, Error, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{ClientAuth, TlsFrontend};
+use crate::dscp;
+use crate::state::State;
+
+use super::socket;
+use super::tcp::{handle_connection, Connections};
+
+/// ALPN protocol identifier of DNS over TLS.
+const ALPN_DOT: &[u8] = b"dot";
+
+pub struct TlsServer {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    idle_timeout: Duration,
+    label: String,
+}
+
+impl TlsServer {
+    pub async fn new(config: &TlsFrontend, v6only: bool) -> Self {
+        let (certs, key) = load_cert(&config.cert, &config.key);
+
+        let mut tls = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier(config.client_auth.as_ref()))
+            .with_single_cert(certs, key)
+            .unwrap();
+        tls.alpn_protocols = vec![ALPN_DOT.to_vec()];
+        if config.session_tickets {
+            tls.ticketer = ticketer();
+        }
+
+        let listener = socket::bind_tcp(config.bind, v6only).unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        Self {
+            listener,
+            acceptor: TlsAcceptor::from(Arc::new(tls)),
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            label: config.label(),
+        }
+    }
+
+    pub async fn poll(&self, state: &'static State) -> Result<(), io::Error> {
+        dscp::set_frontend(&self.listener, state.config.dscp);
+
+        let connections = Arc::new(Connections::new(state.config.frontend.tcp.max_connections));
+        let listener = state.metrics.listeners.register("tls", &self.label);
+
+        loop {
+            let (stream, addr) = select_biased! {
+                () = state.shutdown.triggered().fuse() => return Ok(()),
+                res = self.listener.accept().fuse() => res?,
+            };
+            let conn = connections.insert();
+            let guard = state.shutdown.guard();
+
+            let acceptor = self.acceptor.clone();
+            let idle_timeout = self.idle_timeout;
+            let listener = listener.clone();
+            tokio::task::spawn(async move {
+                let _guard = guard;
+
+                // Clients that never finish the handshake must not hold
+                // on to the connection forever.
+                let handshake = tokio::time::timeout(idle_timeout, acceptor.accept(stream));
+                let res = select_biased! {
+                    () = conn.evicted().fuse() => return,
+                    res = handshake.fuse() => res,
+                };
+
+                let stream = match res {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        tracing::debug!("TLS handshake with {} failed: {}", addr, err);
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!("TLS handshake with {} timed out", addr);
+                        return;
+                    }
+                };
+
+                if let Err(err) =
+                    handle_connection(stream, addr, state, &conn, idle_timeout, &listener).await
+                {
+                    tracing::debug!("connection to {} failed: {}", addr, err);
+                }
+            });
+        }
+    }
+}
+
+/// Loads the PEM encoded certificate chain at `cert` and private key at `key`.
+pub fn load_cert(
+    cert: &Path,
+    key: &Path,
+) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+    let mut certs = BufReader::new(std::fs::File::open(cert).unwrap());
+    let certs = rustls_pemfile::certs(&mut certs)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let mut key = BufReader::new(std::fs::File::open(key).unwrap());
+    let key = rustls_pemfile::private_key(&mut key).unwrap().unwrap();
+
+    (certs, key)
+}
+
+/// Creates the encrypter of session tickets.
+///
+/// The key is rotated every 6 hours, tickets are accepted for up to 12 hours.
+pub fn ticketer() -> Arc<dyn ProducesTickets> {
+    ring::Ticketer::new().unwrap()
+}
+
+/// Builds the verifier of client certificates, accepting all clients without
+/// `client_auth`.
+pub fn client_verifier(client_auth: Option<&ClientAuth>) -> Arc<dyn ClientCertVerifier> {
+    let Some(client_auth) = client_auth else {
+        return WebPkiClientVerifier::no_client_auth();
+    };
+
+    let mut roots = RootCertStore::empty();
+    let mut ca = BufReader::new(std::fs::File::open(&client_auth.ca).unwrap());
+    for cert in rustls_pemfile::certs(&mut ca) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+
+    let verifier = WebPkiClientVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(ring::default_provider()),
+    )
+    .build()
+    .unwrap();
+    if client_auth.allowed_names.is_empty() {
+        return verifier;
+    }
+
+    Arc::new(AllowedNames {
+        inner: verifier,
+        names: allowed_names(client_auth),
+    })
+}
+
+/// Parses the allowed names of `client_auth`, which were checked by
+/// `Config::validate`.
+pub fn allowed_names(client_auth: &ClientAuth) -> Vec<ServerName<'static>> {
+    client_auth
+        .allowed_names
+        .iter()
+        .map(|name| ServerName::try_from(name.clone()).unwrap())
+        .collect()
+}
+
+/// Returns `true` if the certificate `cert` is valid for any of `names`.
+pub fn has_allowed_name(cert: &CertificateDer<'_>, names: &[ServerName<'_>]) -> bool {
+    let Ok(cert) = ParsedCertificate::try_from(cert) else {
+        return false;
+    };
+
+    names
+        .iter()
+        .any(|name| verify_server_name(&cert, name).is_ok())
+}
+
+/// Only accepts client certificates issued for one of the allowed names.
+#[derive(Debug)]
+struct AllowedNames {
+    inner: Arc<dyn ClientCertVerifier>,
+    names: Vec<ServerName<'static>>,
+}
+
+impl ClientCertVerifier for AllowedNames {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        if !has_allowed_name(end_entity, &self.names) {
+            return Err(Error::InvalidCertificate(CertificateError::NotValidForName));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+
+    use crate::config::ClientAuth;
+
+    use super::{client_verifier, has_allowed_name};
+
+    fn client_cert() -> CertificateDer<'static> {
+        let pem = include_bytes!("../../testdata/certs/client.pem");
+        rustls_pemfile::certs(&mut BufReader::new(&pem[..]))
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn allowed_names() {
+        let cert = client_cert();
+        let name = |name: &str| ServerName::try_from(name.to_owned()).unwrap();
+
+        assert!(has_allowed_name(&cert, &[name("edge-1.example")]));
+        assert!(has_allowed_name(
+            &cert,
+            &[name("edge-2.example"), name("10.0.0.1")]
+        ));
+        assert!(!has_allowed_name(&cert, &[name("edge-2.example")]));
+        assert!(!has_allowed_name(&cert, &[]));
+    }
+
+    #[test]
+    fn client_verifier_checks_names() {
+        let verify = |allowed_names: &[&str]| {
+            let verifier = client_verifier(Some(&ClientAuth {
+                ca: "testdata/certs/ca.pem".into(),
+                allowed_names: allowed_names.iter().map(|name| name.to_string()).collect(),
+            }));
+            verifier
+                .verify_client_cert(&client_cert(), &[], UnixTime::now())
+                .is_ok()
+        };
+
+        assert!(verify(&[]));
+        assert!(verify(&["edge-1.example"]));
+        assert!(!verify(&["edge-2.example"]));
+    }
+}
@@ -1,127 +1,518 @@
 use std::io;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
-use futures::stream::{FuturesOrdered, StreamExt};
+use bytes::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt};
 use futures::{select_biased, FutureExt};
+use tokio::io::Interest;
 use tokio::net::UdpSocket;
 
-use crate::proto::{OpCode, Packet, Qr, ResourceRecord, ResponseCode};
+use crate::bufpool::{self, PooledBuf};
+use crate::dscp;
+use crate::metrics::Listener;
+use crate::proto::{DecodeError, Packet, Qr, QueryHead, ResourceRecord};
 use crate::state::State;
 
+use super::{
+    answer_cached, bad_query, check_header, handle_query, offload, reject, socket, Rejection,
+};
+
+/// Maximum size of a response to a client that did not announce a larger payload size.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
+const MIN_PAYLOAD_SIZE: usize = 512;
+
 #[derive(Debug)]
 pub struct UdpServer {
     socket: UdpSocket,
+    /// Received datagrams may be coalesced by the kernel.
+    gro: bool,
+    /// Responses to the same client are coalesced into a single send. Cleared
+    /// if the outgoing device turns out not to support it.
+    gso: AtomicBool,
+    label: String,
 }
 
 impl UdpServer {
-    pub async fn new(addr: SocketAddr) -> Self {
-        let socket = UdpSocket::bind(addr).await.unwrap();
-        Self { socket }
+    /// Binds a new server to `addr`.
+    ///
+    /// With `reuse_port` multiple servers can be bound to the same address and the
+    /// kernel distributes the incoming datagrams between them. With `offload`
+    /// GSO and GRO are used if the kernel supports them. The server shows up
+    /// as `label` in the metrics.
+    pub async fn new(
+        addr: SocketAddr,
+        label: String,
+        v6only: bool,
+        reuse_port: bool,
+        offload: bool,
+    ) -> Result<Self, io::Error> {
+        let socket = socket::bind_udp(addr, v6only, reuse_port)?;
+        Self::from_std(socket, label, offload)
+    }
+
+    /// Serves on an already bound, non-blocking socket.
+    pub fn from_std(
+        socket: std::net::UdpSocket,
+        label: String,
+        offload: bool,
+    ) -> Result<Self, io::Error> {
+        let socket = UdpSocket::from_std(socket)?;
+        Ok(Self::with_socket(socket, label, offload))
+    }
+
+    /// Returns the address the server is bound to, e.g. to learn the port
+    /// picked by the OS when binding port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    fn with_socket(socket: UdpSocket, label: String, offload: bool) -> Self {
+        let gro = offload && offload::enable_gro(&socket);
+        let gso = offload && offload::enable_gso(&socket);
+
+        Self {
+            socket,
+            gro,
+            gso: AtomicBool::new(gso),
+            label,
+        }
     }
 
     pub async fn poll(&self, state: &State) -> Result<(), io::Error> {
-        let mut tasks = FuturesOrdered::new();
+        dscp::set_frontend(&self.socket, state.config.dscp);
+        let listener = state.metrics.listeners.register("udp", &self.label);
+
+        let mut tasks = FuturesUnordered::new();
+        let mut responses = Vec::new();
+        let mut scratch = if self.gro {
+            vec![0; offload::MAX_RECV_SIZE]
+        } else {
+            Vec::new()
+        };
+        let max_inflight = state.config.limits.udp_inflight;
+        let max_query_size = state.config.frontend.udp.max_query_size;
 
         loop {
-            let incoming = async {
-                let mut buf = [0; 1500];
+            // The receive loop only receives datagrams, decoding happens in
+            // the request task so that a burst of expensive packets does not
+            // delay receiving the next ones.
+            let incoming = self.recv(&mut scratch, max_query_size);
 
-                let (len, addr) = self.socket.recv_from(&mut buf).await?;
+            let request = select_biased! {
+                () = state.shutdown.triggered().fuse() => break,
+                response = tasks.select_next_some() => {
+                    responses.extend(response);
+                    None
+                },
+                req = incoming.fuse() => Some(req?),
+            };
 
-                let packet = match Packet::decode(&buf[..len]) {
-                    Ok(packet) => packet,
-                    Err(err) => {
-                        tracing::trace!("failed to decode packet: {:?}", err);
-                        return Ok(None);
-                    }
-                };
+            let Some((buf, addr, stride)) = request else {
+                // Collect all other finished responses, so that responses to
+                // the same client can be sent together.
+                while let Some(Some(response)) = tasks.next().now_or_never() {
+                    responses.extend(response);
+                }
 
-                Ok(Some(Request { packet, addr }))
+                self.send_responses(&mut responses).await;
+                continue;
             };
 
-            if tasks.is_empty() {
-                match incoming.await {
-                    Ok(Some(req)) => {
-                        tasks.push_back(handle_request(req.packet, req.addr, &self.socket, state));
-                    }
-                    Ok(None) => (),
-                    Err(err) => return Err(err),
+            for offset in (0..buf.len()).step_by(stride) {
+                let datagram = buf.slice(offset..buf.len().min(offset + stride));
+
+                if datagram.len() > max_query_size {
+                    state.metrics.rejected_size.fetch_add(1, Ordering::Relaxed);
+                    continue;
                 }
 
-                continue;
-            }
+                if !check_header(&datagram, state) {
+                    continue;
+                }
 
-            select_biased! {
-                task = tasks.next().fuse() => {
-                    debug_assert!(task.is_some());
-                },
-                req = incoming.fuse() => match req {
-                    Ok(Some(req)) => tasks.push_back(handle_request(req.packet,req.addr, &self.socket, state)),
-                    Ok(None) => (),
-                    Err(err) => return Err(err),
+                if max_inflight.is_some_and(|max| tasks.len() >= max) {
+                    reject_request(datagram, addr, &self.socket, state);
+                    continue;
                 }
+
+                tasks.push(handle_request(datagram, addr, state, &listener));
             }
         }
+
+        // Answer the queries that were already received.
+        while let Some(response) = tasks.next().await {
+            responses.extend(response);
+        }
+        self.send_responses(&mut responses).await;
+
+        Ok(())
     }
-}
 
-async fn handle_request(packet: Packet, addr: SocketAddr, socket: &UdpSocket, state: &State) {
-    let mut answers = Vec::new();
-    let mut response_code = ResponseCode::Ok;
-
-    for question in &packet.questions {
-        match state.resolve(question).await {
-            Ok(resp) => {
-                for answer in resp {
-                    answers.push(ResourceRecord {
-                        r#type: answer.r#type,
-                        class: answer.class,
-                        ttl: answer.ttl().as_secs() as u32,
-                        rdata: answer.data,
-                        name: answer.name,
-                    });
-                }
+    /// Receives the next datagrams.
+    ///
+    /// Returns the datagrams from a single client and the size of every
+    /// datagram, only the last one may be shorter. Datagrams larger than
+    /// `max_size` are received with at least `max_size + 1` bytes, so that
+    /// they can be told apart.
+    async fn recv(
+        &self,
+        scratch: &mut [u8],
+        max_size: usize,
+    ) -> Result<(Bytes, SocketAddr, usize), io::Error> {
+        let mut buf = bufpool::get();
+
+        if !self.gro {
+            buf.reserve(max_size + 1);
+            let (len, addr) = self.socket.recv_buf_from(&mut *buf).await?;
+            return Ok((buf.take(), addr, len.max(1)));
+        }
+
+        let (len, addr, stride) = self
+            .socket
+            .async_io(Interest::READABLE, || {
+                offload::recv_from(&self.socket, scratch)
+            })
+            .await?;
+
+        // Coalesced datagrams need a large receive buffer, only keep the
+        // received part around.
+        buf.extend_from_slice(&scratch[..len]);
+        Ok((buf.take(), addr, stride))
+    }
+
+    /// Sends all `responses`, coalescing the responses to the same client.
+    async fn send_responses(&self, responses: &mut Vec<(SocketAddr, PooledBuf)>) {
+        responses.sort_unstable_by_key(|(addr, _)| *addr);
+
+        let mut rest = &responses[..];
+        while let Some(((addr, _), _)) = rest.split_first() {
+            let len = if self.gso.load(Ordering::Relaxed) {
+                offload::batch_len(
+                    rest.iter()
+                        .take_while(|(other, _)| other == addr)
+                        .map(|(_, buf)| buf.len()),
+                )
+            } else {
+                1
+            };
+
+            let (batch, tail) = rest.split_at(len);
+            self.send_batch(*addr, batch).await;
+            rest = tail;
+        }
+
+        responses.clear();
+    }
+
+    async fn send_batch(&self, addr: SocketAddr, batch: &[(SocketAddr, PooledBuf)]) {
+        if let [(_, response)] = batch {
+            if let Err(err) = self.socket.send_to(response, addr).await {
+                tracing::debug!("failed to respond to {}: {}", addr, err);
             }
-            Err(err) => {
-                tracing::error!("failed to resolve query: {:?}", err);
-
-                // NOTE: The DNS standard is not clear how to handle
-                // multiple questions in a single packet.
-                // We attempt to handle all questions, but if any question
-                // fails to resolve we return no answers.
-                answers.clear();
-                response_code = ResponseCode::ServerFailure;
-                break;
+            return;
+        }
+
+        let segment_size = batch[0].1.len();
+        let mut buf = bufpool::get();
+        for (_, response) in batch {
+            buf.extend_from_slice(response);
+        }
+
+        let res = self
+            .socket
+            .async_io(Interest::WRITABLE, || {
+                offload::send_to(&self.socket, &buf, addr, segment_size)
+            })
+            .await;
+
+        match res {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                tracing::warn!("disabling UDP segmentation offload: {}", err);
+                self.gso.store(false, Ordering::Relaxed);
+
+                for (_, response) in batch {
+                    if let Err(err) = self.socket.send_to(response, addr).await {
+                        tracing::debug!("failed to respond to {}: {}", addr, err);
+                    }
+                }
             }
+            Err(err) => tracing::debug!("failed to respond to {}: {}", addr, err),
+        }
+    }
+}
+
+async fn handle_request(
+    buf: Bytes,
+    addr: SocketAddr,
+    state: &State,
+    listener: &Listener,
+) -> Option<(SocketAddr, PooledBuf)> {
+    let inflight = &state.metrics.udp_inflight;
+    inflight.fetch_add(1, Ordering::Relaxed);
+
+    let max_size = state.config.frontend.udp.max_response_size;
+    let response = answer_datagram(buf, addr, state, listener, max_size).await;
+
+    inflight.fetch_sub(1, Ordering::Relaxed);
+    Some((addr, response?))
+}
+
+/// Answers the query in the datagram `buf` from `addr`.
+///
+/// Returns the encoded response, truncated to the payload size of the client and
+/// `max_size`, or `None` if the datagram is not answered. Every answered query is
+/// counted in the metrics of `listener`.
+pub async fn answer_datagram(
+    buf: Bytes,
+    addr: SocketAddr,
+    state: &State,
+    listener: &Listener,
+    max_size: usize,
+) -> Option<PooledBuf> {
+    let start = Instant::now();
+
+    // Answers from the cache only need the header, question and OPT record,
+    // the rest of the query is decoded when it is forwarded.
+    let decoded = decode_request(buf.clone(), addr).and_then(|head| {
+        let Some(head) = head else {
+            return Ok(None);
         };
+        let edns = head.edns().inspect_err(|err| {
+            tracing::trace!("failed to decode packet: {:?}", err);
+        })?;
+        Ok(Some((head, edns)))
+    });
+    let (head, edns) = match decoded {
+        Ok(decoded) => decoded?,
+        Err(err) => return bad_response(&buf, &err, state),
+    };
+
+    let max_len = edns.as_ref().map_or(MIN_PAYLOAD_SIZE, |edns| {
+        usize::from(edns.udp_payload_size).max(MIN_PAYLOAD_SIZE)
+    });
+    let max_len = max_len.min(max_size);
+
+    let mut response = match answer_cached(head, edns, Some(addr.ip()), state) {
+        Ok(response) => response,
+        Err(head) => {
+            let packet = match head.into_packet() {
+                Ok(packet) => packet,
+                Err(err) => {
+                    tracing::trace!("failed to decode packet: {:?}", err);
+                    return bad_response(&buf, &err, state);
+                }
+            };
+
+            handle_query(packet, Some(addr.ip()), state).await
+        }
+    };
+    listener.queries.fetch_add(1, Ordering::Relaxed);
+    truncate(&mut response, max_len);
+
+    let mut buf = bufpool::get();
+    buf.reserve(response.encoded_len());
+    response.encode(&mut *buf);
+    listener.response_times.observe(start.elapsed());
+    Some(buf)
+}
+
+/// Encodes the response to the query `buf` that failed to decode with `err`.
+fn bad_response(buf: &[u8], err: &DecodeError, state: &State) -> Option<PooledBuf> {
+    let response = bad_query(buf, err, state)?;
+    let mut buf = bufpool::get();
+    buf.extend_from_slice(&response);
+    Some(buf)
+}
+
+/// Trims the records of `response` until it fits into `max_len` bytes.
+///
+/// Whole RRsets are removed from the end, additional records first. If answer or
+/// authority records had to be removed the TC flag is set and the client retries
+/// over TCP.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc2181#section-9
+pub fn truncate(response: &mut Packet, max_len: usize) {
+    let mut len = response.encoded_len();
+
+    while len > max_len {
+        if !response.additional.is_empty() {
+            len -= pop_rrset(&mut response.additional);
+        } else if !response.authority.is_empty() {
+            len -= pop_rrset(&mut response.authority);
+            response.truncated = true;
+        } else if !response.answers.is_empty() {
+            len -= pop_rrset(&mut response.answers);
+            response.truncated = true;
+        } else {
+            break;
+        }
+    }
+}
+
+/// Removes the last RRset from `records` and returns its encoded length.
+fn pop_rrset(records: &mut Vec<ResourceRecord>) -> usize {
+    let Some(last) = records.pop() else {
+        return 0;
+    };
+
+    let mut len = last.encoded_len();
+    while let Some(record) = records.pop_if(|record| {
+        record.name == last.name && record.r#type == last.r#type && record.class == last.class
+    }) {
+        len += record.encoded_len();
     }
 
-    let response = Packet {
-        transaction_id: packet.transaction_id,
-        qr: Qr::Response,
-        opcode: OpCode::Query,
-        authoritative_answer: false,
-        recursion_desired: packet.recursion_desired,
-        recursion_available: true,
-        truncated: false,
-        response_code,
-        questions: packet.questions,
-        answers,
-        additional: Vec::new(),
-        authority: Vec::new(),
+    len
+}
+
+/// Answers a request that exceeds the in-flight limit without resolving it.
+///
+/// The response is sent without waiting for the socket, so that the receive
+/// loop is never blocked by rejected requests.
+fn reject_request(buf: Bytes, addr: SocketAddr, socket: &UdpSocket, state: &State) {
+    let Ok(Some(head)) = decode_request(buf, addr) else {
+        return;
+    };
+    let Ok(edns) = head.edns() else {
+        return;
     };
 
-    let mut buf = Vec::new();
-    response.encode(&mut buf);
+    let Some(response) = reject(head.into_query(edns), Rejection::Inflight, state) else {
+        return;
+    };
+    let mut buf = bufpool::get();
+    buf.reserve(response.encoded_len());
+    response.encode(&mut *buf);
 
-    if let Err(err) = socket.send_to(&buf, addr).await {
+    if let Err(err) = socket.try_send_to(&buf, addr) {
         tracing::debug!("failed to respond to {}: {}", addr, err);
     }
 }
 
-#[derive(Clone, Debug)]
-struct Request {
-    packet: Packet,
-    addr: SocketAddr,
+/// Decodes the header and question section of the query in `buf`.
+///
+/// Returns `None` if the packet must not be answered at all.
+fn decode_request(buf: Bytes, addr: SocketAddr) -> Result<Option<QueryHead>, DecodeError> {
+    let head = Packet::decode_query_head(buf).inspect_err(|err| {
+        tracing::trace!("failed to decode packet: {:?}", err);
+    })?;
+
+    tracing::trace!("query from {}: {:?}", addr, head.questions);
+
+    // Never answer responses, they are either misdirected
+    // or spoofed to reflect traffic back at us.
+    if head.header.qr() != Qr::Request {
+        return Ok(None);
+    }
+
+    Ok(Some(head))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::proto::{
+        Class, Fqdn, OpCode, Packet, Qr, RecordData, ResourceRecord, ResponseCode, Type,
+    };
+
+    use super::{truncate, UdpServer};
+
+    fn a(name: &str, addr: Ipv4Addr) -> ResourceRecord {
+        ResourceRecord {
+            name: Fqdn(name.as_bytes().to_vec()),
+            r#type: Type::A,
+            class: Class::In,
+            ttl: 300,
+            rdata: RecordData::A(addr),
+        }
+    }
+
+    fn response(answers: Vec<ResourceRecord>, additional: Vec<ResourceRecord>) -> Packet {
+        Packet {
+            transaction_id: 0,
+            qr: Qr::Response,
+            opcode: OpCode::Query,
+            authoritative_answer: false,
+            truncated: false,
+            recursion_desired: true,
+            recursion_available: true,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::Ok,
+            questions: Vec::new(),
+            raw_questions: None,
+            answers,
+            authority: Vec::new(),
+            additional,
+            edns: None,
+        }
+    }
+
+    #[test]
+    fn truncate_drops_additional_without_tc() {
+        let answers = vec![a("example.com.", Ipv4Addr::new(192, 0, 2, 1))];
+        let mut packet = response(
+            answers.clone(),
+            vec![a("ns.example.com.", Ipv4Addr::new(192, 0, 2, 53))],
+        );
+        let max_len = response(answers.clone(), Vec::new()).encoded_len();
+
+        truncate(&mut packet, max_len);
+        assert!(!packet.truncated);
+        assert_eq!(packet.answers, answers);
+        assert!(packet.additional.is_empty());
+    }
+
+    #[test]
+    fn truncate_removes_whole_rrsets() {
+        let first = a("a.example.com.", Ipv4Addr::new(192, 0, 2, 1));
+        let mut packet = response(
+            vec![
+                first.clone(),
+                a("b.example.com.", Ipv4Addr::new(192, 0, 2, 2)),
+                a("b.example.com.", Ipv4Addr::new(192, 0, 2, 3)),
+            ],
+            Vec::new(),
+        );
+        // Room for the first two records, but not the rest of their RRset.
+        let max_len = packet.encoded_len() - 1;
+
+        truncate(&mut packet, max_len);
+        assert!(packet.truncated);
+        assert_eq!(packet.answers, vec![first]);
+    }
+
+    #[test]
+    fn truncate_fitting_response() {
+        let mut packet = response(
+            vec![a("example.com.", Ipv4Addr::new(192, 0, 2, 1))],
+            Vec::new(),
+        );
+        let len = packet.encoded_len();
+
+        truncate(&mut packet, len);
+        assert!(!packet.truncated);
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn bind_port_zero() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = UdpServer::new(addr, String::new(), false, true, false)
+            .await
+            .unwrap();
+        let local_addr = server.local_addr().unwrap();
+        assert_ne!(local_addr.port(), 0);
+
+        // Further workers bind to the port picked by the OS.
+        let worker = UdpServer::new(local_addr, String::new(), false, true, false)
+            .await
+            .unwrap();
+        assert_eq!(worker.local_addr().unwrap(), local_addr);
+    }
 }
@@ -0,0 +1,128 @@
+//! DNS over HTTPS.
+//!
+//! The handler is independent of the HTTP version, it serves both the
+//! HTTP/1 server and the HTTP/3 frontend.
+//!
+//! See https://datatracker.ietf.org/doc/html/rfc8484
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode};
+
+use crate::frontend::handle_query;
+use crate::metrics::Listener;
+use crate::proto::{Packet, Qr};
+use crate::state::State;
+
+pub const PATH: &str = "/dns-query";
+
+/// Maximum size of a DNS message.
+pub const MAX_BODY_SIZE: usize = u16::MAX as usize;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Answers the DNS query in `req` from `client`.
+///
+/// Every well-formed query is counted in the metrics of `listener`.
+pub async fn dns_query(
+    req: Request<Bytes>,
+    client: Option<IpAddr>,
+    state: &State,
+    listener: &Listener,
+) -> Response<Bytes> {
+    // Only GET requests can be cached by HTTP caches.
+    let cacheable = req.method() == Method::GET;
+    let buf = match *req.method() {
+        Method::GET => {
+            let query = req.uri().query().unwrap_or_default();
+            let Some(dns) = query.split('&').find_map(|pair| pair.strip_prefix("dns=")) else {
+                return error(StatusCode::BAD_REQUEST);
+            };
+
+            match URL_SAFE_NO_PAD.decode(dns) {
+                Ok(buf) => Bytes::from(buf),
+                Err(_) => return error(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            if req.headers().get(CONTENT_TYPE) != Some(&HeaderValue::from_static(DNS_MESSAGE)) {
+                return error(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+
+            req.into_body()
+        }
+        _ => return error(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let Some(packet) = decode_query(buf) else {
+        return error(StatusCode::BAD_REQUEST);
+    };
+
+    listener.queries.fetch_add(1, Ordering::Relaxed);
+    let start = Instant::now();
+
+    let response = handle_query(packet, client, state).await;
+    listener.response_times.observe(start.elapsed());
+    let mut buf = Vec::with_capacity(response.encoded_len());
+    response.encode(&mut buf);
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, DNS_MESSAGE);
+    if cacheable {
+        builder = builder.header(CACHE_CONTROL, format!("max-age={}", max_age(&response)));
+    }
+
+    builder.body(Bytes::from(buf)).unwrap()
+}
+
+/// Returns the HTTP freshness lifetime of `response` in seconds, the smallest
+/// TTL of its answers.
+///
+/// Responses without answers must not be cached, we don't include the SOA
+/// record that determines how long negative responses may be cached.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc8484#section-5.1
+fn max_age(response: &Packet) -> u32 {
+    response
+        .answers
+        .iter()
+        .map(|record| record.ttl)
+        .min()
+        .unwrap_or(0)
+}
+
+fn decode_query(buf: Bytes) -> Option<Packet> {
+    let head = match Packet::decode_query_head(buf) {
+        Ok(head) => head,
+        Err(err) => {
+            tracing::trace!("failed to decode packet: {:?}", err);
+            return None;
+        }
+    };
+
+    if head.header.qr() != Qr::Request {
+        return None;
+    }
+
+    match head.into_packet() {
+        Ok(packet) => Some(packet),
+        Err(err) => {
+            tracing::trace!("failed to decode packet: {:?}", err);
+            None
+        }
+    }
+}
+
+fn error(status: StatusCode) -> Response<Bytes> {
+    Response::builder()
+        .status(status)
+        .body(Bytes::new())
+        .unwrap()
+}
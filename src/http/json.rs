@@ -0,0 +1,173 @@
+//! DNS queries in the JSON format of the Google and Cloudflare resolvers.
+//!
+//! See https://developers.google.com/speed/public-dns/docs/doh/json
+
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::{Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::metrics::Listener;
+use crate::proto::{Class, Fqdn, Question, ResponseCode, Type};
+use crate::state::State;
+use crate::upstream::{QueryFlags, ResolverError};
+
+pub const PATH: &str = "/resolve";
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+    status: u16,
+    #[serde(rename = "TC")]
+    tc: bool,
+    #[serde(rename = "RD")]
+    rd: bool,
+    #[serde(rename = "RA")]
+    ra: bool,
+    #[serde(rename = "AD")]
+    ad: bool,
+    #[serde(rename = "CD")]
+    cd: bool,
+    question: Vec<JsonQuestion>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    answer: Vec<JsonRecord>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct JsonQuestion {
+    name: String,
+    r#type: u16,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct JsonRecord {
+    name: String,
+    r#type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+pub async fn resolve(
+    req: Request<Incoming>,
+    state: &State,
+    listener: &Listener,
+) -> Response<Full<Bytes>> {
+    let mut name = None;
+    let mut qtype = Type::A;
+    let mut checking_disabled = false;
+    for (key, value) in form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()) {
+        match &*key {
+            "name" => name = Some(value.into_owned()),
+            "type" => match parse_type(&value) {
+                Some(t) => qtype = t,
+                None => return error(StatusCode::BAD_REQUEST, "invalid type"),
+            },
+            "cd" => checking_disabled = value == "1" || value == "true",
+            _ => (),
+        }
+    }
+
+    let Some(mut name) = name else {
+        return error(StatusCode::BAD_REQUEST, "missing name");
+    };
+    if !name.ends_with('.') {
+        name.push('.');
+    }
+
+    let Ok(name) = Fqdn::new(name) else {
+        return error(StatusCode::BAD_REQUEST, "invalid name");
+    };
+
+    let question = Question {
+        name,
+        qtype,
+        qclass: Class::In,
+    };
+
+    listener.queries.fetch_add(1, Ordering::Relaxed);
+    let start = Instant::now();
+
+    let flags = QueryFlags {
+        checking_disabled,
+        ..Default::default()
+    };
+    let res = state.resolve(&question, &flags).await;
+    listener.response_times.observe(start.elapsed());
+
+    let (status, answer) = match res {
+        Ok(resolution) => (
+            ResponseCode::Ok,
+            resolution
+                .resources
+                .into_iter()
+                .map(|answer| JsonRecord {
+                    name: answer.name.to_string(),
+                    r#type: answer.r#type.to_u16(),
+                    ttl: answer.ttl().as_secs() as u32,
+                    data: answer.data.to_string(),
+                })
+                .collect(),
+        ),
+        Err(ResolverError::Refused) => (ResponseCode::Refused, Vec::new()),
+        Err(ResolverError::ResponseCode(code, _)) => (code, Vec::new()),
+        Err(err) => {
+            tracing::error!("failed to resolve query: {:?}", err);
+            (ResponseCode::ServerFailure, Vec::new())
+        }
+    };
+
+    let resp = JsonResponse {
+        status: status.to_u16(),
+        tc: false,
+        rd: true,
+        ra: true,
+        ad: false,
+        cd: checking_disabled,
+        question: vec![JsonQuestion {
+            name: question.name.to_string(),
+            r#type: question.qtype.to_u16(),
+        }],
+        answer,
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/dns-json")
+        .body(Full::new(Bytes::from(serde_json::to_vec(&resp).unwrap())))
+        .unwrap()
+}
+
+/// Parses a type from either its mnemonic or its number.
+fn parse_type(s: &str) -> Option<Type> {
+    match s.parse::<u16>() {
+        Ok(n) => Type::from_u16(n),
+        Err(_) => s.to_ascii_uppercase().parse().ok(),
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(message.to_owned())))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::Type;
+
+    use super::parse_type;
+
+    #[test]
+    fn parse_type_number_or_mnemonic() {
+        assert_eq!(parse_type("28"), Some(Type::AAAA));
+        assert_eq!(parse_type("aaaa"), Some(Type::AAAA));
+        assert_eq!(parse_type("MX"), Some(Type::MX));
+        assert_eq!(parse_type("nope"), None);
+    }
+}
@@ -1,33 +1,104 @@
+pub mod doh;
+mod json;
+mod probe;
+
+use std::collections::HashSet;
 use std::convert::Infallible;
 use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
 
 use bytes::Bytes;
 use futures::future::BoxFuture;
-use http_body_util::Full;
-use hyper::body::Incoming;
+use futures::{select_biased, FutureExt};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Incoming};
+use hyper::header::CONTENT_LENGTH;
 use hyper::server::conn::http1::Builder;
 use hyper::service::Service;
-use hyper::{Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::tokio::TokioIo;
 use tokio::net::TcpListener;
 
-use crate::config::Http;
+use crate::config::Transport;
+use crate::metrics::{Histogram, HttpErrorKind, Listener, HISTOGRAM_BUCKETS};
 use crate::state::State;
 
-pub async fn run(http: Http, state: &'static State) {
-    let listener = TcpListener::bind(http.bind).await.unwrap();
+/// The endpoints served by an HTTP listener.
+#[derive(Copy, Clone, Debug)]
+pub struct Routes {
+    /// `/metrics` and `/debug/probe`.
+    pub admin: bool,
+    /// `/dns-query` and `/resolve`.
+    pub doh: bool,
+}
+
+impl Routes {
+    /// Returns the endpoints of the listener on `http.bind`.
+    pub fn public(state: &State) -> Self {
+        let http = &state.config.http;
+        Self {
+            admin: http.admin.is_none(),
+            doh: http.doh,
+        }
+    }
+
+    /// Returns the endpoints of the listener on `http.admin`.
+    pub fn admin() -> Self {
+        Self {
+            admin: true,
+            doh: false,
+        }
+    }
+}
+
+pub async fn run(
+    listener: &TcpListener,
+    label: &str,
+    routes: Routes,
+    state: &'static State,
+) -> Result<(), io::Error> {
+    let metrics = state.metrics.listeners.register("http", label);
 
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
+        let (stream, addr) = select_biased! {
+            () = state.shutdown.triggered().fuse() => return Ok(()),
+            res = listener.accept().fuse() => res?,
+        };
 
-        let conn = Builder::new().serve_connection(TokioIo::new(stream), RootService { state });
-        tokio::task::spawn(conn);
+        let guard = state.shutdown.guard();
+        let service = RootService {
+            state,
+            listener: metrics.clone(),
+            routes,
+            addr,
+        };
+        tokio::task::spawn(async move {
+            let _guard = guard;
+            let conn = Builder::new().serve_connection(TokioIo::new(stream), service);
+            let mut conn = std::pin::pin!(conn);
+
+            select_biased! {
+                _ = conn.as_mut().fuse() => (),
+                () = state.shutdown.triggered().fuse() => {
+                    // Finish the request in progress, then close the connection.
+                    conn.as_mut().graceful_shutdown();
+                    let _ = conn.await;
+                }
+            }
+        });
     }
 }
 
 struct RootService {
     state: &'static State,
+    listener: Arc<Listener>,
+    routes: Routes,
+    /// Address of the client, usually the reverse proxy.
+    addr: SocketAddr,
 }
 
 impl Service<Request<Incoming>> for RootService {
@@ -37,32 +108,407 @@ impl Service<Request<Incoming>> for RootService {
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
         let state = self.state;
+        let listener = self.listener.clone();
+        let addr = self.addr;
+        let Routes { admin, doh } = self.routes;
         Box::pin(async move {
-            let resp = match req.uri().path() {
-                "/metrics" => metrics(state).await,
+            let start = Instant::now();
+            let access = state
+                .config
+                .http
+                .access_log
+                .then(|| AccessLog::new(&req, addr));
+
+            let resp = match (req.method(), req.uri().path()) {
+                (_, "/metrics") if admin => metrics(state).await,
+                (&Method::POST, "/debug/probe") if admin => probe::probe(req, state).await,
+                (_, doh::PATH) if doh => dns_query(req, state, &listener).await,
+                (&Method::GET, json::PATH) if doh => json::resolve(req, state, &listener).await,
                 _ => Response::builder()
                     .status(StatusCode::NOT_FOUND)
                     .body(Full::new(Bytes::new()))
                     .unwrap(),
             };
 
+            if let Some(access) = access {
+                access.log(&resp, start);
+            }
+
             Ok(resp)
         })
     }
 }
 
+/// The details of a request written to the access log.
+///
+/// The query string is not logged, it carries the DNS query of GET requests.
+struct AccessLog {
+    addr: SocketAddr,
+    /// The client address reported by the reverse proxy.
+    forwarded_for: Option<String>,
+    method: Method,
+    path: String,
+    request_size: u64,
+}
+
+impl AccessLog {
+    fn new(req: &Request<Incoming>, addr: SocketAddr) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        Self {
+            addr,
+            forwarded_for: header("x-forwarded-for").map(str::to_owned),
+            method: req.method().clone(),
+            path: req.uri().path().to_owned(),
+            request_size: header(CONTENT_LENGTH.as_str())
+                .and_then(|len| len.parse().ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn log(self, resp: &Response<Full<Bytes>>, start: Instant) {
+        tracing::info!(
+            target: "rdns::access",
+            client = %self.addr.ip(),
+            forwarded_for = self.forwarded_for.as_deref().unwrap_or("-"),
+            method = %self.method,
+            path = self.path,
+            status = resp.status().as_u16(),
+            latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+            request_size = self.request_size,
+            response_size = resp.body().size_hint().exact().unwrap_or_default(),
+        );
+    }
+}
+
+async fn dns_query(
+    req: Request<Incoming>,
+    state: &State,
+    listener: &Listener,
+) -> Response<Full<Bytes>> {
+    let (parts, body) = req.into_parts();
+    let body = match Limited::new(body, doh::MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        }
+    };
+
+    // The client is usually a reverse proxy, its address says nothing about
+    // the subnet of the querying client.
+    let req = Request::from_parts(parts, body);
+    doh::dns_query(req, None, state, listener)
+        .await
+        .map(Full::new)
+}
+
+/// Writes `histogram` in the Prometheus text format.
+///
+/// `labels` are added to every sample and must be empty or end with a comma.
+fn write_histogram(body: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    for (le, count) in HISTOGRAM_BUCKETS.iter().zip(histogram.buckets()) {
+        writeln!(
+            body,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name,
+            labels,
+            *le as f64 / 1000.0,
+            count
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        "{}_bucket{{{}le=\"+Inf\"}} {}",
+        name,
+        labels,
+        histogram.count()
+    )
+    .unwrap();
+
+    let labels = labels.trim_end_matches(',');
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    writeln!(
+        body,
+        "{}_sum{} {}",
+        name,
+        labels,
+        histogram.sum().as_secs_f64()
+    )
+    .unwrap();
+    writeln!(body, "{}_count{} {}", name, labels, histogram.count()).unwrap();
+}
+
+/// Escapes `value` for use as a label value in the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
 async fn metrics(state: &State) -> Response<Full<Bytes>> {
     let mut body = String::new();
     for (key, val) in [
         ("dns_cache_hits", &state.metrics.cache_hits),
         ("dns_cache_misses", &state.metrics.cache_misses),
         ("dns_cache_size", &state.metrics.cache_size),
+        ("dns_diff_comparisons", &state.metrics.diff_comparisons),
+        ("dns_diff_mismatches", &state.metrics.diff_mismatches),
+        ("dns_badvers_responses", &state.metrics.badvers_responses),
+        ("dns_cache_expired", &state.metrics.cache_expired),
+        ("dns_cleanup_wakeups", &state.metrics.cleanup_wakeups),
+        ("dns_cleanup_last_sweep", &state.metrics.cleanup_last_sweep),
+        ("dns_malformed_total", &state.metrics.malformed),
+        ("dns_udp_inflight", &state.metrics.udp_inflight),
     ] {
         writeln!(body, "{} {}", key, val.load(Ordering::Relaxed)).unwrap();
     }
 
+    writeln!(
+        body,
+        "dns_cache_expiration_queue {}",
+        state.cache.expiration_len()
+    )
+    .unwrap();
+    write_histogram(
+        &mut body,
+        "dns_cache_expiration_lag_seconds",
+        "",
+        &state.metrics.expiration_lag,
+    );
+
+    for listener in state.metrics.listeners.entries() {
+        let labels = format!(
+            "protocol=\"{}\",listener=\"{}\",",
+            listener.protocol,
+            escape_label(&listener.name)
+        );
+        writeln!(
+            body,
+            "dns_queries{{{}}} {}",
+            labels.trim_end_matches(','),
+            listener.queries.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        for (key, val) in [
+            ("dns_listener_restarts", &listener.restarts),
+            ("dns_listener_down", &listener.down),
+        ] {
+            writeln!(
+                body,
+                "{}{{{}}} {}",
+                key,
+                labels.trim_end_matches(','),
+                val.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        write_histogram(
+            &mut body,
+            "dns_response_time_seconds",
+            &labels,
+            &listener.response_times,
+        );
+    }
+
+    for (reason, val) in [
+        ("inflight", &state.metrics.rejected_inflight),
+        ("size", &state.metrics.rejected_size),
+    ] {
+        writeln!(
+            body,
+            "dns_rejected{{reason=\"{}\"}} {}",
+            reason,
+            val.load(Ordering::Relaxed)
+        )
+        .unwrap();
+    }
+
+    // Only DoH upstreams have HTTP metrics.
+    let https: HashSet<_> = [&state.zones, &state.diff_zones]
+        .into_iter()
+        .flat_map(|zones| zones.iter())
+        .flat_map(|(_, upstreams)| upstreams)
+        .filter(|upstream| upstream.transport == Some(Transport::Https))
+        .map(|upstream| upstream.resolver.id())
+        .collect();
+    for (id, upstream) in state.metrics.upstream_times.entries() {
+        writeln!(
+            body,
+            "dns_upstream_mismatched_responses{{upstream=\"{}\"}} {}",
+            escape_label(&upstream.addr),
+            upstream.mismatched.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "dns_upstream_overloaded_queries{{upstream=\"{}\"}} {}",
+            escape_label(&upstream.addr),
+            upstream.overloaded.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "dns_upstream_rate_limited_queries{{upstream=\"{}\"}} {}",
+            escape_label(&upstream.addr),
+            upstream.rate_limited.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "dns_upstream_timeouts{{upstream=\"{}\"}} {}",
+            escape_label(&upstream.addr),
+            upstream.timeouts.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            body,
+            "dns_upstream_failures{{upstream=\"{}\"}} {}",
+            escape_label(&upstream.addr),
+            upstream.failures.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        if https.contains(&id) {
+            for (class, val) in upstream.http_statuses.iter().enumerate() {
+                writeln!(
+                    body,
+                    "dns_upstream_http_responses{{upstream=\"{}\",class=\"{}xx\"}} {}",
+                    escape_label(&upstream.addr),
+                    class + 1,
+                    val.load(Ordering::Relaxed)
+                )
+                .unwrap();
+            }
+            for (kind, val) in HttpErrorKind::ALL.iter().zip(&upstream.http_errors) {
+                writeln!(
+                    body,
+                    "dns_upstream_request_errors{{upstream=\"{}\",kind=\"{}\"}} {}",
+                    escape_label(&upstream.addr),
+                    kind.as_str(),
+                    val.load(Ordering::Relaxed)
+                )
+                .unwrap();
+            }
+        }
+
+        write_histogram(
+            &mut body,
+            "dns_upstream_response_time_seconds",
+            &format!("upstream=\"{}\",", escape_label(&upstream.addr)),
+            &upstream.histogram,
+        );
+    }
+
+    for (outcome, val) in [
+        ("formerr", &state.metrics.bad_queries_formerr),
+        ("notimp", &state.metrics.bad_queries_notimp),
+        ("dropped", &state.metrics.bad_queries_dropped),
+    ] {
+        writeln!(
+            body,
+            "dns_bad_queries{{outcome=\"{}\"}} {}",
+            outcome,
+            val.load(Ordering::Relaxed)
+        )
+        .unwrap();
+    }
+
+    // A zone is degraded if none of its upstreams is reachable.
+    for (zone, upstreams) in state.zones.iter() {
+        let degraded = !upstreams
+            .iter()
+            .any(|upstream| upstream.resolver.is_available());
+        writeln!(
+            body,
+            "dns_zone_degraded{{zone=\"{}\"}} {}",
+            String::from_utf8_lossy(zone),
+            u8::from(degraded)
+        )
+        .unwrap();
+    }
+
     Response::builder()
         .status(StatusCode::OK)
         .body(Full::new(Bytes::from(body)))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use http_body_util::BodyExt;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use crate::state::State;
+
+    use super::{metrics, run, Routes};
+
+    #[tokio::test]
+    async fn http_metrics_of_doh_upstreams_only() {
+        let config = json!({
+            "bind": "127.0.0.1:0",
+            "zones": {
+                ".": [
+                    { "Udp": { "addr": "192.0.2.1:53", "timeout": 1 } },
+                    { "Https": { "url": "https://192.0.2.2/dns-query", "timeout": 1 } },
+                ],
+            },
+            "http": { "enabled": false, "bind": "127.0.0.1:0" },
+        });
+        let state = State::new(serde_json::from_value(config).unwrap()).unwrap();
+
+        let body = metrics(&state).await.into_body().collect().await.unwrap();
+        let body = String::from_utf8(body.to_bytes().to_vec()).unwrap();
+        assert!(
+            body.contains("dns_upstream_http_responses{upstream=\"https://192.0.2.2/dns-query\"")
+        );
+        assert!(!body.contains("dns_upstream_http_responses{upstream=\"192.0.2.1:53\""));
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_on_admin_listener_only() {
+        let config = json!({
+            "bind": "127.0.0.1:0",
+            "zones": {},
+            "http": {
+                "enabled": true,
+                "bind": "127.0.0.1:0",
+                "doh": true,
+                "admin": { "addr": "127.0.0.1:0" },
+            },
+        });
+        let state: &State = Box::leak(Box::new(
+            State::new(serde_json::from_value(config).unwrap()).unwrap(),
+        ));
+
+        let mut addrs = Vec::new();
+        for routes in [Routes::public(state), Routes::admin()] {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            tokio::task::spawn(async move { run(&listener, "test", routes, state).await });
+        }
+
+        let status = |addr, path| async move {
+            let url = format!("http://{}{}", addr, path);
+            reqwest::get(url).await.unwrap().status().as_u16()
+        };
+        assert_eq!(status(addrs[0], "/metrics").await, 404);
+        assert_eq!(status(addrs[0], "/resolve?name=.").await, 200);
+        assert_eq!(status(addrs[1], "/metrics").await, 200);
+        assert_eq!(status(addrs[1], "/resolve?name=.").await, 404);
+    }
+}
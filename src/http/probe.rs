@@ -0,0 +1,240 @@
+//! One-off queries against upstreams that bypass the cache.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{Class, Fqdn, Packet, Question, ResourceRecord, Type};
+use crate::state::State;
+use crate::upstream::{Resolver, ResolverError};
+
+const MAX_BODY_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, Deserialize)]
+struct ProbeRequest {
+    name: String,
+    r#type: String,
+    /// Address of the upstream to query. If `None` all upstreams of the zone that `name`
+    /// belongs to are queried.
+    #[serde(default)]
+    upstream: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ProbeResult {
+    upstream: String,
+    timings: Timings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<ProbeResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Time spent in every stage of the query in microseconds.
+#[derive(Clone, Debug, Default, Serialize)]
+struct Timings {
+    exchange: u64,
+    decode: u64,
+    total: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ProbeResponse {
+    transaction_id: u16,
+    authoritative_answer: bool,
+    truncated: bool,
+    recursion_available: bool,
+    response_code: String,
+    answers: Vec<ProbeRecord>,
+    authority: Vec<ProbeRecord>,
+    additional: Vec<ProbeRecord>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ProbeRecord {
+    name: String,
+    r#type: String,
+    class: String,
+    ttl: u32,
+    data: String,
+}
+
+impl From<Packet> for ProbeResponse {
+    fn from(packet: Packet) -> Self {
+        fn records(records: Vec<ResourceRecord>) -> Vec<ProbeRecord> {
+            records
+                .into_iter()
+                .map(|record| ProbeRecord {
+                    name: record.name.to_string(),
+                    r#type: record.r#type.to_string(),
+                    class: record.class.to_string(),
+                    ttl: record.ttl,
+                    data: record.rdata.to_string(),
+                })
+                .collect()
+        }
+
+        Self {
+            transaction_id: packet.transaction_id,
+            authoritative_answer: packet.authoritative_answer,
+            truncated: packet.truncated,
+            recursion_available: packet.recursion_available,
+            response_code: format!("{:?}", packet.response_code),
+            answers: records(packet.answers),
+            authority: records(packet.authority),
+            additional: records(packet.additional),
+        }
+    }
+}
+
+pub async fn probe(req: Request<Incoming>, state: &State) -> Response<Full<Bytes>> {
+    let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    let req: ProbeRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    let Ok(qtype) = req.r#type.parse::<Type>() else {
+        return error(StatusCode::BAD_REQUEST, "invalid type");
+    };
+
+    let mut name = req.name;
+    if !name.ends_with('.') {
+        name.push('.');
+    }
+
+    let Ok(name) = Fqdn::new(name) else {
+        return error(StatusCode::BAD_REQUEST, "invalid name");
+    };
+    if !state.is_allowed(&name) {
+        return error(StatusCode::FORBIDDEN, "name not in allowlist");
+    }
+
+    let question = Question {
+        name,
+        qtype,
+        qclass: Class::In,
+    };
+
+    let resolvers: Vec<&Resolver> = match &req.upstream {
+        Some(upstream) => state
+            .zones
+            .resolvers()
+            .filter(|resolver| resolver.addr() == *upstream)
+            .take(1)
+            .collect(),
+        None => state
+            .zones
+            .lookup(&question.name)
+            .map(|upstreams| {
+                upstreams
+                    .iter()
+                    .map(|upstream| &upstream.resolver)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+
+    if resolvers.is_empty() {
+        return error(StatusCode::NOT_FOUND, "no matching upstream");
+    }
+
+    let mut results = Vec::new();
+    for resolver in resolvers {
+        results.push(probe_resolver(resolver, &question).await);
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(
+            serde_json::to_vec(&results).unwrap(),
+        )))
+        .unwrap()
+}
+
+async fn probe_resolver(resolver: &Resolver, question: &Question) -> ProbeResult {
+    let mut timings = Timings::default();
+    let query = resolver.profile().build_query(question);
+
+    let start = Instant::now();
+    let res = resolver.exchange(&query).await;
+    let exchanged = Instant::now();
+    timings.exchange = micros(exchanged - start);
+
+    let res = res.and_then(|buf| {
+        let packet = Packet::decode(buf).map_err(ResolverError::Decode);
+        timings.decode = micros(exchanged.elapsed());
+        packet
+    });
+    timings.total = micros(start.elapsed());
+
+    let (response, error) = match res {
+        Ok(packet) => (Some(packet.into()), None),
+        Err(err) => (None, Some(format!("{:?}", err))),
+    };
+
+    ProbeResult {
+        upstream: resolver.addr(),
+        timings,
+        response,
+        error,
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(message.to_owned())))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use crate::http::{run, Routes};
+    use crate::state::State;
+
+    #[tokio::test]
+    async fn probe_checks_name() {
+        let config = json!({
+            "bind": "127.0.0.1:0",
+            "zones": { ".": [{ "Udp": { "addr": "203.0.113.1:53", "timeout": 1 } }] },
+            "http": { "enabled": true, "bind": "127.0.0.1:0" },
+            "allowlist": { "enabled": true, "domains": ["example.com."] },
+        });
+        let state: &State = Box::leak(Box::new(
+            State::new(serde_json::from_value(config).unwrap()).unwrap(),
+        ));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}/debug/probe", listener.local_addr().unwrap());
+        tokio::task::spawn(async move { run(&listener, "test", Routes::admin(), state).await });
+
+        let client = reqwest::Client::new();
+        let status = |name: &str| {
+            let req = client
+                .post(&url)
+                .body(json!({ "name": name, "type": "A" }).to_string());
+            async move { req.send().await.unwrap().status().as_u16() }
+        };
+        assert_eq!(status("example.net.").await, 403);
+        assert_eq!(status(&"a".repeat(300)).await, 400);
+    }
+}
@@ -0,0 +1,157 @@
+//! Answers for the names and addresses of the server itself.
+//!
+//! Reverse lookups of the server's own addresses (e.g. from traceroute or
+//! sshd) and lookups of its hostname are answered locally so they neither
+//! leak to nor wait on the upstreams.
+
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use ahash::HashMap;
+
+use crate::proto::{Fqdn, RecordData, Type};
+
+#[derive(Debug, Default)]
+pub struct LocalNames {
+    /// Records keyed by the lowercase owner name.
+    records: HashMap<Vec<u8>, Vec<(Type, RecordData)>>,
+}
+
+impl LocalNames {
+    /// Creates the A/AAAA records of every name in `names` and the PTR
+    /// record of every address in `addrs`.
+    ///
+    /// Reverse lookups point at the first name.
+    pub fn new(names: &[String], addrs: &[IpAddr]) -> Self {
+        let mut records: HashMap<Vec<u8>, Vec<(Type, RecordData)>> = HashMap::default();
+
+        for name in names {
+            let entry = records.entry(normalize(name)).or_default();
+            for addr in addrs {
+                entry.push(match addr {
+                    IpAddr::V4(addr) => (Type::A, RecordData::A(*addr)),
+                    IpAddr::V6(addr) => (Type::AAAA, RecordData::AAAA(*addr)),
+                });
+            }
+        }
+
+        if let Some(name) = names.first() {
+            let target = Fqdn(normalize(name));
+            for addr in addrs {
+                records
+                    .entry(reverse_name(*addr).into_bytes())
+                    .or_default()
+                    .push((Type::PTR, RecordData::PTR(target.clone())));
+            }
+        }
+
+        Self { records }
+    }
+
+    /// Returns the records of type `qtype` for `name`.
+    ///
+    /// Returns `None` if `name` is not a local name. An empty `Vec` is
+    /// returned if the name exists but has no records of `qtype`.
+    pub fn lookup(&self, name: &Fqdn, qtype: Type) -> Option<Vec<RecordData>> {
+        let records = self.records.get(&name.as_bytes().to_ascii_lowercase())?;
+        Some(
+            records
+                .iter()
+                .filter(|(r#type, _)| *r#type == qtype)
+                .map(|(_, data)| data.clone())
+                .collect(),
+        )
+    }
+}
+
+/// Returns the lowercase, fully qualified form of `name`.
+fn normalize(name: &str) -> Vec<u8> {
+    let mut name = name.to_ascii_lowercase().into_bytes();
+    if name.last() != Some(&b'.') {
+        name.push(b'.');
+    }
+    name
+}
+
+/// Returns the name of the PTR record of `addr`.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc1035#section-3.5 and
+/// https://datatracker.ietf.org/doc/html/rfc3596#section-2.5
+fn reverse_name(addr: IpAddr) -> String {
+    let mut name = String::new();
+    match addr {
+        IpAddr::V4(addr) => {
+            for octet in addr.octets().iter().rev() {
+                write!(name, "{}.", octet).unwrap();
+            }
+            name.push_str("in-addr.arpa.");
+        }
+        IpAddr::V6(addr) => {
+            for octet in addr.octets().iter().rev() {
+                write!(name, "{:x}.{:x}.", octet & 0xf, octet >> 4).unwrap();
+            }
+            name.push_str("ip6.arpa.");
+        }
+    }
+    name
+}
+
+/// Returns the hostname of the system.
+pub fn hostname() -> Option<String> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    let hostname = hostname.trim();
+    (!hostname.is_empty()).then(|| hostname.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use crate::proto::{Fqdn, RecordData, Type};
+
+    use super::{reverse_name, LocalNames};
+
+    #[test]
+    fn reverse_name_v4() {
+        assert_eq!(
+            reverse_name(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            "1.2.0.192.in-addr.arpa."
+        );
+    }
+
+    #[test]
+    fn reverse_name_v6() {
+        let addr: Ipv6Addr = "2001:db8::567:89ab".parse().unwrap();
+        assert_eq!(
+            reverse_name(IpAddr::V6(addr)),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa."
+        );
+    }
+
+    #[test]
+    fn local_names_lookup() {
+        let addr = Ipv4Addr::new(192, 0, 2, 1);
+        let names = LocalNames::new(&["Router.lan".to_owned()], &[IpAddr::V4(addr)]);
+
+        let records = names
+            .lookup(&Fqdn(b"router.LAN.".to_vec()), Type::A)
+            .unwrap();
+        assert!(matches!(records[..], [RecordData::A(a)] if a == addr));
+
+        let records = names
+            .lookup(&Fqdn(b"router.lan.".to_vec()), Type::AAAA)
+            .unwrap();
+        assert!(records.is_empty());
+
+        let records = names
+            .lookup(&Fqdn(b"1.2.0.192.in-addr.arpa.".to_vec()), Type::PTR)
+            .unwrap();
+        assert!(
+            matches!(&records[..], [RecordData::PTR(name)] if name.as_bytes() == b"router.lan.")
+        );
+
+        assert!(names
+            .lookup(&Fqdn(b"example.com.".to_vec()), Type::A)
+            .is_none());
+    }
+}
@@ -1,45 +1,225 @@
+mod bufpool;
 mod cache;
 mod config;
+mod ddr;
+mod diff;
+mod dscp;
 mod frontend;
 mod http;
+mod local;
 mod metrics;
+mod privileges;
 mod proto;
+mod sandbox;
+mod shutdown;
 mod state;
+mod supervisor;
+mod systemd;
 mod upstream;
 
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+
+#[cfg(feature = "dtls")]
+use crate::frontend::dtls::DtlsServer;
+use crate::frontend::quic::QuicServer;
+use crate::frontend::tcp::TcpServer;
+use crate::frontend::tls::TlsServer;
 use crate::frontend::udp::UdpServer;
 use config::Config;
+use privileges::Identity;
 use state::State;
+use supervisor::Supervisor;
 
-#[tokio::main]
-async fn main() {
+const CONFIG_PATH: &str = "./config.json";
+
+fn main() {
     pretty_env_logger::init();
 
-    let config = Config::from_file("./config.json");
+    let config = match Config::from_file(CONFIG_PATH) {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("invalid config: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Landlock only applies to the threads created afterwards, so it must
+    // come before the workers of the runtime.
+    if config.sandbox {
+        let mut files = config.files();
+        files.push(Path::new(CONFIG_PATH));
+        match sandbox::restrict_files(&files) {
+            Ok(true) => (),
+            Ok(false) => tracing::warn!("Landlock is not supported, files are not restricted"),
+            Err(err) => panic!("failed to restrict files: {}", err),
+        }
+    }
 
-    let addr = config.bind;
-    let http = config.http.clone();
-    let state = State::new(config);
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(config));
+}
+
+async fn run(config: Config) {
+    let grace_period = Duration::from_secs(config.grace_period);
+    let state = match State::new(config) {
+        Ok(state) => state,
+        Err(err) => {
+            tracing::error!("invalid config: {}", err);
+            std::process::exit(1);
+        }
+    };
     let state: &'static State = Box::leak(Box::new(state));
+    let config = &state.config;
+
+    // All listeners are bound before we report to be ready.
+    let mut listeners = systemd::Listeners::from_env();
+    let mut frontends = Supervisor::default();
+
+    let udp_workers = config.frontend.udp.workers.max(1);
+    let udp_offload = config.frontend.udp.offload;
+    for bind in &config.bind {
+        let mut addr = bind.addr;
+        let v6only = config.v6only(addr);
 
-    let mut handles = Vec::new();
-    handles.push(tokio::task::spawn(async move {
-        let server = UdpServer::new(addr).await;
-        if let Err(err) = server.poll(&state).await {
-            tracing::error!("failed to server DNS server: {}", err)
+        // Workers share the socket passed by systemd instead of binding their own.
+        let udp_socket = listeners.take_udp(addr);
+        for _ in 0..udp_workers {
+            let server = match &udp_socket {
+                Some(socket) => {
+                    UdpServer::from_std(socket.try_clone().unwrap(), bind.label(), udp_offload)
+                }
+                None => {
+                    UdpServer::new(addr, bind.label(), v6only, udp_workers > 1, udp_offload).await
+                }
+            };
+            let server = server
+                .unwrap_or_else(|err| panic!("failed to bind UDP socket to {}: {}", addr, err));
+            // With port 0 the OS picks the port, the other workers and the
+            // TCP listener bind to the same one.
+            addr = server.local_addr().unwrap();
+
+            let server = Arc::new(server);
+            frontends.add("udp", bind.label(), move || {
+                let server = server.clone();
+                async move { server.poll(state).await }
+            });
         }
-    }));
-    handles.push(tokio::task::spawn(async move {
-        state.cleanup().await;
-    }));
+        drop(udp_socket);
+        tracing::info!("listening on {} (UDP)", addr);
+
+        let server = match listeners.take_tcp(addr) {
+            Some(listener) => TcpServer::from_std(listener, bind.label()),
+            None => TcpServer::new(addr, bind.label(), v6only).await,
+        };
+        let server =
+            server.unwrap_or_else(|err| panic!("failed to bind TCP listener to {}: {}", addr, err));
+        tracing::info!("listening on {} (TCP)", server.local_addr().unwrap());
 
+        let server = Arc::new(server);
+        frontends.add("tcp", bind.label(), move || {
+            let server = server.clone();
+            async move { server.poll(state).await }
+        });
+    }
+    if let Some(tls) = &config.frontend.tls {
+        let server = TlsServer::new(tls, config.v6only(tls.bind)).await;
+        let server = Arc::new(server);
+        frontends.add("tls", tls.label(), move || {
+            let server = server.clone();
+            async move { server.poll(state).await }
+        });
+    }
+    if let Some(quic) = &config.frontend.quic {
+        let server = QuicServer::new(quic, config.v6only(quic.bind)).await;
+        let server = Arc::new(server);
+        frontends.add("quic", quic.label(), move || {
+            let server = server.clone();
+            async move { server.poll(state).await }
+        });
+    }
+    #[cfg(feature = "dtls")]
+    if let Some(dtls) = &config.frontend.dtls {
+        let server = DtlsServer::new(dtls, config.v6only(dtls.bind)).await;
+        let server = Arc::new(server);
+        frontends.add("dtls", dtls.label(), move || {
+            let server = server.clone();
+            async move { server.poll(state).await }
+        });
+    }
+    let http = &config.http;
     if http.enabled {
-        handles.push(tokio::task::spawn(async move {
-            http::run(http, state).await;
-        }));
+        let listener = match listeners.take_tcp(http.bind) {
+            Some(listener) => listener,
+            None => frontend::socket::bind_tcp(http.bind, config.v6only(http.bind)).unwrap(),
+        };
+        let listener = Arc::new(TcpListener::from_std(listener).unwrap());
+        let label = http.label();
+        frontends.add("http", label.clone(), move || {
+            let listener = listener.clone();
+            let label = label.clone();
+            let routes = http::Routes::public(state);
+            async move { http::run(&listener, &label, routes, state).await }
+        });
+
+        if let Some(admin) = &http.admin {
+            let listener = match listeners.take_tcp(admin.addr) {
+                Some(listener) => listener,
+                None => frontend::socket::bind_tcp(admin.addr, config.v6only(admin.addr)).unwrap(),
+            };
+            let listener = Arc::new(TcpListener::from_std(listener).unwrap());
+            let label = admin.label();
+            frontends.add("http", label.clone(), move || {
+                let listener = listener.clone();
+                let label = label.clone();
+                async move { http::run(&listener, &label, http::Routes::admin(), state).await }
+            });
+        }
+    }
+    listeners.warn_unused();
+
+    // Switch to the unprivileged user before serving any traffic.
+    let identity = Identity::lookup(config.user.as_deref(), config.group.as_deref())
+        .unwrap_or_else(|err| panic!("invalid user or group: {}", err));
+    if let Some(identity) = identity {
+        privileges::drop_to(identity)
+            .unwrap_or_else(|err| panic!("failed to drop privileges: {}", err));
+        tracing::info!("running as uid {} gid {}", identity.uid, identity.gid);
+    }
+    if config.sandbox {
+        sandbox::apply().unwrap_or_else(|err| panic!("failed to apply sandbox: {}", err));
     }
+    let handles = frontends.start(state);
+
+    // Background tasks are simply dropped on shutdown.
+    tokio::task::spawn(async move {
+        state.cleanup().await;
+    });
+    tokio::task::spawn(async move {
+        state.diff().await;
+    });
 
-    for handle in handles {
-        let _ = handle.await;
+    systemd::notify("READY=1");
+    tokio::task::spawn(systemd::watchdog());
+
+    shutdown::signal().await;
+    tracing::info!("shutting down");
+    systemd::notify("STOPPING=1");
+    state.shutdown.trigger();
+
+    let drain = async {
+        for handle in handles {
+            let _ = handle.await;
+        }
+        state.shutdown.drained().await;
+    };
+    if tokio::time::timeout(grace_period, drain).await.is_err() {
+        tracing::warn!("grace period expired, dropping queries in progress");
     }
 }
@@ -1,8 +1,313 @@
-use std::sync::atomic::AtomicU64;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
 
 #[derive(Debug, Default)]
 pub struct Metrics {
     pub cache_hits: AtomicU64,
     pub cache_misses: AtomicU64,
     pub cache_size: AtomicU64,
+    pub listeners: Listeners,
+    /// Number of cache entries removed by the cleanup task.
+    pub cache_expired: AtomicU64,
+    /// Number of times the cleanup task woke up, either to expire entries or
+    /// because an entry was inserted.
+    pub cleanup_wakeups: AtomicU64,
+    /// Number of entries expired by the last sweep of the cleanup task.
+    pub cleanup_last_sweep: AtomicU64,
+    /// Time between the scheduled and the actual expiration of cache entries.
+    pub expiration_lag: Histogram,
+    /// Number of answers compared against shadow upstreams.
+    pub diff_comparisons: AtomicU64,
+    /// Number of compared answers that differed.
+    pub diff_mismatches: AtomicU64,
+    /// Number of queries rejected because of an unsupported EDNS version.
+    pub badvers_responses: AtomicU64,
+    /// Number of queries rejected because too many were in flight.
+    pub rejected_inflight: AtomicU64,
+    /// Number of queries dropped because they exceeded the maximum size.
+    pub rejected_size: AtomicU64,
+    /// Number of queries currently resolved by the UDP workers.
+    pub udp_inflight: AtomicU64,
+    /// Number of malformed queries answered with FORMERR.
+    pub bad_queries_formerr: AtomicU64,
+    /// Number of queries with an unknown opcode answered with NOTIMP.
+    pub bad_queries_notimp: AtomicU64,
+    /// Number of malformed queries that were dropped.
+    pub bad_queries_dropped: AtomicU64,
+    /// Number of packets dropped by [`check_header`] before decoding them.
+    ///
+    /// [`check_header`]: crate::frontend::check_header
+    pub malformed: AtomicU64,
+    pub upstream_times: UpstreamTimes,
+}
+
+/// Per-listener query metrics keyed by protocol and listener name.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    entries: RwLock<Vec<Arc<Listener>>>,
+}
+
+impl Listeners {
+    /// Returns the metrics of the listener `name` serving `protocol`,
+    /// registering it if it was not seen before.
+    pub fn register(&self, protocol: &'static str, name: &str) -> Arc<Listener> {
+        let find = |entries: &[Arc<Listener>]| {
+            entries
+                .iter()
+                .find(|entry| entry.protocol == protocol && entry.name == name)
+                .cloned()
+        };
+
+        if let Some(entry) = find(&self.entries.read()) {
+            return entry;
+        }
+
+        let mut entries = self.entries.write();
+        // Workers of the same listener may register concurrently.
+        if let Some(entry) = find(&entries) {
+            return entry;
+        }
+
+        let entry = Arc::new(Listener {
+            protocol,
+            name: name.to_owned(),
+            queries: AtomicU64::new(0),
+            response_times: Histogram::default(),
+            restarts: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+        });
+        entries.push(entry.clone());
+        entry
+    }
+
+    /// Returns all listeners in the order they were registered.
+    pub fn entries(&self) -> Vec<Arc<Listener>> {
+        self.entries.read().clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct Listener {
+    pub protocol: &'static str,
+    /// The configured name of the listener or its address.
+    pub name: String,
+    /// Number of well-formed queries received.
+    pub queries: AtomicU64,
+    /// Time from receiving a query until its response is ready.
+    pub response_times: Histogram,
+    /// Number of times a task serving the listener failed and was restarted.
+    pub restarts: AtomicU64,
+    /// Number of failed tasks of the listener waiting to be restarted.
+    pub down: AtomicU64,
+}
+
+/// A stable identifier of an upstream resolver.
+///
+/// The same upstream address always maps to the same `ResolverId`, even if the zones are
+/// regenerated from a new config.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResolverId(u32);
+
+/// Per-upstream resolve times keyed by [`ResolverId`].
+#[derive(Debug, Default)]
+pub struct UpstreamTimes {
+    ids: RwLock<HashMap<String, ResolverId>>,
+    entries: RwLock<HashMap<ResolverId, Arc<UpstreamTime>>>,
+}
+
+impl UpstreamTimes {
+    /// Returns the [`ResolverId`] for the upstream at `addr`, registering it if it was not
+    /// seen before.
+    pub fn register(&self, addr: &str) -> ResolverId {
+        if let Some(id) = self.ids.read().get(addr) {
+            return *id;
+        }
+
+        let mut ids = self.ids.write();
+        // Another thread may have registered the same upstream while we
+        // were waiting for the write lock.
+        if let Some(id) = ids.get(addr) {
+            return *id;
+        }
+
+        let id = ResolverId(ids.len() as u32);
+        ids.insert(addr.to_owned(), id);
+        self.entries.write().insert(
+            id,
+            Arc::new(UpstreamTime {
+                addr: addr.to_owned(),
+                histogram: Histogram::default(),
+                mismatched: AtomicU64::new(0),
+                overloaded: AtomicU64::new(0),
+                rate_limited: AtomicU64::new(0),
+                timeouts: AtomicU64::new(0),
+                failures: AtomicU64::new(0),
+                http_statuses: Default::default(),
+                http_errors: Default::default(),
+            }),
+        );
+        id
+    }
+
+    pub fn get(&self, id: ResolverId) -> Option<Arc<UpstreamTime>> {
+        self.entries.read().get(&id).cloned()
+    }
+
+    /// Returns all registered upstreams ordered by their [`ResolverId`].
+    pub fn entries(&self) -> Vec<(ResolverId, Arc<UpstreamTime>)> {
+        let mut entries: Vec<_> = self
+            .entries
+            .read()
+            .iter()
+            .map(|(id, entry)| (*id, entry.clone()))
+            .collect();
+        entries.sort_by_key(|(id, _)| *id);
+        entries
+    }
+}
+
+#[derive(Debug)]
+pub struct UpstreamTime {
+    pub addr: String,
+    /// Time until the upstream answered a question, including retries.
+    pub histogram: Histogram,
+    /// Number of responses discarded because they did not match the query,
+    /// e.g. spoofing attempts.
+    pub mismatched: AtomicU64,
+    /// Number of queries failed over because too many were in flight.
+    pub overloaded: AtomicU64,
+    /// Number of queries failed over because they exceeded the rate limit.
+    pub rate_limited: AtomicU64,
+    /// Number of questions the upstream didn't answer in time.
+    pub timeouts: AtomicU64,
+    /// Number of questions the upstream failed to answer otherwise, e.g.
+    /// with SERVFAIL or because it was unreachable.
+    pub failures: AtomicU64,
+    /// Number of responses of DoH upstreams by status class, from 1xx to
+    /// 5xx.
+    pub http_statuses: [AtomicU64; 5],
+    /// Number of failed requests to DoH upstreams by [`HttpErrorKind`].
+    pub http_errors: [AtomicU64; HttpErrorKind::ALL.len()],
+}
+
+impl UpstreamTime {
+    /// Counts a response of a DoH upstream with `status`.
+    pub fn http_status(&self, status: u16) {
+        let class = usize::from(status / 100).clamp(1, 5) - 1;
+        self.http_statuses[class].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a failed request to a DoH upstream.
+    pub fn http_error(&self, kind: HttpErrorKind) {
+        self.http_errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Why a request to a DoH upstream failed, other than timing out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HttpErrorKind {
+    /// The TLS handshake failed, e.g. because of an invalid certificate.
+    Tls,
+    /// The connection could not be established.
+    Connect,
+    /// The connection failed while receiving the response.
+    Body,
+    Other,
+}
+
+impl HttpErrorKind {
+    pub const ALL: [Self; 4] = [Self::Tls, Self::Connect, Self::Body, Self::Other];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Tls => "tls",
+            Self::Connect => "connect",
+            Self::Body => "body",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Upper bounds of the [`Histogram`] buckets in milliseconds.
+pub const HISTOGRAM_BUCKETS: [u64; 10] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 5000];
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let millis = duration.as_millis() as u64;
+        if let Some(index) = HISTOGRAM_BUCKETS.iter().position(|le| millis <= *le) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the cumulative count of observations for every bucket in [`HISTOGRAM_BUCKETS`].
+    pub fn buckets(&self) -> [u64; HISTOGRAM_BUCKETS.len()] {
+        let mut buckets = [0; HISTOGRAM_BUCKETS.len()];
+        let mut total = 0;
+        for (bucket, count) in buckets.iter_mut().zip(&self.buckets) {
+            total += count.load(Ordering::Relaxed);
+            *bucket = total;
+        }
+        buckets
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Listeners, UpstreamTimes};
+
+    #[test]
+    fn upstream_times_register_stable() {
+        let times = UpstreamTimes::default();
+        let a = times.register("1.1.1.1:53");
+        let b = times.register("8.8.8.8:53");
+        assert_ne!(a, b);
+        assert_eq!(times.register("1.1.1.1:53"), a);
+
+        times
+            .get(a)
+            .unwrap()
+            .histogram
+            .observe(Duration::from_millis(3));
+        assert_eq!(times.get(a).unwrap().histogram.count(), 1);
+        assert_eq!(times.get(b).unwrap().histogram.count(), 0);
+    }
+
+    #[test]
+    fn listeners_register_stable() {
+        let listeners = Listeners::default();
+        let udp = listeners.register("udp", "127.0.0.1:53");
+        let tcp = listeners.register("tcp", "127.0.0.1:53");
+        assert!(!Arc::ptr_eq(&udp, &tcp));
+        assert!(Arc::ptr_eq(
+            &listeners.register("udp", "127.0.0.1:53"),
+            &udp
+        ));
+        assert_eq!(listeners.entries().len(), 2);
+    }
 }
@@ -0,0 +1,129 @@
+//! Dropping of root privileges once the listeners are bound.
+
+use std::ffi::CString;
+use std::io;
+use std::ptr;
+
+/// The user and group the process switches to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl Identity {
+    /// Looks up the configured `user` and `group`.
+    ///
+    /// The group defaults to the primary group of the user. Returns `None`
+    /// if neither is configured.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> io::Result<Option<Self>> {
+        let user = user.map(lookup_user).transpose()?;
+        let gid = match group {
+            Some(group) => Some(lookup_group(group)?),
+            None => user.map(|(_, gid)| gid),
+        };
+
+        Ok(match (user, gid) {
+            (Some((uid, _)), Some(gid)) => Some(Self { uid, gid }),
+            // Only the group changes.
+            (None, Some(gid)) => Some(Self {
+                uid: unsafe { libc::getuid() },
+                gid,
+            }),
+            (_, None) => None,
+        })
+    }
+}
+
+/// Switches the process to `identity` and clears the supplementary groups.
+///
+/// This applies to all threads of the process.
+pub fn drop_to(identity: Identity) -> io::Result<()> {
+    // The groups must be changed first, setuid takes away the permission
+    // to do so.
+    if unsafe { libc::setgroups(0, ptr::null()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setgid(identity.gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setuid(identity.uid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Regaining root must not be possible.
+    if identity.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::other("root privileges could be regained"));
+    }
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(name).map_err(io::Error::other)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let mut buf = vec![0; 4096];
+    let res = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user {}", name.to_string_lossy()),
+        ));
+    }
+
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let name = CString::new(name).map_err(io::Error::other)?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let mut buf = vec![0; 4096];
+    let res = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group {}", name.to_string_lossy()),
+        ));
+    }
+
+    Ok(group.gr_gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Identity;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(Identity::lookup(None, None).unwrap(), None);
+
+        let root = Identity::lookup(Some("root"), None).unwrap().unwrap();
+        assert_eq!(root, Identity { uid: 0, gid: 0 });
+
+        assert!(Identity::lookup(Some("rdns-does-not-exist"), None).is_err());
+        assert!(Identity::lookup(None, Some("rdns-does-not-exist")).is_err());
+    }
+}
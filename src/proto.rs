@@ -1,5 +1,6 @@
-use std::fmt::{self, Debug, Formatter};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 use bytes::{Buf, BufMut, Bytes};
 
@@ -24,9 +25,18 @@ pub enum OpCode {
     Query,
     InverseQuery,
     Status,
+    // RFC 1996
+    Notify,
+    // RFC 2136
+    Update,
+    // RFC 8490
+    Dso,
 }
 
 impl Header {
+    /// Size of the encoded header in bytes.
+    pub const SIZE: usize = 12;
+
     pub fn qr(&self) -> Qr {
         match self.flags >> 15 {
             0 => Qr::Request,
@@ -35,14 +45,10 @@ impl Header {
         }
     }
 
-    pub fn opcode(&self) -> OpCode {
-        match (self.flags & 0b0111_1000_0000_0000) >> 11 {
-            0 => OpCode::Query,
-            1 => OpCode::InverseQuery,
-            2 => OpCode::Status,
-            //Reserved
-            _ => todo!(),
-        }
+    /// Returns `None` if the opcode is unassigned.
+    pub fn opcode(&self) -> Option<OpCode> {
+        let tag = (self.flags & 0b0111_1000_0000_0000) >> 11;
+        OpCode::from_u16(tag)
     }
 
     pub fn aa(&self) -> bool {
@@ -77,43 +83,22 @@ impl Header {
         }
     }
 
-    pub fn rcode(&self) -> ResponseCode {
-        let tag = self.flags & 0b0000_0000_0000_1111;
-        ResponseCode::from_u16(tag).unwrap()
+    pub fn ad(&self) -> bool {
+        self.flags & 0b0000_0000_0010_0000 != 0
     }
 
-    pub fn encode<B>(&self, mut buf: B)
-    where
-        B: BufMut,
-    {
-        buf.put_u16(self.transaction_id);
-        buf.put_u16(self.flags);
-        buf.put_u16(self.qdcount);
-        buf.put_u16(self.ancount);
-        buf.put_u16(self.nscount);
-        buf.put_u16(self.arcount);
+    pub fn cd(&self) -> bool {
+        self.flags & 0b0000_0000_0001_0000 != 0
     }
 
-    pub fn decode<B>(mut buf: B) -> Result<Self, ()>
-    where
-        B: Buf,
-    {
-        if buf.remaining() < 12 {
-            return Err(());
-        }
-
-        Ok(Self {
-            transaction_id: buf.get_u16(),
-            flags: buf.get_u16(),
-            qdcount: buf.get_u16(),
-            ancount: buf.get_u16(),
-            nscount: buf.get_u16(),
-            arcount: buf.get_u16(),
-        })
+    /// Returns `None` if the response code is unassigned.
+    pub fn rcode(&self) -> Option<ResponseCode> {
+        let tag = self.flags & 0b0000_0000_0000_1111;
+        ResponseCode::from_u16(tag)
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Packet {
     pub transaction_id: u16,
     pub qr: Qr,
@@ -122,20 +107,41 @@ pub struct Packet {
     pub truncated: bool,
     pub recursion_desired: bool,
     pub recursion_available: bool,
+    /// All data in the response was validated using DNSSEC.
+    ///
+    /// See https://datatracker.ietf.org/doc/html/rfc4035#section-3.2.3
+    pub authentic_data: bool,
+    /// The client does not want the resolver to validate the response.
+    pub checking_disabled: bool,
     pub response_code: ResponseCode,
     pub questions: Vec<Question>,
+    /// The question section as received.
+    ///
+    /// If set it is encoded instead of `questions`, so that the response echoes
+    /// the question byte for byte, including the case of names. It must
+    /// contain exactly the questions in `questions`.
+    pub raw_questions: Option<Bytes>,
     pub answers: Vec<ResourceRecord>,
     pub authority: Vec<ResourceRecord>,
+    /// The additional section without the OPT pseudo-record.
     pub additional: Vec<ResourceRecord>,
+    /// The OPT pseudo-record, if present.
+    pub edns: Option<Edns>,
 }
 
 impl Packet {
-    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
-        let mut reader = Reader::new(buf);
+    /// Decodes a `Packet` from `buf`.
+    ///
+    /// Opaque record data is not copied but references `buf` using [`Bytes::slice`].
+    pub fn decode(buf: Bytes) -> Result<Self, DecodeError> {
+        Self::decode_query_head(buf)?.into_packet()
+    }
 
-        if buf.remaining() < 12 {
-            return Err(DecodeError::Eof);
-        }
+    /// Decodes only the header and question section of a `Packet` from `buf`.
+    ///
+    /// The remaining sections can be decoded later using [`QueryHead::into_packet`].
+    pub fn decode_query_head(buf: Bytes) -> Result<QueryHead, DecodeError> {
+        let mut reader = Reader::new(&buf);
 
         let transaction_id = reader.read_u16().ok_or(DecodeError::Eof)?;
         let flags = reader.read_u16().ok_or(DecodeError::Eof)?;
@@ -144,82 +150,54 @@ impl Packet {
         let nscount = reader.read_u16().ok_or(DecodeError::Eof)?;
         let arcount = reader.read_u16().ok_or(DecodeError::Eof)?;
 
-        let qr = match flags >> 15 {
-            0 => Qr::Request,
-            1 => Qr::Response,
-            _ => unreachable!(),
-        };
-
-        let opcode = match (flags & 0b0111_1000_0000_0000) >> 11 {
-            0 => OpCode::Query,
-            1 => OpCode::InverseQuery,
-            2 => OpCode::Status,
-            _ => return Err(DecodeError::InvalidOpCode),
-        };
-
-        let aa = match (flags & 0b0000_0100_0000_0000) >> 10 {
-            0 => false,
-            1 => true,
-            _ => unreachable!(),
-        };
-
-        let tc = match (flags & 0b0000_0010_0000_0000) >> 9 {
-            0 => false,
-            1 => true,
-            _ => unreachable!(),
-        };
-
-        let rd = match (flags & 0b0000_0001_0000_0000) >> 8 {
-            0 => false,
-            1 => true,
-            _ => unreachable!(),
-        };
-
-        let ra = match (flags & 0b0000_0000_1000_0000) >> 7 {
-            0 => false,
-            1 => true,
-            _ => unreachable!(),
+        let header = Header {
+            transaction_id,
+            flags,
+            qdcount,
+            ancount,
+            nscount,
+            arcount,
         };
 
-        let rcode = ResponseCode::from_u16(flags & 0b0000_0000_0000_1111)
-            .ok_or(DecodeError::InvalidResponseCode)?;
+        // Reject invalid flags early, before any work is spent on the
+        // question.
+        let opcode = header.opcode().ok_or(DecodeError::InvalidOpCode)?;
+        let response_code = header.rcode().ok_or(DecodeError::InvalidResponseCode)?;
 
         let mut questions = Vec::new();
         for _ in 0..qdcount {
             questions.push(Question::decode(&mut reader)?);
         }
 
-        let mut answers = Vec::new();
-        for _ in 0..ancount {
-            answers.push(ResourceRecord::decode(&mut reader)?);
-        }
-
-        let mut authority = Vec::new();
-        for _ in 0..nscount {
-            authority.push(ResourceRecord::decode(&mut reader)?);
-        }
-
-        let mut additional = Vec::new();
-        for _ in 0..arcount {
-            additional.push(ResourceRecord::decode(&mut reader)?);
-        }
-
-        Ok(Self {
-            transaction_id,
-            qr,
+        let cursor = reader.cursor;
+        Ok(QueryHead {
+            header,
             opcode,
-            authoritative_answer: aa,
-            truncated: tc,
-            recursion_desired: rd,
-            recursion_available: ra,
-            response_code: rcode,
+            response_code,
             questions,
-            answers,
-            additional,
-            authority,
+            buf,
+            cursor,
         })
     }
 
+    /// Returns the number of bytes written by [`encode`].
+    ///
+    /// [`encode`]: Self::encode
+    pub fn encoded_len(&self) -> usize {
+        let questions = match &self.raw_questions {
+            Some(raw) => raw.len(),
+            None => self.questions.iter().map(Question::encoded_len).sum(),
+        };
+
+        let records: usize = [&self.answers, &self.authority, &self.additional]
+            .into_iter()
+            .flatten()
+            .map(ResourceRecord::encoded_len)
+            .sum();
+
+        Header::SIZE + questions + records + self.edns.as_ref().map_or(0, Edns::encoded_len)
+    }
+
     pub fn encode<B>(&self, mut buf: B)
     where
         B: BufMut,
@@ -229,11 +207,7 @@ impl Packet {
             Qr::Request => 0,
             Qr::Response => 1 << 15,
         };
-        flags |= match self.opcode {
-            OpCode::Query => 0,
-            OpCode::InverseQuery => 1 << 11,
-            OpCode::Status => 2 << 11,
-        };
+        flags |= self.opcode.to_u16() << 11;
         flags |= match self.authoritative_answer {
             false => 0,
             true => 1 << 10,
@@ -250,6 +224,14 @@ impl Packet {
             false => 0,
             true => 1 << 7,
         };
+        flags |= match self.authentic_data {
+            false => 0,
+            true => 1 << 5,
+        };
+        flags |= match self.checking_disabled {
+            false => 0,
+            true => 1 << 4,
+        };
         flags |= self.response_code.to_u16();
 
         buf.put_u16(self.transaction_id);
@@ -257,10 +239,15 @@ impl Packet {
         buf.put_u16(self.questions.len() as u16);
         buf.put_u16(self.answers.len() as u16);
         buf.put_u16(self.authority.len() as u16);
-        buf.put_u16(self.additional.len() as u16);
+        buf.put_u16(self.additional.len() as u16 + u16::from(self.edns.is_some()));
 
-        for question in &self.questions {
-            question.encode(&mut buf);
+        match &self.raw_questions {
+            Some(raw) => buf.put_slice(raw),
+            None => {
+                for question in &self.questions {
+                    question.encode(&mut buf);
+                }
+            }
         }
 
         for resource in &self.answers {
@@ -274,6 +261,399 @@ impl Packet {
         for resource in &self.additional {
             resource.encode(&mut buf);
         }
+
+        if let Some(edns) = &self.edns {
+            edns.encode(&mut buf);
+        }
+    }
+}
+
+/// The header and question section of a [`Packet`].
+///
+/// Returned by [`Packet::decode_query_head`].
+#[derive(Clone, Debug)]
+pub struct QueryHead {
+    pub header: Header,
+    /// The opcode of the header, which is always valid.
+    pub opcode: OpCode,
+    response_code: ResponseCode,
+    pub questions: Vec<Question>,
+    buf: Bytes,
+    cursor: usize,
+}
+
+impl QueryHead {
+    /// Decodes the TLVs of a DSO message.
+    ///
+    /// See https://datatracker.ietf.org/doc/html/rfc8490#section-5.4
+    pub fn decode_dso(&self) -> Result<Vec<DsoTlv>, DecodeError> {
+        let header = &self.header;
+        if header.qdcount != 0 || header.ancount != 0 || header.nscount != 0 || header.arcount != 0
+        {
+            return Err(DecodeError::InvalidDso);
+        }
+
+        let mut reader = Reader::new(&self.buf);
+        reader.advance(self.cursor);
+
+        let mut tlvs = Vec::new();
+        while reader.cursor < self.buf.len() {
+            let r#type = reader.read_u16().ok_or(DecodeError::Eof)?;
+            let len = reader.read_u16().ok_or(DecodeError::Eof)?;
+            let data = reader
+                .read_bytes(usize::from(len))
+                .ok_or(DecodeError::Eof)?;
+            tlvs.push(DsoTlv { r#type, data });
+        }
+
+        Ok(tlvs)
+    }
+
+    /// Decodes only the OPT record.
+    ///
+    /// Other records are skipped, queries usually have none. Returns `None`
+    /// if there is no OPT record.
+    pub fn edns(&self) -> Result<Option<Edns>, DecodeError> {
+        let mut reader = Reader::new(&self.buf);
+        reader.advance(self.cursor);
+
+        for _ in 0..u32::from(self.header.ancount) + u32::from(self.header.nscount) {
+            ResourceRecord::decode(&mut reader)?;
+        }
+
+        for _ in 0..self.header.arcount {
+            if let Some(opt) = Edns::decode(&mut reader)? {
+                return Ok(Some(opt));
+            }
+
+            ResourceRecord::decode(&mut reader)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the query without any records besides the `edns` OPT record,
+    /// e.g. to respond to it without decoding the remaining sections.
+    pub fn into_query(self, edns: Option<Edns>) -> Packet {
+        Packet {
+            transaction_id: self.header.transaction_id,
+            qr: self.header.qr(),
+            opcode: self.opcode,
+            authoritative_answer: self.header.aa(),
+            truncated: self.header.tc(),
+            recursion_desired: self.header.rd(),
+            recursion_available: self.header.ra(),
+            authentic_data: self.header.ad(),
+            checking_disabled: self.header.cd(),
+            response_code: self.response_code,
+            questions: self.questions,
+            // Compression pointers in the question section stay valid as
+            // the section always starts right after the fixed size header.
+            raw_questions: Some(self.buf.slice(Header::SIZE..self.cursor)),
+            answers: Vec::new(),
+            additional: Vec::new(),
+            authority: Vec::new(),
+            edns,
+        }
+    }
+
+    /// Decodes the remaining sections and returns the full [`Packet`].
+    pub fn into_packet(self) -> Result<Packet, DecodeError> {
+        let mut reader = Reader::new(&self.buf);
+        reader.advance(self.cursor);
+
+        let mut answers = Vec::new();
+        for _ in 0..self.header.ancount {
+            answers.push(ResourceRecord::decode(&mut reader)?);
+        }
+
+        let mut authority = Vec::new();
+        for _ in 0..self.header.nscount {
+            authority.push(ResourceRecord::decode(&mut reader)?);
+        }
+
+        let mut additional = Vec::new();
+        let mut edns = None;
+        for _ in 0..self.header.arcount {
+            if let Some(opt) = Edns::decode(&mut reader)? {
+                edns = Some(opt);
+                continue;
+            }
+
+            additional.push(ResourceRecord::decode(&mut reader)?);
+        }
+
+        Ok(Packet {
+            answers,
+            additional,
+            authority,
+            ..self.into_query(edns)
+        })
+    }
+}
+
+/// The OPT pseudo-record.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc6891#section-6.1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    /// The upper 8 bits of the extended response code.
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The client is able to handle DNSSEC records.
+    ///
+    /// See https://datatracker.ietf.org/doc/html/rfc3225
+    pub dnssec_ok: bool,
+    /// The undecoded EDNS options.
+    pub options: Bytes,
+}
+
+impl Edns {
+    /// The upper bits of the BADVERS extended response code (16).
+    ///
+    /// See https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.3
+    pub const BADVERS: u8 = 1;
+
+    /// Returns the code and data of every option.
+    ///
+    /// Iteration stops at the first option that is truncated.
+    pub fn iter_options(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        let mut rest = &self.options[..];
+        std::iter::from_fn(move || {
+            let code = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+            let len = usize::from(u16::from_be_bytes([*rest.get(2)?, *rest.get(3)?]));
+            let data = rest.get(4..4 + len)?;
+            rest = &rest[4 + len..];
+            Some((code, data))
+        })
+    }
+
+    /// Encodes an option with its code and length, as it appears in
+    /// [`Edns::options`].
+    pub fn encode_option(buf: &mut Vec<u8>, code: u16, data: &[u8]) {
+        buf.put_u16(code);
+        buf.put_u16(data.len() as u16);
+        buf.put_slice(data);
+    }
+
+    /// Decodes the next record from `reader` if it is an OPT record.
+    ///
+    /// Returns `None` without advancing `reader` if the record is of any other type.
+    fn decode(reader: &mut Reader<'_>) -> Result<Option<Self>, DecodeError> {
+        let start = reader.cursor;
+        Fqdn::decode(reader)?;
+        if reader.read_u16().ok_or(DecodeError::Eof)? != Type::OPT.to_u16() {
+            reader.cursor = start;
+            return Ok(None);
+        }
+
+        let udp_payload_size = reader.read_u16().ok_or(DecodeError::Eof)?;
+        let ttl = reader.read_u32().ok_or(DecodeError::Eof)?;
+        let rdlength = reader.read_u16().ok_or(DecodeError::Eof)?;
+        let options = reader
+            .read_bytes(usize::from(rdlength))
+            .ok_or(DecodeError::Eof)?;
+
+        Ok(Some(Self {
+            udp_payload_size,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & (1 << 15) != 0,
+            options,
+        }))
+    }
+
+    fn encode<B>(&self, mut buf: B)
+    where
+        B: BufMut,
+    {
+        let mut ttl = u32::from(self.extended_rcode) << 24 | u32::from(self.version) << 16;
+        if self.dnssec_ok {
+            ttl |= 1 << 15;
+        }
+
+        // The owner name is always the root.
+        buf.put_u8(0);
+        buf.put_u16(Type::OPT.to_u16());
+        buf.put_u16(self.udp_payload_size);
+        buf.put_u32(ttl);
+        buf.put_u16(self.options.len() as u16);
+        buf.put_slice(&self.options);
+    }
+
+    fn encoded_len(&self) -> usize {
+        11 + self.options.len()
+    }
+}
+
+/// A TLV of a DSO message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DsoTlv {
+    pub r#type: u16,
+    pub data: Bytes,
+}
+
+// Not every TLV type is handled yet.
+#[allow(dead_code)]
+impl DsoTlv {
+    pub const KEEPALIVE: u16 = 1;
+    pub const RETRY_DELAY: u16 = 2;
+    pub const ENCRYPTION_PADDING: u16 = 3;
+}
+
+/// An Extended DNS Error carried in an EDNS option.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc8914
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedError {
+    pub info_code: u16,
+    pub extra_text: String,
+}
+
+impl ExtendedError {
+    /// The EDNS option code of Extended DNS Errors.
+    pub const OPTION_CODE: u16 = 15;
+
+    pub const OTHER: u16 = 0;
+    #[allow(dead_code)]
+    pub const PROHIBITED: u16 = 18;
+
+    /// Encodes the option, including its code and length, as it appears in [`Edns::options`].
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u16(Self::OPTION_CODE);
+        buf.put_u16(2 + self.extra_text.len() as u16);
+        buf.put_u16(self.info_code);
+        buf.put_slice(self.extra_text.as_bytes());
+    }
+}
+
+/// An EDNS Client Subnet option.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc7871
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClientSubnet {
+    /// The address with all bits beyond `source_prefix` cleared.
+    pub addr: IpAddr,
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// The EDNS option code of EDNS Client Subnet.
+    pub const OPTION_CODE: u16 = 8;
+
+    /// Returns the subnet of `addr` with the given `prefix` length.
+    pub fn new(addr: IpAddr, prefix: u8) -> Self {
+        let (addr, prefix) = match addr {
+            IpAddr::V4(addr) => {
+                let prefix = prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                (IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask)), prefix)
+            }
+            IpAddr::V6(addr) => {
+                let prefix = prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                (IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask)), prefix)
+            }
+        };
+
+        Self {
+            addr,
+            source_prefix: prefix,
+            scope_prefix: 0,
+        }
+    }
+
+    /// Decodes the data of the option.
+    ///
+    /// Returns `None` for unknown families and addresses that don't match
+    /// the source prefix.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let family = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+        let source_prefix = *data.get(2)?;
+        let scope_prefix = *data.get(3)?;
+        let addr = &data[4..];
+        if addr.len() != usize::from(source_prefix).div_ceil(8) {
+            return None;
+        }
+
+        let addr = match family {
+            1 if source_prefix <= 32 => {
+                let mut octets = [0; 4];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::from(octets)
+            }
+            2 if source_prefix <= 128 => {
+                let mut octets = [0; 16];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::from(octets)
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            scope_prefix,
+            ..Self::new(addr, source_prefix)
+        })
+    }
+
+    /// Returns the subnet shortened to at most `prefix` bits.
+    pub fn truncate(self, prefix: u8) -> Self {
+        Self {
+            scope_prefix: self.scope_prefix.min(prefix),
+            ..Self::new(self.addr, self.source_prefix.min(prefix))
+        }
+    }
+
+    /// Encodes the option, including its code and length, as it appears in [`Edns::options`].
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(addr) => (1, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2, addr.octets().to_vec()),
+        };
+        let len = usize::from(self.source_prefix).div_ceil(8);
+
+        buf.put_u16(Self::OPTION_CODE);
+        buf.put_u16(4 + len as u16);
+        buf.put_u16(family);
+        buf.put_u8(self.source_prefix);
+        buf.put_u8(self.scope_prefix);
+        buf.put_slice(&octets[..len]);
+    }
+}
+
+/// An edns-tcp-keepalive option.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc7828
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Idle timeout of the connection in units of 100 milliseconds. Only
+    /// sent by servers.
+    pub timeout: Option<u16>,
+}
+
+impl TcpKeepalive {
+    /// The EDNS option code of edns-tcp-keepalive.
+    pub const OPTION_CODE: u16 = 11;
+
+    /// Decodes the data of the option.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        match *data {
+            [] => Some(Self { timeout: None }),
+            [high, low] => Some(Self {
+                timeout: Some(u16::from_be_bytes([high, low])),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encodes the option, including its code and length, as it appears in [`Edns::options`].
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self.timeout {
+            Some(timeout) => Edns::encode_option(buf, Self::OPTION_CODE, &timeout.to_be_bytes()),
+            None => Edns::encode_option(buf, Self::OPTION_CODE, &[]),
+        }
     }
 }
 
@@ -309,12 +689,48 @@ impl Question {
         buf.put_u16(self.qtype.to_u16());
         buf.put_u16(self.qclass.to_u16());
     }
+
+    pub fn encoded_len(&self) -> usize {
+        usize::from(self.name.len()) + 4
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Fqdn(pub Vec<u8>);
 
 impl Fqdn {
+    /// Maximum length of a name in wire format.
+    const MAX_LEN: usize = 255;
+    /// Maximum length of a single label.
+    const MAX_LABEL_LEN: usize = 63;
+
+    /// Creates a name from its dotted form, e.g. `example.com.`.
+    ///
+    /// The name must be absolute and fit into a message.
+    pub fn new(fqdn: String) -> Result<Self, InvalidFqdn> {
+        if fqdn == "." {
+            return Ok(Self(fqdn.into_bytes()));
+        }
+
+        let Some(relative) = fqdn.strip_suffix('.') else {
+            return Err(InvalidFqdn);
+        };
+        if relative
+            .split('.')
+            .any(|label| label.is_empty() || label.len() > Self::MAX_LABEL_LEN)
+        {
+            return Err(InvalidFqdn);
+        }
+
+        // The length octet of the first label and the root label add one
+        // byte each to the dotted form without the leading label.
+        if fqdn.len() + 1 > Self::MAX_LEN {
+            return Err(InvalidFqdn);
+        }
+
+        Ok(Self(fqdn.into_bytes()))
+    }
+
     pub fn new_unchecked(fqdn: String) -> Self {
         Self(fqdn.into_bytes())
     }
@@ -322,6 +738,42 @@ impl Fqdn {
     pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+
+    /// Returns the labels of the name, without the empty root label.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        self.as_bytes()
+            .split(|b| *b == b'.')
+            .filter(|label| !label.is_empty())
+    }
+
+    /// Returns the name itself, followed by all of its parents up to the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = &[u8]> {
+        std::iter::successors(Some(self.as_bytes()), |name| parent(name))
+    }
+
+    /// Returns `true` if the name is equal to or below `other`.
+    ///
+    /// Labels are compared case-insensitively.
+    pub fn is_subdomain_of(&self, other: &Fqdn) -> bool {
+        let mut labels = self.labels().rev();
+        other.labels().rev().all(|label| {
+            labels
+                .next()
+                .is_some_and(|own| own.eq_ignore_ascii_case(label))
+        })
+    }
+}
+
+/// Returns the name `name` with the leftmost label removed, or `None` for the root.
+fn parent(name: &[u8]) -> Option<&[u8]> {
+    if name.is_empty() || name == b"." {
+        return None;
+    }
+
+    match memchr::memchr(b'.', name) {
+        Some(index) if index + 1 < name.len() => Some(&name[index + 1..]),
+        _ => Some(b"."),
+    }
 }
 
 impl Fqdn {
@@ -388,6 +840,11 @@ impl Fqdn {
             }
         }
 
+        // The root is written as a single dot.
+        if labels.is_empty() {
+            labels.push(b'.');
+        }
+
         Ok((Self(labels), advance_count))
     }
 }
@@ -397,11 +854,7 @@ impl Encode for Fqdn {
     where
         B: BufMut,
     {
-        for label in self.as_bytes().split(|b| *b == b'.') {
-            if label.is_empty() {
-                continue;
-            }
-
+        for label in self.labels() {
             buf.put_u8(label.len() as u8);
             buf.put_slice(label);
         }
@@ -410,7 +863,10 @@ impl Encode for Fqdn {
     }
 
     fn len(&self) -> u16 {
-        self.0.len() as u16 + 1
+        self.labels()
+            .map(|label| label.len() as u16 + 1)
+            .sum::<u16>()
+            + 1
     }
 }
 
@@ -434,46 +890,91 @@ impl Debug for Fqdn {
     }
 }
 
-#[derive(Clone, Debug)]
+impl Display for Fqdn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+// Variants are named after their record types.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum RecordData {
     A(Ipv4Addr),
     NS(Fqdn),
     CNAME(Fqdn),
     SOA(SoaData),
     PTR(Fqdn),
+    HINFO(HinfoData),
     MX(MxData),
-    TXT(String),
+    TXT(Vec<String>),
     AAAA(Ipv6Addr),
+    LOC(LocData),
+    DNAME(Fqdn),
+    SSHFP(SshfpData),
+    /// SVCB and HTTPS records, which share the same format.
+    SVCB(SvcbData),
     Other(Type, Bytes),
 }
 
 impl RecordData {
     fn decode(len: u16, typ: Type, reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        // Records without any data are used by UPDATE messages to
+        // delete whole RRsets.
+        if len == 0 {
+            return Ok(Self::Other(typ, Bytes::new()));
+        }
+
+        let start = reader.cursor;
         let res = match typ {
             Type::A => Ok(Self::A(Ipv4Addr::decode(reader)?)),
             Type::NS => Ok(Self::NS(Fqdn::decode(reader)?)),
             Type::CNAME => Ok(Self::CNAME(Fqdn::decode(reader)?)),
             Type::SOA => Ok(Self::SOA(SoaData::decode(reader)?)),
             Type::PTR => Ok(Self::PTR(Fqdn::decode(reader)?)),
+            Type::HINFO => Ok(Self::HINFO(HinfoData::decode(reader)?)),
             Type::MX => Ok(Self::MX(MxData::decode(reader)?)),
             Type::TXT => {
-                let buf = reader
-                    .remaining_buffer()
-                    .get(usize::from(len)..)
+                let mut buf = reader
+                    .read_bytes(usize::from(len))
                     .ok_or(DecodeError::Eof)?;
-                let txt = String::from_utf8(buf.to_vec()).map_err(|_| DecodeError::InvalidUtf8)?;
+
+                // TXT-DATA is one or more <character-string>s.
+                // See https://datatracker.ietf.org/doc/html/rfc1035#section-3.3.14
+                let mut txt = Vec::new();
+                while !buf.is_empty() {
+                    let len = usize::from(buf.get_u8());
+                    if buf.len() < len {
+                        return Err(DecodeError::Eof);
+                    }
+
+                    let string = buf.split_to(len);
+                    let string =
+                        std::str::from_utf8(&string).map_err(|_| DecodeError::InvalidUtf8)?;
+                    txt.push(string.to_owned());
+                }
+
                 Ok(Self::TXT(txt))
             }
             Type::AAAA => Ok(Self::AAAA(Ipv6Addr::decode(reader)?)),
+            Type::LOC => Ok(Self::LOC(LocData::decode(reader)?)),
+            Type::DNAME => Ok(Self::DNAME(Fqdn::decode(reader)?)),
+            Type::SSHFP => Ok(Self::SSHFP(SshfpData::decode(len, reader)?)),
+            Type::SVCB | Type::HTTPS => Ok(Self::SVCB(SvcbData::decode(len, reader)?)),
             _ => {
                 let bytes = reader
-                    .remaining_buffer()
-                    .get(usize::from(len)..)
+                    .read_bytes(usize::from(len))
                     .ok_or(DecodeError::Eof)?;
-                Ok(Self::Other(typ, Bytes::from(bytes.to_vec())))
+                Ok(Self::Other(typ, bytes))
             }
         };
 
+        // Fixed size and name based record data is decoded without
+        // looking at RDLENGTH, so it must be checked afterwards.
+        if res.is_ok() && reader.cursor - start != usize::from(len) {
+            return Err(DecodeError::InvalidRdLength);
+        }
+
         res
     }
 
@@ -487,13 +988,21 @@ impl RecordData {
             Self::CNAME(data) => data.encode(buf),
             Self::SOA(data) => data.encode(buf),
             Self::PTR(data) => data.encode(buf),
+            Self::HINFO(data) => data.encode(buf),
             Self::MX(data) => data.encode(buf),
             Self::TXT(data) => {
-                buf.put_slice(data.as_bytes());
+                for chunk in txt_chunks(data) {
+                    buf.put_u8(chunk.len() as u8);
+                    buf.put_slice(chunk);
+                }
             }
             Self::AAAA(data) => data.encode(buf),
+            Self::LOC(data) => data.encode(buf),
+            Self::DNAME(data) => data.encode(buf),
+            Self::SSHFP(data) => data.encode(buf),
+            Self::SVCB(data) => data.encode(buf),
             Self::Other(_, data) => {
-                buf.put_slice(&data);
+                buf.put_slice(data);
             }
         }
     }
@@ -505,14 +1014,71 @@ impl RecordData {
             Self::CNAME(data) => data.len(),
             Self::SOA(data) => data.len(),
             Self::PTR(data) => data.len(),
+            Self::HINFO(data) => data.len(),
             Self::MX(data) => data.len(),
-            Self::TXT(data) => data.len() as u16,
+            Self::TXT(data) => txt_chunks(data).map(|chunk| chunk.len() as u16 + 1).sum(),
             Self::AAAA(data) => data.len(),
+            Self::LOC(data) => data.len(),
+            Self::DNAME(data) => data.len(),
+            Self::SSHFP(data) => data.len(),
+            Self::SVCB(data) => data.len(),
             Self::Other(_, data) => data.len() as u16,
         }
     }
 }
 
+impl Display for RecordData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A(data) => Display::fmt(data, f),
+            Self::NS(data) => Display::fmt(data, f),
+            Self::CNAME(data) => Display::fmt(data, f),
+            Self::SOA(data) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                data.mname,
+                data.rname,
+                data.serial,
+                data.refresh,
+                data.retry,
+                data.expire,
+                data.minimum
+            ),
+            Self::PTR(data) => Display::fmt(data, f),
+            Self::HINFO(data) => write!(f, "{:?} {:?}", data.cpu, data.os),
+            Self::MX(data) => write!(f, "{} {}", data.preference, data.exchange),
+            Self::TXT(data) => {
+                for (index, string) in data.iter().enumerate() {
+                    if index != 0 {
+                        f.write_str(" ")?;
+                    }
+
+                    write!(f, "{:?}", string)?;
+                }
+
+                Ok(())
+            }
+            Self::AAAA(data) => Display::fmt(data, f),
+            Self::LOC(data) => Display::fmt(data, f),
+            Self::DNAME(data) => Display::fmt(data, f),
+            Self::SSHFP(data) => Display::fmt(data, f),
+            Self::SVCB(data) => Display::fmt(data, f),
+            // Unknown types use the generic format from RFC 3597.
+            Self::Other(_, data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    f.write_str(" ")?;
+                    write_hex(f, data)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+// Types use the names from the DNS RFCs, not Rust casing.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Type {
     // RFC 1035
@@ -630,11 +1196,32 @@ macro_rules! enum_as_int {
                 }
             }
 
-        }
-    };
-}
-
-enum_as_int! {
+            /// Returns the name of the variant.
+            #[allow(dead_code)]
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(
+                        Self::$val => stringify!($val),
+                    )*
+                }
+            }
+
+            #[allow(dead_code)]
+            fn from_name(name: &str) -> Option<Self> {
+                $(
+                    if name.eq_ignore_ascii_case(stringify!($val)) {
+                        return Some(Self::$val);
+                    }
+                )*
+
+                None
+            }
+
+        }
+    };
+}
+
+enum_as_int! {
     Type,
     1 => A,
     2 => NS,
@@ -694,17 +1281,103 @@ enum_as_int! {
     41 => OPT,
 }
 
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Type {
+    type Err = ParseMnemonicError;
+
+    /// Parses a type from its mnemonic (e.g. `AAAA`) or the generic `TYPE<N>` form from
+    /// RFC 3597.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s)
+            .or_else(|| parse_generic(s, "TYPE").and_then(Self::from_u16))
+            .ok_or(ParseMnemonicError)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Class {
     In,
+    Ch,
+    // RFC 2136
+    None,
+    Any,
 }
 
 enum_as_int! {
     Class,
     1 => In,
+    3 => Ch,
+    254 => None,
+    255 => Any,
 }
 
-#[derive(Clone, Debug)]
+impl Display for Class {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::In => f.write_str("IN"),
+            Self::Ch => f.write_str("CH"),
+            Self::None => f.write_str("NONE"),
+            Self::Any => f.write_str("ANY"),
+        }
+    }
+}
+
+impl FromStr for Class {
+    type Err = ParseMnemonicError;
+
+    /// Parses a class from its mnemonic (e.g. `IN`) or the generic `CLASS<N>` form from
+    /// RFC 3597.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s)
+            .or_else(|| parse_generic(s, "CLASS").and_then(Self::from_u16))
+            .ok_or(ParseMnemonicError)
+    }
+}
+
+/// Parses the `<PREFIX><N>` form of an unknown type or class.
+fn parse_generic(s: &str, prefix: &str) -> Option<u16> {
+    let prefix_len = prefix.len();
+    // Slicing at a byte offset would panic within a multibyte character.
+    if !s
+        .get(..prefix_len)
+        .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+    {
+        return None;
+    }
+
+    s.get(prefix_len..)?.parse().ok()
+}
+
+/// An error returned when parsing an unknown [`Type`] or [`Class`] mnemonic.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ParseMnemonicError;
+
+impl Display for ParseMnemonicError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown mnemonic")
+    }
+}
+
+impl std::error::Error for ParseMnemonicError {}
+
+/// An error returned when creating an [`Fqdn`] from an invalid name.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InvalidFqdn;
+
+impl Display for InvalidFqdn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid domain name")
+    }
+}
+
+impl std::error::Error for InvalidFqdn {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ResourceRecord {
     pub name: Fqdn,
     pub r#type: Type,
@@ -720,14 +1393,22 @@ impl ResourceRecord {
         let rtype = reader.read_u16().ok_or(DecodeError::Eof)?;
         let r#type = Type::from_u16(rtype).ok_or(DecodeError::InvalidType)?;
 
-        // Skip OPT for now
+        // OPT records outside of the additional section are invalid. The
+        // class field holds the payload size, so we skip the record.
         if r#type == Type::OPT {
+            reader.read_u16().ok_or(DecodeError::Eof)?;
+            let ttl = reader.read_u32().ok_or(DecodeError::Eof)?;
+            let rdlength = reader.read_u16().ok_or(DecodeError::Eof)?;
+            let rdata = reader
+                .read_bytes(usize::from(rdlength))
+                .ok_or(DecodeError::Eof)?;
+
             return Ok(Self {
                 name,
                 r#type,
-                ttl: 0,
+                ttl,
                 class: Class::In,
-                rdata: RecordData::Other(Type::OPT, Bytes::new()),
+                rdata: RecordData::Other(Type::OPT, rdata),
             });
         }
 
@@ -755,9 +1436,28 @@ impl ResourceRecord {
         buf.put_u16(self.r#type.to_u16());
         buf.put_u16(self.class.to_u16());
         buf.put_u32(self.ttl);
-        buf.put_u16(self.rdata.len());
-        self.rdata.encode(&mut buf);
+
+        // RDLENGTH must match the bytes actually written, so encode
+        // the rdata first.
+        let mut rdata = Vec::new();
+        self.rdata.encode(&mut rdata);
+        buf.put_u16(rdata.len() as u16);
+        buf.put_slice(&rdata);
     }
+
+    pub fn encoded_len(&self) -> usize {
+        usize::from(self.name.len()) + 10 + usize::from(self.rdata.len())
+    }
+}
+
+enum_as_int! {
+    OpCode,
+    0 => Query,
+    1 => InverseQuery,
+    2 => Status,
+    4 => Notify,
+    5 => Update,
+    6 => Dso,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -768,6 +1468,14 @@ pub enum ResponseCode {
     NameError,
     NotImplemented,
     Refused,
+    // RFC 2136
+    YxDomain,
+    YxRrSet,
+    NxRrSet,
+    NotAuth,
+    NotZone,
+    // RFC 8490
+    DsoTypeNi,
 }
 
 enum_as_int! {
@@ -778,29 +1486,37 @@ enum_as_int! {
     3 => NameError,
     4 => NotImplemented,
     5 => Refused,
+    6 => YxDomain,
+    7 => YxRrSet,
+    8 => NxRrSet,
+    9 => NotAuth,
+    10 => NotZone,
+    11 => DsoTypeNi,
 }
 
 #[derive(Clone, Debug)]
 pub enum DecodeError {
     Eof,
     InvalidOpCode,
+    InvalidDso,
     InvalidResponseCode,
     InvalidType,
     InvalidClass,
     BadPointer,
     FqdnTooLong,
-    UnsupportedType(Type),
     InvalidUtf8,
+    /// The record data is shorter or longer than its RDLENGTH.
+    InvalidRdLength,
 }
 
 #[derive(Clone, Debug)]
 struct Reader<'a> {
-    buf: &'a [u8],
+    buf: &'a Bytes,
     cursor: usize,
 }
 
 impl<'a> Reader<'a> {
-    fn new(buf: &'a [u8]) -> Self {
+    fn new(buf: &'a Bytes) -> Self {
         Self { buf, cursor: 0 }
     }
 
@@ -822,16 +1538,20 @@ impl<'a> Reader<'a> {
         Some(u32::from_be_bytes(slice.try_into().unwrap()))
     }
 
-    fn full_buffer(&self) -> &[u8] {
-        &self.buf
-    }
+    /// Returns the next `len` bytes without copying them.
+    fn read_bytes(&mut self, len: usize) -> Option<Bytes> {
+        let end = self.cursor.checked_add(len)?;
+        if end > self.buf.len() {
+            return None;
+        }
 
-    fn remaining_buffer(&self) -> &[u8] {
-        &self.buf[self.cursor..]
+        let bytes = self.buf.slice(self.cursor..end);
+        self.cursor = end;
+        Some(bytes)
     }
 
-    fn peek_u8(&self) -> Option<u8> {
-        self.buf.get(self.cursor).copied()
+    fn full_buffer(&self) -> &[u8] {
+        self.buf
     }
 
     fn advance(&mut self, n: usize) {
@@ -843,7 +1563,7 @@ macro_rules! define_record {
     ($struct_vis:vis struct $struct_name:ident {
         $($field_vis:vis $field_name:ident: $field_type:ty,)*
     }) => {
-        #[derive(Clone, Debug)]
+        #[derive(Clone, Debug, PartialEq, Eq)]
         $struct_vis struct $struct_name {
             $(
                 $field_vis $field_name: $field_type,
@@ -900,6 +1620,368 @@ define_record! {
     }
 }
 
+define_record! {
+    pub struct LocData {
+        pub version: u8,
+        pub size: u8,
+        pub horiz_pre: u8,
+        pub vert_pre: u8,
+        pub latitude: u32,
+        pub longitude: u32,
+        pub altitude: u32,
+    }
+}
+
+impl Display for LocData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // See https://datatracker.ietf.org/doc/html/rfc1876#section-3
+        fn write_coordinate(f: &mut Formatter<'_>, value: u32, dirs: [char; 2]) -> fmt::Result {
+            // Coordinates are thousandths of an arc second offset by 2^31.
+            let value = i64::from(value) - (1 << 31);
+            let dir = if value >= 0 { dirs[0] } else { dirs[1] };
+            let value = value.unsigned_abs();
+
+            write!(
+                f,
+                "{} {} {}.{:03} {}",
+                value / 3_600_000,
+                value / 60_000 % 60,
+                value / 1000 % 60,
+                value % 1000,
+                dir
+            )
+        }
+
+        fn write_meters(f: &mut Formatter<'_>, centimeters: i64) -> fmt::Result {
+            let sign = if centimeters < 0 { "-" } else { "" };
+            let centimeters = centimeters.unsigned_abs();
+            write!(f, "{}{}.{:02}m", sign, centimeters / 100, centimeters % 100)
+        }
+
+        // Sizes are encoded as `mantissa * 10^exponent` centimeters.
+        fn precision(value: u8) -> i64 {
+            i64::from(value >> 4) * 10_i64.pow(u32::from(value & 0x0f))
+        }
+
+        write_coordinate(f, self.latitude, ['N', 'S'])?;
+        f.write_str(" ")?;
+        write_coordinate(f, self.longitude, ['E', 'W'])?;
+        f.write_str(" ")?;
+        // Altitude is in centimeters from a base of 100000m below the WGS 84 spheroid.
+        write_meters(f, i64::from(self.altitude) - 10_000_000)?;
+        f.write_str(" ")?;
+        write_meters(f, precision(self.size))?;
+        f.write_str(" ")?;
+        write_meters(f, precision(self.horiz_pre))?;
+        f.write_str(" ")?;
+        write_meters(f, precision(self.vert_pre))
+    }
+}
+
+/// Host information.
+///
+/// Also used for minimal responses to ANY queries.
+/// See https://datatracker.ietf.org/doc/html/rfc8482#section-4.2
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HinfoData {
+    pub cpu: String,
+    pub os: String,
+}
+
+impl HinfoData {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(Self {
+            cpu: read_character_string(reader)?,
+            os: read_character_string(reader)?,
+        })
+    }
+}
+
+impl Encode for HinfoData {
+    fn encode<B>(&self, mut buf: B)
+    where
+        B: BufMut,
+    {
+        for string in [&self.cpu, &self.os] {
+            let bytes = character_string(string);
+            buf.put_u8(bytes.len() as u8);
+            buf.put_slice(bytes);
+        }
+    }
+
+    fn len(&self) -> u16 {
+        [&self.cpu, &self.os]
+            .into_iter()
+            .map(|string| character_string(string).len() as u16 + 1)
+            .sum()
+    }
+}
+
+/// Reads a single <character-string>.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc1035#section-3.3
+fn read_character_string(reader: &mut Reader<'_>) -> Result<String, DecodeError> {
+    let len = u8::decode(reader)?;
+    let bytes = reader
+        .read_bytes(usize::from(len))
+        .ok_or(DecodeError::Eof)?;
+    let string = std::str::from_utf8(&bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+    Ok(string.to_owned())
+}
+
+/// Returns the bytes of `string` that fit into a single <character-string>.
+fn character_string(string: &str) -> &[u8] {
+    let bytes = string.as_bytes();
+    &bytes[..bytes.len().min(255)]
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SshfpData {
+    pub algorithm: u8,
+    pub fingerprint_type: u8,
+    pub fingerprint: Bytes,
+}
+
+impl SshfpData {
+    fn decode(len: u16, reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let algorithm = u8::decode(reader)?;
+        let fingerprint_type = u8::decode(reader)?;
+        let fingerprint = reader
+            .read_bytes(usize::from(len.checked_sub(2).ok_or(DecodeError::Eof)?))
+            .ok_or(DecodeError::Eof)?;
+
+        Ok(Self {
+            algorithm,
+            fingerprint_type,
+            fingerprint,
+        })
+    }
+}
+
+impl Encode for SshfpData {
+    fn encode<B>(&self, mut buf: B)
+    where
+        B: BufMut,
+    {
+        self.algorithm.encode(&mut buf);
+        self.fingerprint_type.encode(&mut buf);
+        self.fingerprint[..].encode(&mut buf);
+    }
+
+    fn len(&self) -> u16 {
+        2 + self.fingerprint.len() as u16
+    }
+}
+
+impl Display for SshfpData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.algorithm, self.fingerprint_type)?;
+        write_hex(f, &self.fingerprint)
+    }
+}
+
+/// See https://datatracker.ietf.org/doc/html/rfc9460#section-2.2
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SvcbData {
+    /// 0 for alias mode, the preference of the endpoint otherwise.
+    pub priority: u16,
+    pub target: Fqdn,
+    /// The parameters in ascending order of their keys.
+    pub params: Vec<SvcParam>,
+}
+
+impl SvcbData {
+    fn decode(len: u16, reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let end = reader.cursor + usize::from(len);
+        let priority = u16::decode(reader)?;
+        let target = Fqdn::decode(reader)?;
+
+        let mut params = Vec::new();
+        while reader.cursor < end {
+            let key = u16::decode(reader)?;
+            let len = u16::decode(reader)?;
+            let value = reader
+                .read_bytes(usize::from(len))
+                .ok_or(DecodeError::Eof)?;
+            params.push(SvcParam { key, value });
+        }
+
+        Ok(Self {
+            priority,
+            target,
+            params,
+        })
+    }
+}
+
+impl Encode for SvcbData {
+    fn encode<B>(&self, mut buf: B)
+    where
+        B: BufMut,
+    {
+        self.priority.encode(&mut buf);
+        self.target.encode(&mut buf);
+        for param in &self.params {
+            param.key.encode(&mut buf);
+            (param.value.len() as u16).encode(&mut buf);
+            param.value[..].encode(&mut buf);
+        }
+    }
+
+    fn len(&self) -> u16 {
+        2 + self.target.len()
+            + self
+                .params
+                .iter()
+                .map(|param| 4 + param.value.len() as u16)
+                .sum::<u16>()
+    }
+}
+
+impl Display for SvcbData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.priority, self.target)?;
+        for param in &self.params {
+            write!(f, " {}", param)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A single SvcParam of a [`SvcbData`] in wire format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SvcParam {
+    pub key: u16,
+    pub value: Bytes,
+}
+
+impl SvcParam {
+    pub const ALPN: u16 = 1;
+    pub const PORT: u16 = 3;
+    pub const IPV4HINT: u16 = 4;
+    pub const IPV6HINT: u16 = 6;
+    /// See https://datatracker.ietf.org/doc/html/rfc9461#section-5
+    pub const DOHPATH: u16 = 7;
+
+    /// The ALPN protocol identifiers supported by the endpoint.
+    pub fn alpn(protocols: &[&str]) -> Self {
+        let mut value = Vec::new();
+        for protocol in protocols {
+            value.push(protocol.len() as u8);
+            value.extend_from_slice(protocol.as_bytes());
+        }
+
+        Self {
+            key: Self::ALPN,
+            value: Bytes::from(value),
+        }
+    }
+
+    pub fn port(port: u16) -> Self {
+        Self {
+            key: Self::PORT,
+            value: Bytes::copy_from_slice(&port.to_be_bytes()),
+        }
+    }
+
+    /// The URI template of DNS over HTTPS, relative to the target.
+    pub fn dohpath(template: &str) -> Self {
+        Self {
+            key: Self::DOHPATH,
+            value: Bytes::copy_from_slice(template.as_bytes()),
+        }
+    }
+}
+
+impl Display for SvcParam {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let value = &self.value[..];
+        match self.key {
+            Self::ALPN => {
+                f.write_str("alpn=")?;
+                let mut rest = value;
+                let mut first = true;
+                while let Some((len, tail)) = rest.split_first() {
+                    let (protocol, tail) = tail.split_at(usize::from(*len).min(tail.len()));
+                    if !first {
+                        f.write_str(",")?;
+                    }
+                    f.write_str(&String::from_utf8_lossy(protocol))?;
+                    first = false;
+                    rest = tail;
+                }
+
+                Ok(())
+            }
+            Self::PORT if value.len() == 2 => {
+                write!(f, "port={}", u16::from_be_bytes([value[0], value[1]]))
+            }
+            Self::IPV4HINT if value.len().is_multiple_of(4) => {
+                f.write_str("ipv4hint=")?;
+                for (index, addr) in value.chunks_exact(4).enumerate() {
+                    if index != 0 {
+                        f.write_str(",")?;
+                    }
+                    let addr: [u8; 4] = addr.try_into().unwrap();
+                    write!(f, "{}", Ipv4Addr::from(addr))?;
+                }
+
+                Ok(())
+            }
+            Self::IPV6HINT if value.len().is_multiple_of(16) => {
+                f.write_str("ipv6hint=")?;
+                for (index, addr) in value.chunks_exact(16).enumerate() {
+                    if index != 0 {
+                        f.write_str(",")?;
+                    }
+                    let addr: [u8; 16] = addr.try_into().unwrap();
+                    write!(f, "{}", Ipv6Addr::from(addr))?;
+                }
+
+                Ok(())
+            }
+            Self::DOHPATH => write!(f, "dohpath={:?}", String::from_utf8_lossy(value)),
+            key => {
+                write!(f, "key{}=", key)?;
+                write_hex(f, value)
+            }
+        }
+    }
+}
+
+/// Splits the strings of a TXT record into <character-string>s of at most 255 bytes.
+///
+/// Chunks end at a character boundary, so that each is valid UTF-8 when decoded.
+fn txt_chunks(strings: &[String]) -> impl Iterator<Item = &[u8]> {
+    strings.iter().flat_map(|string| {
+        let mut rest = string.as_str();
+        // An empty string is still encoded as a zero-length <character-string>.
+        let mut empty = string.is_empty();
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return std::mem::take(&mut empty).then_some(&b""[..]);
+            }
+
+            let mut end = rest.len().min(255);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (chunk, tail) = rest.split_at(end);
+            rest = tail;
+            Some(chunk.as_bytes())
+        })
+    })
+}
+
+fn write_hex(f: &mut Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(f, "{:02X}", byte)?;
+    }
+
+    Ok(())
+}
+
 trait Encode {
     fn encode<B>(&self, buf: B)
     where
@@ -972,8 +2054,8 @@ impl Decode for u32 {
 impl<const N: usize> Decode for [u8; N] {
     fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
         let mut buf = [0; N];
-        for index in 0..N {
-            buf[index] = u8::decode(reader)?;
+        for byte in &mut buf {
+            *byte = u8::decode(reader)?;
         }
         Ok(buf)
     }
@@ -1030,15 +2112,25 @@ impl Decode for Ipv6Addr {
     }
 }
 
+#[cfg(test)]
+mod conformance;
+
 #[cfg(test)]
 mod tests {
-    use super::{Decode, Fqdn, Packet, Reader};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use bytes::Bytes;
+
+    use super::{
+        txt_chunks, Class, ClientSubnet, Decode, DecodeError, DsoTlv, Edns, Encode, ExtendedError,
+        Fqdn, Header, InvalidFqdn, LocData, OpCode, Packet, Reader, RecordData, Type,
+    };
 
     #[test]
     fn fqdn_decode_basic() {
-        let input = [
+        let input = Bytes::from_static(&[
             7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
-        ];
+        ]);
         let mut reader = Reader::new(&input);
 
         let fqdn = Fqdn::decode(&mut reader).unwrap();
@@ -1065,6 +2157,7 @@ mod tests {
         ];
         let start = input.len();
         input.extend([3, b'w', b'w', b'w', 0b1100_0000, 0b0000_0000]);
+        let input = Bytes::from(input);
 
         let mut reader = Reader::new(&input);
         reader.advance(start);
@@ -1078,6 +2171,7 @@ mod tests {
         let mut input = vec![7, b'e', b'x', b'a', b'm', b'p', b'l', b'e'];
         let start = input.len();
         input.extend([0b1100_0000, 0b0000_0000]);
+        let input = Bytes::from(input);
 
         let mut reader = Reader::new(&input);
         reader.advance(start);
@@ -1100,6 +2194,670 @@ mod tests {
             0x97, 0x65, 0x42, 0xa7,
         ];
 
-        let packet = Packet::decode(&payload[..]).unwrap();
+        let packet = Packet::decode(Bytes::copy_from_slice(&payload)).unwrap();
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.answers.len(), 5);
+    }
+
+    #[test]
+    fn packet_decode_query_head() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // Header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // Question
+            0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01,
+            b'a', // Additional
+        ]);
+
+        let head = Packet::decode_query_head(payload).unwrap();
+        assert_eq!(head.header.transaction_id, 1);
+        assert!(head.header.rd());
+        assert_eq!(head.questions.len(), 1);
+        assert_eq!(head.questions[0].name.as_bytes(), b"example.com.");
+        assert_eq!(head.questions[0].qtype, Type::A);
+
+        let packet = head.into_packet().unwrap();
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.additional.len(), 1);
+    }
+
+    #[test]
+    fn header_unassigned_codes() {
+        let header = Header {
+            transaction_id: 0,
+            // Opcode 3 and response code 15 are unassigned.
+            flags: 0b0001_1000_0000_1111,
+            qdcount: 0,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        };
+        assert_eq!(header.opcode(), None);
+        assert_eq!(header.rcode(), None);
+
+        let payload = Bytes::from_static(&[0x00, 0x01, 0x18, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Packet::decode_query_head(payload),
+            Err(DecodeError::InvalidOpCode)
+        ));
+    }
+
+    #[test]
+    fn query_head_edns() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, // Header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // Question
+            0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01,
+            b'a', // Additional
+            0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, // OPT
+        ]);
+
+        let head = Packet::decode_query_head(payload).unwrap();
+        let edns = head.edns().unwrap().unwrap();
+        assert_eq!(edns.udp_payload_size, 4096);
+        assert!(edns.dnssec_ok);
+
+        let query = head.into_query(Some(edns.clone()));
+        assert_eq!(query.transaction_id, 1);
+        assert!(query.recursion_desired);
+        assert_eq!(query.questions.len(), 1);
+        assert!(query.additional.is_empty());
+        assert_eq!(query.edns, Some(edns));
+    }
+
+    #[test]
+    fn packet_decode_dnssec_bits() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x01, 0x30, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // Header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // Question
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, // OPT
+        ]);
+
+        let packet = Packet::decode(payload.clone()).unwrap();
+        assert!(packet.authentic_data);
+        assert!(packet.checking_disabled);
+        assert!(packet.additional.is_empty());
+        assert_eq!(
+            packet.edns,
+            Some(Edns {
+                udp_payload_size: 1232,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: true,
+                options: Bytes::new(),
+            })
+        );
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(buf, payload);
+    }
+
+    #[test]
+    fn packet_echo_raw_questions() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Header
+            0x07, b'e', b'X', b'a', b'M', b'p', b'l', b'E', 0x03, b'c', b'O', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // Question
+        ]);
+
+        let mut packet = Packet::decode(payload.clone()).unwrap();
+        packet.questions[0].name = Fqdn(b"example.com.".to_vec());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(buf, payload);
+        assert_eq!(packet.encoded_len(), buf.len());
+    }
+
+    #[test]
+    fn packet_encoded_len() {
+        let payload = [
+            0x66, 0xe1, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x03, 0x77,
+            0x77, 0x77, 0x06, 0x74, 0x77, 0x69, 0x74, 0x63, 0x68, 0x02, 0x74, 0x76, 0x00, 0x00,
+            0x01, 0x00, 0x01, 0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0d, 0x0f, 0x00,
+            0x17, 0x06, 0x74, 0x77, 0x69, 0x74, 0x63, 0x68, 0x03, 0x6d, 0x61, 0x70, 0x06, 0x66,
+            0x61, 0x73, 0x74, 0x6c, 0x79, 0x03, 0x6e, 0x65, 0x74, 0x00, 0xc0, 0x2b, 0x00, 0x01,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x2b, 0x00, 0x04, 0x97, 0x65, 0x02, 0xa7,
+        ];
+
+        let mut packet = Packet::decode(Bytes::copy_from_slice(&payload)).unwrap();
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(packet.encoded_len(), buf.len());
+
+        packet.raw_questions = None;
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(packet.encoded_len(), buf.len());
+    }
+
+    #[test]
+    fn packet_decode_other_rdata() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x81, 0x80, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, // Header
+            0x00, 0x00, 0x0a, 0x00, 0x01, 0x00, 0x00, 0x00, 0x2b, 0x00, 0x03, 0x01, 0x02,
+            0x03, // NULL
+            0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x2b, 0x00, 0x04, 0x7f, 0x00, 0x00,
+            0x01, // A
+        ]);
+
+        let packet = Packet::decode(payload.clone()).unwrap();
+        assert_eq!(packet.answers.len(), 2);
+
+        match &packet.answers[0].rdata {
+            RecordData::Other(Type::NULL, data) => {
+                assert_eq!(&data[..], &[0x01, 0x02, 0x03]);
+                // The rdata must reference the original buffer.
+                assert_eq!(data.as_ptr(), payload[23..].as_ptr());
+            }
+            rdata => panic!("unexpected rdata: {:?}", rdata),
+        }
+
+        assert!(matches!(packet.answers[1].rdata, RecordData::A(_)));
+    }
+
+    #[test]
+    fn loc_display() {
+        let loc = LocData {
+            version: 0,
+            size: 0x12,
+            horiz_pre: 0x16,
+            vert_pre: 0x13,
+            latitude: 2299997648,
+            longitude: 1891505648,
+            altitude: 9997600,
+        };
+
+        assert_eq!(
+            loc.to_string(),
+            "42 21 54.000 N 71 6 18.000 W -24.00m 1.00m 10000.00m 10.00m"
+        );
+    }
+
+    #[test]
+    fn packet_decode_sshfp() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x81, 0x80, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // Header
+            0x00, 0x00, 0x2c, 0x00, 0x01, 0x00, 0x00, 0x00, 0x2b, 0x00, 0x06, 0x04, 0x02, 0xde,
+            0xad, 0xbe, 0xef, // SSHFP
+        ]);
+
+        let packet = Packet::decode(payload.clone()).unwrap();
+        assert_eq!(packet.answers[0].rdata.to_string(), "4 2 DEADBEEF");
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(buf, payload);
+    }
+
+    #[test]
+    fn packet_decode_hinfo() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x81, 0x80, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // Header
+            0x00, 0x00, 0x0d, 0x00, 0x01, 0x00, 0x00, 0x0d, 0x2b, 0x00, 0x09, 0x07, b'R', b'F',
+            b'C', b'8', b'4', b'8', b'2', 0x00, // HINFO
+        ]);
+
+        let packet = Packet::decode(payload.clone()).unwrap();
+        assert_eq!(packet.answers[0].rdata.to_string(), r#""RFC8482" """#);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(buf, payload);
+    }
+
+    #[test]
+    fn txt_character_strings() {
+        let payload = Bytes::from_static(&[
+            0x00, 0x01, 0x81, 0x80, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // Header
+            0x00, 0x00, 0x10, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x03, b'f', b'o',
+            b'o', 0x00, 0x02, b'b', b'a', // TXT
+        ]);
+
+        let packet = Packet::decode(payload.clone()).unwrap();
+        assert_eq!(packet.answers[0].class, Class::Ch);
+        assert_eq!(packet.answers[0].rdata.to_string(), r#""foo" "" "ba""#);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf);
+        assert_eq!(buf, payload);
+    }
+
+    #[test]
+    fn txt_chunks_at_char_boundaries() {
+        // 2 byte characters, the 128th one would cross the 255 byte limit.
+        let text = "é".repeat(200);
+        let chunks: Vec<_> = txt_chunks(std::slice::from_ref(&text)).collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), 254);
+        for chunk in &chunks {
+            assert!(std::str::from_utf8(chunk).is_ok());
+        }
+
+        let rdata = RecordData::TXT(vec![text]);
+        let mut buf = Vec::new();
+        rdata.encode(&mut buf);
+        assert_eq!(usize::from(rdata.len()), buf.len());
+    }
+
+    #[test]
+    fn type_class_from_str() {
+        assert_eq!("A".parse::<Type>(), Ok(Type::A));
+        assert_eq!("aaaa".parse::<Type>(), Ok(Type::AAAA));
+        assert_eq!("HTTPS".parse::<Type>(), Ok(Type::HTTPS));
+        assert_eq!("TYPE64".parse::<Type>(), Ok(Type::SVCB));
+        assert!("TYPE".parse::<Type>().is_err());
+        assert!("TYPE65000".parse::<Type>().is_err());
+        assert!("FOO".parse::<Type>().is_err());
+        assert!("TYPé1".parse::<Type>().is_err());
+        assert!("CLAßS1".parse::<Class>().is_err());
+
+        assert_eq!("IN".parse::<Class>(), Ok(Class::In));
+        assert_eq!("CLASS3".parse::<Class>(), Ok(Class::Ch));
+
+        assert_eq!(Type::AAAA.to_string(), "AAAA");
+        assert_eq!(Class::Ch.to_string(), "CH");
+    }
+
+    #[test]
+    fn decode_dso() {
+        let payload = Bytes::from_static(&[
+            0x12, 0x34, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Header
+            0x00, 0x01, 0x00, 0x08, 0x00, 0x00, 0x03, 0xe8, 0x00, 0x00, 0x03,
+            0xe8, // Keepalive
+        ]);
+
+        let head = Packet::decode_query_head(payload).unwrap();
+        assert_eq!(head.opcode, OpCode::Dso);
+
+        let tlvs = head.decode_dso().unwrap();
+        assert_eq!(tlvs.len(), 1);
+        assert_eq!(tlvs[0].r#type, DsoTlv::KEEPALIVE);
+        assert_eq!(tlvs[0].data.len(), 8);
+    }
+
+    #[test]
+    fn packet_decode_update() {
+        let payload = Bytes::from_static(&[
+            0x12, 0x34, 0x28, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, // Header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x00, 0x00, 0x06, 0x00,
+            0x01, // Zone
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, // Delete all A records
+        ]);
+
+        let packet = Packet::decode(payload).unwrap();
+        assert_eq!(packet.opcode, OpCode::Update);
+        assert_eq!(packet.authority[0].class, Class::Any);
+    }
+
+    #[test]
+    fn fqdn_len() {
+        for name in ["example.com.", "example.com", "."] {
+            let fqdn = Fqdn(name.as_bytes().to_vec());
+            let mut buf = Vec::new();
+            fqdn.encode(&mut buf);
+            assert_eq!(usize::from(Encode::len(&fqdn)), buf.len());
+        }
+    }
+
+    #[test]
+    fn fqdn_new() {
+        for name in ["example.com.", "a.", "."] {
+            assert!(Fqdn::new(name.to_owned()).is_ok(), "{}", name);
+        }
+
+        let long_label = format!("{}.com.", "a".repeat(64));
+        let long_name = "a.".repeat(128);
+        for name in [
+            "",
+            "example.com",
+            "example..com.",
+            ".com.",
+            &long_label,
+            &long_name,
+        ] {
+            assert_eq!(Fqdn::new(name.to_owned()), Err(InvalidFqdn), "{}", name);
+        }
+
+        // Exactly 255 bytes in wire format.
+        let name = format!("{}.", "a".repeat(63)).repeat(3) + &format!("{}.", "a".repeat(61));
+        assert_eq!(Encode::len(&Fqdn::new(name).unwrap()), 255);
+    }
+
+    #[test]
+    fn fqdn_labels_and_ancestors() {
+        let fqdn = Fqdn(b"www.example.com.".to_vec());
+        assert_eq!(
+            fqdn.labels().collect::<Vec<_>>(),
+            [&b"www"[..], b"example", b"com"]
+        );
+
+        assert_eq!(
+            fqdn.ancestors().collect::<Vec<_>>(),
+            [&b"www.example.com."[..], b"example.com.", b"com.", b"."]
+        );
+        assert_eq!(
+            Fqdn(b".".to_vec()).ancestors().collect::<Vec<_>>(),
+            [&b"."[..]]
+        );
+
+        assert_eq!(Fqdn(b".".to_vec()).labels().count(), 0);
+    }
+
+    #[test]
+    fn fqdn_is_subdomain_of() {
+        let fqdn = Fqdn(b"www.Example.com.".to_vec());
+        assert!(fqdn.is_subdomain_of(&Fqdn(b"example.com.".to_vec())));
+        assert!(fqdn.is_subdomain_of(&Fqdn(b"www.example.com.".to_vec())));
+        assert!(fqdn.is_subdomain_of(&Fqdn(b".".to_vec())));
+        assert!(!fqdn.is_subdomain_of(&Fqdn(b"ample.com.".to_vec())));
+        assert!(!fqdn.is_subdomain_of(&Fqdn(b"a.www.example.com.".to_vec())));
+    }
+
+    #[test]
+    fn extended_error_encode() {
+        let error = ExtendedError {
+            info_code: ExtendedError::PROHIBITED,
+            extra_text: "rate".to_owned(),
+        };
+
+        let mut buf = Vec::new();
+        error.encode(&mut buf);
+        assert_eq!(buf, [0, 15, 0, 6, 0, 18, b'r', b'a', b't', b'e']);
+    }
+
+    #[test]
+    fn edns_iter_options() {
+        let mut options = Vec::new();
+        Edns::encode_option(
+            &mut options,
+            ClientSubnet::OPTION_CODE,
+            &[0, 1, 24, 0, 192, 0, 2],
+        );
+        Edns::encode_option(&mut options, 10, &[]);
+        // Truncated.
+        options.extend_from_slice(&[0, 15, 0, 6, 0]);
+
+        let edns = Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: options.into(),
+        };
+        let options: Vec<_> = edns.iter_options().collect();
+        assert_eq!(
+            options,
+            [
+                (ClientSubnet::OPTION_CODE, &[0, 1, 24, 0, 192, 0, 2][..]),
+                (10, &[][..])
+            ]
+        );
+    }
+
+    #[test]
+    fn client_subnet_truncate() {
+        let subnet = ClientSubnet::decode(&[0, 1, 24, 0, 192, 0, 2]).unwrap();
+        assert_eq!(subnet.addr, Ipv4Addr::new(192, 0, 2, 0));
+        assert_eq!(subnet.source_prefix, 24);
+
+        let mut buf = Vec::new();
+        subnet.truncate(20).encode(&mut buf);
+        assert_eq!(buf, [0, 8, 0, 7, 0, 1, 20, 0, 192, 0, 0]);
+
+        let subnet = ClientSubnet::new(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6).into(), 56);
+        assert_eq!(subnet.addr, Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0));
+        let mut buf = Vec::new();
+        subnet.encode(&mut buf);
+        assert_eq!(buf[4..], [0, 2, 56, 0, 0x20, 0x01, 0xd, 0xb8, 0, 1, 0]);
+
+        // Bits beyond the source prefix must be cleared.
+        assert_eq!(
+            ClientSubnet::decode(&[0, 1, 20, 0, 192, 0, 255])
+                .unwrap()
+                .addr,
+            Ipv4Addr::new(192, 0, 240, 0)
+        );
+        assert!(ClientSubnet::decode(&[0, 1, 24, 0, 192, 0]).is_none());
+        assert!(ClientSubnet::decode(&[0, 3, 0, 0]).is_none());
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use bytes::Bytes;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use super::{
+        Class, Decode, Edns, Encode, Fqdn, HinfoData, LocData, MxData, OpCode, Packet, Qr,
+        Question, Reader, RecordData, ResourceRecord, ResponseCode, SoaData, SshfpData, SvcParam,
+        SvcbData, Type,
+    };
+
+    fn fqdn() -> impl Strategy<Value = Fqdn> {
+        vec("[a-z0-9-]{1,20}", 0..5).prop_map(|labels| {
+            let mut name = String::new();
+            for label in labels {
+                name.push_str(&label);
+                name.push('.');
+            }
+
+            if name.is_empty() {
+                name.push('.');
+            }
+
+            Fqdn::new_unchecked(name)
+        })
+    }
+
+    fn class() -> impl Strategy<Value = Class> {
+        select(vec![Class::In, Class::Ch, Class::None, Class::Any])
+    }
+
+    fn record_data() -> impl Strategy<Value = (Type, RecordData)> {
+        prop_oneof![
+            any::<[u8; 4]>().prop_map(|addr| (Type::A, RecordData::A(Ipv4Addr::from(addr)))),
+            fqdn().prop_map(|name| (Type::NS, RecordData::NS(name))),
+            fqdn().prop_map(|name| (Type::CNAME, RecordData::CNAME(name))),
+            (fqdn(), fqdn(), any::<[u32; 5]>()).prop_map(|(mname, rname, values)| {
+                let [serial, refresh, retry, expire, minimum] = values;
+                let data = SoaData {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                };
+                (Type::SOA, RecordData::SOA(data))
+            }),
+            fqdn().prop_map(|name| (Type::PTR, RecordData::PTR(name))),
+            ("[ -~]{0,255}", "[ -~]{0,255}")
+                .prop_map(|(cpu, os)| (Type::HINFO, RecordData::HINFO(HinfoData { cpu, os }))),
+            (any::<u16>(), fqdn()).prop_map(|(preference, exchange)| {
+                let data = MxData {
+                    preference,
+                    exchange,
+                };
+                (Type::MX, RecordData::MX(data))
+            }),
+            // Longer strings are split into multiple <character-string>s.
+            vec("[ -~]{0,255}", 1..4).prop_map(|txt| (Type::TXT, RecordData::TXT(txt))),
+            any::<[u8; 16]>().prop_map(|addr| (Type::AAAA, RecordData::AAAA(Ipv6Addr::from(addr)))),
+            (any::<[u8; 4]>(), any::<[u32; 3]>()).prop_map(|(bytes, coords)| {
+                let data = LocData {
+                    version: bytes[0],
+                    size: bytes[1],
+                    horiz_pre: bytes[2],
+                    vert_pre: bytes[3],
+                    latitude: coords[0],
+                    longitude: coords[1],
+                    altitude: coords[2],
+                };
+                (Type::LOC, RecordData::LOC(data))
+            }),
+            fqdn().prop_map(|name| (Type::DNAME, RecordData::DNAME(name))),
+            (any::<u8>(), any::<u8>(), vec(any::<u8>(), 0..64)).prop_map(
+                |(algorithm, fingerprint_type, fingerprint)| {
+                    let data = SshfpData {
+                        algorithm,
+                        fingerprint_type,
+                        fingerprint: Bytes::from(fingerprint),
+                    };
+                    (Type::SSHFP, RecordData::SSHFP(data))
+                }
+            ),
+            (
+                select(vec![Type::SVCB, Type::HTTPS]),
+                any::<u16>(),
+                fqdn(),
+                vec((any::<u16>(), vec(any::<u8>(), 0..32)), 0..4)
+            )
+                .prop_map(|(r#type, priority, target, params)| {
+                    let data = SvcbData {
+                        priority,
+                        target,
+                        params: params
+                            .into_iter()
+                            .map(|(key, value)| SvcParam {
+                                key,
+                                value: Bytes::from(value),
+                            })
+                            .collect(),
+                    };
+                    (r#type, RecordData::SVCB(data))
+                }),
+            (
+                select(vec![Type::NULL, Type::SRV, Type::CAA, Type::TLSA]),
+                vec(any::<u8>(), 1..64)
+            )
+                .prop_map(|(r#type, data)| (r#type, RecordData::Other(r#type, Bytes::from(data)))),
+        ]
+    }
+
+    fn record() -> impl Strategy<Value = ResourceRecord> {
+        (fqdn(), record_data(), class(), any::<u32>()).prop_map(
+            |(name, (r#type, rdata), class, ttl)| ResourceRecord {
+                name,
+                r#type,
+                class,
+                ttl,
+                rdata,
+            },
+        )
+    }
+
+    fn question() -> impl Strategy<Value = Question> {
+        (
+            fqdn(),
+            select(vec![Type::A, Type::AAAA, Type::TXT, Type::MX]),
+            class(),
+        )
+            .prop_map(|(name, qtype, qclass)| Question {
+                name,
+                qtype,
+                qclass,
+            })
+    }
+
+    fn edns() -> impl Strategy<Value = Edns> {
+        (any::<(u16, u8, u8, bool)>(), vec(any::<u8>(), 0..16)).prop_map(
+            |((udp_payload_size, extended_rcode, version, dnssec_ok), options)| Edns {
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                options: Bytes::from(options),
+            },
+        )
+    }
+
+    fn packet() -> impl Strategy<Value = Packet> {
+        (
+            any::<(u16, [bool; 7])>(),
+            select(vec![0, 1, 2, 4, 5, 6]).prop_map(|op| OpCode::from_u16(op).unwrap()),
+            (0..=11_u16).prop_map(|code| ResponseCode::from_u16(code).unwrap()),
+            vec(question(), 0..3),
+            [
+                vec(record(), 0..4),
+                vec(record(), 0..4),
+                vec(record(), 0..4),
+            ],
+            proptest::option::of(edns()),
+        )
+            .prop_map(
+                |((transaction_id, flags), opcode, response_code, questions, records, edns)| {
+                    let [answers, authority, additional] = records;
+                    Packet {
+                        transaction_id,
+                        qr: if flags[0] { Qr::Response } else { Qr::Request },
+                        opcode,
+                        authoritative_answer: flags[1],
+                        truncated: flags[2],
+                        recursion_desired: flags[3],
+                        recursion_available: flags[4],
+                        authentic_data: flags[5],
+                        checking_disabled: flags[6],
+                        response_code,
+                        questions,
+                        raw_questions: None,
+                        answers,
+                        authority,
+                        additional,
+                        edns,
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn fqdn_roundtrip(fqdn in fqdn()) {
+            let mut buf = Vec::new();
+            fqdn.encode(&mut buf);
+            prop_assert_eq!(buf.len(), usize::from(Encode::len(&fqdn)));
+
+            let buf = Bytes::from(buf);
+            let mut reader = Reader::new(&buf);
+            prop_assert_eq!(Fqdn::decode(&mut reader).unwrap(), fqdn);
+        }
+
+        #[test]
+        fn record_roundtrip(record in record()) {
+            let mut buf = Vec::new();
+            record.encode(&mut buf);
+            prop_assert_eq!(buf.len(), record.encoded_len());
+
+            let buf = Bytes::from(buf);
+            let mut reader = Reader::new(&buf);
+            prop_assert_eq!(ResourceRecord::decode(&mut reader).unwrap(), record);
+            prop_assert_eq!(reader.cursor, buf.len());
+        }
+
+        #[test]
+        fn packet_roundtrip(packet in packet()) {
+            let mut buf = Vec::new();
+            packet.encode(&mut buf);
+            prop_assert_eq!(buf.len(), packet.encoded_len());
+
+            let mut decoded = Packet::decode(Bytes::from(buf)).unwrap();
+            decoded.raw_questions = None;
+            prop_assert_eq!(decoded, packet);
+        }
+
+        #[test]
+        fn packet_decode_arbitrary(buf in vec(any::<u8>(), 0..512)) {
+            // Must never panic.
+            let _ = Packet::decode(Bytes::from(buf));
+        }
     }
 }
@@ -0,0 +1,124 @@
+//! Conformance tests against packets modelled after real resolver traffic.
+//!
+//! Every file in `testdata/packets` lists the expected decoding of a packet,
+//! one line per header field, question, record and OPT record, followed by
+//! `hex` and the packet itself. Lines starting with `#` are comments.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+
+use super::{Packet, Qr, ResourceRecord};
+
+#[test]
+fn packets() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/packets");
+    let mut fixtures: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty());
+
+    for path in fixtures {
+        check(&path);
+    }
+}
+
+fn check(path: &Path) {
+    let fixture = std::fs::read_to_string(path).unwrap();
+    let (expected, hex) = fixture
+        .split_once("\nhex\n")
+        .unwrap_or_else(|| panic!("{}: missing hex section", path.display()));
+    let expected: Vec<&str> = expected
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect();
+
+    let packet = Packet::decode(Bytes::from(decode_hex(hex)))
+        .unwrap_or_else(|err| panic!("{}: failed to decode: {:?}", path.display(), err));
+    assert_eq!(describe(&packet), expected, "{}", path.display());
+
+    // Names are not compressed when encoding, but the packet must survive
+    // the round-trip unchanged.
+    let mut buf = Vec::new();
+    packet.encode(&mut buf);
+    assert_eq!(buf.len(), packet.encoded_len(), "{}", path.display());
+    assert_eq!(
+        Packet::decode(Bytes::from(buf)).unwrap(),
+        packet,
+        "{}",
+        path.display()
+    );
+}
+
+fn describe(packet: &Packet) -> Vec<String> {
+    let mut flags = String::from("flags");
+    for (name, set) in [
+        ("qr", packet.qr == Qr::Response),
+        ("aa", packet.authoritative_answer),
+        ("tc", packet.truncated),
+        ("rd", packet.recursion_desired),
+        ("ra", packet.recursion_available),
+        ("ad", packet.authentic_data),
+        ("cd", packet.checking_disabled),
+    ] {
+        if set {
+            write!(flags, " {}", name).unwrap();
+        }
+    }
+
+    let mut lines = vec![flags, format!("rcode {:?}", packet.response_code)];
+    for question in &packet.questions {
+        lines.push(format!(
+            "question {} {} {}",
+            question.name, question.qclass, question.qtype
+        ));
+    }
+
+    for (section, records) in [
+        ("answer", &packet.answers),
+        ("authority", &packet.authority),
+        ("additional", &packet.additional),
+    ] {
+        lines.extend(
+            records
+                .iter()
+                .map(|record| describe_record(section, record)),
+        );
+    }
+
+    if let Some(edns) = &packet.edns {
+        let mut options = String::new();
+        for byte in &edns.options {
+            write!(options, "{:02x}", byte).unwrap();
+        }
+
+        lines.push(format!(
+            "edns {} do={} options={}",
+            edns.udp_payload_size, edns.dnssec_ok, options
+        ));
+    }
+
+    lines
+}
+
+fn describe_record(section: &str, record: &ResourceRecord) -> String {
+    format!(
+        "{} {} {} {} {} {}",
+        section, record.name, record.ttl, record.class, record.r#type, record.rdata
+    )
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    let digits: Vec<u8> = hex
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
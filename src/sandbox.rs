@@ -0,0 +1,568 @@
+//! Restrictions of the process once it is set up.
+//!
+//! Landlock limits the filesystem to reading the files named by the config
+//! and the system files needed to resolve names and users. It only applies to
+//! the calling thread and the threads it creates, so it is applied before the
+//! runtime starts its workers.
+//!
+//! The seccomp filter applied once the listeners are bound only allows the
+//! syscalls needed to serve queries and kills the process on any other one.
+
+use std::io;
+use std::path::Path;
+
+/// System paths that stay readable besides the files of the config.
+#[cfg(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SYSTEM_PATHS: &[&str] = &[
+    // Name resolution for HTTPS upstreams and the user database.
+    "/etc",
+    // The usual target of /etc/resolv.conf with systemd-resolved.
+    "/run/systemd/resolve",
+    // NSS modules loaded by the libc while resolving names.
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    // Interface states and cgroup limits.
+    "/sys",
+    "/proc/self",
+    "/proc/sys/kernel/hostname",
+];
+
+/// Restricts the filesystem to reading `files` and the [`SYSTEM_PATHS`].
+///
+/// Returns `Ok(false)` if the kernel doesn't support Landlock.
+#[cfg(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn restrict_files(files: &[&Path]) -> io::Result<bool> {
+    let system = SYSTEM_PATHS.iter().map(Path::new);
+    landlock::restrict_self(files.iter().copied().chain(system))
+}
+
+#[cfg(not(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn restrict_files(_: &[&Path]) -> io::Result<bool> {
+    Err(unsupported())
+}
+
+/// Applies the seccomp filter to all threads of the process.
+#[cfg(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn apply() -> io::Result<()> {
+    seccomp::install(&seccomp::filter())
+}
+
+#[cfg(not(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn apply() -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "built without the sandbox feature or on an unsupported platform",
+    )
+}
+
+#[cfg(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod landlock {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+
+    /// Constants and structures from `linux/landlock.h`.
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// The rights up to `MAKE_SYM` of the first version.
+    const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+    const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Restricts the calling thread to reading `paths`. Directories are
+    /// readable with everything beneath them, missing paths are skipped.
+    pub fn restrict_self<'a>(paths: impl Iterator<Item = &'a Path>) -> io::Result<bool> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENOSYS | libc::EOPNOTSUPP) => Ok(false),
+                _ => Err(io::Error::last_os_error()),
+            };
+        }
+
+        // Rights unknown to the kernel would be rejected.
+        let handled_access_fs = match abi {
+            1 => ACCESS_FS_V1,
+            2 => ACCESS_FS_V1 | ACCESS_FS_REFER,
+            3 | 4 => ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE,
+            _ => ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE | ACCESS_FS_IOCTL_DEV,
+        };
+        let attr = RulesetAttr { handled_access_fs };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        for path in paths {
+            let file = match File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+            {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            // Only rights that apply to files may be granted on files.
+            let allowed_access = if file.metadata()?.is_dir() {
+                ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR
+            } else {
+                ACCESS_FS_READ_FILE
+            };
+            let rule = PathBeneathAttr {
+                allowed_access,
+                parent_fd: file.as_raw_fd(),
+            };
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &rule,
+                    0,
+                )
+            };
+            if res != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // Required to restrict the thread without CAP_SYS_ADMIN.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let res =
+            unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(true)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::path::Path;
+
+        use super::restrict_self;
+
+        #[test]
+        fn restricts_reads() {
+            let dir = std::env::temp_dir().join(format!("rdns-landlock-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let allowed = dir.join("allowed");
+            std::fs::write(&allowed, "allowed").unwrap();
+            let denied = dir.join("denied");
+            std::fs::write(&denied, "denied").unwrap();
+
+            // Landlock applies to the calling thread only.
+            let (read_allowed, read_denied, write_allowed) = std::thread::spawn({
+                let allowed = allowed.clone();
+                let denied = denied.clone();
+                move || {
+                    let missing = Path::new("/nonexistent/rdns");
+                    let paths = [allowed.as_path(), missing];
+                    if !restrict_self(paths.into_iter()).unwrap() {
+                        return (true, false, false);
+                    }
+
+                    (
+                        std::fs::read_to_string(&allowed).is_ok(),
+                        std::fs::read_to_string(&denied).is_ok(),
+                        std::fs::write(&allowed, "written").is_ok(),
+                    )
+                }
+            })
+            .join()
+            .unwrap();
+
+            assert!(read_allowed);
+            assert!(!read_denied);
+            assert!(!write_allowed);
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+#[cfg(all(
+    feature = "sandbox",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    use std::io;
+
+    use libc::{
+        sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET,
+        BPF_W, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+    };
+
+    /// `AUDIT_ARCH_X86_64` from `linux/audit.h`.
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    /// `AUDIT_ARCH_AARCH64` from `linux/audit.h`.
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Offsets of the fields of `struct seccomp_data`.
+    const OFFSET_NR: u32 = 0;
+    const OFFSET_ARCH: u32 = 4;
+    /// The lower half of the first argument on little-endian architectures.
+    const OFFSET_ARG0: u32 = 16;
+
+    /// The flags of `clone` that create new namespaces.
+    const CLONE_NAMESPACES: u32 = (libc::CLONE_NEWNS
+        | libc::CLONE_NEWCGROUP
+        | libc::CLONE_NEWUTS
+        | libc::CLONE_NEWIPC
+        | libc::CLONE_NEWUSER
+        | libc::CLONE_NEWPID
+        | libc::CLONE_NEWNET) as u32;
+
+    /// The syscalls of the runtime, the libc and the TLS libraries while
+    /// serving. `clone` is allowed separately, only for threads.
+    const ALLOWED: &[libc::c_long] = &[
+        // Memory.
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        // Threads and synchronization.
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_set_tid_address,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_prctl,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        // Signals.
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        // Time and randomness.
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        libc::SYS_getrandom,
+        // Files, restricted further by Landlock.
+        libc::SYS_read,
+        libc::SYS_readv,
+        libc::SYS_pread64,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_openat,
+        libc::SYS_close,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_lseek,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_getdents64,
+        libc::SYS_faccessat,
+        libc::SYS_faccessat2,
+        libc::SYS_readlinkat,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_eventfd2,
+        // Polling.
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_epoll_pwait2,
+        libc::SYS_ppoll,
+        // Sockets.
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_connect,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_shutdown,
+        // System information, e.g. for sorting the results of getaddrinfo.
+        libc::SYS_uname,
+        libc::SYS_sysinfo,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_prlimit64,
+        // Legacy variants still used by the libc on x86_64.
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_stat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_lstat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_access,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_readlink,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_pipe,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_epoll_wait,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_arch_prctl,
+    ];
+
+    /// Builds the BPF program of the filter.
+    pub fn filter() -> Vec<sock_filter> {
+        let allow = stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW);
+        let kill = stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS);
+
+        let mut filter = vec![
+            // Syscall numbers are only meaningful for the native architecture.
+            stmt(BPF_LD | BPF_W | BPF_ABS, OFFSET_ARCH),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            kill,
+            stmt(BPF_LD | BPF_W | BPF_ABS, OFFSET_NR),
+        ];
+
+        // The x32 ABI shares the architecture of x86_64, its syscalls have
+        // the same numbers with the x32 bit set.
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            jump(BPF_JMP | libc::BPF_JGE | BPF_K, 0x4000_0000, 0, 1),
+            kill,
+        ]);
+
+        filter.extend([
+            // Threads are created with `clone`, new namespaces are not.
+            jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 4),
+            stmt(BPF_LD | BPF_W | BPF_ABS, OFFSET_ARG0),
+            jump(BPF_JMP | BPF_JSET | BPF_K, CLONE_NAMESPACES, 0, 1),
+            kill,
+            allow,
+            // The flags of `clone3` are behind a pointer. The libc falls back
+            // to `clone` if it is not implemented.
+            jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1),
+            stmt(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        ]);
+
+        for nr in ALLOWED {
+            filter.extend([jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 1), allow]);
+        }
+
+        filter.push(kill);
+        filter
+    }
+
+    /// Installs `filter` on all threads of the process.
+    ///
+    /// Only async-signal-safe functions are called, so this may be used
+    /// after `fork`.
+    pub fn install(filter: &[sock_filter]) -> io::Result<()> {
+        // Required to install a filter without CAP_SYS_ADMIN.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let prog = sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr().cast_mut(),
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog,
+            )
+        };
+        match res {
+            0 => Ok(()),
+            // A thread already has a different filter, the result is the ID
+            // of that thread.
+            res if res > 0 => Err(io::Error::other("failed to synchronize threads")),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn stmt(code: u32, k: u32) -> sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{filter, install};
+
+        /// Runs `f` in a child process with the filter installed and returns
+        /// its wait status.
+        fn sandboxed(f: fn() -> i32) -> i32 {
+            // The filter applies to the whole process, so it is only
+            // installed in a child.
+            let filter = filter();
+            let pid = unsafe { libc::fork() };
+            if pid == 0 {
+                let code = match install(&filter) {
+                    Ok(()) => f(),
+                    Err(_) => 2,
+                };
+                unsafe { libc::_exit(code) };
+            }
+
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            status
+        }
+
+        fn killed(status: i32) -> bool {
+            libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSYS
+        }
+
+        #[test]
+        fn allows_syscalls() {
+            let status = sandboxed(|| {
+                let mut fds = [0; 2];
+                let allowed = unsafe { libc::getpid() } > 0
+                    && unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == 0;
+                i32::from(!allowed)
+            });
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+
+        #[test]
+        fn kills_on_other_syscalls() {
+            let status = sandboxed(|| unsafe { libc::setuid(libc::getuid()) });
+            assert!(killed(status));
+
+            let status = sandboxed(|| unsafe { libc::unshare(libc::CLONE_NEWUSER) });
+            assert!(killed(status));
+
+            let status = sandboxed(|| unsafe {
+                libc::syscall(libc::SYS_io_uring_setup, 1, std::ptr::null_mut::<u8>()) as i32
+            });
+            assert!(killed(status));
+        }
+
+        #[test]
+        fn restricts_clone() {
+            let status = sandboxed(|| unsafe {
+                let flags = libc::CLONE_NEWNET | libc::SIGCHLD;
+                libc::syscall(libc::SYS_clone, flags, 0, 0, 0, 0) as i32
+            });
+            assert!(killed(status));
+
+            let status = sandboxed(|| {
+                let res = unsafe { libc::syscall(libc::SYS_clone3, 0, 0) };
+                let enosys = std::io::Error::last_os_error().raw_os_error() == Some(libc::ENOSYS);
+                i32::from(!(res == -1 && enosys))
+            });
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        #[test]
+        fn kills_x32_syscalls() {
+            let status =
+                sandboxed(|| unsafe { libc::syscall(0x4000_0000 | libc::SYS_getpid) as i32 });
+            assert!(killed(status));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Graceful shutdown.
+//!
+//! Once triggered, the frontends stop accepting new queries and connections.
+//! Work that is still in progress holds a [`Guard`], so that the shutdown can
+//! wait for it to drain.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{select_biased, FutureExt};
+use tokio::sync::{watch, Notify};
+
+#[derive(Debug)]
+pub struct Shutdown {
+    triggered: watch::Sender<bool>,
+    active: AtomicUsize,
+    drained: Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            triggered: watch::Sender::new(false),
+            active: AtomicUsize::new(0),
+            drained: Notify::new(),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    /// Completes once the shutdown was triggered.
+    pub async fn triggered(&self) {
+        let mut rx = self.triggered.subscribe();
+        // The sender lives as long as `self`.
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Tracks work in progress until the returned guard is dropped.
+    pub fn guard(&self) -> Guard<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Guard { shutdown: self }
+    }
+
+    /// Completes once all guards were dropped.
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.drained.notified();
+            if self.active.load(Ordering::Acquire) == 0 {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Work in progress, see [`Shutdown::guard`].
+#[derive(Debug)]
+pub struct Guard<'a> {
+    shutdown: &'a Shutdown,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        if self.shutdown.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shutdown.drained.notify_waiters();
+        }
+    }
+}
+
+/// Completes once the process receives SIGINT or SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    select_biased! {
+        res = tokio::signal::ctrl_c().fuse() => {
+            if let Err(err) = res {
+                tracing::error!("failed to listen for SIGINT: {}", err);
+                std::future::pending::<()>().await;
+            }
+        },
+        () = terminate.fuse() => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::Shutdown;
+
+    #[test]
+    fn drained_waits_for_guards() {
+        let shutdown = Shutdown::new();
+        assert!(shutdown.triggered().now_or_never().is_none());
+        shutdown.trigger();
+        assert!(shutdown.triggered().now_or_never().is_some());
+
+        let first = shutdown.guard();
+        let second = shutdown.guard();
+        let mut drained = Box::pin(shutdown.drained());
+        assert!(drained.as_mut().now_or_never().is_none());
+
+        drop(first);
+        assert!(drained.as_mut().now_or_never().is_none());
+        drop(second);
+        assert!(drained.as_mut().now_or_never().is_some());
+    }
+}
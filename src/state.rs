@@ -1,79 +1,186 @@
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use futures::{select_biased, FutureExt};
-use reqwest::Url;
-use tokio::sync::Notify;
+use bytes::Bytes;
+use futures::stream::FuturesUnordered;
+use futures::{select_biased, FutureExt, StreamExt};
+use reqwest::{ClientBuilder, Url};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio_rustls::rustls::pki_types::ServerName;
 
-use crate::cache::{Cache, Resource};
-use crate::config::Config;
+use crate::cache::{self, Cache, Resource};
+use crate::config::{self, Config, LocalZone, ResolverConfig};
+use crate::ddr;
+use crate::diff::{self, Job, Outcome};
+use crate::local::{self, LocalNames};
 use crate::metrics::Metrics;
-use crate::proto::{Fqdn, Question, RecordData, Type};
-use crate::upstream::https::HttpsResolver;
+use crate::proto::{Class, Fqdn, Question, RecordData, ResponseCode, Type};
+use crate::shutdown::Shutdown;
+use crate::upstream::discovery::{Backend, DiscoveryResolver};
+#[cfg(feature = "fault-injection")]
+use crate::upstream::fault::FaultyResolver;
+use crate::upstream::https::bootstrap::Bootstrap;
+use crate::upstream::https::{self, tls, ClientOptions, HttpsResolver};
+use crate::upstream::interface::Interface;
+use crate::upstream::limit::LimitedResolver;
+use crate::upstream::proxy::Proxy;
+use crate::upstream::rate::RateLimitedResolver;
+use crate::upstream::registry::{self, Registry};
+use crate::upstream::retry::RetryingResolver;
+use crate::upstream::system::SystemResolver;
+use crate::upstream::tcp::{KeepAlive, TcpResolver, Tls};
+use crate::upstream::udp::upgrade::TlsUpgrade;
 use crate::upstream::udp::UdpResolver;
-use crate::upstream::{Resolver, ResolverError, Zones};
+use crate::upstream::{
+    self, Answer, QueryFlags, QueryProfile, Resolver, ResolverError, SocketOptions, Upstream, Zones,
+};
+
+/// Maximum number of answers waiting to be compared against shadow upstreams.
+const DIFF_QUEUE_SIZE: usize = 1024;
+/// Maximum number of concurrent queries to shadow upstreams.
+const DIFF_CONCURRENCY: usize = 16;
+
+/// The answer to a single question.
+#[derive(Clone, Debug, Default)]
+pub struct Resolution {
+    pub resources: Vec<Resource>,
+    /// EDNS options of the upstream responses passed on to the client.
+    pub options: Vec<u8>,
+}
+
+impl From<Vec<Resource>> for Resolution {
+    fn from(resources: Vec<Resource>) -> Self {
+        Self {
+            resources,
+            options: Vec::new(),
+        }
+    }
+}
+
+/// A record found by [`State::lookup_cache`].
+struct Cached {
+    answer: Resource,
+    /// The question for the target of a CNAME `answer`.
+    next: Option<Question>,
+}
 
 pub struct State {
     pub cache: Cache,
     pub zones: Zones,
     pub config: Config,
     pub metrics: Metrics,
+    pub shutdown: Shutdown,
+    /// Shadow upstreams used to compare answers.
+    pub diff_zones: Zones,
+    local: LocalNames,
+    /// SVCB records of the encrypted frontends, see [`ddr`].
+    ddr: Vec<RecordData>,
+    /// Domains that may be resolved. `None` if all domains may be resolved.
+    allowlist: Option<Vec<Fqdn>>,
+    /// Builds the upstreams with custom transports.
+    registry: Registry,
     cache_wakeup: Notify,
+    diff_tx: mpsc::Sender<Job>,
+    diff_rx: Mutex<mpsc::Receiver<Job>>,
 }
 
 impl State {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self, String> {
+        Self::with_registry(config, Registry::default())
+    }
+
+    /// Returns the state with the custom transports of `registry`.
+    ///
+    /// Fails if an upstream with a custom transport cannot be built.
+    pub fn with_registry(config: Config, registry: Registry) -> Result<Self, String> {
+        let (diff_tx, diff_rx) = mpsc::channel(DIFF_QUEUE_SIZE);
+        let local = Self::local_names(&config);
+        let ddr = ddr::records(&config);
+        let allowlist = config.allowlist.enabled.then(|| {
+            config
+                .allowlist
+                .domains
+                .iter()
+                .map(|domain| Fqdn::new_unchecked(domain.clone()))
+                .collect()
+        });
+
         let mut this = Self {
             cache: Cache::default(),
             zones: Zones::default(),
+            diff_zones: Zones::default(),
+            local,
+            ddr,
+            allowlist,
+            registry,
             cache_wakeup: Notify::default(),
             metrics: Metrics::default(),
+            shutdown: Shutdown::new(),
+            diff_tx,
+            diff_rx: Mutex::new(diff_rx),
             config,
         };
-        this.generate_zones();
-        this
+        this.generate_zones()?;
+        Ok(this)
     }
 
     /// Resolve a single [`Question`].
-    pub async fn resolve(&self, question: &Question) -> Result<Vec<Resource>, ResolverError> {
+    ///
+    /// The `flags` of the client are forwarded to the upstreams. With the CD
+    /// bit set their answers may not be validated and are therefore not
+    /// cached. Answers to the DO bit are cached apart from the others.
+    /// Answers for a client subnet are specific to the client and bypass the
+    /// cache entirely. The client subnet is sent according to the policy of
+    /// the zone.
+    pub async fn resolve(
+        &self,
+        question: &Question,
+        flags: &QueryFlags,
+    ) -> Result<Resolution, ResolverError> {
+        if question.qclass == Class::Ch {
+            return self.resolve_chaos(question).map(Resolution::from);
+        }
+
+        if let Some(answers) = self.resolve_ddr(question) {
+            return Ok(answers.into());
+        }
+
+        if let Some(answers) = self.resolve_local(question) {
+            return Ok(answers.into());
+        }
+
+        if !self.is_allowed(&question.name) {
+            tracing::info!("denied query for {} (not in allowlist)", question.name);
+            return Err(ResolverError::ResponseCode(
+                ResponseCode::NameError,
+                Bytes::new(),
+            ));
+        }
+
         let mut answers = Vec::new();
+        let mut options = Vec::new();
 
         let mut question_slot = Some(question.clone());
         while let Some(question) = question_slot.take() {
-            // If we have an exact match in the cache, return it.
-            if let Some(answer) = self.cache.get(&question) {
-                self.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
-                tracing::debug!("using cached result (valid for {:?})", answer.ttl());
-
-                answers.push(answer);
-                continue;
+            // Cached CNAME chains end at the first denied target, like the
+            // records stripped in `resolve_origin`.
+            if !self.is_allowed(&question.name) {
+                tracing::info!(
+                    "stopped CNAME chain at {} (not in allowlist)",
+                    question.name
+                );
+                break;
             }
 
-            // If we fail to find a RR for the requested `question` we
-            // have to check whether we have a CNAME record on the FQDN.
-            // If we do we need to resolve the FQDN that the CNAME points
-            // at and repeat the `question` with the new FQDN.
-            // See https://datatracker.ietf.org/doc/html/rfc1034#section-3.6.2
-            if question.qtype != Type::CNAME {
-                if let Some(answer) = self.cache.get(&Question {
-                    name: question.name.clone(),
-                    qtype: Type::CNAME,
-                    qclass: question.qclass,
-                }) {
-                    let origin = match &answer.data {
-                        RecordData::CNAME(fqdn) => fqdn.clone(),
-                        _ => continue,
-                    };
-
-                    answers.push(answer);
-                    question_slot = Some(Question {
-                        name: origin,
-                        qtype: question.qtype,
-                        qclass: question.qclass,
-                    });
+            // The targets of CNAME chains may be in zones with another policy.
+            let flags = self.apply_client_subnet(&question, flags);
 
-                    continue;
-                }
+            if let Some(cached) = self.lookup_cache(&question, &flags) {
+                question_slot = cached.next;
+                answers.push(cached.answer);
+                continue;
             }
 
             // If we don't have the answer in the cache, resolve it from
@@ -81,87 +188,698 @@ impl State {
             // Note that blocking is ok here since if this function is called
             // multiple times, we have a dependency on the previous record
             // and cannot resolve concurrently.
-            answers.extend(self.resolve_origin(&question).await?);
+            let resolution = self.resolve_origin(&question, &flags).await?;
+            answers.extend(resolution.resources);
+            options.extend(resolution.options);
         }
 
+        // Following a CNAME chain may return the same records more than once.
+        cache::dedup(&mut answers);
+
         if !answers.is_empty() {
-            Ok(answers)
+            Ok(Resolution {
+                resources: answers,
+                options,
+            })
         } else {
             Err(ResolverError::NoAnswer)
         }
     }
 
-    async fn resolve_origin(&self, question: &Question) -> Result<Vec<Resource>, ResolverError> {
-        let Some(resolvers) = self.zones.lookup(&question.name) else {
+    /// Answers `question` from the cache only, following cached CNAME chains.
+    ///
+    /// Returns `None` if the answer is not cached or not taken from the cache
+    /// at all, e.g. for local or denied names. Those questions go through [`State::resolve`].
+    pub fn resolve_cached(&self, question: &Question, flags: &QueryFlags) -> Option<Resolution> {
+        if question.qclass != Class::In
+            || self.resolve_ddr(question).is_some()
+            || self.resolve_local(question).is_some()
+            || !self.is_allowed(&question.name)
+        {
+            return None;
+        }
+
+        let mut answers = Vec::new();
+        let mut question_slot = Some(question.clone());
+        while let Some(question) = question_slot.take() {
+            if !self.is_allowed(&question.name) {
+                break;
+            }
+
+            let flags = self.apply_client_subnet(&question, flags);
+            let cached = self.lookup_cache(&question, &flags)?;
+            question_slot = cached.next;
+            answers.push(cached.answer);
+        }
+
+        cache::dedup(&mut answers);
+        Some(answers.into())
+    }
+
+    /// Looks up `question` in the cache.
+    ///
+    /// If only a CNAME record for the name is cached, it is returned with the
+    /// question for its target.
+    fn lookup_cache(&self, question: &Question, flags: &QueryFlags) -> Option<Cached> {
+        if !flags.is_shared() {
+            return None;
+        }
+
+        // If we have an exact match in the cache, return it.
+        if let Some(answer) = self.cache.get(question, flags.dnssec_ok) {
+            self.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!("using cached result (valid for {:?})", answer.ttl());
+
+            return Some(Cached { answer, next: None });
+        }
+
+        // If we fail to find a RR for the requested `question` we
+        // have to check whether we have a CNAME record on the FQDN.
+        // If we do we need to resolve the FQDN that the CNAME points
+        // at and repeat the `question` with the new FQDN.
+        // See https://datatracker.ietf.org/doc/html/rfc1034#section-3.6.2
+        if question.qtype == Type::CNAME {
+            return None;
+        }
+
+        let answer = self.cache.get(
+            &Question {
+                name: question.name.clone(),
+                qtype: Type::CNAME,
+                qclass: question.qclass,
+            },
+            flags.dnssec_ok,
+        )?;
+        let RecordData::CNAME(target) = &answer.data else {
+            return None;
+        };
+
+        let next = Question {
+            name: target.clone(),
+            qtype: question.qtype,
+            qclass: question.qclass,
+        };
+        Some(Cached {
+            answer,
+            next: Some(next),
+        })
+    }
+
+    /// Answers the CHAOS class introspection names.
+    ///
+    /// See https://datatracker.ietf.org/doc/html/rfc4892
+    fn resolve_chaos(&self, question: &Question) -> Result<Vec<Resource>, ResolverError> {
+        let chaos = &self.config.chaos;
+        if !chaos.enabled || question.qtype != Type::TXT {
+            return Err(ResolverError::Refused);
+        }
+
+        let txt = match question.name.as_bytes().to_ascii_lowercase().as_slice() {
+            b"version.bind." | b"version.server." => chaos.version.as_ref(),
+            b"hostname.bind." => chaos.hostname.as_ref(),
+            b"id.server." => chaos.id.as_ref(),
+            _ => None,
+        };
+
+        let Some(txt) = txt else {
+            return Err(ResolverError::Refused);
+        };
+
+        Ok(vec![Resource {
+            name: question.name.clone(),
+            r#type: Type::TXT,
+            class: Class::Ch,
+            data: RecordData::TXT(vec![txt.clone()]),
+            valid_until: Instant::now(),
+        }])
+    }
+
+    /// Answers the discovery of the encrypted frontends.
+    ///
+    /// Returns `None` if DDR is disabled or the question is for another name.
+    fn resolve_ddr(&self, question: &Question) -> Option<Vec<Resource>> {
+        if self.ddr.is_empty()
+            || question.qclass != Class::In
+            || !question
+                .name
+                .as_bytes()
+                .eq_ignore_ascii_case(ddr::NAME.as_bytes())
+        {
+            return None;
+        }
+
+        if question.qtype != Type::SVCB {
+            return Some(Vec::new());
+        }
+
+        Some(
+            self.ddr
+                .iter()
+                .map(|data| Resource {
+                    name: question.name.clone(),
+                    r#type: Type::SVCB,
+                    class: Class::In,
+                    data: data.clone(),
+                    valid_until: Instant::now(),
+                })
+                .collect(),
+        )
+    }
+
+    /// Answers questions for the names and addresses of the server itself.
+    ///
+    /// Returns `None` if the name is not local. Local names without a record
+    /// of the requested type are answered with no records.
+    fn resolve_local(&self, question: &Question) -> Option<Vec<Resource>> {
+        if question.qclass != Class::In {
+            return None;
+        }
+
+        let records = self.local.lookup(&question.name, question.qtype)?;
+        Some(
+            records
+                .into_iter()
+                .map(|data| Resource {
+                    name: question.name.clone(),
+                    r#type: question.qtype,
+                    class: Class::In,
+                    data,
+                    valid_until: Instant::now(),
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns `true` if `name` may be resolved from the upstreams.
+    pub fn is_allowed(&self, name: &Fqdn) -> bool {
+        match &self.allowlist {
+            Some(domains) => domains.iter().any(|domain| name.is_subdomain_of(domain)),
+            None => true,
+        }
+    }
+
+    fn local_names(config: &Config) -> LocalNames {
+        let local = &config.local;
+        if !local.enabled {
+            return LocalNames::default();
+        }
+
+        let mut names = local.names.clone();
+        if names.is_empty() {
+            names.extend(local::hostname());
+        }
+
+        let mut addrs = local.addrs.clone();
+        if addrs.is_empty() {
+            addrs.extend(
+                config
+                    .bind
+                    .iter()
+                    .map(|bind| bind.addr.ip())
+                    .filter(|ip| !ip.is_unspecified()),
+            );
+        }
+
+        LocalNames::new(&names, &addrs)
+    }
+
+    /// Returns `flags` with the client subnet policy of the zone of `question`.
+    fn apply_client_subnet(&self, question: &Question, flags: &QueryFlags) -> QueryFlags {
+        let policy = self
+            .zones
+            .lookup_zone(&question.name)
+            .map(|(zone, _)| self.zones.strategy(zone).client_subnet)
+            .unwrap_or_default();
+        flags.with_policy(policy)
+    }
+
+    /// Resolves `question` from the upstreams of its zone.
+    ///
+    /// The span and the debug events tell which upstreams were tried in
+    /// which order and how long every attempt took.
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(name = %question.name, qtype = ?question.qtype, zone = tracing::field::Empty),
+    )]
+    async fn resolve_origin(
+        &self,
+        question: &Question,
+        flags: &QueryFlags,
+    ) -> Result<Resolution, ResolverError> {
+        // Names in local zones must never reach an upstream.
+        if let Some(code) = self.zones.lookup_local(&question.name) {
+            tracing::debug!("answering {} locally with {:?}", question.name, code);
+            return Err(ResolverError::ResponseCode(code, Bytes::new()));
+        }
+
+        // Answers that are the same for all clients are cached.
+        let shared = flags.is_shared() && !flags.checking_disabled;
+        // DNSSEC records would show up as differences between upstreams.
+        let compared = shared && !flags.dnssec_ok;
+
+        let Some((zone, upstreams)) = self.zones.lookup_zone(&question.name) else {
             tracing::error!("no nameservers for root zone configured");
             return Err(ResolverError::NoAnswer);
         };
+        let zone_name = String::from_utf8_lossy(zone);
+        tracing::Span::current().record("zone", &*zone_name);
+
+        // The first upstreams are queried concurrently. Every failed query
+        // is replaced by one to the next upstream, and the queries still in
+        // flight are cancelled once one of them answered.
+        let start = Instant::now();
+        let strategy = self.zones.strategy(zone);
+        let mut order = upstream::order(upstreams, strategy).into_iter();
+        tracing::debug!(
+            "resolving {} {:?} in zone {} from upstreams {:?}",
+            question.name,
+            question.qtype,
+            zone_name,
+            order
+                .as_slice()
+                .iter()
+                .map(|upstream| upstream.resolver.addr())
+                .collect::<Vec<_>>()
+        );
+        let mut queries = FuturesUnordered::new();
+        for upstream in order.by_ref().take(strategy.race) {
+            queries.push(query_upstream(upstream, question, flags));
+        }
 
-        for resolver in resolvers {
-            tracing::debug!("trying upstream {}", resolver.addr());
-            let answers = match resolver.resolve(&question).await {
+        let failover = strategy.failover;
+        // The error response of the last upstream that was failed over.
+        let mut server_error = None;
+        while let Some((upstream, result)) = queries.next().await {
+            let resolver = &upstream.resolver;
+            let Answer { records, options } = match result {
                 Ok(answer) => answer,
+                // The upstream failed to answer the question itself, another
+                // upstream may succeed.
+                Err(err) if failover && err.is_server_error() => {
+                    tracing::warn!("upstream {} failed: {:?}", resolver.addr(), err);
+                    server_error = Some(err);
+                    if let Some(upstream) = order.next() {
+                        queries.push(query_upstream(upstream, question, flags));
+                    }
+                    continue;
+                }
+                // The upstream gave a definitive answer that the question
+                // cannot be answered. Asking a different upstream will not
+                // change that, so we forward the response code as is.
+                // Responses with an error code are never cached.
+                Err(ResolverError::ResponseCode(code, options)) => {
+                    tracing::debug!("upstream {} responded with {:?}", resolver.addr(), code);
+                    if compared {
+                        self.queue_diff(question, Err(code));
+                    }
+                    return Err(ResolverError::ResponseCode(code, options));
+                }
                 Err(err) => {
                     tracing::error!("upstream {} failed: {:?}", resolver.addr(), err);
+                    if let Some(upstream) = order.next() {
+                        queries.push(query_upstream(upstream, question, flags));
+                    }
                     continue;
                 }
             };
 
-            let mut resources = Vec::new();
-            for answer in answers {
-                let res = Resource {
+            if compared {
+                self.queue_diff(question, Ok(records.clone()));
+            }
+
+            let mut resources: Vec<_> = records
+                .into_iter()
+                .map(|answer| Resource {
                     name: answer.name,
                     r#type: answer.r#type,
                     class: answer.class,
-                    data: answer.rdata.into(),
-                    valid_until: Instant::now() + Duration::from_secs(answer.ttl.into()),
-                };
+                    data: answer.rdata,
+                    valid_until: Instant::now()
+                        + Duration::from_secs(strategy.ttl(answer.ttl).into()),
+                })
+                .collect();
+
+            // Some upstreams return the same record multiple times.
+            cache::dedup(&mut resources);
+
+            // Upstreams follow CNAME chains themselves, their targets must
+            // be allowed like the question.
+            resources.retain(|res| {
+                let allowed = self.is_allowed(&res.name);
+                if !allowed {
+                    tracing::info!("stripped record for {} (not in allowlist)", res.name);
+                }
+                allowed
+            });
 
-                if answer.ttl != 0 {
-                    self.cache.insert(res.clone());
+            for res in &resources {
+                if !res.ttl().is_zero() && shared {
+                    self.cache.insert(res.clone(), flags.dnssec_ok);
                     self.cache_wakeup.notify_one();
                     self.metrics
                         .cache_size
                         .fetch_add(res.data.len() as u64, Ordering::Relaxed);
                 }
-
-                resources.push(res);
             }
 
-            return Ok(resources);
+            tracing::debug!(
+                "resolved {} {:?} from upstream {} in {:?}",
+                question.name,
+                question.qtype,
+                resolver.addr(),
+                start.elapsed()
+            );
+            return Ok(Resolution {
+                resources,
+                options: options.into(),
+            });
+        }
+
+        tracing::debug!(
+            "no upstream answered {} {:?} within {:?}",
+            question.name,
+            question.qtype,
+            start.elapsed()
+        );
+
+        // Zones that are only reachable through a tunnel must never fall
+        // back to other upstreams, but we want to tell why they fail.
+        if !upstreams
+            .iter()
+            .any(|upstream| upstream.resolver.is_available())
+        {
+            tracing::warn!(
+                "zone for {:?} is degraded: all upstream interfaces are down",
+                question.name
+            );
         }
 
-        Err(ResolverError::NoAnswer)
+        Err(server_error.unwrap_or(ResolverError::NoAnswer))
     }
 
-    pub fn generate_zones(&mut self) {
+    pub fn generate_zones(&mut self) -> Result<(), String> {
         self.zones.clear();
+        self.diff_zones.clear();
 
         for (zone, resolvers) in &self.config.zones {
-            for resolver in resolvers {
-                let resolver = match resolver {
-                    crate::config::ResolverConfig::Udp(conf) => Resolver::Udp(UdpResolver::new(
-                        conf.addr,
-                        Duration::from_secs(conf.timeout),
-                    )),
-                    crate::config::ResolverConfig::Https(conf) => {
-                        Resolver::Https(HttpsResolver::new(
-                            Url::parse(&conf.url).unwrap(),
+            for conf in resolvers {
+                let upstream = self.build_upstream(conf)?;
+                self.zones
+                    .insert(Fqdn::new_unchecked(zone.clone()), upstream);
+            }
+        }
+
+        for (zone, strategy) in &self.config.strategy {
+            self.zones
+                .set_strategy(Fqdn::new_unchecked(zone.clone()), strategy.into());
+        }
+
+        for (zone, local) in &self.config.local_zones {
+            let code = match local {
+                LocalZone::NxDomain => ResponseCode::NameError,
+                LocalZone::Refused => ResponseCode::Refused,
+            };
+            self.zones
+                .set_local(Fqdn::new_unchecked(zone.clone()), code);
+        }
+
+        for (zone, diff) in &self.config.diff {
+            for conf in &diff.resolvers {
+                let upstream = self.build_upstream(conf)?;
+                self.diff_zones
+                    .insert(Fqdn::new_unchecked(zone.clone()), upstream);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build_upstream(&self, conf: &ResolverConfig) -> Result<Upstream, String> {
+        let resolver = self.build_resolver(conf)?;
+        let metrics = self.metrics.upstream_times.get(resolver.id()).unwrap();
+        Ok(Upstream::new(
+            resolver,
+            conf.tier(),
+            conf.transport(),
+            metrics,
+        ))
+    }
+
+    fn build_resolver(&self, conf: &ResolverConfig) -> Result<Resolver, String> {
+        let resolver = match conf {
+            ResolverConfig::Udp(conf) => {
+                let id = self.metrics.upstream_times.register(&conf.addr.to_string());
+                let mut resolver = UdpResolver::new(
+                    id,
+                    self.metrics.upstream_times.get(id).unwrap(),
+                    conf.addr,
+                    Duration::from_secs(conf.timeout),
+                    QueryProfile {
+                        payload_size: Some(conf.payload_size),
+                        ..QueryProfile::for_mode(conf.mode)
+                    },
+                    socket_options(conf.source, &conf.interface, &conf.proxy),
+                    self.config.dscp,
+                );
+                if let Some(keepalive) = &conf.keepalive {
+                    resolver = resolver.with_keepalive(keepalive_probes(keepalive));
+                }
+                if let Some(upgrade) = &conf.tls_upgrade {
+                    resolver = resolver.with_tls_upgrade(tls_upgrade(conf.addr, upgrade));
+                }
+                Resolver::new(resolver)
+            }
+            ResolverConfig::Tcp(conf) => {
+                let id = self.metrics.upstream_times.register(&conf.addr.to_string());
+                let mut resolver = TcpResolver::new(
+                    id,
+                    self.metrics.upstream_times.get(id).unwrap(),
+                    conf.addr,
+                    Duration::from_secs(conf.timeout),
+                    QueryProfile::for_mode(conf.mode),
+                    socket_options(conf.source, &conf.interface, &conf.proxy),
+                    self.config.dscp,
+                );
+                if let Some(keepalive) = &conf.keepalive {
+                    resolver = resolver.with_keepalive(keepalive_probes(keepalive));
+                }
+                Resolver::new(resolver)
+            }
+            ResolverConfig::Https(conf) => {
+                let url = Url::parse(&conf.url).unwrap();
+
+                // Upstreams given by their IP address need no bootstrap.
+                let bootstrap = match (conf.bootstrap, url.domain()) {
+                    (Some(addr), Some(host)) => {
+                        let id = self.metrics.upstream_times.register(&addr.to_string());
+                        let resolver = UdpResolver::new(
+                            id,
+                            self.metrics.upstream_times.get(id).unwrap(),
+                            addr,
                             Duration::from_secs(conf.timeout),
-                        ))
+                            QueryProfile::FORWARDER,
+                            SocketOptions {
+                                source: None,
+                                interface: conf.interface.clone().map(Interface::new),
+                                proxy: None,
+                            },
+                            None,
+                        );
+                        Some(Bootstrap::new(host, Resolver::new(resolver)))
                     }
+                    _ => None,
                 };
 
-                self.zones
-                    .insert(Fqdn::new_unchecked(zone.clone()), resolver);
+                let id = self.metrics.upstream_times.register(&conf.url);
+                Resolver::new(HttpsResolver::new(
+                    id,
+                    self.metrics.upstream_times.get(id).unwrap(),
+                    url,
+                    Duration::from_secs(conf.timeout),
+                    conf.get,
+                    ClientOptions {
+                        idle_timeout: Duration::from_secs(conf.idle_timeout),
+                        max_lifetime: conf.max_lifetime.map(Duration::from_secs),
+                        headers: https::header_map(&conf.headers),
+                        socket: SocketOptions {
+                            source: conf.source,
+                            interface: conf.interface.clone().map(Interface::new),
+                            proxy: conf.proxy.clone(),
+                        },
+                        bootstrap,
+                        tls: (conf.ca_file.is_some() || !conf.pins.is_empty()).then(|| {
+                            Arc::new(tls::client_config(conf.ca_file.as_deref(), &conf.pins))
+                        }),
+                    },
+                ))
+            }
+            ResolverConfig::Consul(conf) => Resolver::new(DiscoveryResolver::new(
+                self.metrics.upstream_times.register(&conf.url),
+                Backend::Consul {
+                    url: Url::parse(&conf.url).unwrap(),
+                    token: conf.token.clone(),
+                },
+                ClientBuilder::new().use_rustls_tls().build().unwrap(),
+                Duration::from_secs(conf.timeout),
+                conf.ttl,
+            )),
+            ResolverConfig::Kubernetes(conf) => Resolver::new(DiscoveryResolver::new(
+                self.metrics.upstream_times.register(&conf.url),
+                Backend::Kubernetes {
+                    url: conf.url()?,
+                    token_file: conf.token_file.clone(),
+                },
+                conf.client()?,
+                Duration::from_secs(conf.timeout),
+                conf.ttl,
+            )),
+            ResolverConfig::System(conf) => {
+                let id = self
+                    .metrics
+                    .upstream_times
+                    .register(&conf.path.display().to_string());
+                Resolver::new(SystemResolver::new(
+                    id,
+                    self.metrics.upstream_times.get(id).unwrap(),
+                    conf.path.clone(),
+                    Duration::from_secs(conf.timeout),
+                    self.config.bind.iter().map(|bind| bind.addr).collect(),
+                ))
+            }
+            ResolverConfig::Custom(conf) => {
+                let id = self.metrics.upstream_times.register(&conf.addr);
+                let cx = registry::Context {
+                    id,
+                    metrics: self.metrics.upstream_times.get(id).unwrap(),
+                    addr: &conf.addr,
+                    timeout: Duration::from_secs(conf.timeout),
+                    options: &conf.options,
+                };
+                let resolver = self
+                    .registry
+                    .build(&conf.transport, cx)
+                    .map_err(|err| format!("upstream {}: {}", conf.addr, err))?;
+                Resolver::from_boxed(resolver)
+            }
+        };
+
+        #[cfg(feature = "fault-injection")]
+        let resolver = match self.config.faults.get(&resolver.addr()) {
+            Some(faults) => {
+                tracing::warn!("injecting faults into upstream {}", resolver.addr());
+                Resolver::new(FaultyResolver::new(resolver, faults.clone()))
+            }
+            None => resolver,
+        };
+
+        let (rate_limit, concurrency, retry) = match conf {
+            ResolverConfig::Udp(conf) => (&conf.rate_limit, &conf.concurrency, &conf.retry),
+            ResolverConfig::Tcp(conf) => (&conf.rate_limit, &conf.concurrency, &conf.retry),
+            ResolverConfig::Https(conf) => (&conf.rate_limit, &conf.concurrency, &conf.retry),
+            ResolverConfig::Consul(_)
+            | ResolverConfig::Kubernetes(_)
+            | ResolverConfig::System(_)
+            | ResolverConfig::Custom(_) => return Ok(resolver),
+        };
+
+        // Every query sent, including retries, counts against the rate.
+        let resolver = match rate_limit {
+            Some(limit) => {
+                let metrics = self.metrics.upstream_times.get(resolver.id()).unwrap();
+                Resolver::new(RateLimitedResolver::new(resolver, limit, metrics))
+            }
+            None => resolver,
+        };
+
+        // Every retry takes its own place in the limit.
+        let resolver = match concurrency {
+            Some(concurrency) => {
+                let metrics = self.metrics.upstream_times.get(resolver.id()).unwrap();
+                Resolver::new(LimitedResolver::new(resolver, concurrency, metrics))
             }
+            None => resolver,
+        };
+
+        // Retries wrap the injected faults, so that they are exercised.
+        if retry.attempts > 1 {
+            Ok(Resolver::new(RetryingResolver::new(
+                resolver,
+                retry.clone(),
+            )))
+        } else {
+            Ok(resolver)
         }
     }
 
+    /// Queues the primary answer to `question` for comparison if the zone
+    /// has shadow upstreams and the query is sampled.
+    fn queue_diff(&self, question: &Question, primary: Outcome) {
+        let Some((zone, _)) = self.diff_zones.lookup_zone(&question.name) else {
+            return;
+        };
+
+        let sample = self
+            .config
+            .diff
+            .get(&*String::from_utf8_lossy(zone))
+            .map_or(0.0, |diff| diff.sample);
+        if rand::random::<f64>() >= sample {
+            return;
+        }
+
+        // Comparisons are best-effort. If the shadow upstreams cannot keep
+        // up we drop the job instead of buffering an unbounded number of queries.
+        let job = Job {
+            question: question.clone(),
+            primary,
+        };
+        if self.diff_tx.try_send(job).is_err() {
+            tracing::debug!("diff queue is full, dropping comparison");
+        }
+    }
+
+    /// Compares queued answers against the shadow upstreams.
+    pub async fn diff(&self) {
+        let mut rx = self.diff_rx.lock().await;
+        let jobs = futures::stream::poll_fn(|cx| rx.poll_recv(cx));
+
+        jobs.for_each_concurrent(DIFF_CONCURRENCY, |job| async move {
+            let Some(resolvers) = self.diff_zones.lookup(&job.question.name) else {
+                return;
+            };
+
+            let Some(shadow) = diff::resolve(resolvers, &job.question).await else {
+                tracing::debug!("no shadow upstream answered {:?}", job.question);
+                return;
+            };
+
+            self.metrics
+                .diff_comparisons
+                .fetch_add(1, Ordering::Relaxed);
+            let diffs = diff::compare(&job.primary, &shadow);
+            if diffs.is_empty() {
+                return;
+            }
+
+            self.metrics.diff_mismatches.fetch_add(1, Ordering::Relaxed);
+            for diff in diffs {
+                tracing::warn!(
+                    "answer for {} {} differs: {}",
+                    job.question.name,
+                    job.question.qtype,
+                    diff
+                );
+            }
+        })
+        .await;
+    }
+
     pub async fn cleanup(&self) -> ! {
         loop {
             let Some(instant) = self.cache.next_expiration() else {
                 self.cache_wakeup.notified().await;
+                self.metrics.cleanup_wakeups.fetch_add(1, Ordering::Relaxed);
                 continue;
             };
 
@@ -169,16 +887,544 @@ impl State {
             // a shorter TTL gets inserted. In this case we must
             // interrupt the current sleep to ensure we always sleep
             // on the next expiration time.
-            select_biased! {
-                _ = self.cache_wakeup.notified().fuse() => continue,
-                _ = tokio::time::sleep_until(instant.into()).fuse() => (),
+            let woken_early = select_biased! {
+                _ = self.cache_wakeup.notified().fuse() => true,
+                _ = tokio::time::sleep_until(instant.into()).fuse() => false,
+            };
+            self.metrics.cleanup_wakeups.fetch_add(1, Ordering::Relaxed);
+            if woken_early {
+                continue;
             }
 
-            if let Some(record) = self.cache.remove_first() {
-                self.metrics
-                    .cache_size
-                    .fetch_sub(record.data.len() as u64, Ordering::Relaxed);
+            self.metrics
+                .expiration_lag
+                .observe(Instant::now().saturating_duration_since(instant));
+
+            // Every sweep removes all entries that expired in the meantime,
+            // not only the one it woke up for.
+            let records = self.cache.remove_expired(Instant::now());
+            let size: u64 = records
+                .iter()
+                .map(|record| u64::from(record.data.len()))
+                .sum();
+            self.metrics.cache_size.fetch_sub(size, Ordering::Relaxed);
+            let expired = records.len() as u64;
+
+            self.metrics
+                .cache_expired
+                .fetch_add(expired, Ordering::Relaxed);
+            self.metrics
+                .cleanup_last_sweep
+                .store(expired, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Resolves `question` from `resolver`, returning the resolver with the result.
+async fn query_upstream<'a>(
+    upstream: &'a Upstream,
+    question: &Question,
+    flags: &QueryFlags,
+) -> (&'a Upstream, Result<Answer, ResolverError>) {
+    tracing::debug!(
+        "trying upstream {} for {} {:?}",
+        upstream.resolver.addr(),
+        question.name,
+        question.qtype
+    );
+    let start = Instant::now();
+    let result = upstream.resolver.resolve(question, flags).await;
+    match &result {
+        Ok(answer) => tracing::debug!(
+            "upstream {} answered {} {:?} with {} records in {:?}",
+            upstream.resolver.addr(),
+            question.name,
+            question.qtype,
+            answer.records.len(),
+            start.elapsed()
+        ),
+        Err(err) => tracing::debug!(
+            "upstream {} failed {} {:?} after {:?}: {:?}",
+            upstream.resolver.addr(),
+            question.name,
+            question.qtype,
+            start.elapsed(),
+            err
+        ),
+    }
+
+    let metrics = &upstream.metrics;
+    match &result {
+        Ok(_) | Err(ResolverError::ResponseCode(..)) => {
+            metrics.histogram.observe(start.elapsed());
+            // Negative answers are answers, not failures.
+            if result.as_ref().is_err_and(ResolverError::is_server_error) {
+                metrics.failures.fetch_add(1, Ordering::Relaxed);
             }
         }
+        Err(ResolverError::Timeout) => {
+            metrics.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        // Counted by the limits themselves.
+        Err(ResolverError::Overloaded) => (),
+        Err(_) => {
+            metrics.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    match &result {
+        // The upstream is reachable, but fails to answer anything.
+        Err(err) if err.is_server_error() => upstream.record(false),
+        // Negative answers still show that the upstream is reachable.
+        Ok(_) | Err(ResolverError::ResponseCode(..)) => upstream.record(true),
+        // The upstream is busy, not unhealthy.
+        Err(ResolverError::Overloaded) => (),
+        Err(_) => upstream.record(false),
+    }
+    (upstream, result)
+}
+
+/// Returns the options of the sockets to an upstream.
+fn socket_options(
+    source: Option<IpAddr>,
+    interface: &Option<String>,
+    proxy: &Option<Proxy>,
+) -> SocketOptions {
+    SocketOptions {
+        source,
+        interface: interface.clone().map(Interface::new),
+        proxy: proxy.clone(),
+    }
+}
+
+/// Returns the probes keeping the TCP connection to an upstream open.
+fn keepalive_probes(keepalive: &config::KeepAlive) -> KeepAlive {
+    KeepAlive {
+        interval: Duration::from_secs(keepalive.interval),
+        max_idle: Duration::from_secs(keepalive.max_idle),
+        name: Fqdn::new_unchecked(keepalive.name.clone()),
+    }
+}
+
+/// Returns how queries to the upstream at `addr` are upgraded to DNS over
+/// TLS.
+fn tls_upgrade(addr: SocketAddr, upgrade: &config::TlsUpgrade) -> TlsUpgrade {
+    let (verifier, server_name) = match &upgrade.server_name {
+        Some(name) => {
+            // Checked by Config::validate.
+            let server_name = ServerName::try_from(name.clone()).unwrap();
+            let verifier = tls::server_verifier(upgrade.ca_file.as_deref(), &upgrade.pins);
+            (verifier, server_name)
+        }
+        None => {
+            let server_name = ServerName::IpAddress(addr.ip().into());
+            (tls::opportunistic_verifier(), server_name)
+        }
+    };
+
+    TlsUpgrade {
+        port: upgrade.port,
+        retry: Duration::from_secs(upgrade.retry),
+        tls: Tls {
+            config: Arc::new(tls::dot_client_config(verifier)),
+            server_name,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
+
+    use crate::proto::{Class, Fqdn, Question, RecordData, ResourceRecord, ResponseCode, Type};
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::{QueryFlags, ResolverError};
+
+    use super::{Resolution, State};
+
+    /// Returns the state of a config with the given top-level `fields`.
+    fn state(fields: Value) -> State {
+        let mut config = json!({
+            "bind": "127.0.0.1:0",
+            "zones": {},
+            "http": { "enabled": false, "bind": "127.0.0.1:0" },
+        });
+        for (key, value) in fields.as_object().unwrap() {
+            config[key] = value.clone();
+        }
+
+        State::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn upstream(server: &MockServer, tier: u32) -> Value {
+        json!({ "Udp": { "addr": server.addr.to_string(), "timeout": 1, "tier": tier } })
+    }
+
+    fn question(name: &str) -> Question {
+        Question {
+            name: Fqdn::new_unchecked(name.to_owned()),
+            qtype: Type::A,
+            qclass: Class::In,
+        }
+    }
+
+    fn addrs(resolution: &Resolution) -> Vec<Ipv4Addr> {
+        resolution
+            .resources
+            .iter()
+            .filter_map(|resource| match resource.data {
+                RecordData::A(addr) => Some(addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    const ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    #[tokio::test]
+    async fn caches_shared_answers() {
+        let server = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let state = state(json!({ "zones": { ".": [upstream(&server, 0)] } }));
+        let question = question("example.com.");
+
+        let flags = QueryFlags::default();
+        for _ in 0..2 {
+            let resolution = state.resolve(&question, &flags).await.unwrap();
+            assert_eq!(addrs(&resolution), [ADDR]);
+        }
+        assert_eq!(server.udp_queries(), 1);
+
+        // Answers to queries with the DO bit are cached apart.
+        let flags = QueryFlags {
+            dnssec_ok: true,
+            ..QueryFlags::default()
+        };
+        for _ in 0..2 {
+            state.resolve(&question, &flags).await.unwrap();
+        }
+        assert_eq!(server.udp_queries(), 2);
+        assert!(state.cache.get(&question, true).is_some());
+    }
+
+    #[tokio::test]
+    async fn resolves_from_cache_only() {
+        let server = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let state = state(json!({ "zones": { ".": [upstream(&server, 0)] } }));
+        let question = question("example.com.");
+        let flags = QueryFlags::default();
+
+        assert!(state.resolve_cached(&question, &flags).is_none());
+        state.resolve(&question, &flags).await.unwrap();
+
+        let resolution = state.resolve_cached(&question, &flags).unwrap();
+        assert_eq!(addrs(&resolution), [ADDR]);
+        assert_eq!(server.udp_queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn follows_zone_strategy() {
+        let primary = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let secondary = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let state = state(json!({
+            "zones": { ".": [upstream(&primary, 0), upstream(&secondary, 0)] },
+            "strategy": { ".": { "balance": "Ordered", "max_ttl": 30 } },
+        }));
+
+        // The answer has a TTL of 60.
+        let resolution = state
+            .resolve(&question("example.com."), &QueryFlags::default())
+            .await
+            .unwrap();
+        assert!(resolution.resources[0].ttl() <= Duration::from_secs(30));
+
+        state
+            .resolve(&question("example.org."), &QueryFlags::default())
+            .await
+            .unwrap_err();
+        assert_eq!(primary.udp_queries(), 2);
+        assert_eq!(secondary.udp_queries(), 0);
+    }
+
+    #[tokio::test]
+    async fn never_forwards_local_zones() {
+        let server = MockServer::start(Script::answer("www.vpn.corp.", ADDR)).await;
+        let state = state(json!({
+            "zones": {
+                ".": [upstream(&server, 0)],
+                "vpn.corp.": [upstream(&server, 0)],
+            },
+            "local_zones": { "corp.": "NxDomain", "internal.": "Refused" },
+        }));
+        let flags = QueryFlags::default();
+
+        let res = state.resolve(&question("www.corp."), &flags).await;
+        assert!(matches!(
+            res,
+            Err(ResolverError::ResponseCode(ResponseCode::NameError, _))
+        ));
+        let res = state.resolve(&question("internal."), &flags).await;
+        assert!(matches!(
+            res,
+            Err(ResolverError::ResponseCode(ResponseCode::Refused, _))
+        ));
+        assert_eq!(server.udp_queries(), 0);
+
+        // Zones below local zones are still forwarded.
+        let resolution = state.resolve(&question("www.vpn.corp."), &flags).await;
+        assert_eq!(addrs(&resolution.unwrap()), [ADDR]);
+        assert_eq!(server.udp_queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn local_zones_ignore_case() {
+        let server = MockServer::start(Script::answer("www.vpn.corp.", ADDR)).await;
+        let state = state(json!({
+            "zones": {
+                ".": [upstream(&server, 0)],
+                "VPN.corp.": [upstream(&server, 0)],
+            },
+            "local_zones": { "Corp.": "NxDomain" },
+        }));
+        let flags = QueryFlags::default();
+
+        let res = state.resolve(&question("www.CORP."), &flags).await;
+        assert!(matches!(
+            res,
+            Err(ResolverError::ResponseCode(ResponseCode::NameError, _))
+        ));
+        assert_eq!(server.udp_queries(), 0);
+
+        let resolution = state.resolve(&question("WWW.vpn.Corp."), &flags).await;
+        assert_eq!(addrs(&resolution.unwrap()), [ADDR]);
+        assert_eq!(server.udp_queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn diff_zones_ignore_case() {
+        let server = MockServer::start(Script::answer("www.example.com.", ADDR)).await;
+        let state = state(json!({
+            "zones": { ".": [upstream(&server, 0)] },
+            "diff": { "Example.COM.": { "resolvers": [upstream(&server, 0)] } },
+        }));
+
+        let question = question("www.Example.com.");
+        state
+            .resolve(&question, &QueryFlags::default())
+            .await
+            .unwrap();
+        let job = state.diff_rx.lock().await.try_recv().unwrap();
+        assert_eq!(job.question, question);
+    }
+
+    #[tokio::test]
+    async fn denies_cached_names_outside_allowlist() {
+        let server = MockServer::start(Script::answer("example.net.", ADDR)).await;
+        let zones = json!({ ".": [upstream(&server, 0)] });
+        let state = state(json!({
+            "zones": zones,
+            "allowlist": { "enabled": true, "domains": ["example.com."] },
+        }));
+        let question = question("example.net.");
+        let flags = QueryFlags::default();
+
+        // E.g. cached before the name was removed from the allowlist.
+        let unrestricted = self::state(json!({ "zones": zones }));
+        let resolution = unrestricted.resolve(&question, &flags).await.unwrap();
+        state.cache.insert(resolution.resources[0].clone(), false);
+        assert!(state.cache.get(&question, false).is_some());
+
+        let res = state.resolve(&question, &flags).await;
+        assert!(matches!(
+            res,
+            Err(ResolverError::ResponseCode(ResponseCode::NameError, _))
+        ));
+        assert!(state.resolve_cached(&question, &flags).is_none());
+        assert_eq!(server.udp_queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn strips_cname_targets_outside_allowlist() {
+        let mut script = Script::answer("evil.net.", ADDR);
+        script.records.push(ResourceRecord {
+            name: Fqdn::new_unchecked("www.example.com.".to_owned()),
+            r#type: Type::CNAME,
+            class: Class::In,
+            ttl: 60,
+            rdata: RecordData::CNAME(Fqdn::new_unchecked("evil.net.".to_owned())),
+        });
+        let server = MockServer::start(script).await;
+        let state = state(json!({
+            "zones": { ".": [upstream(&server, 0)] },
+            "allowlist": { "enabled": true, "domains": ["example.com."] },
+        }));
+        let flags = QueryFlags::default();
+
+        for _ in 0..2 {
+            let resolution = state
+                .resolve(&question("www.example.com."), &flags)
+                .await
+                .unwrap();
+            assert_eq!(resolution.resources.len(), 1);
+            assert!(addrs(&resolution).is_empty());
+        }
+        assert_eq!(server.udp_queries(), 1);
+        assert!(state.cache.get(&question("evil.net."), false).is_none());
+
+        let resolution = state
+            .resolve_cached(&question("www.example.com."), &flags)
+            .unwrap();
+        assert!(addrs(&resolution).is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolves_over_tcp() {
+        let server = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let upstream = json!({ "Tcp": { "addr": server.addr.to_string(), "timeout": 1 } });
+        let state = state(json!({ "zones": { ".": [upstream] } }));
+
+        let resolution = state
+            .resolve(&question("example.com."), &QueryFlags::default())
+            .await
+            .unwrap();
+        assert_eq!(addrs(&resolution), [ADDR]);
+        assert_eq!(server.udp_queries(), 0);
+        assert_eq!(server.tcp_queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn never_caches_error_responses() {
+        let server = MockServer::start(Script::error(ResponseCode::NameError)).await;
+        let state = state(json!({ "zones": { ".": [upstream(&server, 0)] } }));
+        let question = question("example.com.");
+
+        let res = state.resolve(&question, &QueryFlags::default()).await;
+        assert!(matches!(
+            res,
+            Err(ResolverError::ResponseCode(ResponseCode::NameError, _))
+        ));
+
+        server.set_script(Script::answer("example.com.", ADDR));
+        let resolution = state
+            .resolve(&question, &QueryFlags::default())
+            .await
+            .unwrap();
+        assert_eq!(addrs(&resolution), [ADDR]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_on_server_errors() {
+        let primary = MockServer::start(Script::error(ResponseCode::ServerFailure)).await;
+        let secondary = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let upstreams = [upstream(&primary, 0), upstream(&secondary, 1)];
+        let state = state(json!({
+            "zones": { "com.": upstreams, "net.": upstreams },
+            "strategy": { "net.": { "failover": false } },
+        }));
+
+        let resolution = state
+            .resolve(&question("example.com."), &QueryFlags::default())
+            .await
+            .unwrap();
+        assert_eq!(addrs(&resolution), [ADDR]);
+        assert_eq!(secondary.udp_queries(), 1);
+
+        let res = state
+            .resolve(&question("example.net."), &QueryFlags::default())
+            .await;
+        assert!(matches!(
+            res,
+            Err(ResolverError::ResponseCode(ResponseCode::ServerFailure, _))
+        ));
+        assert_eq!(secondary.udp_queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn server_errors_make_upstreams_unhealthy() {
+        let primary = MockServer::start(Script::error(ResponseCode::ServerFailure)).await;
+        let secondary = MockServer::start(Script::error(ResponseCode::NameError)).await;
+        let state = state(json!({
+            "zones": { ".": [upstream(&primary, 0), upstream(&secondary, 1)] },
+        }));
+
+        for name in ["a.example.", "b.example.", "c.example.", "d.example."] {
+            let res = state.resolve(&question(name), &QueryFlags::default()).await;
+            assert!(matches!(
+                res,
+                Err(ResolverError::ResponseCode(ResponseCode::NameError, _))
+            ));
+        }
+        // The last query went to the secondary first.
+        assert_eq!(primary.udp_queries(), 3);
+        assert_eq!(secondary.udp_queries(), 4);
+    }
+
+    #[tokio::test]
+    async fn fails_over_on_timeouts() {
+        let primary = MockServer::start(Script {
+            drop: true,
+            ..Script::default()
+        })
+        .await;
+        let secondary = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let state = state(json!({
+            "zones": { ".": [upstream(&primary, 0), upstream(&secondary, 1)] },
+        }));
+
+        let resolution = state
+            .resolve(&question("example.com."), &QueryFlags::default())
+            .await
+            .unwrap();
+        assert_eq!(addrs(&resolution), [ADDR]);
+        assert_eq!(primary.udp_queries(), 1);
+        assert_eq!(
+            state.metrics.upstream_times.entries()[0]
+                .1
+                .timeouts
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn races_upstreams() {
+        let slow = MockServer::start(Script {
+            delay: Duration::from_millis(500),
+            ..Script::answer("example.com.", ADDR)
+        })
+        .await;
+        let fast = MockServer::start(Script::answer("example.com.", ADDR)).await;
+        let state = state(json!({
+            "zones": { ".": [upstream(&slow, 0), upstream(&fast, 0)] },
+            "strategy": { ".": { "race": 2 } },
+        }));
+
+        let start = Instant::now();
+        state
+            .resolve(&question("example.com."), &QueryFlags::default())
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!((slow.udp_queries(), fast.udp_queries()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn repeats_truncated_queries_over_tcp() {
+        let server = MockServer::start(Script {
+            truncate: true,
+            ..Script::answer("example.com.", ADDR)
+        })
+        .await;
+        let state = state(json!({ "zones": { ".": [upstream(&server, 0)] } }));
+
+        let resolution = state
+            .resolve(&question("example.com."), &QueryFlags::default())
+            .await
+            .unwrap();
+        assert_eq!(addrs(&resolution), [ADDR]);
+        assert_eq!((server.udp_queries(), server.tcp_queries()), (1, 1));
     }
 }
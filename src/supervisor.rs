@@ -0,0 +1,203 @@
+//! Restarting of failed frontend tasks.
+//!
+//! A frontend that returns an error or panics is restarted with an
+//! exponential backoff, so that a single failure doesn't leave the process
+//! running without the listener.
+
+use std::future::Future;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::{select_biased, FutureExt};
+use tokio::task::JoinHandle;
+
+use crate::metrics::Listener;
+use crate::shutdown::Shutdown;
+use crate::state::State;
+
+/// Backoff before the first restart, doubled on every consecutive failure.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Time after which a task that failed is considered to have recovered,
+/// resetting the backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Frontend tasks that are started together once all listeners are bound.
+#[derive(Default)]
+pub struct Supervisor {
+    tasks: Vec<Task>,
+}
+
+struct Task {
+    protocol: &'static str,
+    label: String,
+    run: Box<dyn FnMut() -> BoxFuture<'static, Result<(), io::Error>> + Send>,
+}
+
+impl Supervisor {
+    /// Adds `task` serving the listener `label`, see [`spawn`].
+    pub fn add<F, Fut>(&mut self, protocol: &'static str, label: String, mut task: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), io::Error>> + Send + 'static,
+    {
+        self.tasks.push(Task {
+            protocol,
+            label,
+            run: Box::new(move || task().boxed()),
+        });
+    }
+
+    /// Spawns all tasks.
+    pub fn start(self, state: &'static State) -> Vec<JoinHandle<()>> {
+        self.tasks
+            .into_iter()
+            .map(|task| spawn(state, task.protocol, task.label, task.run))
+            .collect()
+    }
+}
+
+/// Spawns `task` for the listener `label` serving `protocol`, restarting it
+/// when it fails.
+///
+/// The process exits if the task keeps failing after the configured
+/// `max_restarts`.
+fn spawn<F, Fut>(
+    state: &'static State,
+    protocol: &'static str,
+    label: String,
+    task: F,
+) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), io::Error>> + Send + 'static,
+{
+    tokio::task::spawn(async move {
+        let listener = state.metrics.listeners.register(protocol, &label);
+        let max_restarts = state.config.frontend.max_restarts;
+        if supervise(&state.shutdown, &listener, max_restarts, task)
+            .await
+            .is_err()
+        {
+            tracing::error!(
+                "giving up on {} listener {} after {} restarts",
+                protocol,
+                label,
+                max_restarts.unwrap_or_default()
+            );
+            std::process::exit(1);
+        }
+    })
+}
+
+/// Runs `task` until it completes successfully or the shutdown is triggered.
+///
+/// Returns an error once `task` failed more than `max_restarts` times in a
+/// row.
+async fn supervise<F, Fut>(
+    shutdown: &Shutdown,
+    listener: &Listener,
+    max_restarts: Option<u32>,
+    mut task: F,
+) -> Result<(), ()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), io::Error>> + Send + 'static,
+{
+    let mut failures = 0;
+    loop {
+        let start = Instant::now();
+        // The task runs separately to catch its panics.
+        let err = match tokio::task::spawn(task()).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(err)) => err.to_string(),
+            Err(err) => err.to_string(),
+        };
+        tracing::error!(
+            "{} listener {} failed: {}",
+            listener.protocol,
+            listener.name,
+            err
+        );
+
+        if start.elapsed() >= STABLE_AFTER {
+            failures = 0;
+        }
+        if max_restarts.is_some_and(|max| failures >= max) {
+            return Err(());
+        }
+
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << failures.min(16))
+            .min(MAX_BACKOFF);
+        failures += 1;
+
+        listener.down.fetch_add(1, Ordering::Relaxed);
+        let triggered = select_biased! {
+            () = shutdown.triggered().fuse() => true,
+            () = tokio::time::sleep(backoff).fuse() => false,
+        };
+        listener.down.fetch_sub(1, Ordering::Relaxed);
+        if triggered {
+            return Ok(());
+        }
+
+        listener.restarts.fetch_add(1, Ordering::Relaxed);
+        tracing::info!(
+            "restarting {} listener {}",
+            listener.protocol,
+            listener.name
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use crate::metrics::Listeners;
+    use crate::shutdown::Shutdown;
+
+    use super::supervise;
+
+    #[tokio::test]
+    async fn restarts_failed_task() {
+        let shutdown = Shutdown::new();
+        let listener = Listeners::default().register("udp", "test");
+        let runs = Arc::new(AtomicU32::new(0));
+
+        let task = || {
+            let runs = runs.clone();
+            async move {
+                match runs.fetch_add(1, Ordering::Relaxed) {
+                    0 => Err(io::Error::other("failed")),
+                    1 => panic!("panicked"),
+                    _ => Ok(()),
+                }
+            }
+        };
+        supervise(&shutdown, &listener, None, task).await.unwrap();
+
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        assert_eq!(listener.restarts.load(Ordering::Relaxed), 2);
+        assert_eq!(listener.down.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_restarts() {
+        let shutdown = Shutdown::new();
+        let listener = Listeners::default().register("udp", "test");
+
+        let task = || async { Err(io::Error::other("failed")) };
+        assert!(supervise(&shutdown, &listener, Some(1), task)
+            .await
+            .is_err());
+        assert_eq!(listener.restarts.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,149 @@
+//! Integration with the systemd service manager.
+//!
+//! With socket activation systemd binds the listeners, so that the server
+//! doesn't need the privileges to bind port 53 itself. The readiness and
+//! watchdog notifications let systemd supervise the server.
+//!
+//! See https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+//! and https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::os::fd::{FromRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{self, UnixDatagram};
+use std::time::Duration;
+
+use socket2::{Socket, Type};
+
+/// The first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd socket activation.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    sockets: Vec<Socket>,
+}
+
+impl Listeners {
+    /// Takes the sockets passed to this process.
+    ///
+    /// Returns no sockets if the process was not socket activated.
+    pub fn from_env() -> Self {
+        // The variables are inherited by child processes, only the process
+        // they were meant for may take the sockets.
+        let pid = std::env::var("LISTEN_PID").ok();
+        if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+            return Self::default();
+        }
+
+        let count: RawFd = match std::env::var("LISTEN_FDS").map(|fds| fds.parse()) {
+            Ok(Ok(count)) => count,
+            _ => return Self::default(),
+        };
+
+        let sockets = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                let socket = unsafe { Socket::from_raw_fd(fd) };
+                if let Err(err) = socket.set_cloexec(true) {
+                    tracing::warn!("failed to set FD_CLOEXEC on fd {}: {}", fd, err);
+                }
+                socket
+            })
+            .collect();
+
+        Self { sockets }
+    }
+
+    /// Takes the UDP socket bound to `addr`.
+    pub fn take_udp(&mut self, addr: SocketAddr) -> Option<UdpSocket> {
+        self.take(Type::DGRAM, addr).map(UdpSocket::from)
+    }
+
+    /// Takes the TCP listener bound to `addr`.
+    pub fn take_tcp(&mut self, addr: SocketAddr) -> Option<TcpListener> {
+        self.take(Type::STREAM, addr).map(TcpListener::from)
+    }
+
+    fn take(&mut self, ty: Type, addr: SocketAddr) -> Option<Socket> {
+        let index = self.sockets.iter().position(|socket| {
+            socket.r#type().is_ok_and(|t| t == ty)
+                && socket
+                    .local_addr()
+                    .is_ok_and(|local| local.as_socket() == Some(addr))
+        })?;
+
+        let socket = self.sockets.swap_remove(index);
+        if let Err(err) = socket.set_nonblocking(true) {
+            tracing::warn!("failed to make socket for {} non-blocking: {}", addr, err);
+        }
+        Some(socket)
+    }
+
+    /// Logs the sockets that were not taken by any listener.
+    pub fn warn_unused(&self) {
+        for socket in &self.sockets {
+            let addr = socket.local_addr().ok().and_then(|addr| addr.as_socket());
+            tracing::warn!("ignoring socket for {:?} passed by systemd", addr);
+        }
+    }
+}
+
+/// Sends the state change `state` to the service manager, if there is one.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let res = (|| {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            // Names starting with '@' are in the abstract namespace, which
+            // only exists on Linux.
+            #[cfg(target_os = "linux")]
+            Some(name) => net::SocketAddr::from_abstract_name(name)?,
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract socket addresses are only supported on Linux",
+                ))
+            }
+            None => net::SocketAddr::from_pathname(&path)?,
+        };
+
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        Ok::<_, io::Error>(())
+    })();
+
+    if let Err(err) = res {
+        tracing::warn!("failed to notify service manager: {}", err);
+    }
+}
+
+/// Pings the service manager for as long as the future is polled, if the
+/// service manager requested it.
+pub async fn watchdog() {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse().ok() != Some(std::process::id()) {
+            return;
+        }
+    }
+
+    let Some(timeout) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+
+    // Ping at twice the required rate to tolerate delays.
+    let mut interval = tokio::time::interval(timeout / 2);
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
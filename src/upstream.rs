@@ -1,17 +1,44 @@
+pub mod discovery;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
 pub mod https;
+pub mod interface;
+pub mod limit;
+#[cfg(test)]
+pub mod mock;
+pub mod proxy;
+pub mod rate;
+pub mod registry;
+pub mod retry;
+pub mod system;
+pub mod tcp;
 pub mod udp;
 
-use std::io;
-use std::time::Duration;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, io};
 
 use ahash::HashMap;
-use futures::{select_biased, FutureExt};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
 
-use crate::proto::{DecodeError, Fqdn, Question, ResourceRecord};
+use crate::config::{self, Balance, ClientSubnetPolicy, ResolutionMode, Transport};
+use crate::frontend::EDNS_PAYLOAD_SIZE;
+use crate::metrics::{ResolverId, UpstreamTime};
+use crate::proto::{
+    ClientSubnet, DecodeError, Edns, ExtendedError, Fqdn, OpCode, Packet, Qr, Question,
+    ResourceRecord, ResponseCode,
+};
 
-use self::https::HttpsResolver;
-use self::udp::UdpResolver;
+use self::interface::Interface;
+use self::proxy::Proxy;
 
+// The causes are only read through `Debug` in logs.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum ResolverError {
     Io(io::Error),
@@ -19,95 +46,687 @@ pub enum ResolverError {
     Decode(DecodeError),
     NoAnswer,
     Http(reqwest::Error),
+    /// The upstream responded with a non-success status or a body that is
+    /// not a DNS message.
+    HttpStatus(reqwest::StatusCode),
+    Json(serde_json::Error),
+    /// The interface the upstream is reachable through is down.
+    InterfaceDown,
+    /// The upstream has too many queries in flight or exceeded its rate.
+    Overloaded,
+    Refused,
+    /// The upstream responded with a non-zero response code, along with the
+    /// EDNS options passed on to the client.
+    ResponseCode(ResponseCode, Bytes),
 }
 
-#[derive(Debug)]
-pub enum Resolver {
-    Udp(UdpResolver),
-    Https(HttpsResolver),
+impl ResolverError {
+    /// Returns `true` if the upstream responded that it failed to answer,
+    /// rather than that the question has no answer.
+    pub fn is_server_error(&self) -> bool {
+        matches!(
+            self,
+            Self::ResponseCode(
+                ResponseCode::ServerFailure | ResponseCode::Refused | ResponseCode::NotImplemented,
+                _
+            )
+        )
+    }
+}
+
+/// What the client asked for beyond the question, passed on to the
+/// upstreams.
+#[derive(Clone, Debug, Default)]
+pub struct QueryFlags {
+    /// The upstream is asked not to validate the answer.
+    pub checking_disabled: bool,
+    /// The upstream is asked to include DNSSEC records.
+    pub dnssec_ok: bool,
+    /// Address of the client, if it is known.
+    pub client: Option<IpAddr>,
+    /// The EDNS Client Subnet option of the client.
+    pub client_subnet: Option<ClientSubnet>,
+    /// The EDNS Client Subnet option sent to the upstreams, see
+    /// [`QueryFlags::with_policy`].
+    pub upstream_subnet: Option<ClientSubnet>,
+}
+
+impl QueryFlags {
+    /// Returns the flags of the client `query` from `client`.
+    ///
+    /// No client subnet is sent until a policy is applied. All other EDNS
+    /// options, e.g. cookies, only apply to the connection to the client.
+    pub fn from_query(query: &Packet, client: Option<IpAddr>) -> Self {
+        Self::new(query.checking_disabled, query.edns.as_ref(), client)
+    }
+
+    /// Returns the flags of a query with the CD bit `checking_disabled` and
+    /// the OPT record `edns`, see [`QueryFlags::from_query`].
+    pub fn new(checking_disabled: bool, edns: Option<&Edns>, client: Option<IpAddr>) -> Self {
+        Self {
+            checking_disabled,
+            dnssec_ok: edns.is_some_and(|edns| edns.dnssec_ok),
+            client: client.map(|addr| addr.to_canonical()),
+            client_subnet: edns
+                .and_then(|edns| {
+                    edns.iter_options()
+                        .find(|(code, _)| *code == ClientSubnet::OPTION_CODE)
+                })
+                .and_then(|(_, data)| ClientSubnet::decode(data)),
+            upstream_subnet: None,
+        }
+    }
+
+    /// Returns the flags with the client subnet sent according to `policy`.
+    pub fn with_policy(&self, policy: ClientSubnetPolicy) -> Self {
+        let upstream_subnet = match policy {
+            ClientSubnetPolicy::Strip => None,
+            ClientSubnetPolicy::Forward => self.client_subnet,
+            ClientSubnetPolicy::Synthesize {
+                v4_prefix,
+                v6_prefix,
+            } => self
+                .client_subnet
+                .or_else(|| self.client.map(|addr| ClientSubnet::new(addr, u8::MAX)))
+                .map(|subnet| match subnet.addr {
+                    IpAddr::V4(_) => subnet.truncate(v4_prefix),
+                    IpAddr::V6(_) => subnet.truncate(v6_prefix),
+                }),
+        };
+
+        Self {
+            upstream_subnet,
+            ..self.clone()
+        }
+    }
+
+    /// Returns `true` if the answers are the same for all clients with the
+    /// same DO bit, so that they may be taken from the cache.
+    pub fn is_shared(&self) -> bool {
+        self.upstream_subnet.is_none()
+    }
+}
+
+/// The answer of an upstream.
+#[derive(Clone, Debug)]
+pub struct Answer {
+    pub records: Vec<ResourceRecord>,
+    /// EDNS options of the response passed on to the client.
+    pub options: Bytes,
+}
+
+/// A transport queries are sent to an upstream with, e.g. plain UDP or
+/// DNS over HTTPS, or a wrapper adding behavior such as retries to another
+/// [`Resolver`].
+///
+/// Transports apply their [`timeout`] to every exchange themselves, see
+/// [`with_timeout`].
+///
+/// [`timeout`]: UpstreamResolver::timeout
+pub trait UpstreamResolver: fmt::Debug + Send + Sync {
+    /// Sends `query` to the upstream and returns the raw response.
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>>;
+
+    /// Returns the address of the upstream, as shown in logs and metrics.
+    fn addr(&self) -> String;
+
+    fn id(&self) -> ResolverId;
+
+    /// Returns `true` if the upstream is currently reachable.
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn profile(&self) -> QueryProfile {
+        QueryProfile::FORWARDER
+    }
+
+    /// Returns the time a single exchange may take.
+    fn timeout(&self) -> Duration;
 }
 
+/// An upstream resolver of any transport.
+#[derive(Debug)]
+pub struct Resolver(Box<dyn UpstreamResolver>);
+
 impl Resolver {
-    pub async fn resolve(&self, question: &Question) -> Result<Vec<ResourceRecord>, ResolverError> {
-        let timeout = tokio::time::sleep(self.timeout()).fuse();
-        futures::pin_mut!(timeout);
+    pub fn new(resolver: impl UpstreamResolver + 'static) -> Self {
+        Self(Box::new(resolver))
+    }
+
+    /// Wraps a resolver built by a [`registry::Registry`].
+    pub fn from_boxed(resolver: Box<dyn UpstreamResolver>) -> Self {
+        Self(resolver)
+    }
+
+    /// Resolves `question` from the upstream with the `flags` of the client.
+    pub async fn resolve(
+        &self,
+        question: &Question,
+        flags: &QueryFlags,
+    ) -> Result<Answer, ResolverError> {
+        let profile = self.profile();
+        let mut query = profile.build_query(question);
+        query.checking_disabled = flags.checking_disabled;
 
-        match self {
-            Self::Udp(resolver) => select_biased! {
-                res = resolver.resolve(question).fuse() => res,
-                _ = timeout => Err(ResolverError::Timeout),
-            },
-            Self::Https(resolver) => select_biased! {
-                res = resolver.resolve(question).fuse() => res,
-                _ = timeout => Err(ResolverError::Timeout),
-            },
+        let upstream_subnet = flags.upstream_subnet.filter(|_| profile.client_subnet);
+        if flags.dnssec_ok || upstream_subnet.is_some() {
+            let mut options = Vec::new();
+            if let Some(subnet) = upstream_subnet {
+                subnet.encode(&mut options);
+            }
+
+            let edns = query.edns.get_or_insert_with(|| Edns {
+                udp_payload_size: EDNS_PAYLOAD_SIZE,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: false,
+                options: Default::default(),
+            });
+            edns.dnssec_ok = flags.dnssec_ok;
+            edns.options = options.into();
+        }
+
+        let resp = self.exchange(&query).await?;
+        let packet = Packet::decode(resp).map_err(ResolverError::Decode)?;
+        // The scope only tells the client something if its own subnet was sent.
+        let client_subnet = flags.client_subnet.filter(|_| upstream_subnet.is_some());
+        let options = passed_on_options(packet.edns.as_ref(), client_subnet);
+        if packet.response_code != ResponseCode::Ok {
+            return Err(ResolverError::ResponseCode(packet.response_code, options));
         }
+
+        let mut records = packet.answers;
+        profile.restore_case(question, &mut records);
+        Ok(Answer { records, options })
+    }
+
+    /// Sends `query` to the upstream and returns the raw response.
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        self.0.exchange(query).await
     }
 
     pub fn addr(&self) -> String {
-        match self {
-            Self::Udp(resolver) => resolver.addr.to_string(),
-            Self::Https(resolver) => resolver.url.to_string(),
+        self.0.addr()
+    }
+
+    pub fn id(&self) -> ResolverId {
+        self.0.id()
+    }
+
+    /// Returns `true` if the upstream is currently reachable.
+    pub fn is_available(&self) -> bool {
+        self.0.is_available()
+    }
+
+    pub fn profile(&self) -> QueryProfile {
+        self.0.profile()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.0.timeout()
+    }
+}
+
+/// Fails `exchange` with [`ResolverError::Timeout`] if it takes longer than
+/// `timeout`.
+pub async fn with_timeout(
+    timeout: Duration,
+    exchange: impl Future<Output = Result<Bytes, ResolverError>>,
+) -> Result<Bytes, ResolverError> {
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .unwrap_or(Err(ResolverError::Timeout))
+}
+
+/// How the connections to an upstream are made.
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    /// The local address connections are made from.
+    pub source: Option<IpAddr>,
+    /// The interface connections are bound to.
+    pub interface: Option<Interface>,
+    /// The proxy connections are made through.
+    pub proxy: Option<Proxy>,
+}
+
+impl SocketOptions {
+    /// Returns the local address of a socket connecting to `addr`.
+    pub fn local_addr(&self, addr: SocketAddr) -> SocketAddr {
+        let ip = match (self.source, addr) {
+            (Some(ip), _) => ip,
+            (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        SocketAddr::new(ip, 0)
+    }
+}
+
+/// Returns the EDNS options of an upstream response that are passed on to
+/// the client.
+///
+/// Extended DNS Errors explain failures to the client. The scope of the
+/// answer is returned in the `client_subnet` of the client, which may be
+/// longer than the one sent to the upstream.
+fn passed_on_options(edns: Option<&Edns>, client_subnet: Option<ClientSubnet>) -> Bytes {
+    let Some(edns) = edns else {
+        return Bytes::new();
+    };
+
+    let mut options = Vec::new();
+    for (code, data) in edns.iter_options() {
+        match code {
+            ExtendedError::OPTION_CODE => Edns::encode_option(&mut options, code, data),
+            ClientSubnet::OPTION_CODE => {
+                if let (Some(client), Some(upstream)) = (client_subnet, ClientSubnet::decode(data))
+                {
+                    ClientSubnet {
+                        scope_prefix: upstream.scope_prefix.min(client.source_prefix),
+                        ..client
+                    }
+                    .encode(&mut options);
+                }
+            }
+            _ => (),
+        }
+    }
+    options.into()
+}
+
+/// How queries are sent to an upstream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QueryProfile {
+    /// Whether to set the RD bit.
+    pub recursion_desired: bool,
+    /// Whether to randomize the case of the queried name.
+    ///
+    /// See https://datatracker.ietf.org/doc/html/draft-vixie-dnsext-dns0x20-00
+    pub randomize_case: bool,
+    /// Whether to send the EDNS Client Subnet option of the client.
+    pub client_subnet: bool,
+    /// EDNS UDP payload size advertised in every query. Queries only carry
+    /// an OPT record if the client asks for EDNS features otherwise.
+    pub payload_size: Option<u16>,
+}
+
+impl QueryProfile {
+    /// Profile for upstreams that resolve recursively on our behalf.
+    pub const FORWARDER: Self = Self {
+        recursion_desired: true,
+        randomize_case: false,
+        client_subnet: true,
+        payload_size: None,
+    };
+
+    /// Profile for upstreams that are authoritative for the zone.
+    ///
+    /// Authoritative servers have no need to know that we are a recursive
+    /// resolver and the randomized case makes spoofing harder. No EDNS
+    /// options such as client subnet are sent.
+    pub const AUTHORITATIVE: Self = Self {
+        recursion_desired: false,
+        randomize_case: true,
+        client_subnet: false,
+        payload_size: None,
+    };
+
+    pub fn for_mode(mode: ResolutionMode) -> Self {
+        match mode {
+            ResolutionMode::Forward => Self::FORWARDER,
+            ResolutionMode::Authoritative => Self::AUTHORITATIVE,
+        }
+    }
+
+    /// Builds the query [`Packet`] for `question`.
+    pub fn build_query(&self, question: &Question) -> Packet {
+        let mut question = question.clone();
+        if self.randomize_case {
+            randomize_case(&mut question.name);
+        }
+
+        Packet {
+            transaction_id: rand::random(),
+            qr: Qr::Request,
+            opcode: OpCode::Query,
+            authoritative_answer: false,
+            truncated: false,
+            recursion_desired: self.recursion_desired,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::Ok,
+            questions: vec![question],
+            raw_questions: None,
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
+            edns: self.payload_size.map(|udp_payload_size| Edns {
+                udp_payload_size,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: false,
+                options: Default::default(),
+            }),
+        }
+    }
+
+    /// Restores the case of names in `answers` that were changed by [`build_query`].
+    ///
+    /// [`build_query`]: Self::build_query
+    pub fn restore_case(&self, question: &Question, answers: &mut [ResourceRecord]) {
+        if !self.randomize_case {
+            return;
+        }
+
+        for answer in answers {
+            if answer
+                .name
+                .as_bytes()
+                .eq_ignore_ascii_case(question.name.as_bytes())
+            {
+                answer.name = question.name.clone();
+            }
+        }
+    }
+}
+
+fn randomize_case(fqdn: &mut Fqdn) {
+    let mut bits: u64 = rand::random();
+    for (index, byte) in fqdn.0.iter_mut().enumerate() {
+        if index % 64 == 0 && index != 0 {
+            bits = rand::random();
+        }
+
+        if byte.is_ascii_alphabetic() && bits & 1 == 1 {
+            *byte ^= 0x20;
+        }
+        bits >>= 1;
+    }
+}
+
+/// Returns `duration` shortened by a random fraction of up to `jitter`.
+///
+/// Timers started at the same time, e.g. retries after an upstream went
+/// down, would otherwise fire in synchronized waves.
+pub fn jitter(duration: Duration, jitter: f64) -> Duration {
+    duration.mul_f64(1.0 - jitter.clamp(0.0, 1.0) * rand::random::<f64>())
+}
+
+/// Number of consecutive failures after which an upstream is unhealthy.
+const MAX_FAILURES: u32 = 3;
+
+/// Time after which an unhealthy upstream is tried again.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An upstream of a zone.
+#[derive(Debug)]
+pub struct Upstream {
+    pub resolver: Resolver,
+    /// Upstreams of lower tiers are preferred.
+    pub tier: u32,
+    /// `None` for upstreams that answer queries themselves.
+    pub transport: Option<Transport>,
+    pub metrics: Arc<UpstreamTime>,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    /// Number of consecutive failed queries.
+    failures: u32,
+    last_failure: Option<Instant>,
+}
+
+impl Upstream {
+    pub fn new(
+        resolver: Resolver,
+        tier: u32,
+        transport: Option<Transport>,
+        metrics: Arc<UpstreamTime>,
+    ) -> Self {
+        Self {
+            resolver,
+            tier,
+            transport,
+            metrics,
+            health: Mutex::default(),
+        }
+    }
+
+    /// Returns `true` if the upstream is reachable and didn't fail
+    /// repeatedly.
+    ///
+    /// Unhealthy upstreams become healthy again once they answered a query,
+    /// they are given the chance to every [`RECHECK_INTERVAL`].
+    pub fn is_healthy(&self) -> bool {
+        if !self.resolver.is_available() {
+            return false;
+        }
+
+        let health = self.health.lock();
+        health.failures < MAX_FAILURES
+            || health
+                .last_failure
+                .is_some_and(|instant| instant.elapsed() >= RECHECK_INTERVAL)
+    }
+
+    /// Records whether a query to the upstream succeeded.
+    pub fn record(&self, success: bool) {
+        let mut health = self.health.lock();
+        if success {
+            health.failures = 0;
+        } else {
+            health.failures = health.failures.saturating_add(1);
+            health.last_failure = Some(Instant::now());
         }
     }
+}
+
+/// Returns the order in which `upstreams` are queried.
+///
+/// Healthy upstreams come first, by tier, and within a tier those with the
+/// preferred transport. The upstreams of a tier share the load as the
+/// `strategy` balances it. Unhealthy upstreams are only queried if all
+/// others failed.
+pub fn order<'a>(upstreams: &'a [Upstream], strategy: &Strategy) -> Vec<&'a Upstream> {
+    let (mut healthy, unhealthy): (Vec<_>, Vec<_>) =
+        upstreams.iter().partition(|upstream| upstream.is_healthy());
+
+    let is_preferred =
+        |upstream: &Upstream| strategy.prefer.is_none() || upstream.transport == strategy.prefer;
+    // Every query takes one turn, whichever upstreams it reaches.
+    let turn = match strategy.balance {
+        Balance::RoundRobin => strategy.next.fetch_add(1, Ordering::Relaxed),
+        Balance::Random | Balance::Ordered => 0,
+    };
+    for tier in healthy.chunk_by_mut(|a, b| a.tier == b.tier) {
+        tier.sort_by_key(|upstream| !is_preferred(upstream));
+        for group in tier.chunk_by_mut(|a, b| is_preferred(a) == is_preferred(b)) {
+            let offset = match strategy.balance {
+                Balance::Random => rand::random::<usize>(),
+                Balance::RoundRobin => turn,
+                Balance::Ordered => 0,
+            };
+            group.rotate_left(offset % group.len());
+        }
+    }
+
+    healthy.extend(unhealthy);
+    healthy
+}
+
+/// How the upstreams of a zone are queried and their answers cached.
+#[derive(Debug)]
+pub struct Strategy {
+    pub prefer: Option<Transport>,
+    pub balance: Balance,
+    /// Number of upstreams queried concurrently.
+    pub race: usize,
+    /// Whether error responses move on to the next upstream.
+    pub failover: bool,
+    pub client_subnet: ClientSubnetPolicy,
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// The upstream the next query starts at with [`Balance::RoundRobin`].
+    next: AtomicUsize,
+}
+
+impl Strategy {
+    /// Returns the TTL an answer with `ttl` is cached for.
+    pub fn ttl(&self, ttl: u32) -> u32 {
+        ttl.clamp(self.min_ttl, self.max_ttl)
+    }
+}
 
-    fn timeout(&self) -> Duration {
-        match self {
-            Self::Udp(resolver) => resolver.timeout,
-            Self::Https(resolver) => resolver.timeout,
+impl From<&config::Strategy> for Strategy {
+    fn from(strategy: &config::Strategy) -> Self {
+        Self {
+            prefer: strategy.prefer,
+            balance: strategy.balance,
+            race: strategy.race,
+            failover: strategy.failover,
+            client_subnet: strategy.client_subnet,
+            min_ttl: strategy.min_ttl.unwrap_or(0),
+            max_ttl: strategy.max_ttl.unwrap_or(u32::MAX),
+            next: AtomicUsize::new(0),
         }
     }
 }
 
+/// The strategy of zones without one.
+static DEFAULT_STRATEGY: Strategy = Strategy {
+    prefer: None,
+    balance: Balance::Ordered,
+    race: 1,
+    failover: true,
+    client_subnet: ClientSubnetPolicy::Strip,
+    min_ttl: 0,
+    max_ttl: u32::MAX,
+    next: AtomicUsize::new(0),
+};
+
+/// The zones and their settings, keyed by the lowercase zone name.
 #[derive(Debug, Default)]
 pub struct Zones {
-    resolvers: HashMap<Box<[u8]>, Vec<Resolver>>,
+    /// The upstreams of every zone, ordered by tier.
+    upstreams: HashMap<Box<[u8]>, Vec<Upstream>>,
+    strategies: HashMap<Box<[u8]>, Strategy>,
+    /// Zones that are never forwarded, with the response code of all
+    /// queries for their names.
+    local: HashMap<Box<[u8]>, ResponseCode>,
 }
 
 impl Zones {
-    pub fn lookup(&self, fqdn: &Fqdn) -> Option<&[Resolver]> {
-        let mut zone = fqdn.as_bytes();
+    pub fn lookup(&self, fqdn: &Fqdn) -> Option<&[Upstream]> {
+        self.lookup_zone(fqdn).map(|(_, upstreams)| upstreams)
+    }
 
-        loop {
-            if let Some(resolvers) = self.resolvers.get(zone) {
-                return Some(resolvers);
-            }
+    /// Returns the closest zone enclosing `fqdn` with its upstreams.
+    pub fn lookup_zone(&self, fqdn: &Fqdn) -> Option<(&[u8], &[Upstream])> {
+        lowercase(fqdn).ancestors().find_map(|name| {
+            self.upstreams
+                .get_key_value(name)
+                .map(|(zone, upstreams)| (&**zone, upstreams.as_slice()))
+        })
+    }
 
-            if let Some(index) = memchr::memchr(b'.', zone) {
-                let (_, rem) = zone.split_at(index + 1);
-                zone = rem;
-                if zone.is_empty() {
-                    zone = b".";
-                }
-            } else {
+    /// Returns the response code of `fqdn` if the closest zone enclosing it
+    /// is never forwarded.
+    pub fn lookup_local(&self, fqdn: &Fqdn) -> Option<ResponseCode> {
+        for name in lowercase(fqdn).ancestors() {
+            if let Some(code) = self.local.get(name) {
+                return Some(*code);
+            }
+            if self.upstreams.contains_key(name) {
                 return None;
             }
         }
+
+        None
+    }
+
+    pub fn insert(&mut self, fqdn: Fqdn, upstream: Upstream) {
+        let upstreams = self.upstreams.entry(key(fqdn)).or_default();
+        let index = upstreams.partition_point(|other| other.tier <= upstream.tier);
+        upstreams.insert(index, upstream);
+    }
+
+    /// Sets how the upstreams of `fqdn` are queried and their answers
+    /// cached.
+    pub fn set_strategy(&mut self, fqdn: Fqdn, strategy: Strategy) {
+        self.strategies.insert(key(fqdn), strategy);
+    }
+
+    /// Returns how the upstreams of `zone` are queried and their answers
+    /// cached.
+    pub fn strategy(&self, zone: &[u8]) -> &Strategy {
+        self.strategies.get(zone).unwrap_or(&DEFAULT_STRATEGY)
     }
 
-    pub fn insert(&mut self, fqdn: Fqdn, resolver: Resolver) {
-        self.resolvers
-            .entry(fqdn.0.into_boxed_slice())
-            .or_default()
-            .push(resolver);
+    /// Answers all queries for names in `fqdn` with `code`, instead of
+    /// forwarding them.
+    pub fn set_local(&mut self, fqdn: Fqdn, code: ResponseCode) {
+        self.local.insert(key(fqdn), code);
+    }
+
+    /// Returns all zones with their upstreams.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[Upstream])> {
+        self.upstreams
+            .iter()
+            .map(|(zone, upstreams)| (&zone[..], &upstreams[..]))
+    }
+
+    /// Returns all resolvers of all zones.
+    pub fn resolvers(&self) -> impl Iterator<Item = &Resolver> {
+        self.upstreams
+            .values()
+            .flatten()
+            .map(|upstream| &upstream.resolver)
     }
 
     pub fn clear(&mut self) {
-        self.resolvers.clear();
+        self.upstreams.clear();
+        self.strategies.clear();
+        self.local.clear();
     }
 }
 
+/// Returns the key of the zone `fqdn` in [`Zones`].
+fn key(fqdn: Fqdn) -> Box<[u8]> {
+    lowercase(&fqdn).0.into_boxed_slice()
+}
+
+/// Returns `fqdn` in lowercase, as names are compared case-insensitively.
+fn lowercase(fqdn: &Fqdn) -> Fqdn {
+    Fqdn(fqdn.as_bytes().to_ascii_lowercase())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::proto::Fqdn;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::future::BoxFuture;
 
-    use super::Zones;
+    use crate::config::{self, Balance, ClientSubnetPolicy, Transport};
+    use crate::metrics::{ResolverId, UpstreamTimes};
+    use crate::proto::{
+        Class, ClientSubnet, Edns, Fqdn, Packet, Qr, Question, RecordData, ResourceRecord,
+        ResponseCode, Type,
+    };
+
+    use super::udp::UdpResolver;
+    use super::{
+        jitter, order, passed_on_options, QueryFlags, QueryProfile, Resolver, ResolverError,
+        SocketOptions, Strategy, Upstream, UpstreamResolver, Zones, DEFAULT_STRATEGY,
+    };
 
     #[test]
     fn zones_lookup_exact() {
         let mut zones = Zones::default();
         zones
-            .resolvers
+            .upstreams
             .insert(b"example.com.".to_vec().into_boxed_slice(), Vec::new());
 
         assert!(zones.lookup(&Fqdn(b"example.com.".to_vec())).is_some());
@@ -117,9 +736,318 @@ mod tests {
     fn zones_lookup_root() {
         let mut zones = Zones::default();
         zones
-            .resolvers
+            .upstreams
             .insert(b".".to_vec().into_boxed_slice(), Vec::new());
 
         assert!(zones.lookup(&Fqdn(b"example.com.".to_vec())).is_some());
     }
+
+    #[test]
+    fn zones_strategy() {
+        let mut zones = Zones::default();
+        let strategy = config::Strategy {
+            race: 3,
+            failover: false,
+            ..Default::default()
+        };
+        zones.set_strategy(Fqdn(b"example.com.".to_vec()), (&strategy).into());
+
+        assert_eq!(zones.strategy(b"example.com.").race, 3);
+        assert!(!zones.strategy(b"example.com.").failover);
+        assert_eq!(zones.strategy(b".").race, 1);
+        assert!(zones.strategy(b".").failover);
+
+        zones.clear();
+        assert_eq!(zones.strategy(b"example.com.").race, 1);
+    }
+
+    #[test]
+    fn jitter_shortens_durations() {
+        let duration = Duration::from_secs(10);
+        for _ in 0..100 {
+            let jittered = jitter(duration, 0.5);
+            assert!(jittered <= duration && jittered >= duration / 2);
+        }
+        assert_eq!(jitter(duration, 0.0), duration);
+    }
+
+    #[test]
+    fn server_errors() {
+        let error = |code| ResolverError::ResponseCode(code, Bytes::new());
+        assert!(error(ResponseCode::ServerFailure).is_server_error());
+        assert!(error(ResponseCode::Refused).is_server_error());
+        assert!(error(ResponseCode::NotImplemented).is_server_error());
+        assert!(!error(ResponseCode::NameError).is_server_error());
+        assert!(!ResolverError::Timeout.is_server_error());
+    }
+
+    fn upstream(times: &UpstreamTimes, port: u16, tier: u32) -> Upstream {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let id = times.register(&addr.to_string());
+        let resolver = UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_secs(5),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        );
+        Upstream::new(
+            Resolver::new(resolver),
+            tier,
+            Some(Transport::Udp),
+            times.get(id).unwrap(),
+        )
+    }
+
+    fn ports<'a>(upstreams: impl Iterator<Item = &'a Upstream>) -> Vec<u16> {
+        upstreams
+            .map(|upstream| {
+                let addr: SocketAddr = upstream.resolver.addr().parse().unwrap();
+                addr.port()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zones_insert_orders_tiers() {
+        let times = UpstreamTimes::default();
+        let mut zones = Zones::default();
+        for (port, tier) in [(1, 1), (2, 0), (3, 1), (4, 0)] {
+            zones.insert(Fqdn(b".".to_vec()), upstream(&times, port, tier));
+        }
+
+        let upstreams = zones.lookup(&Fqdn(b".".to_vec())).unwrap();
+        assert_eq!(ports(upstreams.iter()), [2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn order_falls_back_to_unhealthy_upstreams() {
+        let times = UpstreamTimes::default();
+        let upstreams = [
+            upstream(&times, 1, 0),
+            upstream(&times, 2, 0),
+            upstream(&times, 3, 1),
+        ];
+        for _ in 0..3 {
+            upstreams[0].record(false);
+            upstreams[1].record(false);
+        }
+        assert!(!upstreams[0].is_healthy());
+
+        assert_eq!(
+            ports(order(&upstreams, &DEFAULT_STRATEGY).into_iter()),
+            [3, 1, 2]
+        );
+
+        upstreams[1].record(true);
+        assert_eq!(
+            ports(order(&upstreams, &DEFAULT_STRATEGY).into_iter()),
+            [2, 3, 1]
+        );
+    }
+
+    #[test]
+    fn order_follows_strategy() {
+        let times = UpstreamTimes::default();
+        let https = |port, tier| Upstream {
+            transport: Some(Transport::Https),
+            ..upstream(&times, port, tier)
+        };
+        let upstreams = [
+            upstream(&times, 1, 0),
+            https(2, 0),
+            https(3, 0),
+            upstream(&times, 4, 1),
+        ];
+
+        // Zones with and without a strategy keep the order of the config.
+        let strategy = Strategy::from(&config::Strategy::default());
+        for strategy in [&strategy, &DEFAULT_STRATEGY] {
+            for _ in 0..4 {
+                assert_eq!(ports(order(&upstreams, strategy).into_iter()), [1, 2, 3, 4]);
+            }
+        }
+
+        let strategy = Strategy::from(&config::Strategy {
+            prefer: Some(Transport::Https),
+            balance: Balance::Ordered,
+            ..Default::default()
+        });
+        assert_eq!(
+            ports(order(&upstreams, &strategy).into_iter()),
+            [2, 3, 1, 4]
+        );
+
+        let strategy = Strategy::from(&config::Strategy {
+            balance: Balance::RoundRobin,
+            ..Default::default()
+        });
+        let first: Vec<_> = (0..4)
+            .map(|_| ports(order(&upstreams, &strategy).into_iter())[0])
+            .collect();
+        assert_eq!(first, [1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn strategy_bounds_ttls() {
+        let strategy = Strategy::from(&config::Strategy {
+            min_ttl: Some(30),
+            max_ttl: Some(300),
+            ..Default::default()
+        });
+        assert_eq!(strategy.ttl(0), 30);
+        assert_eq!(strategy.ttl(60), 60);
+        assert_eq!(strategy.ttl(3600), 300);
+        assert_eq!(DEFAULT_STRATEGY.ttl(3600), 3600);
+    }
+
+    #[test]
+    fn query_profile_authoritative() {
+        let question = Question {
+            name: Fqdn(b"www.example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+
+        let query = QueryProfile::AUTHORITATIVE.build_query(&question);
+        assert!(!query.recursion_desired);
+        assert!(query.questions[0]
+            .name
+            .as_bytes()
+            .eq_ignore_ascii_case(question.name.as_bytes()));
+
+        let query = QueryProfile::FORWARDER.build_query(&question);
+        assert!(query.recursion_desired);
+        assert_eq!(query.questions[0], question);
+    }
+
+    #[test]
+    fn query_flags_exclude_connection_options() {
+        let mut options = Vec::new();
+        ClientSubnet::new(Ipv4Addr::new(192, 0, 2, 0).into(), 24).encode(&mut options);
+        // A client cookie.
+        Edns::encode_option(&mut options, 10, &[1; 8]);
+        let edns = Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: true,
+            options: options.into(),
+        };
+
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+        let mut query = QueryProfile::FORWARDER.build_query(&question);
+        query.edns = Some(edns.clone());
+
+        let flags = QueryFlags::from_query(&query, None);
+        assert!(flags.dnssec_ok);
+        assert!(flags.client_subnet.is_some());
+        assert!(flags.upstream_subnet.is_none());
+        assert!(flags.is_shared());
+
+        // Cookies are never passed back, the client subnet only if it was sent.
+        let subnet = flags.client_subnet;
+        assert!(passed_on_options(Some(&edns), None).is_empty());
+        assert_eq!(
+            passed_on_options(Some(&edns), subnet)[..],
+            [0, 8, 0, 7, 0, 1, 24, 0, 192, 0, 2]
+        );
+    }
+
+    #[test]
+    fn query_flags_client_subnet_policy() {
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+        let query = QueryProfile::FORWARDER.build_query(&question);
+        let client = "::ffff:198.51.100.7".parse().ok();
+        let synthesize = ClientSubnetPolicy::Synthesize {
+            v4_prefix: 24,
+            v6_prefix: 56,
+        };
+
+        let flags = QueryFlags::from_query(&query, client);
+        assert!(flags.with_policy(ClientSubnetPolicy::Forward).is_shared());
+        let subnet = flags.with_policy(synthesize).upstream_subnet.unwrap();
+        assert_eq!(subnet.addr, Ipv4Addr::new(198, 51, 100, 0));
+        assert_eq!(subnet.source_prefix, 24);
+
+        // Subnets of the client are only ever shortened.
+        let flags = QueryFlags {
+            client_subnet: Some(ClientSubnet::new(Ipv4Addr::new(192, 0, 2, 0).into(), 20)),
+            ..flags
+        };
+        assert!(flags
+            .with_policy(ClientSubnetPolicy::Strip)
+            .upstream_subnet
+            .is_none());
+        let subnet = flags.with_policy(synthesize).upstream_subnet.unwrap();
+        assert_eq!(subnet.source_prefix, 20);
+    }
+
+    /// Answers every query with 192.0.2.1 without sending it anywhere.
+    #[derive(Debug)]
+    struct Static(ResolverId);
+
+    impl UpstreamResolver for Static {
+        fn exchange<'a>(
+            &'a self,
+            query: &'a Packet,
+        ) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+            let mut resp = query.clone();
+            resp.qr = Qr::Response;
+            resp.answers.push(ResourceRecord {
+                name: query.questions[0].name.clone(),
+                r#type: Type::A,
+                class: Class::In,
+                ttl: 60,
+                rdata: RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            });
+
+            let mut buf = Vec::new();
+            resp.encode(&mut buf);
+            Box::pin(async move { Ok(Bytes::from(buf)) })
+        }
+
+        fn addr(&self) -> String {
+            "static".to_owned()
+        }
+
+        fn id(&self) -> ResolverId {
+            self.0
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_from_custom_transport() {
+        let times = UpstreamTimes::default();
+        let resolver = Resolver::new(Static(times.register("static")));
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+
+        let answer = resolver
+            .resolve(&question, &QueryFlags::default())
+            .await
+            .unwrap();
+        assert_eq!(answer.records.len(), 1);
+        assert_eq!(
+            answer.records[0].rdata,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
 }
@@ -0,0 +1,665 @@
+//! Answers names of services registered in Consul or Kubernetes.
+//!
+//! Services are looked up using the respective API on the first query and
+//! then kept up to date in the background using blocking queries (Consul)
+//! or watches (Kubernetes).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use parking_lot::RwLock;
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+use crate::metrics::ResolverId;
+use crate::proto::{
+    Class, Fqdn, OpCode, Packet, Qr, RecordData, ResourceRecord, ResponseCode, Type,
+};
+
+use super::{jitter, with_timeout, ResolverError, UpstreamResolver};
+
+/// Maximum time a blocking query/watch waits for changes.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time to wait before retrying a failed watch.
+const WATCH_RETRY: Duration = Duration::from_secs(1);
+
+/// Services that were not queried for this long are no longer watched.
+const SERVICE_IDLE: Duration = Duration::from_secs(600);
+
+/// Maximum number of watched services.
+const MAX_SERVICES: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub enum Backend {
+    /// Answers `<service>.service.consul`-style names from the Consul health API.
+    Consul { url: Url, token: Option<String> },
+    /// Answers `<service>.<namespace>.svc.cluster.local`-style names from the endpoints of
+    /// a Kubernetes (headless) service.
+    ///
+    /// The token file is read on every request since service account tokens are rotated.
+    Kubernetes {
+        url: Url,
+        token_file: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug)]
+pub struct DiscoveryResolver {
+    pub id: ResolverId,
+    pub timeout: Duration,
+    /// TTL of the returned records.
+    pub ttl: u32,
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    client: Client,
+    backend: Backend,
+    epoch: Instant,
+    services: RwLock<HashMap<Service, Entry>>,
+}
+
+/// A watched service.
+#[derive(Debug)]
+struct Entry {
+    addrs: Vec<IpAddr>,
+    /// Seconds since [`Inner::epoch`] of the last query for the service.
+    last_used: AtomicU64,
+    watch: JoinHandle<()>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.watch.abort();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Service {
+    name: String,
+    namespace: Option<String>,
+}
+
+impl DiscoveryResolver {
+    pub fn new(
+        id: ResolverId,
+        backend: Backend,
+        client: Client,
+        timeout: Duration,
+        ttl: u32,
+    ) -> Self {
+        Self {
+            id,
+            timeout,
+            ttl,
+            inner: Arc::new(Inner {
+                client,
+                backend,
+                epoch: Instant::now(),
+                services: RwLock::default(),
+            }),
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        match &self.inner.backend {
+            Backend::Consul { url, .. } => url,
+            Backend::Kubernetes { url, .. } => url,
+        }
+    }
+
+    /// Answers `query` from the service registry and returns the encoded response.
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        let question = query.questions.first().ok_or(ResolverError::NoAnswer)?;
+
+        let mut response_code = ResponseCode::NameError;
+        let mut answers = Vec::new();
+        if let Some(service) = self.inner.backend.service(&question.name) {
+            let addrs = self.lookup(service).await?;
+
+            if !addrs.is_empty() {
+                response_code = ResponseCode::Ok;
+            }
+
+            for addr in addrs {
+                let rdata = match (question.qtype, addr) {
+                    (Type::A, IpAddr::V4(addr)) => RecordData::A(addr),
+                    (Type::AAAA, IpAddr::V6(addr)) => RecordData::AAAA(addr),
+                    _ => continue,
+                };
+
+                answers.push(ResourceRecord {
+                    name: question.name.clone(),
+                    r#type: question.qtype,
+                    class: Class::In,
+                    ttl: self.ttl,
+                    rdata,
+                });
+            }
+        }
+
+        let response = Packet {
+            transaction_id: query.transaction_id,
+            qr: Qr::Response,
+            opcode: OpCode::Query,
+            authoritative_answer: true,
+            truncated: false,
+            recursion_desired: query.recursion_desired,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: query.checking_disabled,
+            response_code,
+            questions: query.questions.clone(),
+            raw_questions: None,
+            answers,
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: None,
+        };
+
+        let mut buf = Vec::new();
+        response.encode(&mut buf);
+        Ok(Bytes::from(buf))
+    }
+
+    async fn lookup(&self, service: Service) -> Result<Vec<IpAddr>, ResolverError> {
+        if let Some(entry) = self.inner.services.read().get(&service) {
+            entry.last_used.store(self.inner.now(), Ordering::Relaxed);
+            return Ok(entry.addrs.clone());
+        }
+
+        let (addrs, version) = self.inner.fetch(&service, None).await?;
+
+        // Only watch services that exist, otherwise every query for a
+        // non-existent service would spawn a new watch.
+        if !addrs.is_empty() {
+            let mut services = self.inner.services.write();
+            if !services.contains_key(&service) {
+                self.inner.expire(&mut services);
+
+                let watch = tokio::task::spawn(self.inner.clone().watch(service.clone(), version));
+                services.insert(
+                    service,
+                    Entry {
+                        addrs: addrs.clone(),
+                        last_used: AtomicU64::new(self.inner.now()),
+                        watch,
+                    },
+                );
+            }
+        }
+
+        Ok(addrs)
+    }
+}
+
+impl UpstreamResolver for DiscoveryResolver {
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(with_timeout(
+            self.timeout,
+            DiscoveryResolver::exchange(self, query),
+        ))
+    }
+
+    fn addr(&self) -> String {
+        self.url().to_string()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.id
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl Backend {
+    fn service(&self, name: &Fqdn) -> Option<Service> {
+        let name = std::str::from_utf8(name.as_bytes()).ok()?;
+        let mut labels = name.split('.');
+
+        match self {
+            Self::Consul { .. } => Some(Service {
+                name: labels.next().filter(|l| !l.is_empty())?.to_owned(),
+                namespace: None,
+            }),
+            Self::Kubernetes { .. } => Some(Service {
+                name: labels.next().filter(|l| !l.is_empty())?.to_owned(),
+                namespace: Some(labels.next().filter(|l| !l.is_empty())?.to_owned()),
+            }),
+        }
+    }
+}
+
+impl Inner {
+    /// Keeps the addresses of `service` up to date.
+    ///
+    /// The watch ends once the service is no longer queried.
+    async fn watch(self: Arc<Self>, service: Service, mut version: String) {
+        loop {
+            if self.is_idle(&service) {
+                tracing::debug!("no longer watching idle service {:?}", service);
+                // Dropping the entry aborts this task at the next await.
+                self.services.write().remove(&service);
+                return;
+            }
+
+            let res = match &self.backend {
+                Backend::Consul { .. } => {
+                    self.fetch(&service, Some(&version))
+                        .await
+                        .map(|(addrs, version)| {
+                            self.update(&service, addrs);
+                            version
+                        })
+                }
+                Backend::Kubernetes { .. } => self.watch_kubernetes(&service, &version).await,
+            };
+
+            match res {
+                Ok(new_version) => version = new_version,
+                Err(err) => {
+                    tracing::debug!("failed to watch service {:?}: {:?}", service, err);
+                    // All watches fail together when the registry is down.
+                    tokio::time::sleep(jitter(WATCH_RETRY, 0.5)).await;
+
+                    // The version may no longer be valid. Start again from
+                    // the current state.
+                    match self.fetch(&service, None).await {
+                        Ok((addrs, new_version)) => {
+                            self.update(&service, addrs);
+                            version = new_version;
+                        }
+                        Err(err) => {
+                            tracing::debug!("failed to fetch service {:?}: {:?}", service, err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces the addresses of `service`, unless it is no longer watched.
+    fn update(&self, service: &Service, addrs: Vec<IpAddr>) {
+        if let Some(entry) = self.services.write().get_mut(service) {
+            entry.addrs = addrs;
+        }
+    }
+
+    /// Returns `true` if `service` was not queried for [`SERVICE_IDLE`].
+    fn is_idle(&self, service: &Service) -> bool {
+        self.services
+            .read()
+            .get(service)
+            .is_none_or(|entry| self.idle_secs(entry) >= SERVICE_IDLE.as_secs())
+    }
+
+    /// Stops watching idle services and makes room for a new one in `services`.
+    fn expire(&self, services: &mut HashMap<Service, Entry>) {
+        services.retain(|_, entry| self.idle_secs(entry) < SERVICE_IDLE.as_secs());
+
+        if services.len() >= MAX_SERVICES {
+            let oldest = services
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(service, _)| service.clone());
+            if let Some(service) = oldest {
+                tracing::debug!("service limit reached, no longer watching {:?}", service);
+                services.remove(&service);
+            }
+        }
+    }
+
+    fn idle_secs(&self, entry: &Entry) -> u64 {
+        self.now()
+            .saturating_sub(entry.last_used.load(Ordering::Relaxed))
+    }
+
+    /// Seconds since the creation of the resolver.
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_secs()
+    }
+
+    /// Fetches the current addresses of `service`.
+    ///
+    /// If `version` is given the request blocks until the service changes (Consul only).
+    async fn fetch(
+        &self,
+        service: &Service,
+        version: Option<&str>,
+    ) -> Result<(Vec<IpAddr>, String), ResolverError> {
+        match &self.backend {
+            Backend::Consul { url, .. } => {
+                let mut url = join(url, &["v1", "health", "service", &service.name]);
+                url.query_pairs_mut().append_pair("passing", "true");
+                if let Some(version) = version {
+                    url.query_pairs_mut()
+                        .append_pair("index", version)
+                        .append_pair("wait", &format!("{}s", WATCH_TIMEOUT.as_secs()));
+                }
+
+                let resp = self.execute(self.client.get(url)).await?;
+                let index = resp
+                    .headers()
+                    .get("x-consul-index")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("0")
+                    .to_owned();
+
+                let entries: Vec<ConsulEntry> = json(resp).await?;
+                let addrs = entries
+                    .iter()
+                    .filter_map(|entry| {
+                        // The service address defaults to the node address.
+                        let addr = if entry.service.address.is_empty() {
+                            &entry.node.address
+                        } else {
+                            &entry.service.address
+                        };
+                        addr.parse().ok()
+                    })
+                    .collect();
+
+                Ok((addrs, index))
+            }
+            Backend::Kubernetes { url, .. } => {
+                let namespace = service.namespace.as_deref().unwrap_or("default");
+                let url = join(
+                    url,
+                    &[
+                        "api",
+                        "v1",
+                        "namespaces",
+                        namespace,
+                        "endpoints",
+                        &service.name,
+                    ],
+                );
+
+                let resp = match self.execute(self.client.get(url)).await {
+                    Ok(resp) => resp,
+                    Err(ResolverError::Http(err))
+                        if err.status() == Some(StatusCode::NOT_FOUND) =>
+                    {
+                        return Ok((Vec::new(), String::new()));
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                let endpoints: Endpoints = json(resp).await?;
+                Ok((endpoints.addrs(), endpoints.metadata.resource_version))
+            }
+        }
+    }
+
+    /// Watches the endpoints of a Kubernetes service until the watch times out and returns the
+    /// last seen resource version.
+    async fn watch_kubernetes(
+        &self,
+        service: &Service,
+        version: &str,
+    ) -> Result<String, ResolverError> {
+        let Backend::Kubernetes { url, .. } = &self.backend else {
+            unreachable!();
+        };
+
+        let namespace = service.namespace.as_deref().unwrap_or("default");
+        let mut url = join(url, &["api", "v1", "namespaces", namespace, "endpoints"]);
+        url.query_pairs_mut()
+            .append_pair("watch", "1")
+            .append_pair("fieldSelector", &format!("metadata.name={}", service.name))
+            .append_pair("resourceVersion", version)
+            .append_pair("timeoutSeconds", &WATCH_TIMEOUT.as_secs().to_string());
+
+        let mut resp = self.execute(self.client.get(url)).await?;
+
+        // Events are newline-delimited JSON objects.
+        let mut version = version.to_owned();
+        let mut buf = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(ResolverError::Http)? {
+            buf.extend_from_slice(&chunk);
+
+            while let Some(index) = memchr::memchr(b'\n', &buf) {
+                let line: Vec<u8> = buf.drain(..=index).collect();
+                let event: WatchEvent = match serde_json::from_slice(&line) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        tracing::debug!("invalid watch event: {}", err);
+                        continue;
+                    }
+                };
+
+                let addrs = match event.r#type.as_str() {
+                    "ADDED" | "MODIFIED" => event.object.addrs(),
+                    "DELETED" => Vec::new(),
+                    // Most likely the resource version is too old.
+                    _ => return Err(ResolverError::NoAnswer),
+                };
+
+                self.update(service, addrs);
+                version = event.object.metadata.resource_version;
+            }
+        }
+
+        Ok(version)
+    }
+
+    async fn execute(&self, mut req: RequestBuilder) -> Result<reqwest::Response, ResolverError> {
+        match &self.backend {
+            Backend::Consul { token, .. } => {
+                if let Some(token) = token {
+                    req = req.header("x-consul-token", token);
+                }
+            }
+            Backend::Kubernetes { token_file, .. } => {
+                if let Some(path) = token_file {
+                    let token = tokio::fs::read_to_string(path)
+                        .await
+                        .map_err(ResolverError::Io)?;
+                    req = req.bearer_auth(token.trim());
+                }
+            }
+        }
+
+        req.send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(ResolverError::Http)
+    }
+}
+
+async fn json<T>(resp: reqwest::Response) -> Result<T, ResolverError>
+where
+    T: DeserializeOwned,
+{
+    let body = resp.bytes().await.map_err(ResolverError::Http)?;
+    serde_json::from_slice(&body).map_err(ResolverError::Json)
+}
+
+/// Appends `segments` to the path of `url`.
+fn join(url: &Url, segments: &[&str]) -> Url {
+    let mut url = url.clone();
+    if let Ok(mut path) = url.path_segments_mut() {
+        path.pop_if_empty().extend(segments);
+    }
+    url
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ConsulEntry {
+    #[serde(rename = "Node")]
+    node: ConsulNode,
+    #[serde(rename = "Service")]
+    service: ConsulService,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ConsulNode {
+    #[serde(rename = "Address")]
+    address: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ConsulService {
+    #[serde(rename = "Address", default)]
+    address: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct WatchEvent {
+    r#type: String,
+    object: Endpoints,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Endpoints {
+    #[serde(default)]
+    metadata: Metadata,
+    #[serde(default)]
+    subsets: Vec<Subset>,
+}
+
+impl Endpoints {
+    fn addrs(&self) -> Vec<IpAddr> {
+        self.subsets
+            .iter()
+            .flat_map(|subset| &subset.addresses)
+            .filter_map(|addr| addr.ip.parse().ok())
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Metadata {
+    #[serde(rename = "resourceVersion", default)]
+    resource_version: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Subset {
+    #[serde(default)]
+    addresses: Vec<EndpointAddress>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct EndpointAddress {
+    ip: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Instant;
+
+    use parking_lot::RwLock;
+    use reqwest::{Client, Url};
+
+    use crate::proto::Fqdn;
+
+    use super::{Backend, Endpoints, Entry, Inner, Service, MAX_SERVICES, SERVICE_IDLE};
+
+    #[test]
+    fn backend_service_name() {
+        let url = Url::parse("http://localhost").unwrap();
+
+        let consul = Backend::Consul {
+            url: url.clone(),
+            token: None,
+        };
+        assert_eq!(
+            consul.service(&Fqdn(b"web.service.consul.".to_vec())),
+            Some(Service {
+                name: "web".to_owned(),
+                namespace: None,
+            })
+        );
+
+        let kubernetes = Backend::Kubernetes {
+            url,
+            token_file: None,
+        };
+        assert_eq!(
+            kubernetes.service(&Fqdn(b"web.prod.svc.cluster.local.".to_vec())),
+            Some(Service {
+                name: "web".to_owned(),
+                namespace: Some("prod".to_owned()),
+            })
+        );
+        assert_eq!(kubernetes.service(&Fqdn(b"local.".to_vec())), None);
+    }
+
+    #[test]
+    fn endpoints_addrs() {
+        let endpoints: Endpoints = serde_json::from_str(
+            r#"{
+                "metadata": {"resourceVersion": "42"},
+                "subsets": [{"addresses": [{"ip": "10.0.0.1"}, {"ip": "fd00::1"}]}]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(endpoints.metadata.resource_version, "42");
+        assert_eq!(
+            endpoints.addrs(),
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "fd00::1".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn expire_aborts_watches() {
+        let inner = Inner {
+            client: Client::new(),
+            backend: Backend::Consul {
+                url: Url::parse("http://localhost").unwrap(),
+                token: None,
+            },
+            epoch: Instant::now() - SERVICE_IDLE,
+            services: RwLock::default(),
+        };
+        let service = |name: &str| Service {
+            name: name.to_owned(),
+            namespace: None,
+        };
+        let entry = |last_used| Entry {
+            addrs: Vec::new(),
+            last_used: AtomicU64::new(last_used),
+            watch: tokio::task::spawn(std::future::pending()),
+        };
+
+        let mut services = HashMap::new();
+        let idle = entry(0);
+        let idle_watch = idle.watch.abort_handle();
+        services.insert(service("idle"), idle);
+        services.insert(service("oldest"), entry(inner.now() - 1));
+        for i in 0..MAX_SERVICES - 2 {
+            services.insert(service(&i.to_string()), entry(inner.now()));
+        }
+
+        inner.expire(&mut services);
+        tokio::task::yield_now().await;
+        assert!(idle_watch.is_finished());
+        assert!(!services.contains_key(&service("idle")));
+
+        // The least recently used service makes room for a new one.
+        services.insert(service("new"), entry(inner.now()));
+        inner.expire(&mut services);
+        assert_eq!(services.len(), MAX_SERVICES - 1);
+        assert!(!services.contains_key(&service("oldest")));
+    }
+}
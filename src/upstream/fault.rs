@@ -0,0 +1,253 @@
+//! Fault injection for upstreams.
+//!
+//! Only available with the `fault-injection` feature. Used to exercise
+//! failover and retry logic in integration tests and staging.
+
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::config::Faults;
+use crate::metrics::ResolverId;
+use crate::proto::Packet;
+
+use super::{with_timeout, QueryProfile, Resolver, ResolverError, UpstreamResolver};
+
+/// A [`Resolver`] that injects faults into the exchanges with the wrapped upstream.
+#[derive(Debug)]
+pub struct FaultyResolver {
+    pub inner: Resolver,
+    faults: Faults,
+    rng: Mutex<StdRng>,
+}
+
+/// The fault injected into a single exchange.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Fault {
+    None,
+    /// The query is never answered.
+    Drop,
+    /// A byte of the response is flipped.
+    Corrupt,
+    /// The records of the response are removed and it is marked truncated.
+    Truncate,
+}
+
+impl FaultyResolver {
+    pub fn new(inner: Resolver, faults: Faults) -> Self {
+        let rng = match faults.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Self {
+            inner,
+            faults,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        let (fault, spike) = self.roll();
+
+        let mut latency = self.faults.latency;
+        if spike {
+            tracing::debug!("delaying query to upstream {}", self.inner.addr());
+            latency += self.faults.spike_latency;
+        }
+        if latency != 0 {
+            tokio::time::sleep(Duration::from_millis(latency)).await;
+        }
+
+        if fault == Fault::Drop {
+            tracing::debug!("dropping query to upstream {}", self.inner.addr());
+            // The caller gives up once the timeout is reached.
+            return futures::future::pending().await;
+        }
+
+        let resp = self.inner.exchange(query).await?;
+        if fault == Fault::Corrupt && !resp.is_empty() {
+            tracing::debug!("corrupting response from upstream {}", self.inner.addr());
+            let mut resp = BytesMut::from(&resp[..]);
+            let index = self.rng.lock().gen_range(0..resp.len());
+            resp[index] ^= 0xff;
+            return Ok(resp.freeze());
+        }
+        if fault == Fault::Truncate {
+            tracing::debug!("truncating response from upstream {}", self.inner.addr());
+            return Ok(truncate(resp));
+        }
+
+        Ok(resp)
+    }
+
+    /// Returns the fault of the next exchange and whether it is delayed by a
+    /// latency spike.
+    fn roll(&self) -> (Fault, bool) {
+        let mut rng = self.rng.lock();
+        let fault = if rng.gen_bool(self.faults.drop_rate.clamp(0.0, 1.0)) {
+            Fault::Drop
+        } else if rng.gen_bool(self.faults.corrupt_rate.clamp(0.0, 1.0)) {
+            Fault::Corrupt
+        } else if rng.gen_bool(self.faults.truncate_rate.clamp(0.0, 1.0)) {
+            Fault::Truncate
+        } else {
+            Fault::None
+        };
+        let spike = rng.gen_bool(self.faults.spike_rate.clamp(0.0, 1.0));
+        (fault, spike)
+    }
+}
+
+/// Returns `resp` without its records and with the TC bit set, like a
+/// response that did not fit into a datagram. Malformed responses are
+/// returned unchanged.
+fn truncate(resp: Bytes) -> Bytes {
+    let Ok(mut packet) = Packet::decode(resp.clone()) else {
+        return resp;
+    };
+    packet.truncated = true;
+    packet.answers.clear();
+    packet.authority.clear();
+    packet.additional.clear();
+
+    let mut buf = Vec::new();
+    packet.encode(&mut buf);
+    Bytes::from(buf)
+}
+
+impl UpstreamResolver for FaultyResolver {
+    // Dropped queries are given up once the timeout is reached.
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(with_timeout(
+            self.inner.timeout(),
+            FaultyResolver::exchange(self, query),
+        ))
+    }
+
+    fn addr(&self) -> String {
+        self.inner.addr()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.inner.id()
+    }
+
+    fn is_available(&self) -> bool {
+        self.inner.is_available()
+    }
+
+    fn profile(&self) -> QueryProfile {
+        self.inner.profile()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use crate::config::Faults;
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Question, Type};
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::udp::UdpResolver;
+    use crate::upstream::{QueryFlags, QueryProfile, Resolver, SocketOptions};
+
+    use super::{Fault, FaultyResolver};
+
+    fn resolver(faults: Faults) -> FaultyResolver {
+        let times = UpstreamTimes::default();
+        let id = times.register("127.0.0.1:53");
+        let inner = Resolver::new(UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Duration::from_secs(1),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        ));
+        FaultyResolver::new(inner, faults)
+    }
+
+    #[test]
+    fn fault_rates() {
+        let faults = Faults {
+            drop_rate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(resolver(faults).roll(), (Fault::Drop, false));
+
+        let faults = Faults {
+            corrupt_rate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(resolver(faults).roll(), (Fault::Corrupt, false));
+
+        let faults = Faults {
+            truncate_rate: 1.0,
+            spike_rate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(resolver(faults).roll(), (Fault::Truncate, true));
+
+        assert_eq!(resolver(Faults::default()).roll(), (Fault::None, false));
+    }
+
+    #[tokio::test]
+    async fn truncated_responses_are_empty() {
+        let server = MockServer::start(Script::answer("example.com.", Ipv4Addr::LOCALHOST)).await;
+        let times = UpstreamTimes::default();
+        let id = times.register(&server.addr.to_string());
+        let inner = Resolver::new(UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            server.addr,
+            Duration::from_secs(1),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        ));
+        let faults = Faults {
+            truncate_rate: 1.0,
+            ..Default::default()
+        };
+        let resolver = Resolver::new(FaultyResolver::new(inner, faults));
+
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+        // Like truncated responses of real upstreams, which are passed on.
+        let answer = resolver
+            .resolve(&question, &QueryFlags::default())
+            .await
+            .unwrap();
+        assert!(answer.records.is_empty());
+    }
+
+    #[test]
+    fn fault_seed_is_deterministic() {
+        let faults = Faults {
+            drop_rate: 0.5,
+            seed: Some(42),
+            ..Default::default()
+        };
+
+        let a = resolver(faults.clone());
+        let b = resolver(faults);
+        for _ in 0..32 {
+            assert_eq!(a.roll(), b.roll());
+        }
+    }
+}
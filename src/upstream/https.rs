@@ -1,69 +1,540 @@
-use std::time::Duration;
+pub mod bootstrap;
+pub mod tls;
 
-use reqwest::header::HeaderValue;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use parking_lot::RwLock;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE,
+};
 use reqwest::{Body, Client, ClientBuilder, Method, Request, Url};
+use tokio_rustls::rustls::{self, ClientConfig};
+
+use crate::metrics::{HttpErrorKind, ResolverId, UpstreamTime};
+use crate::proto::Packet;
 
-use crate::proto::{OpCode, Packet, Qr, Question, ResourceRecord, ResponseCode};
+use self::bootstrap::Bootstrap;
+use super::{with_timeout, ResolverError, SocketOptions, UpstreamResolver};
 
-use super::ResolverError;
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Maximum length of the URL of GET requests.
+///
+/// Longer URLs are not supported by all servers and caches, the query is sent
+/// with POST instead.
+const MAX_URL_LEN: usize = 2048;
 
 #[derive(Debug)]
 pub struct HttpsResolver {
-    client: Client,
+    pub id: ResolverId,
+    metrics: Arc<UpstreamTime>,
+    pool: RwLock<Pool>,
     pub url: Url,
     pub timeout: Duration,
+    /// Whether queries are sent with GET.
+    get: bool,
+    options: ClientOptions,
 }
 
-impl HttpsResolver {
-    pub fn new(url: Url, timeout: Duration) -> Self {
-        let client = ClientBuilder::new().use_rustls_tls().build().unwrap();
+/// How the connections to the upstream are made.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    /// Time after which idle pooled connections are closed.
+    pub idle_timeout: Duration,
+    /// Time after which pooled connections are replaced, even if they are in use.
+    pub max_lifetime: Option<Duration>,
+    /// Headers added to every request.
+    pub headers: HeaderMap,
+    pub socket: SocketOptions,
+    /// Resolves the hostname of the upstream instead of the system resolver.
+    pub bootstrap: Option<Bootstrap>,
+    /// Replaces the verification of the certificate against the public CAs.
+    pub tls: Option<Arc<ClientConfig>>,
+}
 
+/// The connection pool of the upstream.
+#[derive(Debug)]
+struct Pool {
+    client: Client,
+    created: Instant,
+    /// The generation of the bootstrapped addresses the pool connects to.
+    generation: u64,
+}
+
+impl HttpsResolver {
+    pub fn new(
+        id: ResolverId,
+        metrics: Arc<UpstreamTime>,
+        url: Url,
+        timeout: Duration,
+        get: bool,
+        options: ClientOptions,
+    ) -> Self {
         Self {
-            client,
+            id,
+            metrics,
+            pool: RwLock::new(Pool::new(timeout, &options)),
             url,
             timeout,
+            get,
+            options,
         }
     }
 
-    pub async fn resolve(&self, question: &Question) -> Result<Vec<ResourceRecord>, ResolverError> {
-        let packet = Packet {
-            transaction_id: rand::random(),
-            qr: Qr::Request,
-            opcode: OpCode::Query,
-            authoritative_answer: false,
-            truncated: false,
-            recursion_available: false,
-            recursion_desired: true,
-            response_code: ResponseCode::Ok,
-            questions: vec![question.clone()],
-            additional: vec![],
-            answers: vec![],
-            authority: vec![],
+    /// Sends `query` to the upstream and returns the raw response.
+    ///
+    /// The request is aborted by the client once `deadline` is reached, so
+    /// no connection keeps working on a query that was already given up.
+    pub async fn exchange(
+        &self,
+        query: &Packet,
+        deadline: Instant,
+    ) -> Result<Bytes, ResolverError> {
+        let mut buf = Vec::new();
+        query.encode(&mut buf);
+
+        let mut req = match self.get_url(&mut buf) {
+            Some(url) => Request::new(Method::GET, url),
+            None => {
+                let mut req = Request::new(Method::POST, self.url.clone());
+                req.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+                *req.body_mut() = Some(Body::from(buf));
+                req
+            }
         };
+        req.headers_mut()
+            .insert("accept", HeaderValue::from_static(DNS_MESSAGE));
+        *req.timeout_mut() = Some(deadline.saturating_duration_since(Instant::now()));
+
+        let resp = self
+            .client()
+            .execute(req)
+            .await
+            .map_err(|err| self.http_error(err))?;
+
+        // Proxies in front of the upstream answer errors with HTML pages.
+        let is_dns_message = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(';').next() == Some(DNS_MESSAGE));
+        self.metrics.http_status(resp.status().as_u16());
+        if !resp.status().is_success() || !is_dns_message {
+            return Err(ResolverError::HttpStatus(resp.status()));
+        }
+
+        let freshness = Freshness::from_headers(resp.headers());
+        let body = resp.bytes().await.map_err(|err| self.http_error(err))?;
+        Ok(freshness.apply(body))
+    }
+
+    /// Counts the failed request unless it timed out, which is counted
+    /// with the timeouts of the upstream.
+    fn http_error(&self, err: reqwest::Error) -> ResolverError {
+        if err.is_timeout() {
+            ResolverError::Timeout
+        } else {
+            self.metrics.http_error(error_kind(&err));
+            ResolverError::Http(err)
+        }
+    }
+
+    /// Returns the URL to send the encoded query in `buf` with GET, or `None`
+    /// if it must be sent with POST.
+    fn get_url(&self, buf: &mut [u8]) -> Option<Url> {
+        if !self.get {
+            return None;
+        }
+
+        // Equal queries must have equal URLs to be answered from caches.
+        // See https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
+        buf[..2].fill(0);
+
+        let mut url = self.url.clone();
+        url.query_pairs_mut()
+            .append_pair("dns", &URL_SAFE_NO_PAD.encode(&buf));
+        (url.as_str().len() <= MAX_URL_LEN).then_some(url)
+    }
+
+    /// Returns the client to send the next request with.
+    ///
+    /// Once the pool exceeds its maximum lifetime or the addresses of the
+    /// upstream changed it is replaced by a new one. Requests still in
+    /// flight keep using the old connections, which are closed once they
+    /// complete.
+    fn client(&self) -> Client {
+        if let Some(bootstrap) = &self.options.bootstrap {
+            bootstrap.refresh_if_expired();
+        }
+
+        {
+            let pool = self.pool.read();
+            if !self.is_expired(&pool) {
+                return pool.client.clone();
+            }
+        }
+
+        let mut pool = self.pool.write();
+        // Another request may have replaced the pool while we were waiting
+        // for the write lock.
+        if self.is_expired(&pool) {
+            tracing::debug!("replacing connections to upstream {}", self.url);
+            *pool = Pool::new(self.timeout, &self.options);
+        }
+
+        pool.client.clone()
+    }
+
+    /// Returns `true` if the connections of `pool` must be replaced.
+    fn is_expired(&self, pool: &Pool) -> bool {
+        self.options
+            .max_lifetime
+            .is_some_and(|max_lifetime| pool.created.elapsed() >= max_lifetime)
+            || self
+                .options
+                .bootstrap
+                .as_ref()
+                .is_some_and(|bootstrap| bootstrap.generation() != pool.generation)
+    }
+}
+
+impl UpstreamResolver for HttpsResolver {
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        let deadline = Instant::now() + self.timeout;
+        Box::pin(with_timeout(
+            self.timeout,
+            HttpsResolver::exchange(self, query, deadline),
+        ))
+    }
+
+    fn addr(&self) -> String {
+        self.url.to_string()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.id
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Converts the configured `headers`.
+///
+/// Credentials in the `authorization` header are never logged.
+pub fn header_map(headers: &HashMap<String, String>) -> HeaderMap {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = HeaderName::try_from(name)
+                .unwrap_or_else(|_| panic!("invalid config: invalid header name {}", name));
+            let mut value = HeaderValue::try_from(value)
+                .unwrap_or_else(|_| panic!("invalid config: invalid value of header {}", name));
+            value.set_sensitive(name == AUTHORIZATION);
+            (name, value)
+        })
+        .collect()
+}
+
+/// How long a response may still be used, according to the HTTP caches it
+/// was served from.
+///
+/// See https://datatracker.ietf.org/doc/html/rfc8484#section-5.1
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct Freshness {
+    /// Seconds the response was stored in caches.
+    age: u32,
+    /// Seconds the response was fresh for when it left the upstream.
+    max_age: Option<u32>,
+}
+
+impl Freshness {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let age = headers
+            .get(AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0);
+        let max_age = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|directive| {
+                let (name, value) = directive.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("max-age")
+                    .then(|| value.trim().trim_matches('"').parse().ok())?
+            });
+
+        Self { age, max_age }
+    }
+
+    /// Returns `resp` with the age subtracted from the TTLs of all records.
+    /// TTLs beyond the time the response is still fresh are lowered to it.
+    ///
+    /// Responses are only encoded again if a TTL changed. Malformed
+    /// responses are returned unchanged.
+    fn apply(self, resp: Bytes) -> Bytes {
+        if self == Self::default() {
+            return resp;
+        }
+        let Ok(mut packet) = Packet::decode(resp.clone()) else {
+            return resp;
+        };
+
+        let max_ttl = self
+            .max_age
+            .map_or(u32::MAX, |max_age| max_age.saturating_sub(self.age));
+        let mut changed = false;
+        for record in packet
+            .answers
+            .iter_mut()
+            .chain(&mut packet.authority)
+            .chain(&mut packet.additional)
+        {
+            let ttl = record.ttl.saturating_sub(self.age).min(max_ttl);
+            changed |= ttl != record.ttl;
+            record.ttl = ttl;
+        }
+        if !changed {
+            return resp;
+        }
 
         let mut buf = Vec::new();
         packet.encode(&mut buf);
+        Bytes::from(buf)
+    }
+}
+
+/// Returns why the request failed with `err`.
+fn error_kind(err: &reqwest::Error) -> HttpErrorKind {
+    // Failed handshakes are connect errors, only their cause tells them apart.
+    if err.source().is_some_and(is_tls_error) {
+        HttpErrorKind::Tls
+    } else if err.is_connect() {
+        HttpErrorKind::Connect
+    } else if err.is_body() || err.is_decode() {
+        HttpErrorKind::Body
+    } else {
+        HttpErrorKind::Other
+    }
+}
+
+/// Returns `true` if `err` or any of its causes is a TLS error.
+fn is_tls_error(err: &(dyn Error + 'static)) -> bool {
+    // I/O errors hide the error they wrap from the chain of causes.
+    let inner = err.downcast_ref::<io::Error>().and_then(io::Error::get_ref);
+    err.is::<rustls::Error>()
+        || inner.is_some_and(|inner| is_tls_error(inner))
+        || err.source().is_some_and(is_tls_error)
+}
+
+impl Pool {
+    fn new(timeout: Duration, options: &ClientOptions) -> Self {
+        // New connections, including the TLS handshake, must not take
+        // longer than any query that is waiting on them.
+        let mut builder = ClientBuilder::new()
+            .use_rustls_tls()
+            .connect_timeout(timeout)
+            .pool_idle_timeout(options.idle_timeout)
+            .default_headers(options.headers.clone())
+            .local_address(options.socket.source);
+        if let Some(interface) = &options.socket.interface {
+            builder = builder.interface(interface.name());
+        }
+        if let Some(proxy) = &options.socket.proxy {
+            builder = builder.proxy(proxy.reqwest.clone());
+        }
+        if let Some(tls) = &options.tls {
+            builder = builder.use_preconfigured_tls(ClientConfig::clone(tls));
+        }
+        let mut generation = 0;
+        if let Some(bootstrap) = &options.bootstrap {
+            generation = bootstrap.generation();
+            builder = builder.dns_resolver(Arc::new(bootstrap.clone()));
+        }
+        let client = builder.build().unwrap();
+
+        Self {
+            client,
+            created: Instant::now(),
+            generation,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use reqwest::header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL};
+    use reqwest::Url;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use crate::metrics::{HttpErrorKind, UpstreamTimes};
+    use crate::proto::{
+        Class, Fqdn, OpCode, Packet, Qr, Question, RecordData, ResourceRecord, ResponseCode, Type,
+    };
+    use crate::upstream::QueryProfile;
 
-        let mut req = Request::new(Method::POST, self.url.clone());
-        req.headers_mut().insert(
-            "content-type",
-            HeaderValue::from_static("application/dns-message"),
+    use super::{ClientOptions, Freshness, HttpsResolver, MAX_URL_LEN};
+
+    #[test]
+    fn get_url_falls_back_to_post() {
+        let url = "https://dns.example/dns-query";
+        let times = UpstreamTimes::default();
+        let id = times.register(url);
+        let resolver = HttpsResolver::new(
+            id,
+            times.get(id).unwrap(),
+            Url::parse(url).unwrap(),
+            Duration::from_secs(5),
+            true,
+            ClientOptions::default(),
         );
 
-        *req.body_mut() = Some(Body::from(buf));
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00];
+        assert_eq!(
+            resolver.get_url(&mut buf).unwrap().as_str(),
+            "https://dns.example/dns-query?dns=AAABAA"
+        );
+        assert_eq!(buf[..2], [0, 0]);
 
-        let resp = self
-            .client
-            .execute(req)
+        let mut buf = vec![0; MAX_URL_LEN];
+        assert_eq!(resolver.get_url(&mut buf), None);
+    }
+
+    #[tokio::test]
+    async fn counts_request_errors() {
+        // Answers the TLS handshake in plain text.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let plain = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+            }
+        });
+
+        // Nothing listens on the port once the listener is dropped.
+        let closed = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
             .await
-            .map_err(ResolverError::Http)?;
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let times = UpstreamTimes::default();
+        let query = QueryProfile::FORWARDER.build_query(&Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        });
+        for (addr, kind) in [
+            (plain, HttpErrorKind::Tls),
+            (closed, HttpErrorKind::Connect),
+        ] {
+            let url = format!("https://{}/dns-query", addr);
+            let id = times.register(&url);
+            let resolver = HttpsResolver::new(
+                id,
+                times.get(id).unwrap(),
+                Url::parse(&url).unwrap(),
+                Duration::from_secs(5),
+                false,
+                ClientOptions::default(),
+            );
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            assert!(resolver.exchange(&query, deadline).await.is_err());
+            let counts: Vec<_> = HttpErrorKind::ALL
+                .iter()
+                .map(|other| {
+                    times.get(id).unwrap().http_errors[*other as usize].load(Ordering::Relaxed)
+                })
+                .collect();
+            let expected: Vec<_> = HttpErrorKind::ALL
+                .iter()
+                .map(|other| u64::from(*other == kind))
+                .collect();
+            assert_eq!(counts, expected, "{:?}", kind);
+        }
+    }
 
-        if resp.status().is_success() {}
+    #[test]
+    fn freshness_reduces_ttls() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AGE, HeaderValue::from_static("100"));
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=250"),
+        );
+        let freshness = Freshness::from_headers(&headers);
+        assert_eq!(
+            freshness,
+            Freshness {
+                age: 100,
+                max_age: Some(250),
+            }
+        );
+        assert_eq!(
+            Freshness::from_headers(&HeaderMap::new()),
+            Freshness::default()
+        );
+
+        let record = |ttl| ResourceRecord {
+            name: Fqdn(b"example.com.".to_vec()),
+            r#type: Type::A,
+            class: Class::In,
+            ttl,
+            rdata: RecordData::A(Ipv4Addr::LOCALHOST),
+        };
+        let resp = Packet {
+            transaction_id: 1,
+            qr: Qr::Response,
+            opcode: OpCode::Query,
+            authoritative_answer: false,
+            truncated: false,
+            recursion_desired: true,
+            recursion_available: true,
+            authentic_data: false,
+            checking_disabled: false,
+            response_code: ResponseCode::Ok,
+            questions: Vec::new(),
+            raw_questions: None,
+            answers: vec![record(50), record(300), record(3600)],
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: None,
+        };
+        let mut buf = Vec::new();
+        resp.encode(&mut buf);
 
-        let data = resp.bytes().await.map_err(ResolverError::Http)?;
+        let buf = Bytes::from(buf);
 
-        let resp = Packet::decode(&data).map_err(ResolverError::Decode)?;
+        let reduced = Packet::decode(freshness.apply(buf.clone())).unwrap();
+        let ttls: Vec<_> = reduced.answers.iter().map(|record| record.ttl).collect();
+        assert_eq!(ttls, [0, 150, 150]);
 
-        Ok(resp.answers)
+        // Fresh responses with lower TTLs are passed on as they are.
+        let freshness = Freshness {
+            age: 0,
+            max_age: Some(3600),
+        };
+        assert_eq!(freshness.apply(buf.clone()).as_ptr(), buf.as_ptr());
     }
 }
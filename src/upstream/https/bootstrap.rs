@@ -0,0 +1,237 @@
+//! Resolution of the hostname of an upstream through a bootstrap server.
+//!
+//! The addresses are resolved again once their TTL expired, so that the
+//! upstream stays reachable when its provider changes them.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::proto::{Class, Fqdn, Question, RecordData, Type};
+use crate::upstream::{jitter, QueryFlags, Resolver, ResolverError};
+
+/// Minimum time the addresses are used before they are resolved again.
+const MIN_TTL: Duration = Duration::from_secs(30);
+
+/// Maximum time the addresses are used before they are resolved again.
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// Time after which a failed resolution is retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Fraction by which refreshes are randomly brought forward, so that
+/// upstreams sharing a host don't refresh at the same time.
+const TTL_JITTER: f64 = 0.1;
+
+/// Resolves the hostname of an upstream with the `resolver` instead of the
+/// system resolver.
+#[derive(Clone, Debug)]
+pub struct Bootstrap {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    host: String,
+    resolver: Resolver,
+    resolved: RwLock<Resolved>,
+    /// Whether the addresses are being resolved in the background.
+    refreshing: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct Resolved {
+    ips: Vec<IpAddr>,
+    /// `None` until the addresses were resolved once.
+    valid_until: Option<Instant>,
+    /// Incremented whenever the addresses change.
+    generation: u64,
+}
+
+impl Bootstrap {
+    pub fn new(host: &str, resolver: Resolver) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                host: host.to_owned(),
+                resolver,
+                resolved: RwLock::default(),
+                refreshing: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Returns a number that changes whenever the addresses change.
+    pub fn generation(&self) -> u64 {
+        self.shared.resolved.read().generation
+    }
+
+    /// Resolves the addresses again in the background if they expired.
+    ///
+    /// The expired addresses are used until then.
+    pub fn refresh_if_expired(&self) {
+        let expired = self
+            .shared
+            .resolved
+            .read()
+            .valid_until
+            .is_some_and(|valid_until| valid_until <= Instant::now());
+        if !expired || self.shared.refreshing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let this = self.clone();
+        tokio::task::spawn(async move {
+            let _ = this.refresh().await;
+            this.shared.refreshing.store(false, Ordering::Release);
+        });
+    }
+
+    /// Resolves the addresses of the host and stores them.
+    async fn refresh(&self) -> Result<Vec<IpAddr>, ResolverError> {
+        let shared = &self.shared;
+        let result = lookup(&shared.resolver, &shared.host).await;
+
+        let mut resolved = shared.resolved.write();
+        match result {
+            Ok((mut ips, ttl)) => {
+                ips.sort();
+                if ips != resolved.ips {
+                    // Connections made before the first resolution used no
+                    // addresses at all.
+                    if !resolved.ips.is_empty() {
+                        tracing::info!("addresses of {} changed to {:?}", shared.host, ips);
+                        resolved.generation += 1;
+                    }
+                    resolved.ips = ips.clone();
+                }
+                resolved.valid_until = Some(Instant::now() + jitter(ttl, TTL_JITTER));
+                Ok(ips)
+            }
+            Err(err) => {
+                tracing::warn!("failed to resolve {}: {:?}", shared.host, err);
+                resolved.valid_until = Some(Instant::now() + jitter(RETRY_INTERVAL, TTL_JITTER));
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Resolve for Bootstrap {
+    fn resolve(&self, name: Name) -> Resolving {
+        let this = self.clone();
+        Box::pin(async move {
+            let ips = if name.as_str().eq_ignore_ascii_case(&this.shared.host) {
+                let ips = this.shared.resolved.read().ips.clone();
+                if ips.is_empty() {
+                    this.refresh().await
+                } else {
+                    Ok(ips)
+                }
+            } else {
+                lookup(&this.shared.resolver, name.as_str())
+                    .await
+                    .map(|(ips, _)| ips)
+            };
+
+            // The port is set by the client.
+            let ips = ips.map_err(|err| io::Error::other(format!("{:?}", err)))?;
+            let addrs: Addrs = Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+/// Resolves the IPv4 and IPv6 addresses of `host`.
+///
+/// Returns the addresses with the time until they must be resolved again.
+async fn lookup(resolver: &Resolver, host: &str) -> Result<(Vec<IpAddr>, Duration), ResolverError> {
+    let name = Fqdn::new_unchecked(format!("{}.", host.trim_end_matches('.')));
+    let question = |qtype| Question {
+        name: name.clone(),
+        qtype,
+        qclass: Class::In,
+    };
+    let (v4, v6) = (question(Type::A), question(Type::AAAA));
+    let flags = QueryFlags::default();
+    let (v4, v6) = futures::join!(resolver.resolve(&v4, &flags), resolver.resolve(&v6, &flags));
+
+    // Hosts without IPv6 addresses may fail the AAAA query.
+    let answers = match (v4, v6) {
+        (Err(err), Err(_)) => return Err(err),
+        (v4, v6) => v4.into_iter().chain(v6).flat_map(|answer| answer.records),
+    };
+
+    let mut ips = Vec::new();
+    let mut ttl = MAX_TTL;
+    for answer in answers {
+        let ip = match answer.rdata {
+            RecordData::A(addr) => IpAddr::V4(addr),
+            RecordData::AAAA(addr) => IpAddr::V6(addr),
+            _ => continue,
+        };
+        ips.push(ip);
+        ttl = ttl.min(Duration::from_secs(answer.ttl.into()));
+    }
+
+    if ips.is_empty() {
+        return Err(ResolverError::NoAnswer);
+    }
+
+    Ok((ips, ttl.max(MIN_TTL)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use crate::metrics::UpstreamTimes;
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::udp::UdpResolver;
+    use crate::upstream::{QueryProfile, Resolver, SocketOptions};
+
+    use super::{Bootstrap, MIN_TTL};
+
+    #[tokio::test]
+    async fn refresh_tracks_changed_addresses() {
+        let script = |addr| {
+            let mut script = Script::answer("dns.example.", addr);
+            script.records[0].ttl = 5;
+            script
+        };
+        let server = MockServer::start(script(Ipv4Addr::new(192, 0, 2, 1))).await;
+        let addr = server.addr;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let resolver = UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_secs(5),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        );
+        let bootstrap = Bootstrap::new("dns.example", Resolver::new(resolver));
+        assert_eq!(bootstrap.generation(), 0);
+
+        let ips = bootstrap.refresh().await.unwrap();
+        assert_eq!(ips, [IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(bootstrap.generation(), 0);
+
+        // The TTL of the records is below the minimum.
+        let valid_until = bootstrap.shared.resolved.read().valid_until.unwrap();
+        assert!(valid_until > Instant::now() + MIN_TTL / 2);
+
+        server.set_script(script(Ipv4Addr::new(192, 0, 2, 2)));
+        let ips = bootstrap.refresh().await.unwrap();
+        assert_eq!(ips, [IpAddr::from([192, 0, 2, 2])]);
+        assert_eq!(bootstrap.generation(), 1);
+    }
+}
@@ -0,0 +1,252 @@
+//! Verification of upstream certificates issued by a private CA or pinned
+//! by their public key, or not at all for opportunistic DNS over TLS.
+
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::ring as provider;
+use tokio_rustls::rustls::crypto::{
+    verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::ParsedCertificate;
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+};
+
+/// Builds the TLS configuration of the connections to an upstream.
+///
+/// See [`server_verifier`].
+pub fn client_config(ca_file: Option<&Path>, pins: &[String]) -> ClientConfig {
+    let mut config = ClientConfig::builder_with_provider(Arc::new(provider::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(server_verifier(ca_file, pins))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config
+}
+
+/// Builds the TLS configuration of DNS over TLS connections to an upstream.
+pub fn dot_client_config(verifier: Arc<dyn ServerCertVerifier>) -> ClientConfig {
+    let mut config = ClientConfig::builder_with_provider(Arc::new(provider::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"dot".to_vec()];
+    config
+}
+
+/// Builds the verifier of the certificate of an upstream.
+///
+/// With `ca_file` the certificate must be issued by one of its CAs instead
+/// of a public one. With `pins` the certificate or one of the intermediates
+/// sent by the upstream must also have one of the public keys.
+pub fn server_verifier(ca_file: Option<&Path>, pins: &[String]) -> Arc<dyn ServerCertVerifier> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let file = std::fs::File::open(path).unwrap_or_else(|err| {
+                panic!("invalid config: failed to open {}: {}", path.display(), err)
+            });
+            for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                roots.add(cert.unwrap()).unwrap();
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let verifier = WebPkiServerVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(provider::default_provider()),
+    )
+    .build()
+    .unwrap();
+    if pins.is_empty() {
+        return verifier;
+    }
+
+    Arc::new(PinnedKeys {
+        inner: verifier,
+        pins: pins.iter().map(|pin| parse_pin(pin)).collect(),
+    })
+}
+
+/// Builds a verifier that accepts any certificate of an upstream.
+///
+/// This only protects from passive eavesdroppers, see
+/// https://datatracker.ietf.org/doc/html/rfc8310#section-5
+pub fn opportunistic_verifier() -> Arc<dyn ServerCertVerifier> {
+    Arc::new(AnyCertificate {
+        algorithms: provider::default_provider().signature_verification_algorithms,
+    })
+}
+
+/// Parses the base64 encoded SHA-256 hash of a SubjectPublicKeyInfo.
+fn parse_pin(pin: &str) -> [u8; 32] {
+    STANDARD
+        .decode(pin)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .unwrap_or_else(|| panic!("invalid config: invalid public key pin {}", pin))
+}
+
+/// Returns `true` if the public key of `cert` is one of `pins`.
+fn is_pinned(cert: &CertificateDer<'_>, pins: &[[u8; 32]]) -> bool {
+    let Ok(cert) = ParsedCertificate::try_from(cert) else {
+        return false;
+    };
+
+    let hash = digest(&SHA256, cert.subject_public_key_info().as_ref());
+    pins.iter().any(|pin| pin[..] == *hash.as_ref())
+}
+
+/// Only accepts certificate chains that contain one of the pinned public
+/// keys.
+#[derive(Debug)]
+struct PinnedKeys {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedKeys {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if !std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| is_pinned(cert, &self.pins))
+        {
+            return Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Accepts any certificate, but still verifies that the upstream has its
+/// private key.
+#[derive(Debug)]
+struct AnyCertificate {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::path::Path;
+
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+
+    use super::server_verifier;
+
+    #[test]
+    fn server_verifier_checks_pins() {
+        let pem = include_bytes!("../../../testdata/certs/upstream.pem");
+        let cert: CertificateDer<'_> = rustls_pemfile::certs(&mut BufReader::new(&pem[..]))
+            .next()
+            .unwrap()
+            .unwrap();
+        let name = ServerName::try_from("dns.example").unwrap();
+
+        let verify = |ca_file: &str, pins: &[&str]| {
+            let pins: Vec<_> = pins.iter().map(|pin| pin.to_string()).collect();
+            server_verifier(Some(Path::new(ca_file)), &pins)
+                .verify_server_cert(&cert, &[], &name, &[], UnixTime::now())
+                .is_ok()
+        };
+
+        let ca = "testdata/certs/upstream-ca.pem";
+        let leaf = "NhFLryT2HrSl22JzHreltzRKRiOqCqsCWPOGAiFrS90=";
+        assert!(verify(ca, &[]));
+        assert!(verify(ca, &[leaf]));
+        assert!(!verify(
+            ca,
+            &["7pp9qw/vlOLLtFBwELKTVMBN3eUbmueBLYhVwaRZxAE="]
+        ));
+        // Pins don't replace the verification of the chain.
+        assert!(!verify("testdata/certs/ca.pem", &[leaf]));
+    }
+}
@@ -0,0 +1,159 @@
+//! The state of the network interfaces upstreams are bound to.
+//!
+//! Reading the state from sysfs blocks, so it is polled in the background and
+//! queries only load an atomic.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// Time between two reads of the state of an interface.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A network interface connections are bound to.
+#[derive(Clone, Debug)]
+pub struct Interface {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    name: String,
+    up: AtomicBool,
+    polling: AtomicBool,
+}
+
+impl Interface {
+    /// Reads the current state of the interface `name`.
+    pub fn new(name: String) -> Self {
+        let up = operstate_is_up(
+            std::fs::read_to_string(operstate_path(&name))
+                .ok()
+                .as_deref(),
+        );
+
+        Self {
+            shared: Arc::new(Shared {
+                name,
+                up: AtomicBool::new(up),
+                polling: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.shared.name
+    }
+
+    /// Returns `true` if the interface exists and is not down.
+    ///
+    /// The state is polled in the background, starting with the first call.
+    pub fn is_up(&self) -> bool {
+        if !self.shared.polling.swap(true, Ordering::Relaxed) {
+            tokio::task::spawn(poll(Arc::downgrade(&self.shared)));
+        }
+
+        self.shared.up.load(Ordering::Relaxed)
+    }
+}
+
+/// Polls the state of the interface until it is no longer used.
+async fn poll(shared: Weak<Shared>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let operstate = tokio::fs::read_to_string(operstate_path(&shared.name)).await;
+        let up = operstate_is_up(operstate.ok().as_deref());
+        if shared.up.swap(up, Ordering::Relaxed) != up {
+            tracing::info!(
+                "interface {} is {}",
+                shared.name,
+                if up { "up" } else { "down" }
+            );
+        }
+    }
+}
+
+/// Returns the addresses of all interfaces of the host.
+pub fn host_addrs() -> io::Result<Vec<IpAddr>> {
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addrs = Vec::new();
+    let mut ifaddr = ifaddrs;
+    while let Some(entry) = unsafe { ifaddr.as_ref() } {
+        ifaddr = entry.ifa_next;
+
+        // Interfaces without an address, e.g. while they are down.
+        let Some(addr) = (unsafe { entry.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        // The address is as long as its family requires.
+        match i32::from(addr.sa_family) {
+            libc::AF_INET => {
+                let addr = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in>() };
+                addrs.push(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into());
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in6>() };
+                addrs.push(Ipv6Addr::from(addr.sin6_addr.s6_addr).into());
+            }
+            _ => (),
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+fn operstate_path(name: &str) -> String {
+    format!("/sys/class/net/{}/operstate", name)
+}
+
+/// Returns `true` if the interface with the sysfs `operstate` exists and is
+/// not down.
+fn operstate_is_up(operstate: Option<&str>) -> bool {
+    // Tunnel interfaces like WireGuard don't report their state
+    // and are always "unknown" while they exist.
+    operstate.is_some_and(|state| state.trim() != "down")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::{host_addrs, operstate_is_up, Interface};
+
+    #[test]
+    fn operstate() {
+        assert!(operstate_is_up(Some("up\n")));
+        assert!(operstate_is_up(Some("unknown\n")));
+        assert!(!operstate_is_up(Some("down\n")));
+        assert!(!operstate_is_up(None));
+    }
+
+    #[test]
+    fn host_addrs_include_loopback() {
+        let addrs = host_addrs().unwrap();
+        assert!(addrs.contains(&Ipv4Addr::LOCALHOST.into()));
+    }
+
+    #[tokio::test]
+    async fn missing_interface_is_down() {
+        let interface = Interface::new("rdns-missing0".to_owned());
+        assert!(!interface.is_up());
+
+        // The background poll agrees.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!interface.is_up());
+    }
+}
@@ -0,0 +1,161 @@
+//! Limits of concurrent queries to an upstream.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use tokio::sync::Semaphore;
+
+use crate::config::Concurrency;
+use crate::metrics::{ResolverId, UpstreamTime};
+use crate::proto::Packet;
+
+use super::{QueryProfile, Resolver, ResolverError, UpstreamResolver};
+
+/// A [`Resolver`] that bounds the number of queries in flight to the
+/// wrapped upstream.
+///
+/// Queries beyond the limit wait in a bounded queue. Once the queue is full
+/// they fail with [`ResolverError::Overloaded`] and go to the next upstream.
+#[derive(Debug)]
+pub struct LimitedResolver {
+    pub inner: Resolver,
+    permits: Semaphore,
+    /// Maximum number of waiting queries.
+    queue: usize,
+    waiting: AtomicUsize,
+    metrics: Arc<UpstreamTime>,
+}
+
+impl LimitedResolver {
+    pub fn new(inner: Resolver, concurrency: &Concurrency, metrics: Arc<UpstreamTime>) -> Self {
+        Self {
+            inner,
+            permits: Semaphore::new(concurrency.max),
+            queue: concurrency.queue,
+            waiting: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        let _permit = match self.permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let waiting = Waiting(&self.waiting);
+                if self.waiting.fetch_add(1, Ordering::AcqRel) >= self.queue {
+                    self.metrics.overloaded.fetch_add(1, Ordering::Relaxed);
+                    return Err(ResolverError::Overloaded);
+                }
+
+                // Queries don't wait for a permit longer than for a response.
+                let permit =
+                    tokio::time::timeout(self.inner.timeout(), self.permits.acquire()).await;
+                drop(waiting);
+                match permit {
+                    Ok(permit) => permit.unwrap(),
+                    Err(_) => return Err(ResolverError::Timeout),
+                }
+            }
+        };
+
+        self.inner.exchange(query).await
+    }
+}
+
+impl UpstreamResolver for LimitedResolver {
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(LimitedResolver::exchange(self, query))
+    }
+
+    fn addr(&self) -> String {
+        self.inner.addr()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.inner.id()
+    }
+
+    fn is_available(&self) -> bool {
+        self.inner.is_available()
+    }
+
+    fn profile(&self) -> QueryProfile {
+        self.inner.profile()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+}
+
+/// Removes a query from the queue, even if it is cancelled while waiting.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::config::Concurrency;
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Question, Type};
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::udp::UdpResolver;
+    use crate::upstream::{QueryFlags, QueryProfile, Resolver, ResolverError, SocketOptions};
+
+    use super::LimitedResolver;
+
+    #[tokio::test]
+    async fn fails_queries_beyond_queue() {
+        // Never answers, so that every query stays in flight.
+        let server = MockServer::start(Script {
+            drop: true,
+            ..Script::default()
+        })
+        .await;
+        let addr = server.addr;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let inner = UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_millis(200),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        );
+        let resolver = Resolver::new(LimitedResolver::new(
+            Resolver::new(inner),
+            &Concurrency { max: 1, queue: 1 },
+            times.get(id).unwrap(),
+        ));
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+
+        let flags = QueryFlags::default();
+        let (first, second, third) = futures::join!(
+            resolver.resolve(&question, &flags),
+            resolver.resolve(&question, &flags),
+            resolver.resolve(&question, &flags),
+        );
+        assert!(matches!(first, Err(ResolverError::Timeout)));
+        // The queued query times out while waiting for the first one.
+        assert!(matches!(second, Err(ResolverError::Timeout)));
+        assert!(matches!(third, Err(ResolverError::Overloaded)));
+        assert_eq!(times.get(id).unwrap().overloaded.load(Ordering::Relaxed), 1);
+    }
+}
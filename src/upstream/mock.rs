@@ -0,0 +1,264 @@
+//! A scriptable DNS server to test resolving from upstreams end-to-end.
+//!
+//! The server listens for UDP and TCP on the same ephemeral port of the
+//! loopback interface, like a real upstream.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::proto::{
+    Class, Fqdn, Packet, Qr, Question, RecordData, ResourceRecord, ResponseCode, Type,
+};
+
+/// How the server responds to queries.
+#[derive(Clone, Debug)]
+pub struct Script {
+    /// Records answered for questions with the same name and type. CNAME
+    /// records are answered for every type, followed by the records of their
+    /// target.
+    pub records: Vec<ResourceRecord>,
+    pub response_code: ResponseCode,
+    /// Time before every response is sent.
+    pub delay: Duration,
+    /// Whether responses over UDP are truncated, so that the query must be
+    /// repeated over TCP.
+    pub truncate: bool,
+    /// Whether queries are never answered.
+    pub drop: bool,
+    /// Number of the first queries over UDP that are never answered, like
+    /// lost packets.
+    pub lost: usize,
+}
+
+impl Script {
+    /// Answers `name` with the A record `addr`.
+    pub fn answer(name: &str, addr: Ipv4Addr) -> Self {
+        Self {
+            records: vec![ResourceRecord {
+                name: Fqdn::new_unchecked(name.to_owned()),
+                r#type: Type::A,
+                class: Class::In,
+                ttl: 60,
+                rdata: RecordData::A(addr),
+            }],
+            ..Self::default()
+        }
+    }
+
+    /// Responds to every query with `response_code`.
+    pub fn error(response_code: ResponseCode) -> Self {
+        Self {
+            response_code,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the records answering `question`, following CNAME chains like
+    /// a recursive resolver.
+    fn lookup(&self, question: &Question) -> Vec<ResourceRecord> {
+        let mut answers = Vec::new();
+        let mut name = question.name.clone();
+        // Bounded in case of CNAME loops.
+        for _ in 0..8 {
+            let records = self.records.iter().filter(|record| {
+                record.name.as_bytes().eq_ignore_ascii_case(name.as_bytes())
+                    && (record.r#type == question.qtype || record.r#type == Type::CNAME)
+            });
+
+            let mut target = None;
+            for record in records {
+                if let (RecordData::CNAME(fqdn), false) =
+                    (&record.rdata, question.qtype == Type::CNAME)
+                {
+                    target = Some(fqdn.clone());
+                }
+
+                answers.push(ResourceRecord {
+                    // Keep the case of the question.
+                    name: name.clone(),
+                    ..record.clone()
+                });
+            }
+
+            match target {
+                Some(target) => name = target,
+                None => break,
+            }
+        }
+
+        answers
+    }
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            records: Vec::new(),
+            response_code: ResponseCode::Ok,
+            delay: Duration::ZERO,
+            truncate: false,
+            drop: false,
+            lost: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MockServer {
+    pub addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    script: Mutex<Script>,
+    udp_queries: AtomicUsize,
+    tcp_queries: AtomicUsize,
+}
+
+impl MockServer {
+    pub async fn start(script: Script) -> Self {
+        // Another socket may already use the TCP port of the UDP socket.
+        let (udp, tcp) = loop {
+            let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            if let Ok(tcp) = TcpListener::bind(udp.local_addr().unwrap()).await {
+                break (udp, tcp);
+            }
+        };
+
+        let shared = Arc::new(Shared {
+            script: Mutex::new(script),
+            udp_queries: AtomicUsize::new(0),
+            tcp_queries: AtomicUsize::new(0),
+        });
+        let addr = udp.local_addr().unwrap();
+        tokio::task::spawn(serve_udp(udp, shared.clone()));
+        tokio::task::spawn(serve_tcp(tcp, shared.clone()));
+
+        Self { addr, shared }
+    }
+
+    /// Replaces the script for all following queries.
+    pub fn set_script(&self, script: Script) {
+        *self.shared.script.lock() = script;
+    }
+
+    /// Returns the number of queries received over UDP.
+    pub fn udp_queries(&self) -> usize {
+        self.shared.udp_queries.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of queries received over TCP.
+    pub fn tcp_queries(&self) -> usize {
+        self.shared.tcp_queries.load(Ordering::Relaxed)
+    }
+}
+
+async fn serve_udp(socket: UdpSocket, shared: Arc<Shared>) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0; 65535];
+    loop {
+        let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+            return;
+        };
+        let index = shared.udp_queries.fetch_add(1, Ordering::Relaxed);
+        if index < shared.script.lock().lost {
+            continue;
+        }
+
+        // Delayed responses must not hold up other queries.
+        let query = Bytes::copy_from_slice(&buf[..len]);
+        let socket = socket.clone();
+        let shared = shared.clone();
+        tokio::task::spawn(async move {
+            if let Some(resp) = shared.respond(query, true).await {
+                let _ = socket.send_to(&resp, peer).await;
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, shared: Arc<Shared>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            return;
+        };
+        tokio::task::spawn(serve_connection(stream, shared.clone()));
+    }
+}
+
+async fn serve_connection(mut stream: TcpStream, shared: Arc<Shared>) {
+    loop {
+        let Ok(len) = stream.read_u16().await else {
+            return;
+        };
+        let mut query = vec![0; usize::from(len)];
+        if stream.read_exact(&mut query).await.is_err() {
+            return;
+        }
+        shared.tcp_queries.fetch_add(1, Ordering::Relaxed);
+
+        let Some(resp) = shared.respond(Bytes::from(query), false).await else {
+            return;
+        };
+        let mut buf = Vec::with_capacity(2 + resp.len());
+        buf.extend_from_slice(&(resp.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&resp);
+        if stream.write_all(&buf).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl Shared {
+    /// Returns the encoded response to `query`, or `None` if it is dropped.
+    async fn respond(&self, query: Bytes, udp: bool) -> Option<Vec<u8>> {
+        let script = self.script.lock().clone();
+        let query = Packet::decode(query).ok()?;
+
+        if !script.delay.is_zero() {
+            tokio::time::sleep(script.delay).await;
+        }
+        if script.drop {
+            return None;
+        }
+
+        let truncated = udp && script.truncate;
+        let mut answers = Vec::new();
+        if !truncated {
+            for question in &query.questions {
+                answers.extend(script.lookup(question));
+            }
+        }
+
+        let resp = Packet {
+            transaction_id: query.transaction_id,
+            qr: Qr::Response,
+            opcode: query.opcode,
+            authoritative_answer: false,
+            truncated,
+            recursion_desired: query.recursion_desired,
+            recursion_available: true,
+            authentic_data: false,
+            checking_disabled: query.checking_disabled,
+            response_code: script.response_code,
+            questions: query.questions,
+            raw_questions: None,
+            answers,
+            authority: Vec::new(),
+            additional: Vec::new(),
+            edns: None,
+        };
+
+        let mut buf = Vec::new();
+        resp.encode(&mut buf);
+        Some(buf)
+    }
+}
@@ -0,0 +1,320 @@
+//! Connections to upstreams through a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! See https://datatracker.ietf.org/doc/html/rfc1928 and
+//! https://datatracker.ietf.org/doc/html/rfc9110#section-9.3.6
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::Url;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Maximum length of the response header of an HTTP proxy.
+const MAX_HTTP_HEADER_LEN: usize = 8192;
+
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub addr: SocketAddr,
+    /// The proxy as configured, including the credentials.
+    pub url: Url,
+    /// The same proxy for the HTTP client of DNS over HTTPS upstreams.
+    pub reqwest: reqwest::Proxy,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+impl Proxy {
+    /// Parses the configured proxy `url`, e.g. `socks5://127.0.0.1:9050`.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("proxy {}: {}", url, reason);
+
+        let url = Url::parse(url).map_err(|err| invalid(&err.to_string()))?;
+        let kind = match url.scheme() {
+            "socks5" => ProxyKind::Socks5,
+            "http" => ProxyKind::Http,
+            _ => return Err(invalid("scheme must be socks5 or http")),
+        };
+
+        // Resolving the proxy would need an upstream itself.
+        let ip = url
+            .host_str()
+            .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+            .and_then(|host| host.parse::<IpAddr>().ok())
+            .ok_or_else(|| invalid("host must be an IP address"))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| invalid("port is missing"))?;
+        let reqwest = reqwest::Proxy::all(url.clone()).map_err(|err| invalid(&err.to_string()))?;
+
+        Ok(Self {
+            kind,
+            addr: SocketAddr::new(ip, port),
+            url,
+            reqwest,
+        })
+    }
+
+    /// Asks the proxy at the other end of `stream` to connect it to `target`.
+    pub async fn handshake(&self, stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+        match self.kind {
+            ProxyKind::Socks5 => self.handshake_socks5(stream, target).await,
+            ProxyKind::Http => self.handshake_http(stream, target).await,
+        }
+    }
+
+    async fn handshake_socks5(&self, stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+        let credentials = self.credentials();
+
+        // Methods: no authentication or username/password.
+        let methods: &[u8] = if credentials.is_some() { &[0, 2] } else { &[0] };
+        stream.write_all(&[5, methods.len() as u8]).await?;
+        stream.write_all(methods).await?;
+
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).await?;
+        match (buf, credentials) {
+            ([5, 0], _) => (),
+            ([5, 2], Some((username, password))) => {
+                // See https://datatracker.ietf.org/doc/html/rfc1929
+                let mut req = vec![1];
+                for field in [username, password] {
+                    let len = u8::try_from(field.len())
+                        .map_err(|_| io::Error::other("proxy credentials are too long"))?;
+                    req.push(len);
+                    req.extend_from_slice(field.as_bytes());
+                }
+                stream.write_all(&req).await?;
+
+                stream.read_exact(&mut buf).await?;
+                if buf[1] != 0 {
+                    return Err(io::Error::other("proxy rejected the credentials"));
+                }
+            }
+            _ => {
+                return Err(io::Error::other(
+                    "proxy requires an unsupported authentication",
+                ))
+            }
+        }
+
+        let mut req = vec![5, 1, 0];
+        match target.ip() {
+            IpAddr::V4(ip) => {
+                req.push(1);
+                req.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                req.push(4);
+                req.extend_from_slice(&ip.octets());
+            }
+        }
+        req.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&req).await?;
+
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        if buf[1] != 0 {
+            return Err(io::Error::other(format!(
+                "proxy failed to connect with reply {}",
+                buf[1]
+            )));
+        }
+
+        // Skip the address the proxy connects from.
+        let len = match buf[3] {
+            1 => 4,
+            4 => 16,
+            3 => usize::from(stream.read_u8().await?),
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        };
+        let mut addr = vec![0; len + 2];
+        stream.read_exact(&mut addr).await?;
+        Ok(())
+    }
+
+    async fn handshake_http(&self, stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+        let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((username, password)) = self.credentials() {
+            let credentials = STANDARD.encode(format!("{}:{}", username, password));
+            req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        req.push_str("\r\n");
+        stream.write_all(req.as_bytes()).await?;
+
+        // The tunnel starts right after the header, so we must not read
+        // any further.
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= MAX_HTTP_HEADER_LEN {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            header.push(stream.read_u8().await?);
+        }
+
+        let status = header.split(|b| *b == b' ').nth(1).unwrap_or_default();
+        if !header.starts_with(b"HTTP/1.") || status != b"200" {
+            return Err(io::Error::other(format!(
+                "proxy failed to connect with status {}",
+                String::from_utf8_lossy(status)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the username and password to authenticate with.
+    fn credentials(&self) -> Option<(String, String)> {
+        if self.url.username().is_empty() {
+            return None;
+        }
+
+        Some((
+            percent_decode(self.url.username()),
+            percent_decode(self.url.password().unwrap_or_default()),
+        ))
+    }
+}
+
+impl<'de> Deserialize<'de> for Proxy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let url = String::deserialize(deserializer)?;
+        Self::parse(&url).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for Proxy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.url.as_str())
+    }
+}
+
+/// Decodes the percent-encoded userinfo of a URL.
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Proxy, ProxyKind};
+
+    #[test]
+    fn parse() {
+        let proxy = Proxy::parse("socks5://[::1]:9050").unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!(
+            proxy.addr,
+            SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 9050))
+        );
+
+        let proxy = Proxy::parse("http://192.0.2.1").unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Http);
+        assert_eq!(proxy.addr, SocketAddr::from(([192, 0, 2, 1], 80)));
+
+        assert!(Proxy::parse("https://192.0.2.1").is_err());
+        assert!(Proxy::parse("socks5://proxy.example.com:1080").is_err());
+        assert!(Proxy::parse("socks5://192.0.2.1").is_err());
+        assert!(Proxy::parse("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn socks5_handshake() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+
+            let mut buf = [0; 14];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x01\x04user\x07p@ss:wd");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            let mut buf = [0; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 0, 1, 192, 0, 2, 1, 0, 53]);
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x12, 0x34, 42])
+                .await
+                .unwrap();
+        });
+
+        let proxy = Proxy::parse(&format!("socks5://user:p%40ss%3Awd@{}", addr)).unwrap();
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        let target = SocketAddr::from(([192, 0, 2, 1], 53));
+        proxy.handshake(&mut stream, target).await.unwrap();
+
+        // Data after the reply belongs to the tunnel.
+        assert_eq!(stream.read_u8().await.unwrap(), 42);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_handshake_fails_on_error_status() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::task::spawn(async move {
+            for status in ["200 Connection established", "403 Forbidden"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 1024];
+                let len = stream.read(&mut buf).await.unwrap();
+                assert!(buf[..len].starts_with(b"CONNECT 192.0.2.1:53 HTTP/1.1\r\n"));
+                let resp = format!("HTTP/1.1 {}\r\nServer: test\r\n\r\n", status);
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+
+        let proxy = Proxy::parse(&format!("http://{}", addr)).unwrap();
+        let target = SocketAddr::from(([192, 0, 2, 1], 53));
+
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        proxy.handshake(&mut stream, target).await.unwrap();
+
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        assert!(proxy.handshake(&mut stream, target).await.is_err());
+    }
+}
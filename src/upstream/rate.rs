@@ -0,0 +1,164 @@
+//! Limits of the rate of queries to an upstream.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+
+use crate::config::RateLimit;
+use crate::metrics::{ResolverId, UpstreamTime};
+use crate::proto::Packet;
+
+use super::{QueryProfile, Resolver, ResolverError, UpstreamResolver};
+
+/// A [`Resolver`] that spaces out the queries to the wrapped upstream, so
+/// that a burst of cache misses doesn't get us blocked by it.
+///
+/// Queries beyond the rate wait for their turn. Queries that would wait
+/// longer than allowed fail with [`ResolverError::Overloaded`] and go to the
+/// next upstream.
+#[derive(Debug)]
+pub struct RateLimitedResolver {
+    pub inner: Resolver,
+    /// Time between two queries at the limit.
+    interval: Duration,
+    /// How far ahead of their turn queries may be sent.
+    burst: Duration,
+    max_wait: Duration,
+    /// The turn of the next query.
+    next: Mutex<Instant>,
+    metrics: Arc<UpstreamTime>,
+}
+
+impl RateLimitedResolver {
+    pub fn new(inner: Resolver, limit: &RateLimit, metrics: Arc<UpstreamTime>) -> Self {
+        let interval = Duration::from_secs(1) / limit.qps;
+        let burst = limit.burst.unwrap_or(limit.qps).max(1);
+
+        Self {
+            inner,
+            interval,
+            burst: interval * (burst - 1),
+            max_wait: Duration::from_millis(limit.max_wait),
+            next: Mutex::new(Instant::now()),
+            metrics,
+        }
+    }
+
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        let wait = {
+            let now = Instant::now();
+            let mut next = self.next.lock();
+            let turn = (*next).max(now);
+            let wait = turn.saturating_duration_since(now + self.burst);
+            if wait > self.max_wait {
+                self.metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+                return Err(ResolverError::Overloaded);
+            }
+
+            *next = turn + self.interval;
+            wait
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+
+        self.inner.exchange(query).await
+    }
+}
+
+impl UpstreamResolver for RateLimitedResolver {
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(RateLimitedResolver::exchange(self, query))
+    }
+
+    fn addr(&self) -> String {
+        self.inner.addr()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.inner.id()
+    }
+
+    fn is_available(&self) -> bool {
+        self.inner.is_available()
+    }
+
+    fn profile(&self) -> QueryProfile {
+        self.inner.profile()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use crate::config::RateLimit;
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Question, Type};
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::udp::UdpResolver;
+    use crate::upstream::{QueryFlags, QueryProfile, Resolver, ResolverError, SocketOptions};
+
+    use super::RateLimitedResolver;
+
+    #[tokio::test]
+    async fn spaces_out_queries() {
+        let server = MockServer::start(Script::default()).await;
+        let addr = server.addr;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let inner = UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_secs(5),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        );
+        let limit = RateLimit {
+            qps: 10,
+            burst: Some(2),
+            max_wait: 150,
+        };
+        let resolver = Resolver::new(RateLimitedResolver::new(
+            Resolver::new(inner),
+            &limit,
+            times.get(id).unwrap(),
+        ));
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+
+        // Two queries are sent at once, the third waits for its turn and
+        // the fourth would wait too long.
+        let start = Instant::now();
+        let flags = QueryFlags::default();
+        let (first, second, third, fourth) = futures::join!(
+            resolver.resolve(&question, &flags),
+            resolver.resolve(&question, &flags),
+            resolver.resolve(&question, &flags),
+            resolver.resolve(&question, &flags),
+        );
+        assert!(first.is_ok() && second.is_ok() && third.is_ok());
+        assert!(matches!(fourth, Err(ResolverError::Overloaded)));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            times.get(id).unwrap().rate_limited.load(Ordering::Relaxed),
+            1
+        );
+    }
+}
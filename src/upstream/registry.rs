@@ -0,0 +1,176 @@
+//! Transports that are not built in, added by their name.
+//!
+//! Upstreams with a `Custom` config name their transport, and the
+//! [`Registry`] builds their resolver with the factory registered for it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::metrics::{ResolverId, UpstreamTime};
+
+use super::UpstreamResolver;
+
+/// What a factory gets to build the resolver of an upstream.
+// Only read by the transports a build adds.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Context<'a> {
+    pub id: ResolverId,
+    pub metrics: Arc<UpstreamTime>,
+    /// The address of the upstream as the transport understands it.
+    pub addr: &'a str,
+    pub timeout: Duration,
+    /// Settings of the transport from the config.
+    pub options: &'a Value,
+}
+
+type Factory = Box<dyn Fn(Context<'_>) -> Result<Box<dyn UpstreamResolver>, String> + Send + Sync>;
+
+/// The factories of the transports, by their name.
+#[derive(Default)]
+pub struct Registry {
+    factories: HashMap<String, Factory>,
+}
+
+impl Registry {
+    /// Adds the `factory` of the transport `name`, replacing an earlier one.
+    // No transport is registered by default.
+    #[allow(dead_code)]
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(Context<'_>) -> Result<Box<dyn UpstreamResolver>, String> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_owned(), Box::new(factory));
+    }
+
+    /// Builds the resolver of an upstream with the transport `name`.
+    pub fn build(&self, name: &str, cx: Context<'_>) -> Result<Box<dyn UpstreamResolver>, String> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| format!("unknown transport {}", name))?;
+        factory(cx)
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.factories.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::future::BoxFuture;
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::metrics::ResolverId;
+    use crate::proto::{Class, Fqdn, Packet, Qr, Question, RecordData, ResourceRecord, Type};
+    use crate::state::State;
+    use crate::upstream::{QueryFlags, ResolverError, UpstreamResolver};
+
+    use super::Registry;
+
+    /// Answers every question with the address from its options.
+    #[derive(Debug)]
+    struct Static {
+        id: ResolverId,
+        addr: Ipv4Addr,
+    }
+
+    impl UpstreamResolver for Static {
+        fn exchange<'a>(
+            &'a self,
+            query: &'a Packet,
+        ) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+            let mut resp = query.clone();
+            resp.qr = Qr::Response;
+            resp.answers.push(ResourceRecord {
+                name: query.questions[0].name.clone(),
+                r#type: Type::A,
+                class: Class::In,
+                ttl: 60,
+                rdata: RecordData::A(self.addr),
+            });
+
+            let mut buf = Vec::new();
+            resp.encode(&mut buf);
+            Box::pin(async move { Ok(Bytes::from(buf)) })
+        }
+
+        fn addr(&self) -> String {
+            "static".to_owned()
+        }
+
+        fn id(&self) -> ResolverId {
+            self.id
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+    }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::default();
+        registry.register("static", |cx| {
+            let addr = cx.options["addr"]
+                .as_str()
+                .and_then(|addr| addr.parse().ok())
+                .ok_or("invalid addr")?;
+            Ok(Box::new(Static { id: cx.id, addr }))
+        });
+        registry
+    }
+
+    fn config(transport: &str, addr: &str) -> Config {
+        let upstream = json!({
+            "Custom": {
+                "transport": transport,
+                "addr": "static",
+                "timeout": 1,
+                "options": { "addr": addr },
+            },
+        });
+        let config = json!({
+            "bind": "127.0.0.1:0",
+            "zones": { ".": [upstream] },
+            "http": { "enabled": false, "bind": "127.0.0.1:0" },
+        });
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn builds_registered_transports() {
+        let state = State::with_registry(config("static", "192.0.2.1"), registry()).unwrap();
+
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+        let resolution = state
+            .resolve(&question, &QueryFlags::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            resolution.resources[0].data,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn rejects_unbuildable_transports() {
+        assert!(State::with_registry(config("other", "192.0.2.1"), registry()).is_err());
+        assert!(State::with_registry(config("static", "invalid"), registry()).is_err());
+    }
+}
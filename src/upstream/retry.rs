@@ -0,0 +1,139 @@
+//! Retries of failed queries to an upstream.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+
+use crate::config::Retry;
+use crate::metrics::ResolverId;
+use crate::proto::Packet;
+
+use super::{jitter, QueryProfile, Resolver, ResolverError, UpstreamResolver};
+
+/// A [`Resolver`] that retries exchanges with the wrapped upstream that
+/// failed with a transient error.
+#[derive(Debug)]
+pub struct RetryingResolver {
+    pub inner: Resolver,
+    retry: Retry,
+}
+
+impl RetryingResolver {
+    pub fn new(inner: Resolver, retry: Retry) -> Self {
+        Self { inner, retry }
+    }
+
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        let max_backoff = Duration::from_millis(self.retry.max_backoff);
+        let mut backoff = Duration::from_millis(self.retry.backoff).min(max_backoff);
+        let mut attempt = 1;
+
+        loop {
+            match self.inner.exchange(query).await {
+                Err(err) if attempt < self.retry.attempts && is_transient(&err) => {
+                    let delay = jitter(backoff, self.retry.jitter);
+                    tracing::debug!(
+                        "retrying query to upstream {} in {:?}: {:?}",
+                        self.inner.addr(),
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl UpstreamResolver for RetryingResolver {
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(RetryingResolver::exchange(self, query))
+    }
+
+    fn addr(&self) -> String {
+        self.inner.addr()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.inner.id()
+    }
+
+    fn is_available(&self) -> bool {
+        self.inner.is_available()
+    }
+
+    fn profile(&self) -> QueryProfile {
+        self.inner.profile()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+}
+
+/// Returns `true` if a new attempt may succeed where `err` failed.
+fn is_transient(err: &ResolverError) -> bool {
+    matches!(
+        err,
+        ResolverError::Io(_) | ResolverError::Timeout | ResolverError::Http(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::Retry;
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Question, Type};
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::udp::UdpResolver;
+    use crate::upstream::{QueryFlags, QueryProfile, Resolver, SocketOptions};
+
+    use super::RetryingResolver;
+
+    #[tokio::test]
+    async fn retries_timeouts() {
+        // The first query is lost.
+        let server = MockServer::start(Script {
+            lost: 1,
+            ..Script::default()
+        })
+        .await;
+        let addr = server.addr;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let inner = Resolver::new(UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_millis(200),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        ));
+        let retry = Retry {
+            attempts: 2,
+            backoff: 10,
+            max_backoff: 10,
+            jitter: 0.5,
+        };
+        let resolver = Resolver::new(RetryingResolver::new(inner, retry));
+
+        let question = Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        };
+        assert!(resolver
+            .resolve(&question, &QueryFlags::default())
+            .await
+            .is_ok());
+        assert_eq!(server.udp_queries(), 2);
+    }
+}
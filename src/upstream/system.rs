@@ -0,0 +1,346 @@
+//! Forwarding to the nameservers of the system.
+//!
+//! The nameservers are read from a resolv.conf, e.g. the one written by a
+//! DHCP client, which is checked for changes at most once per
+//! [`RELOAD_INTERVAL`].
+//!
+//! See https://man7.org/linux/man-pages/man5/resolv.conf.5.html
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+
+use crate::frontend::EDNS_PAYLOAD_SIZE;
+use crate::metrics::{ResolverId, UpstreamTime};
+use crate::proto::Packet;
+
+use super::interface;
+use super::udp::UdpResolver;
+use super::{QueryProfile, Resolver, ResolverError, SocketOptions, UpstreamResolver};
+
+/// Minimum time between two checks of the file for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The nameservers of a resolv.conf are always listening on the DNS port.
+const PORT: u16 = 53;
+
+/// A [`Resolver`] that forwards queries to the nameservers listed in a
+/// resolv.conf, trying them in order.
+#[derive(Debug)]
+pub struct SystemResolver {
+    /// All nameservers share the metrics of the upstream.
+    pub id: ResolverId,
+    pub path: PathBuf,
+    /// Timeout of a query to one nameserver.
+    pub timeout: Duration,
+    /// Addresses we listen on ourselves, which are never forwarded to.
+    own_addrs: Vec<SocketAddr>,
+    metrics: Arc<UpstreamTime>,
+    nameservers: Mutex<Nameservers>,
+}
+
+#[derive(Debug)]
+struct Nameservers {
+    /// Modification time of the file the resolvers were loaded from.
+    modified: Option<SystemTime>,
+    checked: Instant,
+    resolvers: Arc<Vec<Resolver>>,
+}
+
+impl SystemResolver {
+    pub const PROFILE: QueryProfile = QueryProfile {
+        payload_size: Some(EDNS_PAYLOAD_SIZE),
+        ..QueryProfile::FORWARDER
+    };
+
+    /// Loads the nameservers from `path`. A missing file is not an error,
+    /// since it may still be written by a DHCP client.
+    pub fn new(
+        id: ResolverId,
+        metrics: Arc<UpstreamTime>,
+        path: PathBuf,
+        timeout: Duration,
+        own_addrs: Vec<SocketAddr>,
+    ) -> Self {
+        let resolver = Self {
+            id,
+            path,
+            timeout,
+            own_addrs,
+            metrics,
+            nameservers: Mutex::new(Nameservers {
+                modified: None,
+                checked: Instant::now(),
+                resolvers: Arc::default(),
+            }),
+        };
+
+        let file = std::fs::metadata(&resolver.path)
+            .and_then(|meta| meta.modified())
+            .and_then(|modified| Ok((modified, std::fs::read_to_string(&resolver.path)?)));
+        match file {
+            Ok((modified, contents)) => {
+                resolver.update(modified, &contents);
+            }
+            Err(err) => {
+                tracing::warn!("failed to read {}: {}", resolver.path.display(), err);
+            }
+        }
+
+        resolver
+    }
+
+    /// Sends `query` to the nameservers until one responds.
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        let mut res = Err(ResolverError::NoAnswer);
+        for resolver in self.resolvers().await.iter() {
+            res = resolver.exchange(query).await;
+            if res.is_ok() {
+                break;
+            }
+        }
+
+        res
+    }
+
+    /// Returns the current nameservers, reloading them if the file changed.
+    async fn resolvers(&self) -> Arc<Vec<Resolver>> {
+        let modified = {
+            let mut nameservers = self.nameservers.lock();
+            if nameservers.checked.elapsed() < RELOAD_INTERVAL {
+                return nameservers.resolvers.clone();
+            }
+
+            // Concurrent queries use the current nameservers meanwhile.
+            nameservers.checked = Instant::now();
+            nameservers.modified
+        };
+
+        let file = match tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|meta| meta.modified())
+        {
+            Ok(file) if Some(file) != modified => tokio::fs::read_to_string(&self.path)
+                .await
+                .map(|contents| (file, contents)),
+            Ok(_) => return self.nameservers.lock().resolvers.clone(),
+            Err(err) => Err(err),
+        };
+
+        match file {
+            Ok((modified, contents)) => self.update(modified, &contents),
+            // Keep the last nameservers while the file is being replaced.
+            Err(err) => {
+                tracing::warn!("failed to reload {}: {}", self.path.display(), err);
+                self.nameservers.lock().resolvers.clone()
+            }
+        }
+    }
+
+    fn update(&self, modified: SystemTime, contents: &str) -> Arc<Vec<Resolver>> {
+        // The addresses of the host may change, e.g. with the DHCP lease
+        // that rewrote the file.
+        let host_addrs = if self.own_addrs.iter().any(|addr| addr.ip().is_unspecified()) {
+            interface::host_addrs().unwrap_or_else(|err| {
+                tracing::warn!("failed to read the addresses of the host: {}", err);
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+
+        let addrs: Vec<SocketAddr> = parse(contents)
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, PORT))
+            .filter(|addr| !self.is_own_addr(*addr, &host_addrs))
+            .collect();
+        tracing::info!(
+            "loaded nameservers {:?} from {}",
+            addrs,
+            self.path.display()
+        );
+
+        let resolvers: Vec<Resolver> = addrs
+            .into_iter()
+            .map(|addr| {
+                Resolver::new(UdpResolver::new(
+                    self.id,
+                    self.metrics.clone(),
+                    addr,
+                    self.timeout,
+                    Self::PROFILE,
+                    SocketOptions::default(),
+                    None,
+                ))
+            })
+            .collect();
+
+        let mut nameservers = self.nameservers.lock();
+        nameservers.modified = Some(modified);
+        nameservers.resolvers = Arc::new(resolvers);
+        nameservers.resolvers.clone()
+    }
+
+    /// Returns `true` if queries to `addr` would come back to us, e.g. if
+    /// we are the nameserver of the system ourselves.
+    ///
+    /// Wildcard addresses we listen on stand for all `host_addrs`.
+    fn is_own_addr(&self, addr: SocketAddr, host_addrs: &[IpAddr]) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.own_addrs.iter().any(|own| {
+            own.port() == addr.port()
+                && (own.ip() == ip
+                    || own.ip().is_unspecified() && (ip.is_loopback() || host_addrs.contains(&ip)))
+        })
+    }
+}
+
+impl UpstreamResolver for SystemResolver {
+    // Every nameserver has its own timeout.
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(SystemResolver::exchange(self, query))
+    }
+
+    fn addr(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.id
+    }
+
+    /// Returns `true` if the file lists any nameservers.
+    fn is_available(&self) -> bool {
+        !self.nameservers.lock().resolvers.is_empty()
+    }
+
+    fn profile(&self) -> QueryProfile {
+        Self::PROFILE
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Returns the addresses of the `nameserver` lines of a resolv.conf.
+fn parse(contents: &str) -> Vec<IpAddr> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("nameserver") {
+                return None;
+            }
+
+            // Link-local addresses may carry a zone, which we cannot use.
+            let addr = words.next()?;
+            match addr.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    tracing::warn!("ignoring nameserver {}", addr);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
+
+    use crate::metrics::UpstreamTimes;
+
+    use super::{parse, SystemResolver};
+
+    #[test]
+    fn parse_resolv_conf() {
+        let contents = "# Generated by dhcpcd\n\
+            search example.com\n\
+            nameserver 192.0.2.1\n\
+            ; nameserver 192.0.2.2\n\
+            nameserver\t2001:db8::1 # comment\n\
+            nameserver fe80::1%eth0\n\
+            options edns0\n";
+        assert_eq!(
+            parse(contents),
+            [
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reloads_on_change() {
+        let path = std::env::temp_dir().join(format!("rdns-resolv-{}.conf", std::process::id()));
+        std::fs::write(&path, "nameserver 127.0.0.1\nnameserver 203.0.113.1\n").unwrap();
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&path.display().to_string());
+        // Listening on all addresses makes us the loopback nameserver. The
+        // nameservers are from TEST-NET-3, which is not assigned to hosts.
+        let resolver = SystemResolver::new(
+            id,
+            times.get(id).unwrap(),
+            path.clone(),
+            Duration::from_secs(5),
+            vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, 53))],
+        );
+        assert_eq!(
+            addrs(&resolver).await,
+            [SocketAddr::from(([203, 0, 113, 1], 53))]
+        );
+
+        std::fs::write(&path, "nameserver 203.0.113.2\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            addrs(&resolver).await,
+            [SocketAddr::from(([203, 0, 113, 2], 53))]
+        );
+
+        // The last nameservers are kept while the file is missing.
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            addrs(&resolver).await,
+            [SocketAddr::from(([203, 0, 113, 2], 53))]
+        );
+    }
+
+    #[test]
+    fn excludes_host_addrs() {
+        let times = UpstreamTimes::default();
+        let id = times.register("rdns-missing.conf");
+        let resolver = SystemResolver::new(
+            id,
+            times.get(id).unwrap(),
+            "rdns-missing.conf".into(),
+            Duration::from_secs(5),
+            vec![SocketAddr::from((Ipv6Addr::UNSPECIFIED, 53))],
+        );
+        let host_addrs = [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))];
+
+        let own = |addr: SocketAddr| resolver.is_own_addr(addr, &host_addrs);
+        assert!(own(SocketAddr::from(([192, 0, 2, 7], 53))));
+        assert!(own(SocketAddr::from(([127, 0, 0, 53], 53))));
+        assert!(!own(SocketAddr::from(([192, 0, 2, 1], 53))));
+        assert!(!own(SocketAddr::from(([192, 0, 2, 7], 5353))));
+    }
+
+    async fn addrs(resolver: &SystemResolver) -> Vec<SocketAddr> {
+        resolver
+            .resolvers()
+            .await
+            .iter()
+            .map(|resolver| resolver.addr().parse().unwrap())
+            .collect()
+    }
+}
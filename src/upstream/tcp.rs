@@ -0,0 +1,348 @@
+mod stream;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+
+use crate::config::Dscp;
+use crate::dscp;
+use crate::frontend::EDNS_PAYLOAD_SIZE;
+use crate::metrics::{ResolverId, UpstreamTime};
+use crate::proto::{Class, Edns, Fqdn, Packet, Question, TcpKeepalive, Type};
+
+use self::stream::Stream;
+use super::interface::Interface;
+use super::{with_timeout, QueryProfile, ResolverError, SocketOptions, UpstreamResolver};
+
+/// A [`Resolver`] that sends queries over TCP, e.g. where UDP is filtered.
+///
+/// All queries share one connection to the upstream, which is encrypted with
+/// [`with_tls`].
+///
+/// See https://datatracker.ietf.org/doc/html/rfc7766#section-5
+/// See https://datatracker.ietf.org/doc/html/rfc7858
+///
+/// [`with_tls`]: Self::with_tls
+///
+/// [`Resolver`]: super::Resolver
+#[derive(Debug)]
+pub struct TcpResolver {
+    pub id: ResolverId,
+    pub addr: SocketAddr,
+    pub timeout: Duration,
+    pub profile: QueryProfile,
+    pub socket: SocketOptions,
+    pub dscp: Option<Dscp>,
+    pub keepalive: Option<KeepAlive>,
+    pub tls: Option<Tls>,
+    stream: Stream,
+}
+
+/// Probes that keep the TCP connection to an upstream open while it is idle.
+#[derive(Clone, Debug)]
+pub struct KeepAlive {
+    /// Time between two probes on an idle connection.
+    pub interval: Duration,
+    /// Time after the last query until probes are no longer sent.
+    pub max_idle: Duration,
+    /// The name whose NS records are queried.
+    pub name: Fqdn,
+}
+
+/// The TLS session of DNS over TLS on the connection to an upstream.
+#[derive(Clone, Debug)]
+pub struct Tls {
+    pub config: Arc<ClientConfig>,
+    /// The name the certificate of the upstream is verified for.
+    pub server_name: ServerName<'static>,
+}
+
+impl TcpResolver {
+    pub fn new(
+        id: ResolverId,
+        metrics: Arc<UpstreamTime>,
+        addr: SocketAddr,
+        timeout: Duration,
+        profile: QueryProfile,
+        socket: SocketOptions,
+        dscp: Option<Dscp>,
+    ) -> Self {
+        Self {
+            id,
+            addr,
+            timeout,
+            profile,
+            socket,
+            dscp,
+            keepalive: None,
+            tls: None,
+            stream: Stream::new(metrics),
+        }
+    }
+
+    /// Keeps the connection to the upstream open with probes while it is
+    /// idle.
+    pub fn with_keepalive(mut self, keepalive: KeepAlive) -> Self {
+        let probe = self.profile.build_query(&Question {
+            name: keepalive.name.clone(),
+            qtype: Type::NS,
+            qclass: Class::In,
+        });
+        let (interval, max_idle) = (keepalive.interval, keepalive.max_idle);
+        self.keepalive = Some(keepalive);
+
+        let (probe, probe_len) = self.encode(&probe);
+        self.stream
+            .set_keepalive(interval, max_idle, probe, probe_len);
+        self
+    }
+
+    /// Sends queries over TLS instead of plain TCP.
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sends `query` to the upstream and returns the raw response.
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        if self
+            .socket
+            .interface
+            .as_ref()
+            .is_some_and(|interface| !interface.is_up())
+        {
+            return Err(ResolverError::InterfaceDown);
+        }
+
+        let (mut buf, query_len) = self.encode(query);
+        let resp = match &self.tls {
+            Some(tls) => {
+                self.stream
+                    .exchange(&mut buf, query_len, || self.connect_tls(tls))
+                    .await
+            }
+            None => {
+                self.stream
+                    .exchange(&mut buf, query_len, || self.connect())
+                    .await
+            }
+        };
+
+        match resp {
+            Ok(resp) => Ok(resp.freeze()),
+            // The connection has too many queries in flight.
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Err(ResolverError::Overloaded),
+            Err(err) => Err(ResolverError::Io(err)),
+        }
+    }
+
+    /// Encodes `query` and returns it along with the length of its header
+    /// and question section.
+    ///
+    /// Queries ask for the idle timeout of the upstream if the connection is
+    /// kept open.
+    fn encode(&self, query: &Packet) -> (Vec<u8>, usize) {
+        let question_len: usize = query.questions.iter().map(Question::encoded_len).sum();
+        let mut buf = Vec::with_capacity(query.encoded_len() + 4);
+        if self.keepalive.is_none() {
+            query.encode(&mut buf);
+            return (buf, 12 + question_len);
+        }
+
+        let mut query = query.clone();
+        let edns = query.edns.get_or_insert_with(|| Edns {
+            udp_payload_size: self.profile.payload_size.unwrap_or(EDNS_PAYLOAD_SIZE),
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Default::default(),
+        });
+        let mut options = edns.options.to_vec();
+        TcpKeepalive { timeout: None }.encode(&mut options);
+        edns.options = options.into();
+
+        query.encode(&mut buf);
+        (buf, 12 + question_len)
+    }
+
+    /// Opens the connection to the upstream, through the proxy if any.
+    async fn connect(&self) -> io::Result<TcpStream> {
+        match &self.socket.proxy {
+            Some(proxy) => {
+                let mut stream = self.connect_to(proxy.addr).await?;
+                proxy.handshake(&mut stream, self.addr).await?;
+                Ok(stream)
+            }
+            None => self.connect_to(self.addr).await,
+        }
+    }
+
+    /// Opens the connection to the upstream and the TLS session on it.
+    async fn connect_tls(&self, tls: &Tls) -> io::Result<TlsStream<TcpStream>> {
+        let stream = self.connect().await?;
+        TlsConnector::from(tls.config.clone())
+            .connect(tls.server_name.clone(), stream)
+            .await
+    }
+
+    /// Opens a TCP connection to `addr` with the options of the upstream.
+    async fn connect_to(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4(),
+            SocketAddr::V6(_) => TcpSocket::new_v6(),
+        }?;
+        if let Some(interface) = &self.socket.interface {
+            socket.bind_device(Some(interface.name().as_bytes()))?;
+        }
+        if self.socket.source.is_some() {
+            socket.bind(self.socket.local_addr(addr))?;
+        }
+        if let Some(dscp) = self.dscp {
+            dscp::set(&socket, dscp)?;
+        }
+        let stream = socket.connect(addr).await?;
+        // Pipelined queries must not wait for the responses to earlier ones.
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+impl UpstreamResolver for TcpResolver {
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(with_timeout(
+            self.timeout,
+            TcpResolver::exchange(self, query),
+        ))
+    }
+
+    fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.id
+    }
+
+    fn is_available(&self) -> bool {
+        self.socket.interface.as_ref().is_none_or(Interface::is_up)
+    }
+
+    fn profile(&self) -> QueryProfile {
+        self.profile
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Packet, Question, Type};
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::{QueryProfile, SocketOptions};
+
+    use super::{KeepAlive, TcpResolver};
+
+    #[tokio::test]
+    async fn probes_idle_connections() {
+        let server = MockServer::start(Script::answer("example.com.", Ipv4Addr::LOCALHOST)).await;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&server.addr.to_string());
+        let resolver = TcpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            server.addr,
+            Duration::from_secs(5),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        )
+        .with_keepalive(KeepAlive {
+            interval: Duration::from_millis(100),
+            max_idle: Duration::from_millis(450),
+            name: Fqdn(b".".to_vec()),
+        });
+        let query = QueryProfile::FORWARDER.build_query(&Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        });
+
+        let resp = resolver.exchange(&query).await.unwrap();
+        let resp = Packet::decode(resp).unwrap();
+        assert_eq!(resp.answers.len(), 1);
+        assert_eq!(server.udp_queries(), 0);
+
+        // Probes are only sent until the connection was idle for too long.
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        let queries = server.tcp_queries();
+        assert!((3..=5).contains(&queries), "{}", queries);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(server.tcp_queries(), queries);
+        assert_eq!(times.get(id).unwrap().mismatched.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn reuses_the_connection() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Only accepts one connection.
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            loop {
+                let Ok(len) = stream.read_u16().await else {
+                    return;
+                };
+                let mut query = vec![0; usize::from(len)];
+                stream.read_exact(&mut query).await.unwrap();
+                query[2] |= 0x80;
+                stream.write_u16(len).await.unwrap();
+                stream.write_all(&query).await.unwrap();
+            }
+        });
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let resolver = TcpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_secs(5),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        );
+        for name in ["a.example.", "b.example."] {
+            let query = QueryProfile::FORWARDER.build_query(&Question {
+                name: Fqdn(name.as_bytes().to_vec()),
+                qtype: Type::A,
+                qclass: Class::In,
+            });
+
+            let resp = resolver.exchange(&query).await.unwrap();
+            let resp = Packet::decode(resp).unwrap();
+            assert_eq!(resp.transaction_id, query.transaction_id);
+            assert_eq!(resp.questions, query.questions);
+        }
+    }
+}
@@ -0,0 +1,675 @@
+//! A TCP or TLS connection shared by all queries to an upstream that are sent
+//! over it.
+//!
+//! Queries are pipelined: they are sent without waiting for the responses to
+//! earlier queries, which may arrive in any order and are passed to the
+//! waiting queries by their transaction ID. A new connection is opened once
+//! the upstream closed the last one.
+//!
+//! With keepalive, probes are sent while the connection is idle, so that
+//! the upstream doesn't close it.
+//!
+//! A connection is considered dead and closed if a probe was not answered
+//! until the next one is due, or if [`MAX_UNANSWERED`] queries in a row
+//! were given up without hearing from the upstream in between.
+//!
+//! See https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.1.1
+//! See https://datatracker.ietf.org/doc/html/rfc7828
+
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::HashMap;
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::metrics::UpstreamTime;
+use crate::proto::{Packet, TcpKeepalive};
+
+use crate::upstream::udp::is_response_to;
+
+/// Maximum number of queries in flight on a connection.
+///
+/// Far below the 65536 transaction IDs, so that a random unused one is
+/// found after a few tries.
+const MAX_PENDING: usize = 4096;
+
+/// Number of queries in a row given up before a connection is closed, e.g.
+/// after they timed out.
+const MAX_UNANSWERED: u32 = 3;
+
+#[derive(Debug)]
+pub struct Stream {
+    metrics: Arc<UpstreamTime>,
+    probe: Option<Arc<Probe>>,
+    /// Held while connecting, so that concurrent queries share the new
+    /// connection.
+    conn: tokio::sync::Mutex<Option<Arc<Connection>>>,
+}
+
+impl Stream {
+    pub fn new(metrics: Arc<UpstreamTime>) -> Self {
+        Self {
+            metrics,
+            probe: None,
+            conn: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Sends the encoded `query` every `interval` while a connection is
+    /// idle, until no query was sent for `max_idle`.
+    ///
+    /// The header and question section of `query` are `query_len` bytes long.
+    pub fn set_keepalive(
+        &mut self,
+        interval: Duration,
+        max_idle: Duration,
+        query: Vec<u8>,
+        query_len: usize,
+    ) {
+        self.probe = Some(Arc::new(Probe {
+            interval,
+            max_idle,
+            query,
+            query_len,
+        }));
+    }
+
+    /// Sends the encoded `query` and returns the response to it, opening a
+    /// connection with `connect` if there is none.
+    ///
+    /// The header and question section of `query` are `query_len` bytes long.
+    /// The transaction ID is replaced while the query is in flight and
+    /// restored in the response.
+    pub async fn exchange<F, Fut, S>(
+        &self,
+        query: &mut [u8],
+        query_len: usize,
+        connect: F,
+    ) -> io::Result<BytesMut>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let conn = self.connection(connect).await?;
+        let transaction_id = [query[0], query[1]];
+
+        let (id, rx) = {
+            let mut pending = conn.shared.pending.lock();
+            let Some(pending) = pending.as_mut() else {
+                return Err(io::ErrorKind::ConnectionAborted.into());
+            };
+
+            let Some(id) = unused_id(pending) else {
+                self.metrics.overloaded.fetch_add(1, Ordering::Relaxed);
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many queries in flight",
+                ));
+            };
+            query[..2].copy_from_slice(&u16::to_be_bytes(id));
+
+            let (tx, rx) = oneshot::channel();
+            pending.insert(
+                id,
+                Pending {
+                    sent: query[..query_len].to_vec(),
+                    tx,
+                },
+            );
+            (id, rx)
+        };
+
+        // Queries that are given up must not be answered.
+        let _guard = PendingGuard { conn: &conn, id };
+
+        // Queries are written by the task of the connection, so that a query
+        // that is given up never leaves a partial message on the stream.
+        let mut msg = Vec::with_capacity(2 + query.len());
+        msg.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        msg.extend_from_slice(query);
+        conn.queries
+            .send(msg)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))?;
+
+        let mut resp = rx
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))??;
+        resp[..2].copy_from_slice(&transaction_id);
+        Ok(resp)
+    }
+
+    /// Returns the open connection, or opens a new one with `connect`.
+    async fn connection<F, Fut, S>(&self, connect: F) -> io::Result<Arc<Connection>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let mut conn = self.conn.lock().await;
+        if let Some(conn) = conn.as_ref().filter(|conn| !conn.is_closed()) {
+            return Ok(conn.clone());
+        }
+
+        let new = Arc::new(Connection::new(
+            connect().await?,
+            self.metrics.clone(),
+            self.probe.clone(),
+        ));
+        *conn = Some(new.clone());
+        Ok(new)
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    shared: Arc<Shared>,
+    /// The encoded queries to write, prefixed with their length.
+    queries: mpsc::UnboundedSender<Vec<u8>>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Connection {
+    fn new<S>(stream: S, metrics: Arc<UpstreamTime>, probe: Option<Arc<Probe>>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = tokio::io::split(stream);
+
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(Some(HashMap::default())),
+            idle_timeout: Mutex::new(None),
+            unanswered: AtomicU32::new(0),
+        });
+        let (queries, rx) = mpsc::unbounded_channel();
+        let reader = tokio::task::spawn(receive(read, shared.clone(), metrics, probe.is_some()));
+        let writer = tokio::task::spawn(send(write, rx, shared.clone(), probe));
+
+        Self {
+            shared,
+            queries,
+            reader,
+            writer,
+        }
+    }
+
+    /// Returns `true` if no more queries are sent on the connection. The
+    /// upstream asks for this with an idle timeout of zero.
+    fn is_closed(&self) -> bool {
+        self.shared.pending.lock().is_none()
+            || *self.shared.idle_timeout.lock() == Some(Duration::ZERO)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Nobody is waiting for responses anymore.
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// The queries waiting for a response by their transaction ID. `None`
+    /// once the connection is closed.
+    pending: Mutex<Option<HashMap<u16, Pending>>>,
+    /// The idle timeout the upstream announced last.
+    idle_timeout: Mutex<Option<Duration>>,
+    /// Number of queries given up since the last message of the upstream.
+    unanswered: AtomicU32,
+}
+
+impl Shared {
+    /// Returns when the next probe is due after the last message was sent at
+    /// `last_sent`, or `None` if the connection is left to idle out.
+    fn next_probe(
+        &self,
+        probe: &Probe,
+        last_query: Instant,
+        last_sent: Instant,
+    ) -> Option<Instant> {
+        let interval = match *self.idle_timeout.lock() {
+            Some(Duration::ZERO) => return None,
+            Some(timeout) => probe.interval.min(timeout / 2),
+            None => probe.interval,
+        };
+
+        let at = last_sent + interval;
+        (at < last_query + probe.max_idle).then_some(at)
+    }
+
+    /// Returns `true` if the probe `last` is still waiting for its response.
+    fn is_unanswered(&self, last: u16) -> bool {
+        // A query may have taken the ID of an answered probe. Nobody waits
+        // for the response to a probe.
+        self.pending
+            .lock()
+            .as_ref()
+            .and_then(|pending| pending.get(&last))
+            .is_some_and(|pending| pending.tx.is_closed())
+    }
+
+    /// Registers a new probe and returns its ID and the message to write.
+    ///
+    /// Connections with too many queries in flight are not idle and get no
+    /// probe.
+    fn register_probe(&self, probe: &Probe) -> Option<(u16, Vec<u8>)> {
+        let mut pending = self.pending.lock();
+        let pending = pending.as_mut()?;
+        let id = unused_id(pending)?;
+        let mut msg = Vec::with_capacity(2 + probe.query.len());
+        msg.extend_from_slice(&(probe.query.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&probe.query);
+        msg[2..4].copy_from_slice(&id.to_be_bytes());
+
+        // Nobody waits for the response.
+        let (tx, _) = oneshot::channel();
+        pending.insert(
+            id,
+            Pending {
+                sent: msg[2..2 + probe.query_len].to_vec(),
+                tx,
+            },
+        );
+        Some((id, msg))
+    }
+
+    /// Closes the connection and fails all queries waiting on it.
+    fn close(&self, err: &io::Error) {
+        let pending = self.pending.lock().take().unwrap_or_default();
+        for (_, pending) in pending {
+            let _ = pending
+                .tx
+                .send(Err(io::Error::new(err.kind(), err.to_string())));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Probe {
+    interval: Duration,
+    max_idle: Duration,
+    /// The encoded query, without the length prefix.
+    query: Vec<u8>,
+    /// Length of the header and question section of `query`.
+    query_len: usize,
+}
+
+#[derive(Debug)]
+struct Pending {
+    /// The header and question section of the query.
+    sent: Vec<u8>,
+    tx: oneshot::Sender<io::Result<BytesMut>>,
+}
+
+/// Removes a query that is no longer waiting for its response.
+struct PendingGuard<'a> {
+    conn: &'a Connection,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let shared = &self.conn.shared;
+        let given_up = shared
+            .pending
+            .lock()
+            .as_mut()
+            .is_some_and(|pending| pending.remove(&self.id).is_some());
+
+        // The upstream is still there if it answers anything in between.
+        if given_up && shared.unanswered.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_UNANSWERED {
+            shared.close(&io::Error::new(
+                io::ErrorKind::TimedOut,
+                "upstream stopped answering",
+            ));
+        }
+    }
+}
+
+/// Returns a random ID that is not used by any `pending` query, or `None`
+/// if there are [`MAX_PENDING`] queries already.
+fn unused_id(pending: &HashMap<u16, Pending>) -> Option<u16> {
+    if pending.len() >= MAX_PENDING {
+        return None;
+    }
+
+    loop {
+        let id = rand::random();
+        if !pending.contains_key(&id) {
+            return Some(id);
+        }
+    }
+}
+
+/// Writes the queries to the connection in the order they are sent, and the
+/// probes while it is idle.
+async fn send<S: AsyncWrite>(
+    mut stream: WriteHalf<S>,
+    mut queries: mpsc::UnboundedReceiver<Vec<u8>>,
+    shared: Arc<Shared>,
+    probe: Option<Arc<Probe>>,
+) {
+    let mut last_query = Instant::now();
+    let mut last_sent = last_query;
+    let mut last_probe = None;
+    loop {
+        let next_probe = probe
+            .as_ref()
+            .and_then(|probe| shared.next_probe(probe, last_query, last_sent));
+        let msg = tokio::select! {
+            msg = queries.recv() => {
+                let Some(msg) = msg else {
+                    return;
+                };
+                last_query = Instant::now();
+                msg
+            }
+            () = sleep_until(next_probe), if next_probe.is_some() => {
+                if last_probe.is_some_and(|last| shared.is_unanswered(last)) {
+                    shared.close(&io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "upstream did not answer the keepalive probe",
+                    ));
+                    return;
+                }
+
+                let probe = probe.as_ref().unwrap();
+                match shared.register_probe(probe) {
+                    Some((id, msg)) => {
+                        last_probe = Some(id);
+                        msg
+                    }
+                    None if shared.pending.lock().is_none() => return,
+                    None => {
+                        last_sent = Instant::now();
+                        continue;
+                    }
+                }
+            }
+        };
+
+        last_sent = Instant::now();
+        // TLS streams buffer the records until they are flushed.
+        let res = async {
+            stream.write_all(&msg).await?;
+            stream.flush().await
+        };
+        if let Err(err) = res.await {
+            shared.close(&err);
+            return;
+        }
+    }
+}
+
+async fn sleep_until(at: Option<Instant>) {
+    if let Some(at) = at {
+        tokio::time::sleep_until(at.into()).await;
+    }
+}
+
+/// Passes the responses received on the connection to the waiting queries.
+async fn receive<S: AsyncRead>(
+    mut stream: ReadHalf<S>,
+    shared: Arc<Shared>,
+    metrics: Arc<UpstreamTime>,
+    keepalive: bool,
+) {
+    loop {
+        let res = async {
+            let len = stream.read_u16().await?;
+            let mut buf = BytesMut::zeroed(usize::from(len));
+            stream.read_exact(&mut buf).await?;
+            io::Result::Ok(buf)
+        }
+        .await;
+        // The upstream closes connections that are idle, which fails
+        // nothing but the queries it did not answer yet.
+        let buf = match res {
+            Ok(buf) => buf,
+            Err(err) => {
+                shared.close(&err);
+                return;
+            }
+        };
+
+        shared.unanswered.store(0, Ordering::Relaxed);
+
+        if keepalive {
+            if let Some(timeout) = idle_timeout(&buf) {
+                *shared.idle_timeout.lock() = Some(timeout);
+            }
+        }
+
+        let mut pending = shared.pending.lock();
+        let Some(pending) = pending.as_mut() else {
+            return;
+        };
+        let id = buf
+            .get(..2)
+            .map(|id| u16::from_be_bytes([id[0], id[1]]))
+            .filter(|id| {
+                pending
+                    .get(id)
+                    .is_some_and(|pending| is_response_to(&buf, &pending.sent))
+            });
+
+        match id.and_then(|id| pending.remove(&id)) {
+            Some(pending) => {
+                let _ = pending.tx.send(Ok(buf));
+            }
+            // Responses to queries that were given up end up here too.
+            None => {
+                tracing::debug!(
+                    "discarding mismatched response from upstream {}",
+                    metrics.addr
+                );
+                metrics.mismatched.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Returns the idle timeout the upstream announced in the response `buf`.
+fn idle_timeout(buf: &[u8]) -> Option<Duration> {
+    let edns = Packet::decode(Bytes::copy_from_slice(buf)).ok()?.edns?;
+    let timeout = edns
+        .iter_options()
+        .filter(|(code, _)| *code == TcpKeepalive::OPTION_CODE)
+        .find_map(|(_, data)| TcpKeepalive::decode(data))?
+        .timeout?;
+    Some(Duration::from_millis(u64::from(timeout) * 100))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use ahash::HashMap;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Packet, Question, Type};
+    use crate::upstream::QueryProfile;
+
+    use super::{unused_id, Pending, Stream, MAX_PENDING, MAX_UNANSWERED};
+
+    #[test]
+    fn unused_id_is_bounded() {
+        let mut pending = HashMap::default();
+        while let Some(id) = unused_id(&pending) {
+            let (tx, _) = oneshot::channel();
+            let sent = Vec::new();
+            pending.insert(id, Pending { sent, tx });
+        }
+        assert_eq!(pending.len(), MAX_PENDING);
+    }
+
+    #[tokio::test]
+    async fn pipelines_queries() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Only answers once both queries arrived on the same connection.
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut queries = Vec::new();
+            for _ in 0..2 {
+                let len = stream.read_u16().await.unwrap();
+                let mut buf = vec![0; usize::from(len)];
+                stream.read_exact(&mut buf).await.unwrap();
+                buf[2] |= 0x80;
+                queries.push(buf);
+            }
+
+            // A response with the ID of a query, but a different question.
+            let mut spoofed = queries[0].clone();
+            spoofed[13] ^= 0x20;
+            queries.push(spoofed);
+
+            for resp in queries.iter().rev() {
+                stream.write_u16(resp.len() as u16).await.unwrap();
+                stream.write_all(resp).await.unwrap();
+            }
+        });
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let stream = Stream::new(times.get(id).unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+
+        let exchange = |name: &str| {
+            let query = QueryProfile::FORWARDER.build_query(&Question {
+                name: Fqdn(name.as_bytes().to_vec()),
+                qtype: Type::A,
+                qclass: Class::In,
+            });
+            let stream = &stream;
+            let connects = connects.clone();
+            async move {
+                let mut buf = Vec::new();
+                query.encode(&mut buf);
+                let len = buf.len();
+                let resp = stream
+                    .exchange(&mut buf, len, || {
+                        connects.fetch_add(1, Ordering::Relaxed);
+                        TcpStream::connect(addr)
+                    })
+                    .await
+                    .unwrap();
+                let resp = Packet::decode(resp.freeze()).unwrap();
+                assert_eq!(resp.transaction_id, query.transaction_id);
+                assert_eq!(resp.questions, query.questions);
+            }
+        };
+
+        futures::join!(exchange("a.example."), exchange("b.example."));
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+        assert_eq!(times.get(id).unwrap().mismatched.load(Ordering::Relaxed), 1);
+    }
+
+    /// Starts an upstream that never answers on its first connection.
+    async fn dead_first_connection() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connects = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connects.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let dead = accepted.fetch_add(1, Ordering::Relaxed) == 0;
+                tokio::task::spawn(async move {
+                    loop {
+                        let Ok(len) = stream.read_u16().await else {
+                            return;
+                        };
+                        let mut buf = vec![0; usize::from(len)];
+                        stream.read_exact(&mut buf).await.unwrap();
+                        if !dead {
+                            buf[2] |= 0x80;
+                            stream.write_u16(len).await.unwrap();
+                            stream.write_all(&buf).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        (addr, connects)
+    }
+
+    fn query(name: &str) -> (Vec<u8>, usize) {
+        let query = QueryProfile::FORWARDER.build_query(&Question {
+            name: Fqdn(name.as_bytes().to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        });
+        let mut buf = Vec::new();
+        query.encode(&mut buf);
+        let len = buf.len();
+        (buf, len)
+    }
+
+    async fn exchange(
+        stream: &Stream,
+        addr: std::net::SocketAddr,
+        timeout: Duration,
+    ) -> Option<BytesMut> {
+        let (mut buf, len) = query("a.example.");
+        let exchange = stream.exchange(&mut buf, len, || TcpStream::connect(addr));
+        tokio::time::timeout(timeout, exchange).await.ok()?.ok()
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_unanswered_probe() {
+        let (addr, connects) = dead_first_connection().await;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let mut stream = Stream::new(times.get(id).unwrap());
+        let (probe, len) = query(".");
+        let interval = Duration::from_millis(50);
+        stream.set_keepalive(interval, Duration::from_secs(10), probe, len);
+
+        let timeout = Duration::from_millis(20);
+        assert!(exchange(&stream, addr, timeout).await.is_none());
+        tokio::time::sleep(interval * 4).await;
+
+        assert!(exchange(&stream, addr, timeout * 10).await.is_some());
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_unanswered_queries() {
+        let (addr, connects) = dead_first_connection().await;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let stream = Stream::new(times.get(id).unwrap());
+
+        let timeout = Duration::from_millis(20);
+        for _ in 0..MAX_UNANSWERED {
+            assert!(exchange(&stream, addr, timeout).await.is_none());
+        }
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+
+        assert!(exchange(&stream, addr, timeout * 10).await.is_some());
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+    }
+}
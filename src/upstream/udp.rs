@@ -1,60 +1,309 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+mod pool;
+pub mod upgrade;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use tokio::net::UdpSocket;
+use bytes::Bytes;
+use futures::future::BoxFuture;
 
-use crate::proto::{OpCode, Packet, Qr, Question, ResourceRecord, ResponseCode};
+use crate::bufpool;
+use crate::config::Dscp;
+use crate::metrics::{ResolverId, UpstreamTime};
+use crate::proto::{Packet, Question};
 
-use super::ResolverError;
+use self::pool::Pool;
+use self::upgrade::{TlsUpgrade, Upgrade};
+use super::interface::Interface;
+use super::tcp::{KeepAlive, TcpResolver};
+use super::{with_timeout, QueryProfile, ResolverError, SocketOptions, UpstreamResolver};
 
 #[derive(Debug)]
 pub struct UdpResolver {
+    pub id: ResolverId,
     pub addr: SocketAddr,
     pub timeout: Duration,
+    pub profile: QueryProfile,
+    pub socket: SocketOptions,
+    pub dscp: Option<Dscp>,
+    pool: Pool,
+    /// Queries are repeated over TCP if the response is truncated.
+    tcp: TcpResolver,
+    upgrade: Option<Upgrade>,
+    metrics: Arc<UpstreamTime>,
 }
 
 impl UdpResolver {
-    pub fn new(addr: SocketAddr, timeout: Duration) -> Self {
-        Self { addr, timeout }
+    pub fn new(
+        id: ResolverId,
+        metrics: Arc<UpstreamTime>,
+        addr: SocketAddr,
+        timeout: Duration,
+        profile: QueryProfile,
+        socket: SocketOptions,
+        dscp: Option<Dscp>,
+    ) -> Self {
+        // Responses may be as large as the payload size we advertise.
+        let recv_size = profile.payload_size.map_or(bufpool::RECV_SIZE, |size| {
+            usize::from(size).max(bufpool::RECV_SIZE)
+        });
+
+        Self {
+            id,
+            addr,
+            timeout,
+            profile,
+            socket: socket.clone(),
+            dscp,
+            pool: Pool::new(addr, socket.clone(), dscp, recv_size, metrics.clone()),
+            tcp: TcpResolver::new(id, metrics.clone(), addr, timeout, profile, socket, dscp),
+            upgrade: None,
+            metrics,
+        }
     }
 
-    pub async fn resolve(&self, question: &Question) -> Result<Vec<ResourceRecord>, ResolverError> {
-        let local_addr = match self.addr {
-            SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
-            SocketAddr::V6(_) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
-        };
+    /// Keeps the TCP connection to the upstream open with probes while it is
+    /// idle.
+    pub fn with_keepalive(mut self, keepalive: KeepAlive) -> Self {
+        self.tcp = self.tcp.with_keepalive(keepalive);
+        self
+    }
+
+    /// Sends queries over DNS over TLS while the upstream supports it.
+    ///
+    /// The TLS connection is kept open like the TCP connection.
+    pub fn with_tls_upgrade(mut self, upgrade: TlsUpgrade) -> Self {
+        let mut tls = TcpResolver::new(
+            self.id,
+            self.metrics.clone(),
+            SocketAddr::new(self.addr.ip(), upgrade.port),
+            self.timeout,
+            self.profile,
+            self.socket.clone(),
+            self.dscp,
+        )
+        .with_tls(upgrade.tls);
+        if let Some(keepalive) = self.tcp.keepalive.clone() {
+            tls = tls.with_keepalive(keepalive);
+        }
+
+        self.upgrade = Some(Upgrade::new(tls, upgrade.retry));
+        self
+    }
 
-        let socket = UdpSocket::bind(local_addr)
+    /// Sends `query` to the upstream and returns the raw response.
+    pub async fn exchange(&self, query: &Packet) -> Result<Bytes, ResolverError> {
+        if self
+            .socket
+            .interface
+            .as_ref()
+            .is_some_and(|interface| !interface.is_up())
+        {
+            return Err(ResolverError::InterfaceDown);
+        }
+
+        if let Some(upgrade) = &self.upgrade {
+            if let Some(resp) = upgrade.exchange(query).await {
+                return Ok(resp);
+            }
+        }
+
+        // Proxies only forward TCP.
+        if self.socket.proxy.is_some() {
+            return self.tcp.exchange(query).await;
+        }
+
+        let mut buf = bufpool::get();
+        buf.reserve(query.encoded_len());
+        query.encode(&mut *buf);
+
+        // The header is followed by the question section.
+        let question_len: usize = query.questions.iter().map(Question::encoded_len).sum();
+        let resp = self
+            .pool
+            .exchange(&mut buf, 12 + question_len)
             .await
             .map_err(ResolverError::Io)?;
-        socket.connect(self.addr).await.map_err(ResolverError::Io)?;
-
-        let packet = Packet {
-            transaction_id: rand::random(),
-            qr: Qr::Request,
-            opcode: OpCode::Query,
-            authoritative_answer: false,
-            truncated: false,
-            recursion_desired: true,
-            recursion_available: false,
-            response_code: ResponseCode::Ok,
-            questions: vec![question.clone()],
-            answers: vec![],
-            additional: vec![],
-            authority: vec![],
-        };
 
-        let mut buf = Vec::new();
-        packet.encode(&mut buf);
+        // The complete answer is only available over TCP.
+        if is_truncated(&resp) {
+            return self.tcp.exchange(query).await;
+        }
 
-        socket.send(&buf).await.map_err(ResolverError::Io)?;
+        Ok(resp.freeze())
+    }
+}
+
+impl UpstreamResolver for UdpResolver {
+    fn exchange<'a>(&'a self, query: &'a Packet) -> BoxFuture<'a, Result<Bytes, ResolverError>> {
+        Box::pin(with_timeout(
+            self.timeout,
+            UdpResolver::exchange(self, query),
+        ))
+    }
+
+    fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    fn id(&self) -> ResolverId {
+        self.id
+    }
+
+    fn is_available(&self) -> bool {
+        self.socket.interface.as_ref().is_none_or(Interface::is_up)
+    }
 
-        let mut buf = vec![0; 1500];
-        let len = socket.recv(&mut buf).await.map_err(ResolverError::Io)?;
-        buf.truncate(len);
+    fn profile(&self) -> QueryProfile {
+        self.profile
+    }
 
-        let packet = Packet::decode(&buf[..]).map_err(ResolverError::Decode)?;
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Returns `true` if `resp` is a response to the query with the header and
+/// question section `query`.
+///
+/// The question is compared byte by byte, which includes the case of the
+/// name if it was randomized.
+pub fn is_response_to(resp: &[u8], query: &[u8]) -> bool {
+    resp.len() >= query.len()
+        && resp[..2] == query[..2]
+        && resp[2] & 0x80 != 0
+        && resp[4..6] == query[4..6]
+        && resp[12..query.len()] == query[12..]
+}
+
+/// Returns `true` if the TC bit is set in the header of the response `buf`.
+fn is_truncated(buf: &[u8]) -> bool {
+    buf.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::net::UdpSocket;
+
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Packet, Question, Type};
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::{QueryProfile, SocketOptions};
+
+    use super::{is_response_to, UdpResolver};
+
+    #[test]
+    fn is_response_to_matches_query() {
+        let query = QueryProfile::AUTHORITATIVE.build_query(&Question {
+            name: Fqdn(b"www.example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        });
+        let mut sent = Vec::new();
+        query.encode(&mut sent);
+
+        let mut resp = sent.clone();
+        resp[2] |= 0x80;
+        resp.extend_from_slice(&[0xc0, 0x0c]);
+        assert!(is_response_to(&resp, &sent));
+
+        // The query itself, e.g. reflected by an attacker.
+        assert!(!is_response_to(&sent, &sent));
+
+        let mut other = resp.clone();
+        other[0] ^= 1;
+        assert!(!is_response_to(&other, &sent));
+
+        // A different case of the name.
+        let mut other = resp.clone();
+        other[13] ^= 0x20;
+        assert!(!is_response_to(&other, &sent));
+
+        assert!(!is_response_to(&resp[..sent.len() - 1], &sent));
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_responses_over_tcp() {
+        let server = MockServer::start(Script {
+            truncate: true,
+            ..Script::answer("example.com.", Ipv4Addr::new(192, 0, 2, 1))
+        })
+        .await;
+        let addr = server.addr;
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let resolver = UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_secs(5),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        );
+        let query = QueryProfile::FORWARDER.build_query(&Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        });
+
+        let resp = resolver.exchange(&query).await.unwrap();
+        let resp = Packet::decode(resp).unwrap();
+        assert!(!resp.truncated);
+        assert_eq!(times.get(id).unwrap().mismatched.load(Ordering::Relaxed), 0);
+        assert_eq!(resp.transaction_id, query.transaction_id);
+        assert_eq!(resp.questions, query.questions);
+        assert_eq!(resp.answers.len(), 1);
+        assert_eq!((server.udp_queries(), server.tcp_queries()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn receives_responses_up_to_payload_size() {
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = udp.local_addr().unwrap();
+
+        tokio::task::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, peer) = udp.recv_from(&mut buf).await.unwrap();
+            let query = Packet::decode(Bytes::copy_from_slice(&buf[..len])).unwrap();
+            assert_eq!(query.edns.unwrap().udp_payload_size, 4096);
+
+            // Larger than the default receive buffer.
+            let mut resp = buf[..len].to_vec();
+            resp[2] |= 0x80;
+            resp.resize(3000, 0);
+            udp.send_to(&resp, peer).await.unwrap();
+        });
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let profile = QueryProfile {
+            payload_size: Some(4096),
+            ..QueryProfile::FORWARDER
+        };
+        let resolver = UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_secs(5),
+            profile,
+            SocketOptions::default(),
+            None,
+        );
+        let query = profile.build_query(&Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        });
 
-        Ok(packet.answers)
+        let resp = resolver.exchange(&query).await.unwrap();
+        assert_eq!(resp.len(), 3000);
     }
 }
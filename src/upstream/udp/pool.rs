@@ -0,0 +1,339 @@
+//! Long-lived sockets shared by all queries to an upstream.
+//!
+//! Every socket has a task receiving its responses and passing them to the
+//! waiting queries by their transaction ID. Sockets are replaced regularly,
+//! so that the source port of the queries keeps changing.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::HashMap;
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use socket2::{Domain, Socket};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::bufpool;
+use crate::config::Dscp;
+use crate::dscp;
+use crate::metrics::UpstreamTime;
+use crate::upstream::SocketOptions;
+
+use super::is_response_to;
+
+/// Number of sockets per upstream.
+const POOL_SIZE: usize = 8;
+
+/// Time after which a socket is replaced.
+const MAX_SOCKET_AGE: Duration = Duration::from_secs(30);
+
+/// Number of queries after which a socket is replaced.
+const MAX_SOCKET_QUERIES: u32 = 1000;
+
+#[derive(Debug)]
+pub struct Pool {
+    addr: SocketAddr,
+    options: SocketOptions,
+    dscp: Option<Dscp>,
+    /// Capacity reserved for receiving a single response.
+    recv_size: usize,
+    metrics: Arc<UpstreamTime>,
+    sockets: Mutex<[Option<Arc<PooledSocket>>; POOL_SIZE]>,
+}
+
+impl Pool {
+    pub fn new(
+        addr: SocketAddr,
+        options: SocketOptions,
+        dscp: Option<Dscp>,
+        recv_size: usize,
+        metrics: Arc<UpstreamTime>,
+    ) -> Self {
+        Self {
+            addr,
+            options,
+            dscp,
+            recv_size,
+            metrics,
+            sockets: Mutex::new(Default::default()),
+        }
+    }
+
+    /// Sends the encoded `query` and returns the response to it.
+    ///
+    /// The header and question section of `query` are `query_len` bytes long.
+    /// The transaction ID is replaced while the query is in flight and
+    /// restored in the response.
+    pub async fn exchange(&self, query: &mut [u8], query_len: usize) -> io::Result<BytesMut> {
+        let socket = self.socket()?;
+        let transaction_id = [query[0], query[1]];
+
+        let (id, rx) = {
+            let mut pending = socket.shared.pending.lock();
+            let Some(pending) = pending.as_mut() else {
+                return Err(io::ErrorKind::ConnectionAborted.into());
+            };
+
+            let id = loop {
+                let id = rand::random();
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            query[..2].copy_from_slice(&u16::to_be_bytes(id));
+
+            let (tx, rx) = oneshot::channel();
+            pending.insert(
+                id,
+                Pending {
+                    sent: query[..query_len].to_vec(),
+                    tx,
+                },
+            );
+            (id, rx)
+        };
+
+        // Queries that are given up must not be answered.
+        let _guard = PendingGuard {
+            socket: &socket,
+            id,
+        };
+
+        socket.shared.socket.send(query).await?;
+        let mut resp = rx
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))??;
+        resp[..2].copy_from_slice(&transaction_id);
+        Ok(resp)
+    }
+
+    /// Returns a random socket of the pool, replacing it if it expired.
+    fn socket(&self) -> io::Result<Arc<PooledSocket>> {
+        let mut sockets = self.sockets.lock();
+        let slot = &mut sockets[rand::random::<usize>() % POOL_SIZE];
+        if let Some(socket) = slot.as_ref().filter(|socket| !socket.is_expired()) {
+            socket.queries.fetch_add(1, Ordering::Relaxed);
+            return Ok(socket.clone());
+        }
+
+        let socket = Arc::new(PooledSocket::new(
+            self.addr,
+            &self.options,
+            self.dscp,
+            self.recv_size,
+            self.metrics.clone(),
+        )?);
+        *slot = Some(socket.clone());
+        Ok(socket)
+    }
+}
+
+#[derive(Debug)]
+struct PooledSocket {
+    shared: Arc<Shared>,
+    created: Instant,
+    queries: AtomicU32,
+    task: JoinHandle<()>,
+}
+
+impl PooledSocket {
+    fn new(
+        addr: SocketAddr,
+        options: &SocketOptions,
+        dscp: Option<Dscp>,
+        recv_size: usize,
+        metrics: Arc<UpstreamTime>,
+    ) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), socket2::Type::DGRAM, None)?;
+        if let Some(interface) = &options.interface {
+            socket.bind_device(Some(interface.name().as_bytes()))?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&options.local_addr(addr).into())?;
+        socket.connect(&addr.into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        if let Some(dscp) = dscp {
+            dscp::set(&socket, dscp)?;
+        }
+
+        let shared = Arc::new(Shared {
+            socket,
+            pending: Mutex::new(Some(HashMap::default())),
+        });
+        let task = tokio::task::spawn(receive(shared.clone(), recv_size, metrics));
+
+        Ok(Self {
+            shared,
+            created: Instant::now(),
+            queries: AtomicU32::new(1),
+            task,
+        })
+    }
+
+    fn is_expired(&self) -> bool {
+        self.created.elapsed() >= MAX_SOCKET_AGE
+            || self.queries.load(Ordering::Relaxed) >= MAX_SOCKET_QUERIES
+            || self.shared.pending.lock().is_none()
+    }
+}
+
+impl Drop for PooledSocket {
+    fn drop(&mut self) {
+        // Nobody is waiting for responses anymore.
+        self.task.abort();
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    socket: UdpSocket,
+    /// The queries waiting for a response by their transaction ID. `None`
+    /// once the socket failed.
+    pending: Mutex<Option<HashMap<u16, Pending>>>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    /// The header and question section of the query.
+    sent: Vec<u8>,
+    tx: oneshot::Sender<io::Result<BytesMut>>,
+}
+
+/// Removes a query that is no longer waiting for its response.
+struct PendingGuard<'a> {
+    socket: &'a PooledSocket,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pending) = self.socket.shared.pending.lock().as_mut() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+/// Passes the responses received on the socket to the waiting queries.
+async fn receive(shared: Arc<Shared>, recv_size: usize, metrics: Arc<UpstreamTime>) {
+    loop {
+        let mut buf = bufpool::get();
+        buf.reserve(recv_size);
+        if let Err(err) = shared.socket.recv_buf(&mut *buf).await {
+            // Errors of connected sockets, e.g. an unreachable port, don't
+            // belong to a specific query. All of them fail and the socket is
+            // replaced.
+            let pending = shared.pending.lock().take().unwrap_or_default();
+            for (_, pending) in pending {
+                let _ = pending
+                    .tx
+                    .send(Err(io::Error::new(err.kind(), err.to_string())));
+            }
+            return;
+        }
+
+        // Anyone can send datagrams to our port. Responses that don't match
+        // a query are discarded.
+        // See https://datatracker.ietf.org/doc/html/rfc5452#section-9.1
+        let mut pending = shared.pending.lock();
+        let pending = pending.as_mut().unwrap();
+        let id = buf
+            .get(..2)
+            .map(|id| u16::from_be_bytes([id[0], id[1]]))
+            .filter(|id| {
+                pending
+                    .get(id)
+                    .is_some_and(|pending| is_response_to(&buf, &pending.sent))
+            });
+
+        match id.and_then(|id| pending.remove(&id)) {
+            Some(pending) => {
+                let _ = pending.tx.send(Ok(buf.split()));
+            }
+            None => {
+                tracing::debug!(
+                    "discarding mismatched response from upstream {}",
+                    metrics.addr
+                );
+                metrics.mismatched.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+
+    use tokio::net::UdpSocket;
+
+    use crate::bufpool;
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Packet, Question, Type};
+    use crate::upstream::{QueryProfile, SocketOptions};
+
+    use super::Pool;
+
+    #[tokio::test]
+    async fn demultiplexes_responses() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::task::spawn(async move {
+            let mut queries = Vec::new();
+            for _ in 0..2 {
+                let mut buf = vec![0; 512];
+                let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+                buf.truncate(len);
+                buf[2] |= 0x80;
+                queries.push((buf, peer));
+            }
+
+            // A response with the ID of a query, but a different question.
+            let (spoofed, peer) = &queries[0];
+            let mut spoofed = spoofed.clone();
+            spoofed[13] ^= 0x20;
+            server.send_to(&spoofed, peer).await.unwrap();
+
+            for (resp, peer) in queries.iter().rev() {
+                server.send_to(resp, peer).await.unwrap();
+            }
+        });
+
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        let pool = Pool::new(
+            addr,
+            SocketOptions::default(),
+            None,
+            bufpool::RECV_SIZE,
+            times.get(id).unwrap(),
+        );
+
+        let exchange = |name: &str| {
+            let query = QueryProfile::FORWARDER.build_query(&Question {
+                name: Fqdn(name.as_bytes().to_vec()),
+                qtype: Type::A,
+                qclass: Class::In,
+            });
+            let pool = &pool;
+            async move {
+                let mut buf = Vec::new();
+                query.encode(&mut buf);
+                let len = buf.len();
+                let resp = pool.exchange(&mut buf, len).await.unwrap();
+                let resp = Packet::decode(resp.freeze()).unwrap();
+                assert_eq!(resp.transaction_id, query.transaction_id);
+                assert_eq!(resp.questions, query.questions);
+            }
+        };
+
+        futures::join!(exchange("a.example."), exchange("b.example."));
+        assert_eq!(times.get(id).unwrap().mismatched.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,262 @@
+//! Opportunistic upgrade of the queries to an upstream from UDP to DNS over
+//! TLS.
+//!
+//! The TLS port of the upstream is probed in the background. Once it answers,
+//! queries are sent over TLS instead. Queries fall back to UDP whenever TLS
+//! fails, until the port is probed again.
+//!
+//! See https://datatracker.ietf.org/doc/html/rfc9539
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::proto::{Class, Fqdn, Packet, Question, Type};
+use crate::upstream::tcp::{TcpResolver, Tls};
+use crate::upstream::with_timeout;
+
+/// How queries to an upstream are upgraded to DNS over TLS.
+#[derive(Clone, Debug)]
+pub struct TlsUpgrade {
+    /// The port of DNS over TLS on the upstream.
+    pub port: u16,
+    /// Time until the port is probed again after TLS failed.
+    pub retry: Duration,
+    pub tls: Tls,
+}
+
+#[derive(Debug)]
+pub struct Upgrade {
+    tls: Arc<TcpResolver>,
+    retry: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum State {
+    /// Queries are sent over TLS.
+    Up,
+    /// The TLS port is being probed.
+    Probing,
+    /// Queries are sent over UDP until the TLS port is probed again at the
+    /// given time.
+    Down(Instant),
+}
+
+impl Upgrade {
+    pub fn new(tls: TcpResolver, retry: Duration) -> Self {
+        Self {
+            tls: Arc::new(tls),
+            retry,
+            state: Arc::new(Mutex::new(State::Down(Instant::now()))),
+        }
+    }
+
+    /// Sends `query` over TLS and returns the raw response, or `None` if it
+    /// must be sent over UDP instead.
+    pub async fn exchange(&self, query: &Packet) -> Option<Bytes> {
+        {
+            let mut state = self.state.lock();
+            match *state {
+                State::Up => (),
+                State::Down(at) if at <= Instant::now() => {
+                    *state = State::Probing;
+                    self.probe();
+                    return None;
+                }
+                State::Probing | State::Down(_) => return None,
+            }
+        }
+
+        // Leave time to repeat the query over UDP.
+        match with_timeout(self.tls.timeout / 2, self.tls.exchange(query)).await {
+            Ok(resp) => Some(resp),
+            Err(err) => {
+                tracing::debug!(
+                    "falling back to UDP for upstream {}: {:?}",
+                    self.tls.addr,
+                    err
+                );
+                let mut state = self.state.lock();
+                if matches!(*state, State::Up) {
+                    *state = State::Down(Instant::now() + self.retry);
+                }
+                None
+            }
+        }
+    }
+
+    /// Probes the TLS port in the background with a query for the NS
+    /// records of the root.
+    fn probe(&self) {
+        let tls = self.tls.clone();
+        let state = self.state.clone();
+        let retry = self.retry;
+        tokio::task::spawn(async move {
+            let query = tls.profile.build_query(&Question {
+                name: Fqdn(b".".to_vec()),
+                qtype: Type::NS,
+                qclass: Class::In,
+            });
+
+            let res = with_timeout(tls.timeout, tls.exchange(&query)).await;
+            *state.lock() = match res {
+                Ok(_) => {
+                    tracing::info!("upgraded upstream {} to DNS over TLS", tls.addr);
+                    State::Up
+                }
+                Err(err) => {
+                    tracing::debug!("upstream {} doesn't support TLS: {:?}", tls.addr, err);
+                    State::Down(Instant::now() + retry)
+                }
+            };
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::frontend::tls::load_cert;
+    use crate::metrics::UpstreamTimes;
+    use crate::proto::{Class, Fqdn, Packet, Question, Type};
+    use crate::upstream::https::tls;
+    use crate::upstream::mock::{MockServer, Script};
+    use crate::upstream::tcp::Tls;
+    use crate::upstream::udp::UdpResolver;
+    use crate::upstream::{QueryProfile, SocketOptions};
+
+    use super::TlsUpgrade;
+
+    /// Starts a DNS over TLS server that echoes the queries, and returns its
+    /// port and the number of queries it received.
+    async fn serve_tls() -> (u16, Arc<AtomicUsize>) {
+        let (certs, key) = load_cert(
+            Path::new("testdata/certs/dot.pem"),
+            Path::new("testdata/certs/dot-key.pem"),
+        );
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let queries = Arc::new(AtomicUsize::new(0));
+        let count = queries.clone();
+        tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            loop {
+                let Ok(len) = stream.read_u16().await else {
+                    return;
+                };
+                let mut buf = vec![0; usize::from(len)];
+                stream.read_exact(&mut buf).await.unwrap();
+                count.fetch_add(1, Ordering::Relaxed);
+
+                buf[2] |= 0x80;
+                stream.write_u16(len).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        (port, queries)
+    }
+
+    fn resolver(addr: SocketAddr, port: u16) -> UdpResolver {
+        let times = UpstreamTimes::default();
+        let id = times.register(&addr.to_string());
+        UdpResolver::new(
+            id,
+            times.get(id).unwrap(),
+            addr,
+            Duration::from_secs(5),
+            QueryProfile::FORWARDER,
+            SocketOptions::default(),
+            None,
+        )
+        .with_tls_upgrade(TlsUpgrade {
+            port,
+            retry: Duration::from_secs(60),
+            tls: Tls {
+                config: Arc::new(tls::dot_client_config(tls::opportunistic_verifier())),
+                server_name: ServerName::IpAddress(addr.ip().into()),
+            },
+        })
+    }
+
+    fn query() -> Packet {
+        QueryProfile::FORWARDER.build_query(&Question {
+            name: Fqdn(b"example.com.".to_vec()),
+            qtype: Type::A,
+            qclass: Class::In,
+        })
+    }
+
+    #[tokio::test]
+    async fn upgrades_to_tls() {
+        let server = MockServer::start(Script::default()).await;
+        let (port, tls_queries) = serve_tls().await;
+        let resolver = resolver(server.addr, port);
+
+        // The first query is sent over UDP while the TLS port is probed.
+        let query = query();
+        let resp = Packet::decode(resolver.exchange(&query).await.unwrap()).unwrap();
+        assert_eq!(resp.transaction_id, query.transaction_id);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(tls_queries.load(Ordering::Relaxed), 1);
+
+        let resp = Packet::decode(resolver.exchange(&query).await.unwrap()).unwrap();
+        assert_eq!(resp.transaction_id, query.transaction_id);
+        assert_eq!(resp.questions, query.questions);
+        assert_eq!(tls_queries.load(Ordering::Relaxed), 2);
+        assert_eq!(server.udp_queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_udp() {
+        let server = MockServer::start(Script::default()).await;
+
+        // Closes connections instead of accepting TLS.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = connections.clone();
+        tokio::task::spawn(async move {
+            while listener.accept().await.is_ok() {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let resolver = resolver(server.addr, port);
+        for _ in 0..3 {
+            let query = query();
+            let resp = Packet::decode(resolver.exchange(&query).await.unwrap()).unwrap();
+            assert_eq!(resp.transaction_id, query.transaction_id);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // The port is not probed again until the retry is due.
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+        assert_eq!(server.udp_queries(), 3);
+    }
+}